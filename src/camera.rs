@@ -6,6 +6,7 @@ use std::ops::Add;
 use std::ops::Sub;
 use winit;
 
+#[derive(Clone)]
 pub struct Camera {
     position: Vector3<f32>,
     front: Vector3<f32>,
@@ -44,13 +45,7 @@ impl Camera {
         let yaw = -90.0;
         let pitch = 0.0;
 
-        let x: f32 = Rad::cos(Rad::from(Deg(yaw))) * Rad::cos(Rad::from(Deg(pitch)));
-        let y: f32 = Rad::sin(Rad::from(Deg(pitch)));
-        let z: f32 = Rad::sin(Rad::from(Deg(yaw))) * Rad::cos(Rad::from(Deg(pitch)));
-        let front = InnerSpace::normalize(Vector3::new(x, y, z));
-
-        let right = InnerSpace::normalize(front.cross(world_up));
-        let up = InnerSpace::normalize(right.cross(front));
+        let (front, right, up) = Camera::direction_vectors(yaw, pitch, world_up);
 
         Camera {
             position,
@@ -67,6 +62,69 @@ impl Camera {
         }
     }
 
+    /// Replaces the projection with one of the same field of view for a `width / height` of
+    /// `aspect_ratio`.
+    pub fn set_aspect_ratio(&mut self, aspect_ratio: f32) {
+        self.projection = cgmath::perspective(
+            cgmath::Rad(std::f32::consts::FRAC_PI_2),
+            aspect_ratio,
+            0.01,
+            100.0,
+        );
+    }
+
+    fn direction_vectors(
+        yaw: f32,
+        pitch: f32,
+        world_up: Vector3<f32>,
+    ) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+        let x: f32 = Rad::cos(Rad::from(Deg(yaw))) * Rad::cos(Rad::from(Deg(pitch)));
+        let y: f32 = Rad::sin(Rad::from(Deg(pitch)));
+        let z: f32 = Rad::sin(Rad::from(Deg(yaw))) * Rad::cos(Rad::from(Deg(pitch)));
+        let front = InnerSpace::normalize(Vector3::new(x, y, z));
+
+        let right = InnerSpace::normalize(front.cross(world_up));
+        let up = InnerSpace::normalize(right.cross(front));
+
+        (front, right, up)
+    }
+
+    #[inline]
+    pub fn position(&self) -> Vector3<f32> {
+        self.position
+    }
+
+    #[inline]
+    pub fn set_position(&mut self, position: Vector3<f32>) {
+        self.position = position;
+    }
+
+    /// Yaw and pitch in degrees.
+    #[inline]
+    pub fn orientation(&self) -> (f32, f32) {
+        (self.yaw, self.pitch)
+    }
+
+    pub fn set_orientation(&mut self, yaw: f32, pitch: f32) {
+        self.yaw = yaw;
+        self.pitch = pitch.max(-89.0).min(89.0);
+
+        let (front, right, up) = Camera::direction_vectors(self.yaw, self.pitch, self.world_up);
+        self.front = front;
+        self.right = right;
+        self.up = up;
+    }
+
+    #[inline]
+    pub fn movement_speed(&self) -> f32 {
+        self.movement_speed
+    }
+
+    #[inline]
+    pub fn set_movement_speed(&mut self, movement_speed: f32) {
+        self.movement_speed = movement_speed;
+    }
+
     pub fn view_matrix(&self) -> Matrix4<f32> {
         let eye = Point3::new(self.position.x, self.position.y, self.position.z);
         let center = self.position.add(self.front);
@@ -106,3 +164,17 @@ impl Camera {
         };
     }
 }
+
+/// An orthographic projection showing `half_width` and `half_height` around the view axis,
+/// with depth in the `0..1` range of Vulkan. `cgmath::ortho` maps depth to -1..1 like OpenGL,
+/// which would clip away everything in the nearer half. Perspective projections get away with
+/// it as their nearer half is squeezed right in front of the near plane.
+pub fn orthographic(half_width: f32, half_height: f32, near: f32, far: f32) -> Matrix4<f32> {
+    let depth_to_vulkan = Matrix4::new(
+        1.0, 0.0, 0.0, 0.0,
+        0.0, 1.0, 0.0, 0.0,
+        0.0, 0.0, 0.5, 0.0,
+        0.0, 0.0, 0.5, 1.0,
+    );
+    depth_to_vulkan * cgmath::ortho(-half_width, half_width, -half_height, half_height, near, far)
+}
@@ -0,0 +1,1467 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use cgmath::{Deg, InnerSpace, Matrix4, Point3, Vector3};
+use vulkano::buffer::BufferAccess;
+use vulkano::buffer::BufferSlice;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::buffer::DeviceLocalBuffer;
+use vulkano::command_buffer::AutoCommandBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::DynamicState;
+use vulkano::descriptor::descriptor_set::DescriptorSet;
+use vulkano::descriptor::descriptor_set::FixedSizeDescriptorSetsPool;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Device;
+use vulkano::device::Queue;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::blend::BlendFactor;
+use vulkano::pipeline::blend::BlendOp;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::pipeline::ComputePipelineAbstract;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+use camera;
+use cgmath;
+use frame;
+use frame::RenderMode;
+
+/// Everything the demo draws, shared by the window, its split screen viewports, the render
+/// targets and the headless run.
+pub struct DemoScene {
+    pub geometry: DemoGeometry,
+    pub wave: WaveGrid,
+    pub particles: frame::ParticleSystem,
+    pub instances: frame::InstanceRenderer,
+    pub forest: Forest,
+    pub gpu_scene: frame::GpuScene,
+    pub transparent_quads: TransparentQuads,
+    /// Empty until `add_screens`.
+    pub screens: frame::ScreenRenderer,
+    /// Whether the waves and particles are simulated and drawn. They move with time, so the
+    /// golden images leave them out except in the preset about them.
+    pub animated: bool,
+}
+
+impl DemoScene {
+    /// Builds the pipelines against the subpasses of `frame_system`. They work with any frame
+    /// system of the same depth format.
+    pub fn new(queue: Arc<Queue>, frame_system: &frame::FrameSystem) -> DemoScene {
+        let geometry = DemoGeometry::new(
+            queue.clone(),
+            frame_system.deferred_render_pass(),
+            frame_system.selection_render_pass(),
+        );
+        let wave = WaveGrid::new(queue.clone(), frame_system.deferred_render_pass());
+        let particles = particles(queue.clone(), frame_system.transparent_render_pass());
+        let mut instances = frame::InstanceRenderer::new(queue.clone(), frame_system.deferred_render_pass());
+        let forest = Forest::new(&mut instances);
+        let mut gpu_scene = frame::GpuScene::new(queue.clone(), frame_system.deferred_render_pass());
+        forest.add_objects(&mut gpu_scene);
+        let screens = frame::ScreenRenderer::new(queue.clone(), frame_system.deferred_render_pass());
+        let transparent_quads = TransparentQuads::new(
+            queue,
+            frame_system.transparent_render_pass(),
+            frame_system.weighted_blended_render_pass(),
+        );
+
+        DemoScene {
+            geometry,
+            wave,
+            particles,
+            instances,
+            forest,
+            gpu_scene,
+            transparent_quads,
+            screens,
+            animated: true,
+        }
+    }
+
+    /// Adds a mirror behind the test geometry and a monitor to its left showing it from a
+    /// security camera, with the render targets they show in `targets`.
+    pub fn add_screens(&mut self, targets: &mut frame::RenderTargets) {
+        let mirror_model =
+            Matrix4::from_translation(Vector3::new(0.0, 0.3, -2.5)) * Matrix4::from_nonuniform_scale(3.0, 1.8, 1.0);
+        let mirror = targets.add(frame::TargetCamera::mirror(&mirror_model), [640, 360]);
+        self.screens.add(frame::Screen {
+            target: mirror,
+            kind: frame::ScreenKind::Mirror,
+            model: mirror_model,
+            brightness: 0.9,
+        });
+
+        // Low resolution, like a real one.
+        let security_camera = frame::TargetCamera::Fixed {
+            view: Matrix4::look_at(Point3::new(2.5, 1.5, 2.0), Point3::new(0.0, 0.0, 0.0), Vector3::unit_y()),
+            projection: cgmath::perspective(Deg(60.0), 4.0 / 3.0, 0.05, 50.0),
+        };
+        let monitor = targets.add(security_camera, [160, 120]);
+        self.screens.add(frame::Screen {
+            target: monitor,
+            kind: frame::ScreenKind::Monitor,
+            model: Matrix4::from_translation(Vector3::new(-2.2, 0.2, -0.8))
+                * Matrix4::from_angle_y(Deg(30.0))
+                * Matrix4::from_nonuniform_scale(1.2, 0.9, 1.0),
+            brightness: 1.0,
+        });
+    }
+
+    /// Draws the scene into the passes of a frame looking at it like `scene_view`. The waves
+    /// and particles only advance with `simulate`, so they move once per frame however many
+    /// viewports and render targets show them. Passes that aren't about the scene are handed
+    /// back.
+    pub fn draw_pass<'f, 's>(
+        &mut self,
+        pass: frame::Pass<'f, 's>,
+        scene_view: &frame::SceneView,
+        simulate: bool,
+    ) -> Option<frame::Pass<'f, 's>> {
+        let (view, projection) = (scene_view.view, scene_view.projection);
+        let dimensions = scene_view.dimensions;
+        let settings = &scene_view.settings;
+        match pass {
+            frame::Pass::Compute(mut compute_pass) => {
+                if simulate && self.animated {
+                    self.wave.update(&mut compute_pass);
+                    self.particles.simulate(&mut compute_pass);
+                }
+                if settings.culling.gpu_driven {
+                    self.gpu_scene.update(&mut compute_pass, &settings.culling);
+                }
+            }
+            frame::Pass::Deferred(mut draw_pass) => {
+                let sun_buffer = draw_pass.sun_buffer();
+                draw_pass.execute(self.geometry.draw(
+                    dimensions,
+                    view,
+                    projection,
+                    settings.render_mode,
+                    sun_buffer.clone(),
+                ));
+                if self.animated {
+                    draw_pass.execute(self.wave.draw(dimensions, view, projection, sun_buffer.clone()));
+                }
+                if settings.culling.gpu_driven {
+                    draw_pass.execute(self.gpu_scene.draw(dimensions, view, projection, sun_buffer));
+                } else {
+                    self.forest.add_instances(&mut self.instances);
+                    draw_pass.execute(self.instances.draw(dimensions, view, projection, sun_buffer));
+                }
+                draw_pass.execute(self.screens.draw(scene_view));
+            }
+            frame::Pass::Transparent(mut draw_pass) => {
+                if !settings.transparency.order_independent {
+                    draw_pass.execute(self.transparent_quads.draw_sorted(dimensions, view, projection));
+                }
+                if self.animated {
+                    draw_pass.execute(self.particles.draw(dimensions, view, projection));
+                }
+            }
+            frame::Pass::WeightedBlended(mut draw_pass) => {
+                draw_pass.execute(
+                    self.transparent_quads
+                        .draw_weighted_blended(dimensions, view, projection),
+                );
+            }
+            frame::Pass::Selection(mut draw_pass) => {
+                draw_pass.execute(self.geometry.draw_selection(dimensions, view, projection));
+            }
+            pass => return Some(pass),
+        }
+        None
+    }
+}
+
+/// Half the width of the ground shown by `minimap_target`.
+const MINIMAP_EXTENT: f32 = 6.0;
+
+/// Adds a square target `size` pixels wide looking straight down on the scene, for a
+/// picture-in-picture minimap.
+pub fn minimap_target(targets: &mut frame::RenderTargets, size: u32) -> frame::TargetId {
+    let top_down = frame::TargetCamera::Fixed {
+        view: Matrix4::look_at(
+            Point3::new(0.0, 20.0, 0.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.0, 0.0, -1.0),
+        ),
+        projection: camera::orthographic(MINIMAP_EXTENT, MINIMAP_EXTENT, 0.1, 40.0),
+    };
+    targets.add(top_down, [size, size])
+}
+
+/// The test geometry, drawn in the deferred pass by both the windowed and the headless runs.
+pub struct DemoGeometry {
+    queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    objects: Vec<DemoObject>,
+    pipelines: GeometryPipelines,
+    selection_pipelines: SelectionPipelines,
+    uniform_buffer_pool: CpuBufferPool<vs::ty::bufferVals>,
+    ds_pool: FixedSizeDescriptorSetsPool<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    selection_ds_pool: FixedSizeDescriptorSetsPool<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+}
+
+/// A part of the demo geometry that can be selected on its own.
+#[derive(Debug, Clone)]
+pub struct DemoObject {
+    pub name: &'static str,
+    /// Outlined in `frame::Pass::Selection`.
+    pub selected: bool,
+    vertices: Range<usize>,
+}
+
+/// The two draws of selected objects in `frame::Pass::Selection`, which has no color
+/// attachment.
+struct SelectionPipelines {
+    silhouette: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    visible: Arc<GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl SelectionPipelines {
+    fn new<R>(device: &Arc<Device>, subpass: Subpass<R>) -> SelectionPipelines
+    where
+        R: RenderPassAbstract + Clone + Send + Sync + 'static,
+    {
+        let vs = vs::Shader::load(device.clone()).expect("Could not create shader module");
+        let fs = selection_fs::Shader::load(device.clone()).expect("Could not create shader module");
+
+        let pipeline = |depth_stencil: DepthStencil| -> Arc<GraphicsPipelineAbstract + Send + Sync> {
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input_single_buffer::<Vertex>()
+                    .vertex_shader(vs.main_entry_point(), ())
+                    .triangle_list()
+                    .viewports_dynamic_scissors_irrelevant(1)
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .depth_stencil(depth_stencil)
+                    .render_pass(subpass.clone())
+                    .build(device.clone())
+                    .unwrap(),
+            )
+        };
+
+        SelectionPipelines {
+            silhouette: pipeline(frame::selection_silhouette_stencil()),
+            visible: pipeline(frame::selection_visible_stencil()),
+        }
+    }
+}
+
+/// Variants of the geometry pipeline for the `frame::RenderMode`s. They share their shaders,
+/// the fragment shader's mode is picked with a push constant.
+struct GeometryPipelines {
+    shaded: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    /// Drawn as lines if the device supports it, otherwise filled with everything but the
+    /// edges discarded.
+    wireframe: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    /// Like `wireframe`, drawn on top of the shaded geometry without writing depth.
+    overlay: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    overdraw: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    lines_supported: bool,
+}
+
+impl GeometryPipelines {
+    fn new<R>(device: &Arc<Device>, subpass: Subpass<R>) -> GeometryPipelines
+    where
+        R: RenderPassAbstract + Clone + Send + Sync + 'static,
+    {
+        let (vs, fs) = create_shader_modules(device);
+        let lines_supported = device.enabled_features().fill_mode_non_solid;
+
+        let shaded = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil_simple_depth()
+                .render_pass(subpass.clone())
+                .build(device.clone())
+                .unwrap(),
+        ) as Arc<_>;
+
+        let edges = |depth_stencil: DepthStencil| -> Arc<GraphicsPipelineAbstract + Send + Sync> {
+            let builder = GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil(depth_stencil)
+                .render_pass(subpass.clone());
+            if lines_supported {
+                Arc::new(builder.polygon_mode_line().build(device.clone()).unwrap())
+            } else {
+                Arc::new(builder.build(device.clone()).unwrap())
+            }
+        };
+        let wireframe = edges(DepthStencil::simple_depth_test());
+        let overlay = edges(DepthStencil {
+            depth_write: false,
+            depth_compare: Compare::LessOrEqual,
+            ..DepthStencil::disabled()
+        });
+
+        let overdraw = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .blend_collective(AttachmentBlend {
+                    enabled: true,
+                    color_op: BlendOp::Add,
+                    color_source: BlendFactor::One,
+                    color_destination: BlendFactor::One,
+                    alpha_op: BlendOp::Add,
+                    alpha_source: BlendFactor::One,
+                    alpha_destination: BlendFactor::One,
+                    ..AttachmentBlend::pass_through()
+                })
+                .render_pass(subpass.clone())
+                .build(device.clone())
+                .unwrap(),
+        ) as Arc<_>;
+
+        GeometryPipelines {
+            shaded,
+            wireframe,
+            overlay,
+            overdraw,
+            lines_supported,
+        }
+    }
+
+    /// The pipelines to draw with in `mode`, one after the other, with their fragment modes.
+    fn passes(&self, mode: RenderMode) -> Vec<(Arc<GraphicsPipelineAbstract + Send + Sync>, i32, [f32; 4])> {
+        // A zero alpha line color means the vertex colors.
+        match mode {
+            RenderMode::Shaded => vec![(self.shaded.clone(), FRAGMENT_SHADED, [0.0; 4])],
+            RenderMode::Wireframe => vec![(self.wireframe.clone(), FRAGMENT_EDGES, [0.0; 4])],
+            RenderMode::ShadedWireframe => vec![
+                (self.shaded.clone(), FRAGMENT_SHADED, [0.0; 4]),
+                (self.overlay.clone(), FRAGMENT_EDGES, [0.02, 0.02, 0.02, 1.0]),
+            ],
+            RenderMode::Normals => vec![(self.shaded.clone(), FRAGMENT_NORMALS, [0.0; 4])],
+            RenderMode::LinearDepth => vec![(self.shaded.clone(), FRAGMENT_LINEAR_DEPTH, [0.0; 4])],
+            RenderMode::UvChecker => vec![(self.shaded.clone(), FRAGMENT_UV_CHECKER, [0.0; 4])],
+            RenderMode::Overdraw => vec![(self.overdraw.clone(), FRAGMENT_OVERDRAW, [0.0; 4])],
+            // The frame system replaces the colors with the occlusion.
+            RenderMode::AmbientOcclusion => vec![(self.shaded.clone(), FRAGMENT_SHADED, [0.0; 4])],
+        }
+    }
+}
+
+// Fragment shader modes, see `fs`.
+const FRAGMENT_SHADED: i32 = 0;
+const FRAGMENT_EDGES: i32 = 1;
+const FRAGMENT_NORMALS: i32 = 2;
+const FRAGMENT_LINEAR_DEPTH: i32 = 3;
+const FRAGMENT_UV_CHECKER: i32 = 4;
+const FRAGMENT_OVERDRAW: i32 = 5;
+
+impl DemoGeometry {
+    pub fn new<R, S>(queue: Arc<Queue>, subpass: Subpass<R>, selection_subpass: Subpass<S>) -> DemoGeometry
+    where
+        R: RenderPassAbstract + Clone + Send + Sync + 'static,
+        S: RenderPassAbstract + Clone + Send + Sync + 'static,
+    {
+        // Texture coordinates go around each triangle's corners, so they double as barycentric
+        // coordinates for drawing edges.
+        let corners = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]];
+        // Flat shaded, each triangle's vertices share its normal.
+        let vertices: Vec<Vertex> = [
+            ([-0.5, -0.25, -0.5], [1.0, 0.0, 0.0, 1.0]),
+            ([0.0, 0.5, 1.0], [0.0, 1.0, 0.0, 1.0]),
+            ([0.25, -0.1, 0.0], [0.0, 0.0, 1.0, 1.0]),
+            ([0.0, 0.5, 1.0], [0.0, 1.0, 0.0, 1.0]),
+            ([0.25, -0.1, 0.0], [0.0, 0.0, 1.0, 1.0]),
+            ([0.5, 0.5, 0.0], [0.0, 0.0, 1.0, 1.0]),
+            // Emissive triangle, bright enough to bloom
+            ([0.5, 0.5, 0.0], [0.0, 0.0, 4.0, 1.0]),
+            ([1.5, 1.5, 0.0], [0.0, 0.0, 4.0, 1.0]),
+            ([0.5, 1.5, 0.0], [0.0, 4.0, 4.0, 1.0]),
+        ].chunks(3)
+            .flat_map(|triangle| {
+                let [a, b, c] = [triangle[0].0, triangle[1].0, triangle[2].0];
+                let (pa, pb, pc) = (Vector3::from(a), Vector3::from(b), Vector3::from(c));
+                let normal = (pb - pa).cross(pc - pa).normalize();
+                triangle.iter().zip(&corners).map(move |(&(pos, color), &uv)| Vertex {
+                    pos,
+                    color,
+                    uv,
+                    normal: normal.into(),
+                })
+            })
+            .collect();
+        let vertex_buffer =
+            CpuAccessibleBuffer::from_iter(queue.device().clone(), BufferUsage::all(), vertices.into_iter())
+                .expect("Failed to create vertex buffer");
+
+        let objects = vec![
+            DemoObject {
+                name: "quad",
+                selected: false,
+                vertices: 0..6,
+            },
+            DemoObject {
+                name: "emissive triangle",
+                selected: false,
+                vertices: 6..9,
+            },
+        ];
+
+        let pipelines = GeometryPipelines::new(queue.device(), subpass);
+        if !pipelines.lines_supported {
+            println!("fill_mode_non_solid isn't supported, wireframes are drawn as filled triangles");
+        }
+        let selection_pipelines = SelectionPipelines::new(queue.device(), selection_subpass);
+
+        DemoGeometry {
+            uniform_buffer_pool: CpuBufferPool::uniform_buffer(queue.device().clone()),
+            ds_pool: FixedSizeDescriptorSetsPool::new(pipelines.shaded.clone(), 0),
+            selection_ds_pool: FixedSizeDescriptorSetsPool::new(selection_pipelines.silhouette.clone(), 0),
+            queue,
+            vertex_buffer,
+            objects,
+            pipelines,
+            selection_pipelines,
+        }
+    }
+
+    #[inline]
+    pub fn objects(&self) -> &[DemoObject] {
+        &self.objects
+    }
+
+    #[inline]
+    pub fn objects_mut(&mut self) -> &mut [DemoObject] {
+        &mut self.objects
+    }
+
+    /// For `frame::Pass::Selection`, draws the selected objects with both selection pipelines.
+    pub fn draw_selection(
+        &mut self,
+        dimensions: [u32; 2],
+        model_view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+    ) -> AutoCommandBuffer {
+        let uniform_buffer = self
+            .uniform_buffer_pool
+            .next(vs::ty::bufferVals {
+                mvp: (projection * model_view).into(),
+                model_view: model_view.into(),
+            })
+            .unwrap();
+        let descriptor_set = Arc::new(
+            self.selection_ds_pool
+                .next()
+                .add_buffer(uniform_buffer)
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            self.selection_pipelines.silhouette.clone().subpass(),
+        ).unwrap();
+        for pipeline in &[&self.selection_pipelines.silhouette, &self.selection_pipelines.visible] {
+            for object in self.objects.iter().filter(|object| object.selected) {
+                let vertices = BufferSlice::from_typed_buffer_access(self.vertex_buffer.clone())
+                    .slice(object.vertices.clone())
+                    .unwrap();
+                builder = builder
+                    .draw(
+                        (*pipeline).clone(),
+                        dynamic_state.clone(),
+                        vec![Arc::new(vertices) as Arc<BufferAccess + Send + Sync>],
+                        descriptor_set.clone(),
+                        (),
+                    )
+                    .unwrap();
+            }
+        }
+        builder.build().unwrap()
+    }
+
+    /// `sun_buffer` holds the `frame::SunUniforms` the triangles are lit with.
+    pub fn draw(
+        &mut self,
+        dimensions: [u32; 2],
+        model_view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+        mode: RenderMode,
+        sun_buffer: Arc<BufferAccess + Send + Sync>,
+    ) -> AutoCommandBuffer {
+        let uniform_buffer = self
+            .uniform_buffer_pool
+            .next(vs::ty::bufferVals {
+                mvp: (projection * model_view).into(),
+                model_view: model_view.into(),
+            })
+            .unwrap();
+        let descriptor_set = Arc::new(
+            self.ds_pool
+                .next()
+                .add_buffer(uniform_buffer)
+                .unwrap()
+                .add_buffer(sun_buffer)
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            self.pipelines.shaded.clone().subpass(),
+        ).unwrap();
+        for (pipeline, fragment_mode, line_color) in self.pipelines.passes(mode) {
+            let push_constants = fs::ty::PushConstants {
+                line_color,
+                mode: fragment_mode,
+                edges_from_uv: !self.pipelines.lines_supported as i32,
+            };
+            builder = builder
+                .draw(
+                    pipeline,
+                    dynamic_state.clone(),
+                    vec![self.vertex_buffer.clone()],
+                    descriptor_set.clone(),
+                    push_constants,
+                )
+                .unwrap();
+        }
+        builder.build().unwrap()
+    }
+}
+
+/// A translucent quad of the demo scene.
+#[derive(Debug, Clone)]
+struct TransparentQuad {
+    model: Matrix4<f32>,
+    color: [f32; 4],
+}
+
+impl TransparentQuad {
+    fn center(&self) -> Point3<f32> {
+        Point3::new(self.model.w.x, self.model.w.y, self.model.w.z)
+    }
+}
+
+/// Translucent quads, two of them crossing each other, drawn either sorted back to front or
+/// with weighted blended order independent transparency.
+pub struct TransparentQuads {
+    queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[QuadVertex]>>,
+    sorted_pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    weighted_blended_pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    quads: Vec<TransparentQuad>,
+}
+
+impl TransparentQuads {
+    pub fn new<R, W>(
+        queue: Arc<Queue>,
+        transparent_subpass: Subpass<R>,
+        weighted_blended_subpass: Subpass<W>,
+    ) -> TransparentQuads
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+        W: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let vertex_buffer = CpuAccessibleBuffer::from_iter(
+            queue.device().clone(),
+            BufferUsage::all(),
+            [
+                QuadVertex { pos: [-0.5, -0.5] },
+                QuadVertex { pos: [0.5, -0.5] },
+                QuadVertex { pos: [-0.5, 0.5] },
+                QuadVertex { pos: [0.5, 0.5] },
+            ].iter()
+                .cloned(),
+        ).expect("Failed to create vertex buffer");
+
+        let vs = quad_vs::Shader::load(queue.device().clone()).expect("Could not create shader module");
+        let sorted_fs = sorted_fs::Shader::load(queue.device().clone()).expect("Could not create shader module");
+        let weighted_blended_fs =
+            weighted_blended_fs::Shader::load(queue.device().clone()).expect("Could not create shader module");
+
+        // Tested against the opaque geometry, but without hiding each other.
+        let depth_stencil = DepthStencil {
+            depth_write: false,
+            depth_compare: Compare::Less,
+            ..DepthStencil::disabled()
+        };
+
+        let sorted_pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<QuadVertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_strip()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(sorted_fs.main_entry_point(), ())
+                .depth_stencil(depth_stencil.clone())
+                .blend_alpha_blending()
+                .render_pass(transparent_subpass)
+                .build(queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>;
+
+        let weighted_blended_pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<QuadVertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_strip()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(weighted_blended_fs.main_entry_point(), ())
+                .depth_stencil(depth_stencil)
+                .blend_individual(vec![frame::accumulation_blend(), frame::revealage_blend()].into_iter())
+                .render_pass(weighted_blended_subpass)
+                .build(queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>;
+
+        let quads = vec![
+            TransparentQuad {
+                model: Matrix4::from_translation(Vector3::new(-0.6, 0.2, 0.6)),
+                color: [1.0, 0.2, 0.2, 0.5],
+            },
+            TransparentQuad {
+                model: Matrix4::from_translation(Vector3::new(-0.3, 0.3, 0.9)),
+                color: [0.2, 1.0, 0.2, 0.5],
+            },
+            // These two cross, no order of them is right everywhere.
+            TransparentQuad {
+                model: Matrix4::from_translation(Vector3::new(0.8, 0.0, 0.8)) * Matrix4::from_angle_y(Deg(35.0)),
+                color: [0.2, 0.4, 1.0, 0.6],
+            },
+            TransparentQuad {
+                model: Matrix4::from_translation(Vector3::new(0.8, 0.0, 0.8)) * Matrix4::from_angle_y(Deg(-35.0)),
+                color: [1.0, 0.8, 0.2, 0.6],
+            },
+        ];
+
+        TransparentQuads {
+            queue,
+            vertex_buffer,
+            sorted_pipeline,
+            weighted_blended_pipeline,
+            quads,
+        }
+    }
+
+    /// For `Pass::Transparent`, sorts the quads back to front before drawing them.
+    pub fn draw_sorted(
+        &mut self,
+        dimensions: [u32; 2],
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+    ) -> AutoCommandBuffer {
+        frame::sort_back_to_front(&mut self.quads, view, TransparentQuad::center);
+        let pipeline = self.sorted_pipeline.clone();
+        self.draw(pipeline, dimensions, projection * view)
+    }
+
+    /// For `Pass::WeightedBlended`, in any order.
+    pub fn draw_weighted_blended(
+        &self,
+        dimensions: [u32; 2],
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+    ) -> AutoCommandBuffer {
+        let pipeline = self.weighted_blended_pipeline.clone();
+        self.draw(pipeline, dimensions, projection * view)
+    }
+
+    fn draw(
+        &self,
+        pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+        dimensions: [u32; 2],
+        view_projection: Matrix4<f32>,
+    ) -> AutoCommandBuffer {
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap();
+        for quad in &self.quads {
+            let push_constants = quad_vs::ty::PushConstants {
+                mvp: (view_projection * quad.model).into(),
+                color: quad.color,
+            };
+            builder = builder
+                .draw(
+                    pipeline.clone(),
+                    dynamic_state.clone(),
+                    vec![self.vertex_buffer.clone()],
+                    (),
+                    push_constants,
+                )
+                .unwrap();
+        }
+        builder.build().unwrap()
+    }
+}
+
+/// Vertices per side of the wave grid.
+const WAVE_RESOLUTION: u32 = 64;
+/// Matches `local_size_x` and `local_size_y` of `wave_cs`.
+const WAVE_LOCAL_SIZE: u32 = 8;
+
+/// A patch of waves below the test geometry, whose vertices are generated on the GPU every
+/// frame by a compute shader in `frame::Pass::Compute` and then drawn in the deferred pass.
+/// It's drawn shaded in every render mode.
+pub struct WaveGrid {
+    queue: Arc<Queue>,
+    vertex_buffer: Arc<DeviceLocalBuffer<[WaveVertex]>>,
+    index_buffer: Arc<CpuAccessibleBuffer<[u32]>>,
+    compute_pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    compute_set: Arc<DescriptorSet + Send + Sync>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    uniform_buffer_pool: CpuBufferPool<wave_vs::ty::Matrices>,
+    ds_pool: FixedSizeDescriptorSetsPool<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    model: Matrix4<f32>,
+    time: f32,
+}
+
+impl WaveGrid {
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> WaveGrid
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let device = queue.device().clone();
+
+        // Written by the compute shader, read as vertices.
+        let vertex_buffer = DeviceLocalBuffer::array(
+            device.clone(),
+            (WAVE_RESOLUTION * WAVE_RESOLUTION) as usize,
+            BufferUsage {
+                storage_buffer: true,
+                vertex_buffer: true,
+                ..BufferUsage::none()
+            },
+            Some(queue.family()),
+        ).expect("Failed to create wave vertex buffer");
+
+        // Two triangles per grid cell, which never change.
+        let mut indices = Vec::new();
+        for y in 0..WAVE_RESOLUTION - 1 {
+            for x in 0..WAVE_RESOLUTION - 1 {
+                let corner = y * WAVE_RESOLUTION + x;
+                indices.extend_from_slice(&[
+                    corner,
+                    corner + WAVE_RESOLUTION,
+                    corner + 1,
+                    corner + 1,
+                    corner + WAVE_RESOLUTION,
+                    corner + WAVE_RESOLUTION + 1,
+                ]);
+            }
+        }
+        let index_buffer =
+            CpuAccessibleBuffer::from_iter(device.clone(), BufferUsage::index_buffer(), indices.into_iter())
+                .expect("Failed to create wave index buffer");
+
+        let compute_pipeline = {
+            let cs = wave_cs::Shader::load(device.clone()).expect("Could not create shader module");
+            let pipeline = ComputePipeline::new(device.clone(), &cs.main_entry_point(), &()).unwrap();
+            Arc::new(pipeline) as Arc<ComputePipelineAbstract + Send + Sync>
+        };
+        let compute_set = Arc::new(
+            PersistentDescriptorSet::start(compute_pipeline.clone(), 0)
+                .add_buffer(vertex_buffer.clone())
+                .unwrap()
+                .build()
+                .unwrap(),
+        ) as Arc<_>;
+
+        let vs = wave_vs::Shader::load(device.clone()).expect("Could not create shader module");
+        let fs = wave_fs::Shader::load(device.clone()).expect("Could not create shader module");
+        let pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<WaveVertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil_simple_depth()
+                .render_pass(subpass)
+                .build(device.clone())
+                .unwrap(),
+        ) as Arc<GraphicsPipelineAbstract + Send + Sync>;
+
+        WaveGrid {
+            uniform_buffer_pool: CpuBufferPool::uniform_buffer(device.clone()),
+            ds_pool: FixedSizeDescriptorSetsPool::new(pipeline.clone(), 0),
+            queue,
+            vertex_buffer,
+            index_buffer,
+            compute_pipeline,
+            compute_set,
+            pipeline,
+            model: Matrix4::from_translation(Vector3::new(0.5, -0.6, 0.25)),
+            time: 0.0,
+        }
+    }
+
+    /// Advances the waves and regenerates the vertices, before they're drawn in this frame.
+    pub fn update(&mut self, compute_pass: &mut frame::ComputePass) {
+        if compute_pass.stage() != frame::ComputeStage::BeforeRendering {
+            return;
+        }
+        self.time += compute_pass.delta_time();
+
+        let groups = (WAVE_RESOLUTION + WAVE_LOCAL_SIZE - 1) / WAVE_LOCAL_SIZE;
+        compute_pass.dispatch(
+            [groups, groups, 1],
+            self.compute_pipeline.clone(),
+            self.compute_set.clone(),
+            wave_cs::ty::PushConstants {
+                time: self.time,
+                resolution: WAVE_RESOLUTION,
+                size: 3.0,
+                amplitude: 0.04,
+            },
+        );
+    }
+
+    /// For `frame::Pass::Deferred`, lit by the `frame::SunUniforms` in `sun_buffer`.
+    pub fn draw(
+        &mut self,
+        dimensions: [u32; 2],
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+        sun_buffer: Arc<BufferAccess + Send + Sync>,
+    ) -> AutoCommandBuffer {
+        let model_view = view * self.model;
+        let uniform_buffer = self
+            .uniform_buffer_pool
+            .next(wave_vs::ty::Matrices {
+                mvp: (projection * model_view).into(),
+                model_view: model_view.into(),
+                model: self.model.into(),
+            })
+            .unwrap();
+        let descriptor_set = self
+            .ds_pool
+            .next()
+            .add_buffer(uniform_buffer)
+            .unwrap()
+            .add_buffer(sun_buffer)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            self.pipeline.clone().subpass(),
+        ).unwrap()
+            .draw_indexed(
+                self.pipeline.clone(),
+                DynamicState {
+                    viewports: Some(vec![Viewport {
+                        origin: [0.0, 0.0],
+                        dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                        depth_range: 0.0..1.0,
+                    }]),
+                    ..DynamicState::none()
+                },
+                vec![self.vertex_buffer.clone()],
+                self.index_buffer.clone(),
+                descriptor_set,
+                (),
+            )
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+}
+
+/// Trees per side of the forest grid.
+const FOREST_SIZE: usize = 80;
+const FOREST_SPACING: f32 = 0.5;
+/// Keeps the trees away from the test geometry.
+const CLEARING_RADIUS: f32 = 3.0;
+
+/// A few thousand trees around the test geometry, each a trunk and a canopy drawn through
+/// `frame::InstanceRenderer`.
+pub struct Forest {
+    trunk: frame::MeshId,
+    canopy: frame::MeshId,
+    bark: frame::MaterialId,
+    leaves: frame::MaterialId,
+    trees: Vec<Tree>,
+}
+
+struct Tree {
+    model: Matrix4<f32>,
+    tint: [f32; 4],
+}
+
+impl Forest {
+    pub fn new(instances: &mut frame::InstanceRenderer) -> Forest {
+        let trunk = instances.add_mesh(trunk_mesh());
+        let canopy = instances.add_mesh(canopy_mesh());
+        let bark = instances.add_material(BARK);
+        let leaves = instances.add_material(LEAVES);
+
+        // Jittered so the grid doesn't show, the same every run.
+        let mut random = frame::Random::new(0x2545_f491);
+        let half_extent = FOREST_SIZE as f32 * FOREST_SPACING * 0.5;
+        let mut trees = Vec::new();
+        for i in 0..FOREST_SIZE * FOREST_SIZE {
+            let x = (i % FOREST_SIZE) as f32 * FOREST_SPACING - half_extent + (random.next() - 0.5) * FOREST_SPACING;
+            let z = (i / FOREST_SIZE) as f32 * FOREST_SPACING - half_extent + (random.next() - 0.5) * FOREST_SPACING;
+            let scale = 0.7 + random.next() * 0.6;
+            let angle = Deg(random.next() * 360.0);
+            let shade = 0.7 + random.next() * 0.5;
+            if x.abs() < CLEARING_RADIUS && z.abs() < CLEARING_RADIUS {
+                continue;
+            }
+            trees.push(Tree {
+                model: Matrix4::from_translation(Vector3::new(x, -0.6, z))
+                    * Matrix4::from_angle_y(angle)
+                    * Matrix4::from_scale(scale),
+                tint: [shade, shade, shade, 1.0],
+            });
+        }
+
+        Forest {
+            trunk,
+            canopy,
+            bark,
+            leaves,
+            trees,
+        }
+    }
+
+    /// Adds every tree to `scene`, for drawing them with GPU culling instead.
+    pub fn add_objects(&self, scene: &mut frame::GpuScene) {
+        let trunk = scene.add_mesh(trunk_mesh());
+        let canopy = scene.add_mesh(canopy_mesh());
+        let bark = scene.add_material(BARK);
+        let leaves = scene.add_material(LEAVES);
+        for tree in &self.trees {
+            scene.add_object(trunk, bark, tree.model, tree.tint);
+            scene.add_object(canopy, leaves, tree.model, tree.tint);
+        }
+    }
+
+    /// Queues every tree for the next `frame::InstanceRenderer::draw`, trunks and canopies
+    /// interleaved as they'd come from a scene graph; they still end up in two draw calls.
+    pub fn add_instances(&self, instances: &mut frame::InstanceRenderer) {
+        for tree in &self.trees {
+            instances.add_instance(self.trunk, self.bark, tree.model, tree.tint);
+            instances.add_instance(self.canopy, self.leaves, tree.model, tree.tint);
+        }
+    }
+}
+
+const BARK: frame::Material = frame::Material {
+    color: [0.35, 0.22, 0.12, 1.0],
+};
+const LEAVES: frame::Material = frame::Material {
+    color: [0.15, 0.45, 0.15, 1.0],
+};
+
+fn trunk_mesh() -> Vec<frame::MeshVertex> {
+    box_mesh([-0.04, 0.0, -0.04], [0.04, 0.25, 0.04])
+}
+
+fn canopy_mesh() -> Vec<frame::MeshVertex> {
+    pyramid_mesh(0.2, 0.15, 0.6)
+}
+
+fn mesh_vertex(position: [f32; 3], normal: Vector3<f32>) -> frame::MeshVertex {
+    frame::MeshVertex {
+        position,
+        normal: normal.into(),
+    }
+}
+
+/// Flat shaded triangle, the normal follows from the winding.
+fn mesh_triangle(vertices: &mut Vec<frame::MeshVertex>, a: [f32; 3], b: [f32; 3], c: [f32; 3]) {
+    let (pa, pb, pc) = (Vector3::from(a), Vector3::from(b), Vector3::from(c));
+    let normal = (pb - pa).cross(pc - pa).normalize();
+    vertices.push(mesh_vertex(a, normal));
+    vertices.push(mesh_vertex(b, normal));
+    vertices.push(mesh_vertex(c, normal));
+}
+
+fn box_mesh(min: [f32; 3], max: [f32; 3]) -> Vec<frame::MeshVertex> {
+    let corner = |i: usize| {
+        [
+            if i & 1 == 0 { min[0] } else { max[0] },
+            if i & 2 == 0 { min[1] } else { max[1] },
+            if i & 4 == 0 { min[2] } else { max[2] },
+        ]
+    };
+    // Corners of each face, counter-clockwise seen from outside.
+    let faces = [
+        [0, 4, 6, 2],
+        [1, 3, 7, 5],
+        [0, 1, 5, 4],
+        [2, 6, 7, 3],
+        [0, 2, 3, 1],
+        [4, 5, 7, 6],
+    ];
+    let mut vertices = Vec::new();
+    for face in &faces {
+        mesh_triangle(&mut vertices, corner(face[0]), corner(face[1]), corner(face[2]));
+        mesh_triangle(&mut vertices, corner(face[0]), corner(face[2]), corner(face[3]));
+    }
+    vertices
+}
+
+/// Square based, pointing up from `base` to `base + height`.
+fn pyramid_mesh(half_width: f32, base: f32, height: f32) -> Vec<frame::MeshVertex> {
+    let apex = [0.0, base + height, 0.0];
+    let corners = [
+        [-half_width, base, -half_width],
+        [-half_width, base, half_width],
+        [half_width, base, half_width],
+        [half_width, base, -half_width],
+    ];
+    let mut vertices = Vec::new();
+    for i in 0..4 {
+        mesh_triangle(&mut vertices, corners[i], corners[(i + 1) % 4], apex);
+    }
+    mesh_triangle(&mut vertices, corners[0], corners[3], corners[2]);
+    mesh_triangle(&mut vertices, corners[0], corners[2], corners[1]);
+    vertices
+}
+
+/// Room for the demo emitters, a little over a million particles.
+pub const PARTICLE_CAPACITY: u32 = 1 << 20;
+
+/// Smoke and sparks on either side of the test geometry, and a cloud of a million faint points
+/// behind it.
+pub fn particles<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> frame::ParticleSystem
+where
+    R: RenderPassAbstract + Send + Sync + Clone + 'static,
+{
+    let mut particles = frame::ParticleSystem::new(queue, subpass, PARTICLE_CAPACITY);
+    particles.add_emitter(frame::Emitter {
+        position: Point3::new(-1.2, -0.5, 0.0),
+        radius: 0.05,
+        rate: 60.0,
+        lifetime: 3.0..4.5,
+        spread: Deg(12.0),
+        speed: 0.2..0.35,
+        acceleration: Vector3::new(0.05, 0.08, 0.0),
+        drag: 0.3,
+        start_color: [0.4, 0.4, 0.42, 0.35],
+        end_color: [0.6, 0.6, 0.6, 0.0],
+        start_size: 0.15,
+        end_size: 0.6,
+        blend: frame::ParticleBlend::Alpha,
+        ..frame::Emitter::default()
+    });
+    particles.add_emitter(frame::Emitter {
+        position: Point3::new(1.2, -0.5, 0.0),
+        rate: 2000.0,
+        lifetime: 0.6..1.4,
+        spread: Deg(30.0),
+        speed: 1.2..2.0,
+        acceleration: Vector3::new(0.0, -3.0, 0.0),
+        drag: 0.1,
+        // Bright enough to bloom.
+        start_color: [6.0, 2.5, 0.6, 1.0],
+        end_color: [1.5, 0.2, 0.05, 0.0],
+        start_size: 0.02,
+        end_size: 0.01,
+        blend: frame::ParticleBlend::Additive,
+        ..frame::Emitter::default()
+    });
+    particles.add_emitter(frame::Emitter {
+        position: Point3::new(0.0, 0.3, -1.5),
+        radius: 0.8,
+        rate: 200_000.0,
+        lifetime: 4.0..5.0,
+        spread: Deg(180.0),
+        speed: 0.0..0.05,
+        start_color: [0.02, 0.04, 0.1, 1.0],
+        end_color: [0.02, 0.02, 0.05, 0.0],
+        start_size: 0.008,
+        end_size: 0.008,
+        blend: frame::ParticleBlend::Additive,
+        ..frame::Emitter::default()
+    });
+    particles
+}
+
+fn create_shader_modules(device: &Arc<Device>) -> (vs::Shader, fs::Shader) {
+    let vs = vs::Shader::load(device.clone()).expect("Could not create shader module");
+    let fs = fs::Shader::load(device.clone()).expect("Could not create shader module");
+
+    (vs, fs)
+}
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout (std140, binding = 0) uniform bufferVals {
+    mat4 mvp;
+    mat4 model_view;
+} myBufferVals;
+
+layout (location = 0) in vec3 pos;
+layout (location = 1) in vec4 color;
+layout (location = 2) in vec2 uv;
+layout (location = 3) in vec3 normal;
+layout (location = 0) out vec4 out_color;
+layout (location = 1) out vec3 out_normal;
+layout (location = 2) out vec2 out_uv;
+layout (location = 3) out vec3 out_view_position;
+layout (location = 4) out vec3 out_view_normal;
+void main() {
+    out_color = color;
+    // The demo geometry has no model transform, it's already in world space.
+    out_normal = normal;
+    out_uv = uv;
+    out_view_position = (myBufferVals.model_view * vec4(pos, 1.0)).xyz;
+    out_view_normal = (myBufferVals.model_view * vec4(normal, 0.0)).xyz;
+    gl_Position = myBufferVals.mvp * vec4(pos, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout (location = 0) in vec4 color;
+layout (location = 1) in vec3 v_normal;
+layout (location = 2) in vec2 uv;
+layout (location = 3) in vec3 view_position;
+layout (location = 4) in vec3 v_view_normal;
+layout (location = 0) out vec4 f_color;
+layout (location = 1) out vec4 f_normal_depth;
+layout (location = 2) out vec4 f_ambient;
+
+layout (std140, binding = 1) uniform Sun {
+    vec4 direction;
+    vec4 color;
+} sun;
+
+layout (push_constant) uniform PushConstants {
+    vec4 line_color;
+    int mode;
+    int edges_from_uv;
+} push_constants;
+
+const int SHADED = 0;
+const int EDGES = 1;
+const int NORMALS = 2;
+const int LINEAR_DEPTH = 3;
+const int UV_CHECKER = 4;
+const int OVERDRAW = 5;
+
+// Distance shown as white in the linear depth view.
+const float DEPTH_VIEW_DISTANCE = 10.0;
+const float CHECKER_SQUARES = 8.0;
+
+void main() {
+    vec3 normal = normalize(v_normal);
+    float view_depth = -view_position.z;
+
+    // For ambient occlusion, facing the camera like the lighting below.
+    vec3 view_normal = normalize(v_view_normal);
+    if (dot(view_normal, view_position) > 0.0) {
+        view_normal = -view_normal;
+    }
+    f_normal_depth = vec4(view_normal, view_depth);
+    f_ambient = vec4(0.0);
+
+    if (push_constants.mode == EDGES) {
+        if (push_constants.edges_from_uv != 0) {
+            vec3 barycentric = vec3(uv, 1.0 - uv.x - uv.y);
+            if (all(greaterThan(barycentric, fwidth(barycentric)))) {
+                discard;
+            }
+        }
+        f_color = push_constants.line_color.a > 0.0 ? push_constants.line_color : color;
+    } else if (push_constants.mode == NORMALS) {
+        f_color = vec4(normal * 0.5 + 0.5, 1.0);
+    } else if (push_constants.mode == LINEAR_DEPTH) {
+        f_color = vec4(vec3(clamp(view_depth / DEPTH_VIEW_DISTANCE, 0.0, 1.0)), 1.0);
+    } else if (push_constants.mode == UV_CHECKER) {
+        ivec2 square = ivec2(floor(uv * CHECKER_SQUARES));
+        float shade = (square.x + square.y) % 2 == 0 ? 0.9 : 0.2;
+        f_color = vec4(vec3(shade), 1.0);
+    } else if (push_constants.mode == OVERDRAW) {
+        // Saturates to red after 5 layers, to yellow after 12 and to white after 50.
+        f_color = vec4(0.2, 0.08, 0.02, 1.0);
+    } else {
+        // Flat shaded and lit from both sides, the triangles are seen from either.
+        float diffuse = abs(dot(normal, sun.direction.xyz));
+        vec3 ambient = color.rgb * 0.25;
+        f_ambient = vec4(ambient, 0.0);
+        f_color = vec4(ambient + color.rgb * 0.75 * diffuse * sun.color.rgb, color.a);
+    }
+}
+"]
+    struct Dummy;
+}
+
+mod selection_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+// Only the stencil is written.
+void main() {
+}
+"]
+    struct Dummy;
+}
+
+#[derive(Debug, Clone)]
+struct Vertex {
+    pos: [f32; 3],
+    color: [f32; 4],
+    uv: [f32; 2],
+    normal: [f32; 3],
+}
+impl_vertex!(Vertex, pos, color, uv, normal);
+
+/// Laid out like `WaveVertex` in `wave_cs`, std430.
+#[derive(Debug, Clone)]
+struct WaveVertex {
+    position: [f32; 4],
+    normal: [f32; 4],
+}
+impl_vertex!(WaveVertex, position, normal);
+
+mod wave_cs {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[src = "
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+struct WaveVertex {
+    vec4 position;
+    vec4 normal;
+};
+
+layout(std430, set = 0, binding = 0) buffer Vertices {
+    WaveVertex data[];
+} vertices;
+
+layout(push_constant) uniform PushConstants {
+    float time;
+    uint resolution;
+    float size;
+    float amplitude;
+} push_constants;
+
+float height(vec2 p) {
+    float t = push_constants.time;
+    return push_constants.amplitude * (
+        sin(p.x * 3.0 + t * 1.5)
+        + 0.5 * sin(p.y * 5.0 - t * 2.1)
+        + 0.25 * sin((p.x + p.y) * 7.0 + t * 3.3));
+}
+
+void main() {
+    uvec2 id = gl_GlobalInvocationID.xy;
+    uint resolution = push_constants.resolution;
+    if (id.x >= resolution || id.y >= resolution) {
+        return;
+    }
+
+    float spacing = push_constants.size / float(resolution - 1);
+    vec2 p = vec2(id) * spacing - 0.5 * push_constants.size;
+    // Central differences of the height field.
+    vec3 normal = normalize(vec3(
+        height(p - vec2(spacing, 0.0)) - height(p + vec2(spacing, 0.0)),
+        2.0 * spacing,
+        height(p - vec2(0.0, spacing)) - height(p + vec2(0.0, spacing))));
+
+    vertices.data[id.y * resolution + id.x] = WaveVertex(vec4(p.x, height(p), p.y, 1.0), vec4(normal, 0.0));
+}
+"]
+    struct Dummy;
+}
+
+mod wave_vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(std140, set = 0, binding = 0) uniform Matrices {
+    mat4 mvp;
+    mat4 model_view;
+    mat4 model;
+} matrices;
+
+layout(location = 0) in vec4 position;
+layout(location = 1) in vec4 normal;
+
+layout(location = 0) out vec3 v_normal;
+layout(location = 1) out vec3 v_view_position;
+layout(location = 2) out vec3 v_view_normal;
+
+void main() {
+    // The model matrix only translates, so it can be used for normals.
+    v_normal = (matrices.model * vec4(normal.xyz, 0.0)).xyz;
+    v_view_position = (matrices.model_view * position).xyz;
+    v_view_normal = (matrices.model_view * vec4(normal.xyz, 0.0)).xyz;
+    gl_Position = matrices.mvp * position;
+}
+"]
+    struct Dummy;
+}
+
+mod wave_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec3 v_normal;
+layout(location = 1) in vec3 v_view_position;
+layout(location = 2) in vec3 v_view_normal;
+
+layout(location = 0) out vec4 f_color;
+layout(location = 1) out vec4 f_normal_depth;
+layout(location = 2) out vec4 f_ambient;
+
+layout(std140, set = 0, binding = 1) uniform Sun {
+    vec4 direction;
+    vec4 color;
+} sun;
+
+const vec3 WATER_COLOR = vec3(0.05, 0.25, 0.45);
+
+void main() {
+    vec3 normal = normalize(v_normal);
+    float diffuse = max(dot(normal, sun.direction.xyz), 0.0);
+    vec3 ambient = WATER_COLOR * 0.25;
+
+    f_color = vec4(ambient + WATER_COLOR * 0.75 * diffuse * sun.color.rgb, 1.0);
+    f_normal_depth = vec4(normalize(v_view_normal), -v_view_position.z);
+    f_ambient = vec4(ambient, 0.0);
+}
+"]
+    struct Dummy;
+}
+
+#[derive(Debug, Clone)]
+struct QuadVertex {
+    pos: [f32; 2],
+}
+impl_vertex!(QuadVertex, pos);
+
+mod quad_vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 pos;
+
+layout(location = 0) out vec4 v_color;
+
+layout(push_constant) uniform PushConstants {
+    mat4 mvp;
+    vec4 color;
+} push_constants;
+
+void main() {
+    v_color = push_constants.color;
+    gl_Position = push_constants.mvp * vec4(pos, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod sorted_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec4 v_color;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    f_color = v_color;
+}
+"]
+    struct Dummy;
+}
+
+mod weighted_blended_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec4 v_color;
+
+layout(location = 0) out vec4 f_accum;
+layout(location = 1) out float f_reveal;
+
+void main() {
+    // Equation 10 of McGuire and Bavoil, nearer and more opaque surfaces weigh more.
+    float depth = gl_FragCoord.z;
+    float weight = clamp(pow(min(1.0, v_color.a * 10.0) + 0.01, 3.0) * 1e8 * pow(1.0 - depth * 0.9, 3.0), 1e-2, 3e3);
+    f_accum = vec4(v_color.rgb * v_color.a, v_color.a) * weight;
+    f_reveal = v_color.a;
+}
+"]
+    struct Dummy;
+}
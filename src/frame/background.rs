@@ -0,0 +1,279 @@
+use std::sync::Arc;
+
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::device::Queue;
+use vulkano::format::ClearValue;
+use vulkano::format::Format;
+use vulkano::format::FormatTy;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+use super::fullscreen::fullscreen_triangle;
+use super::fullscreen::viewport_state;
+use super::fullscreen::Vertex;
+use super::graph::Load;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::outline::SELECTION_STENCIL_BITS;
+use super::system::DEPTH_IMAGE;
+use super::system::HDR_IMAGE;
+
+/// What's behind the scene where neither geometry, the sky nor a skybox cover it. Colors are
+/// linear scene luminance, like everything else in the HDR image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Background {
+    Color([f32; 4]),
+    /// From `top` at the top of the screen to `bottom` at the bottom.
+    Gradient { top: [f32; 4], bottom: [f32; 4] },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClearSettings {
+    pub background: Background,
+    /// What the depth buffer is cleared to. Has to be in 0..1, anything else is clamped.
+    ///
+    /// The passes assume the default of 1.0, the far plane, with depth tests passing for
+    /// smaller values: there's no reverse depth. Anything smaller hides the scene behind it,
+    /// and the depth pyramid of occlusion culling takes it for geometry.
+    pub depth: f32,
+    /// Ignored unless the depth format has a stencil aspect. Only the low 8 bits are kept, and
+    /// of those the bits `Pass::Selection` marks selected objects with are always cleared.
+    pub stencil: u32,
+}
+
+impl Default for ClearSettings {
+    fn default() -> ClearSettings {
+        ClearSettings {
+            background: Background::Color([0.0, 0.0, 0.0, 0.0]),
+            depth: 1.0,
+            stencil: 0,
+        }
+    }
+}
+
+impl ClearSettings {
+    /// The depth clear value as Vulkan accepts it.
+    pub fn clear_depth(&self) -> f32 {
+        if self.depth.is_nan() {
+            1.0
+        } else {
+            self.depth.max(0.0).min(1.0)
+        }
+    }
+
+    pub fn color_clear_value(&self) -> ClearValue {
+        match self.background {
+            Background::Color(color) => color.into(),
+            // Covered by the gradient anyway.
+            Background::Gradient { top, .. } => top.into(),
+        }
+    }
+
+    /// The clear value for a depth, stencil or depth/stencil attachment of `format`.
+    pub fn depth_stencil_clear_value(&self, format: Format) -> ClearValue {
+        let stencil = self.stencil & 0xff & !SELECTION_STENCIL_BITS;
+        match format.ty() {
+            FormatTy::Depth => ClearValue::Depth(self.clear_depth()),
+            FormatTy::Stencil => ClearValue::Stencil(stencil),
+            FormatTy::DepthStencil => ClearValue::DepthStencil((self.clear_depth(), stencil)),
+            _ => panic!("{:?} isn't a depth or stencil format", format),
+        }
+    }
+}
+
+/// Draws the gradient of `Background::Gradient` behind the scene.
+pub struct BackgroundSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+}
+
+impl BackgroundSystem {
+    pub fn new(gfx_queue: Arc<Queue>) -> BackgroundSystem {
+        let vertex_buffer = fullscreen_triangle(&gfx_queue);
+
+        BackgroundSystem {
+            gfx_queue,
+            vertex_buffer,
+            pipeline: None,
+        }
+    }
+}
+
+/// Depth test of passes drawing behind the scene at the depth clear value. Only pixels no
+/// geometry was drawn in are still at that value.
+pub fn background_depth_stencil() -> DepthStencil {
+    DepthStencil {
+        depth_write: false,
+        depth_compare: Compare::LessOrEqual,
+        ..DepthStencil::disabled()
+    }
+}
+
+impl RenderNode for BackgroundSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.color(HDR_IMAGE, Load::Load)
+            .depth_stencil(DEPTH_IMAGE, Load::Load);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        let vs = vs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = fs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+
+        self.pipeline = Some(Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil(background_depth_stencil())
+                .render_pass(subpass.unwrap())
+                .build(self.gfx_queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        let (top, bottom) = match context.settings().clear.background {
+            Background::Gradient { top, bottom } => (top, bottom),
+            Background::Color(_) => return,
+        };
+        if context.settings().sky.enabled || context.settings().skybox.cubemap.is_some() {
+            return;
+        }
+        let pipeline = self.pipeline.clone().unwrap();
+        let viewport_dimensions = context.dimensions(HDR_IMAGE);
+
+        let push_constants = vs::ty::PushConstants {
+            top,
+            bottom,
+            depth: context.settings().clear.clear_depth(),
+        };
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipeline.clone(),
+                viewport_state(viewport_dimensions),
+                vec![self.vertex_buffer.clone()],
+                (),
+                push_constants,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec4 v_color;
+
+layout(push_constant) uniform PushConstants {
+    vec4 top;
+    vec4 bottom;
+    float depth;
+} push_constants;
+
+void main() {
+    // Framebuffer y points down, -1 is the top.
+    v_color = mix(push_constants.top, push_constants.bottom, position.y * 0.5 + 0.5);
+    gl_Position = vec4(position, push_constants.depth, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec4 v_color;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    f_color = v_color;
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32;
+
+    use vulkano::format::ClearValue;
+    use vulkano::format::Format;
+
+    use super::ClearSettings;
+    use super::SELECTION_STENCIL_BITS;
+
+    fn with_depth(depth: f32) -> ClearSettings {
+        ClearSettings {
+            depth,
+            ..ClearSettings::default()
+        }
+    }
+
+    #[test]
+    fn clear_depth_is_clamped() {
+        assert_eq!(with_depth(0.25).clear_depth(), 0.25);
+        assert_eq!(with_depth(-1.0).clear_depth(), 0.0);
+        assert_eq!(with_depth(2.0).clear_depth(), 1.0);
+        assert_eq!(with_depth(f32::INFINITY).clear_depth(), 1.0);
+        assert_eq!(with_depth(f32::NAN).clear_depth(), 1.0);
+    }
+
+    #[test]
+    fn clear_value_follows_the_format() {
+        let settings = ClearSettings {
+            depth: 3.0,
+            stencil: 0x1ff,
+            ..ClearSettings::default()
+        };
+        assert_eq!(settings.depth_stencil_clear_value(Format::D32Sfloat), ClearValue::Depth(1.0));
+        assert_eq!(settings.depth_stencil_clear_value(Format::S8Uint), ClearValue::Stencil(0xfc));
+        assert_eq!(
+            settings.depth_stencil_clear_value(Format::D24Unorm_S8Uint),
+            ClearValue::DepthStencil((1.0, 0xfc))
+        );
+    }
+
+    #[test]
+    fn clear_stencil_leaves_the_selection_bits_alone() {
+        for stencil in 0..0x100 {
+            let settings = ClearSettings {
+                stencil,
+                ..ClearSettings::default()
+            };
+            match settings.depth_stencil_clear_value(Format::S8Uint) {
+                ClearValue::Stencil(value) => {
+                    assert_eq!(value & SELECTION_STENCIL_BITS, 0);
+                    assert_eq!(value | SELECTION_STENCIL_BITS, stencil | SELECTION_STENCIL_BITS);
+                }
+                value => panic!("{:?} isn't a stencil clear value", value),
+            }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use std::sync::Arc;
+
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::Dimensions;
+use vulkano::image::ImageUsage;
+use vulkano::image::ImageViewAccess;
+use vulkano::image::StorageImage;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::pipeline::ComputePipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::Sampler;
+
+use super::fullscreen::clamp_sampler;
+use super::graph::ImageDesc;
+use super::graph::ImageSize;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::system::BLOOM_IMAGE;
+use super::system::HDR_IMAGE;
+
+const MAX_LEVELS: usize = 6;
+const LOCAL_SIZE: u32 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    /// Luminance above which pixels start contributing to the bloom.
+    pub threshold: f32,
+    /// Width of the soft transition around `threshold`, as a fraction of it.
+    pub knee: f32,
+    pub intensity: f32,
+    /// Radius of the upsampling filter, in texels of the smaller level.
+    pub radius: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> BloomSettings {
+        BloomSettings {
+            enabled: true,
+            threshold: 1.0,
+            knee: 0.5,
+            intensity: 0.05,
+            radius: 1.0,
+        }
+    }
+}
+
+/// Builds the bloom texture with a progressive downsample/upsample chain over a pyramid of
+/// half resolution images. The first level of the pyramid is the graph's bloom image, the
+/// smaller ones are owned by the system.
+pub struct BloomSystem {
+    queue: Arc<Queue>,
+    prefilter_pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    downsample_pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    upsample_pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    sampler: Arc<Sampler>,
+    base_dimensions: [u32; 2],
+    /// Every level but the first.
+    levels: Vec<Arc<StorageImage<Format>>>,
+}
+
+impl BloomSystem {
+    pub fn new(queue: Arc<Queue>) -> BloomSystem {
+        let device = queue.device().clone();
+
+        let prefilter_pipeline = {
+            let cs = prefilter_cs::Shader::load(device.clone()).expect("Could not create shader module");
+            Arc::new(ComputePipeline::new(device.clone(), &cs.main_entry_point(), &()).unwrap()) as Arc<_>
+        };
+        let downsample_pipeline = {
+            let cs = downsample_cs::Shader::load(device.clone()).expect("Could not create shader module");
+            Arc::new(ComputePipeline::new(device.clone(), &cs.main_entry_point(), &()).unwrap()) as Arc<_>
+        };
+        let upsample_pipeline = {
+            let cs = upsample_cs::Shader::load(device.clone()).expect("Could not create shader module");
+            Arc::new(ComputePipeline::new(device.clone(), &cs.main_entry_point(), &()).unwrap()) as Arc<_>
+        };
+
+        let sampler = clamp_sampler(&device, Filter::Linear);
+
+        BloomSystem {
+            queue,
+            prefilter_pipeline,
+            downsample_pipeline,
+            upsample_pipeline,
+            sampler,
+            base_dimensions: [0, 0],
+            levels: Vec::new(),
+        }
+    }
+
+    /// Image description of the first level, which the graph allocates as `BLOOM_IMAGE`.
+    pub fn image_desc() -> ImageDesc {
+        ImageDesc {
+            format: Format::R16G16B16A16Sfloat,
+            size: ImageSize::Scaled(0.5),
+            usage: ImageUsage {
+                storage: true,
+                sampled: true,
+                ..ImageUsage::none()
+            },
+        }
+    }
+
+    /// Recreates the levels below the base image when its size changed. Levels too small for
+    /// the filters are left out of the chain rather than allocated.
+    fn update_levels(&mut self, base_dimensions: [u32; 2]) {
+        if self.base_dimensions == base_dimensions {
+            return;
+        }
+
+        let mut levels = Vec::with_capacity(MAX_LEVELS - 1);
+        let mut width = (base_dimensions[0] / 2).max(1);
+        let mut height = (base_dimensions[1] / 2).max(1);
+        while levels.len() + 1 < MAX_LEVELS && width >= 2 && height >= 2 {
+            levels.push(
+                StorageImage::new(
+                    self.queue.device().clone(),
+                    Dimensions::Dim2d { width, height },
+                    Format::R16G16B16A16Sfloat,
+                    Some(self.queue.family()),
+                ).unwrap(),
+            );
+
+            width /= 2;
+            height /= 2;
+        }
+
+        self.base_dimensions = base_dimensions;
+        self.levels = levels;
+    }
+}
+
+impl RenderNode for BloomSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.read(HDR_IMAGE).write(BLOOM_IMAGE);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        let settings = context.settings().bloom.clone();
+
+        // The tonemapper doesn't sample the bloom image when bloom is disabled.
+        if !settings.enabled {
+            return;
+        }
+
+        self.update_levels(context.dimensions(BLOOM_IMAGE));
+
+        let mut levels = vec![context.image(BLOOM_IMAGE)];
+        levels.extend(self.levels.iter().map(|level| level.clone() as Arc<ImageViewAccess + Send + Sync>));
+        let (threshold, knee) = (settings.threshold, settings.threshold * settings.knee);
+
+        let prefilter_set = PersistentDescriptorSet::start(self.prefilter_pipeline.clone(), 0)
+            .add_sampled_image(context.image(HDR_IMAGE), self.sampler.clone())
+            .unwrap()
+            .add_image(levels[0].clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let prefilter_pipeline = self.prefilter_pipeline.clone();
+        let downsample_pipeline = self.downsample_pipeline.clone();
+        let upsample_pipeline = self.upsample_pipeline.clone();
+        let sampler = self.sampler.clone();
+
+        context.record(move |command_buffer| {
+            let mut command_buffer = command_buffer
+                .dispatch(
+                    dispatch_size(&levels[0]),
+                    prefilter_pipeline,
+                    prefilter_set,
+                    prefilter_cs::ty::PushConstants { threshold, knee },
+                )
+                .unwrap();
+
+            for i in 1..levels.len() {
+                let set = PersistentDescriptorSet::start(downsample_pipeline.clone(), 0)
+                    .add_sampled_image(levels[i - 1].clone(), sampler.clone())
+                    .unwrap()
+                    .add_image(levels[i].clone())
+                    .unwrap()
+                    .build()
+                    .unwrap();
+
+                command_buffer = command_buffer
+                    .dispatch(dispatch_size(&levels[i]), downsample_pipeline.clone(), set, ())
+                    .unwrap();
+            }
+
+            for i in (1..levels.len()).rev() {
+                let set = PersistentDescriptorSet::start(upsample_pipeline.clone(), 0)
+                    .add_sampled_image(levels[i].clone(), sampler.clone())
+                    .unwrap()
+                    .add_image(levels[i - 1].clone())
+                    .unwrap()
+                    .build()
+                    .unwrap();
+
+                command_buffer = command_buffer
+                    .dispatch(
+                        dispatch_size(&levels[i - 1]),
+                        upsample_pipeline.clone(),
+                        set,
+                        upsample_cs::ty::PushConstants {
+                            radius: settings.radius,
+                        },
+                    )
+                    .unwrap();
+            }
+
+            command_buffer
+        });
+    }
+}
+
+fn dispatch_size(image: &Arc<ImageViewAccess + Send + Sync>) -> [u32; 3] {
+    let dimensions = image.dimensions();
+    let (width, height) = (dimensions.width(), dimensions.height());
+    [
+        (width + LOCAL_SIZE - 1) / LOCAL_SIZE,
+        (height + LOCAL_SIZE - 1) / LOCAL_SIZE,
+        1,
+    ]
+}
+
+mod prefilter_cs {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[src = "
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+layout(set = 0, binding = 0) uniform sampler2D u_input;
+layout(set = 0, binding = 1, rgba16f) uniform writeonly image2D u_output;
+
+layout(push_constant) uniform PushConstants {
+    float threshold;
+    float knee;
+} push_constants;
+
+// NaNs and infinities from the scene would spread over the whole pyramid, so they are dropped,
+// as are negative colors.
+vec3 sanitize(vec3 color) {
+    if (any(isnan(color)) || any(isinf(color))) {
+        return vec3(0.0);
+    }
+    return clamp(color, vec3(0.0), vec3(65000.0));
+}
+
+// Quadratic soft threshold, so highlights fade in instead of popping.
+vec3 threshold(vec3 color) {
+    float brightness = max(color.r, max(color.g, color.b));
+    float soft = clamp(brightness - push_constants.threshold + push_constants.knee, 0.0, 2.0 * push_constants.knee);
+    soft = soft * soft / (4.0 * push_constants.knee + 0.00001);
+    float contribution = max(soft, brightness - push_constants.threshold) / max(brightness, 0.00001);
+    return color * contribution;
+}
+
+void main() {
+    ivec2 size = imageSize(u_output);
+    ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+    if (pixel.x >= size.x || pixel.y >= size.y) {
+        return;
+    }
+
+    vec2 texel = 1.0 / vec2(textureSize(u_input, 0));
+    vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+    // 4 bilinear taps covering the 4x4 source footprint of this texel.
+    vec3 color = sanitize(texture(u_input, uv + texel * vec2(-1.0, -1.0)).rgb);
+    color += sanitize(texture(u_input, uv + texel * vec2(1.0, -1.0)).rgb);
+    color += sanitize(texture(u_input, uv + texel * vec2(-1.0, 1.0)).rgb);
+    color += sanitize(texture(u_input, uv + texel * vec2(1.0, 1.0)).rgb);
+
+    imageStore(u_output, pixel, vec4(threshold(color * 0.25), 1.0));
+}
+"]
+    struct Dummy;
+}
+
+mod downsample_cs {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[src = "
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+layout(set = 0, binding = 0) uniform sampler2D u_input;
+layout(set = 0, binding = 1, rgba16f) uniform writeonly image2D u_output;
+
+void main() {
+    ivec2 size = imageSize(u_output);
+    ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+    if (pixel.x >= size.x || pixel.y >= size.y) {
+        return;
+    }
+
+    vec2 texel = 1.0 / vec2(textureSize(u_input, 0));
+    vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+    // 13 tap downsample (Jimenez 2014).
+    vec3 a = texture(u_input, uv + texel * vec2(-2.0, -2.0)).rgb;
+    vec3 b = texture(u_input, uv + texel * vec2(0.0, -2.0)).rgb;
+    vec3 c = texture(u_input, uv + texel * vec2(2.0, -2.0)).rgb;
+    vec3 d = texture(u_input, uv + texel * vec2(-2.0, 0.0)).rgb;
+    vec3 e = texture(u_input, uv).rgb;
+    vec3 f = texture(u_input, uv + texel * vec2(2.0, 0.0)).rgb;
+    vec3 g = texture(u_input, uv + texel * vec2(-2.0, 2.0)).rgb;
+    vec3 h = texture(u_input, uv + texel * vec2(0.0, 2.0)).rgb;
+    vec3 i = texture(u_input, uv + texel * vec2(2.0, 2.0)).rgb;
+    vec3 j = texture(u_input, uv + texel * vec2(-1.0, -1.0)).rgb;
+    vec3 k = texture(u_input, uv + texel * vec2(1.0, -1.0)).rgb;
+    vec3 l = texture(u_input, uv + texel * vec2(-1.0, 1.0)).rgb;
+    vec3 m = texture(u_input, uv + texel * vec2(1.0, 1.0)).rgb;
+
+    vec3 color = e * 0.125;
+    color += (a + c + g + i) * 0.03125;
+    color += (b + d + f + h) * 0.0625;
+    color += (j + k + l + m) * 0.125;
+
+    imageStore(u_output, pixel, vec4(color, 1.0));
+}
+"]
+    struct Dummy;
+}
+
+mod upsample_cs {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[src = "
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+layout(set = 0, binding = 0) uniform sampler2D u_input;
+layout(set = 0, binding = 1, rgba16f) uniform image2D u_output;
+
+layout(push_constant) uniform PushConstants {
+    float radius;
+} push_constants;
+
+void main() {
+    ivec2 size = imageSize(u_output);
+    ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+    if (pixel.x >= size.x || pixel.y >= size.y) {
+        return;
+    }
+
+    vec2 texel = push_constants.radius / vec2(textureSize(u_input, 0));
+    vec2 uv = (vec2(pixel) + 0.5) / vec2(size);
+
+    // 3x3 tent filter over the smaller level.
+    vec3 color = texture(u_input, uv).rgb * 4.0;
+    color += texture(u_input, uv + texel * vec2(-1.0, 0.0)).rgb * 2.0;
+    color += texture(u_input, uv + texel * vec2(1.0, 0.0)).rgb * 2.0;
+    color += texture(u_input, uv + texel * vec2(0.0, -1.0)).rgb * 2.0;
+    color += texture(u_input, uv + texel * vec2(0.0, 1.0)).rgb * 2.0;
+    color += texture(u_input, uv + texel * vec2(-1.0, -1.0)).rgb;
+    color += texture(u_input, uv + texel * vec2(1.0, -1.0)).rgb;
+    color += texture(u_input, uv + texel * vec2(-1.0, 1.0)).rgb;
+    color += texture(u_input, uv + texel * vec2(1.0, 1.0)).rgb;
+    color /= 16.0;
+
+    vec3 current = imageLoad(u_output, pixel).rgb;
+    imageStore(u_output, pixel, vec4(current + color, 1.0));
+}
+"]
+    struct Dummy;
+}
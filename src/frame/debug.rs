@@ -0,0 +1,495 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Vector3, Vector4};
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::AutoCommandBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+use super::fullscreen::viewport_state;
+use super::tonemap::is_srgb;
+
+const CIRCLE_SEGMENTS: usize = 32;
+
+/// Debug primitives accumulated over a frame, drawn and cleared by `DebugPass::draw`.
+///
+/// Primitives added through `depth_tested` are hidden by the scene, those added through
+/// `on_top` are always visible. Colors are display colors, i.e. not tonemapped.
+#[derive(Debug, Clone, Default)]
+pub struct DebugDraw {
+    depth_tested: DebugLines,
+    on_top: DebugLines,
+}
+
+impl DebugDraw {
+    pub fn new() -> DebugDraw {
+        DebugDraw::default()
+    }
+
+    #[inline]
+    pub fn depth_tested(&mut self) -> &mut DebugLines {
+        &mut self.depth_tested
+    }
+
+    #[inline]
+    pub fn on_top(&mut self) -> &mut DebugLines {
+        &mut self.on_top
+    }
+
+    pub fn clear(&mut self) {
+        self.depth_tested.vertices.clear();
+        self.on_top.vertices.clear();
+    }
+}
+
+/// A list of line segments in world space.
+#[derive(Debug, Clone, Default)]
+pub struct DebugLines {
+    vertices: Vec<DebugVertex>,
+}
+
+impl DebugLines {
+    pub fn line(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 4]) -> &mut DebugLines {
+        self.vertices.push(DebugVertex {
+            position: from.into(),
+            color,
+        });
+        self.vertices.push(DebugVertex {
+            position: to.into(),
+            color,
+        });
+        self
+    }
+
+    pub fn aabb(&mut self, min: Point3<f32>, max: Point3<f32>, color: [f32; 4]) -> &mut DebugLines {
+        let corner = |i: usize| {
+            Point3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+        // Every pair of corners differing in exactly one axis is an edge.
+        for i in 0..8 {
+            for &axis in &[1, 2, 4] {
+                if i & axis == 0 {
+                    self.line(corner(i), corner(i | axis), color);
+                }
+            }
+        }
+        self
+    }
+
+    /// Three great circles, one around each axis.
+    pub fn sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 4]) -> &mut DebugLines {
+        self.circle(center, Vector3::unit_x(), radius, color)
+            .circle(center, Vector3::unit_y(), radius, color)
+            .circle(center, Vector3::unit_z(), radius, color)
+    }
+
+    pub fn circle(&mut self, center: Point3<f32>, normal: Vector3<f32>, radius: f32, color: [f32; 4]) -> &mut DebugLines {
+        let (u, v) = orthonormal_basis(normal);
+        let point = |i: usize| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * 2.0 * PI;
+            center + (u * angle.cos() + v * angle.sin()) * radius
+        };
+        for i in 0..CIRCLE_SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+        self
+    }
+
+    /// A line with a four-sided head at `to`.
+    pub fn arrow(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 4]) -> &mut DebugLines {
+        self.line(from, to, color);
+
+        let direction = to - from;
+        let length = direction.magnitude();
+        if length == 0.0 {
+            return self;
+        }
+        let (u, v) = orthonormal_basis(direction);
+        let head_length = length * 0.2;
+        let base = to - direction / length * head_length;
+        for &side in &[u, -u, v, -v] {
+            self.line(to, base + side * head_length * 0.4, color);
+        }
+        self
+    }
+
+    /// A grid on the XZ plane around `center`, `size` wide with `divisions` cells per side.
+    pub fn grid(&mut self, center: Point3<f32>, size: f32, divisions: u32, color: [f32; 4]) -> &mut DebugLines {
+        let half = size / 2.0;
+        for i in 0..divisions + 1 {
+            let offset = -half + size * i as f32 / divisions as f32;
+            self.line(
+                center + Vector3::new(offset, 0.0, -half),
+                center + Vector3::new(offset, 0.0, half),
+                color,
+            );
+            self.line(
+                center + Vector3::new(-half, 0.0, offset),
+                center + Vector3::new(half, 0.0, offset),
+                color,
+            );
+        }
+        self
+    }
+
+    /// The axes of `transform` as red, green and blue arrows.
+    pub fn axes(&mut self, transform: Matrix4<f32>, length: f32) -> &mut DebugLines {
+        let origin = Point3::from_homogeneous(transform * Vector4::new(0.0, 0.0, 0.0, 1.0));
+        let axes = [
+            (Vector4::unit_x(), [1.0, 0.0, 0.0, 1.0]),
+            (Vector4::unit_y(), [0.0, 1.0, 0.0, 1.0]),
+            (Vector4::unit_z(), [0.0, 0.0, 1.0, 1.0]),
+        ];
+        for &(axis, color) in &axes {
+            let direction = (transform * axis).truncate();
+            if direction.magnitude2() > 0.0 {
+                self.arrow(origin, origin + direction.normalize() * length, color);
+            }
+        }
+        self
+    }
+
+    /// The volume seen through `view_projection`, e.g. another camera's.
+    pub fn frustum(&mut self, view_projection: Matrix4<f32>, color: [f32; 4]) -> &mut DebugLines {
+        let inverse = match view_projection.invert() {
+            Some(inverse) => inverse,
+            None => return self,
+        };
+        // Vulkan clip space, depth goes from 0 to 1.
+        let corner = |i: usize| {
+            let ndc = Vector4::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { 0.0 } else { 1.0 },
+                1.0,
+            );
+            Point3::from_homogeneous(inverse * ndc)
+        };
+        for i in 0..8 {
+            for &axis in &[1, 2, 4] {
+                if i & axis == 0 {
+                    self.line(corner(i), corner(i | axis), color);
+                }
+            }
+        }
+        self
+    }
+}
+
+fn orthonormal_basis(normal: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let normal = normal.normalize();
+    let helper = if normal.x.abs() < 0.9 {
+        Vector3::unit_x()
+    } else {
+        Vector3::unit_y()
+    };
+    let u = normal.cross(helper).normalize();
+    let v = normal.cross(u);
+    (u, v)
+}
+
+/// Draws the lines of a `DebugDraw` on top of the tonemapped image.
+pub struct DebugDrawSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer_pool: CpuBufferPool<DebugVertex>,
+    depth_tested_pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    on_top_pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    decode_srgb: bool,
+}
+
+impl DebugDrawSystem {
+    pub fn new<R>(gfx_queue: Arc<Queue>, subpass: Subpass<R>, output_format: Format) -> DebugDrawSystem
+    where
+        R: RenderPassAbstract + Clone + Send + Sync + 'static,
+    {
+        let vs = vs::Shader::load(gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = fs::Shader::load(gfx_queue.device().clone()).expect("Could not create shader module");
+
+        // The closure borrows the queue, which the system takes afterwards.
+        let (depth_tested_pipeline, on_top_pipeline) = {
+            let pipeline = |depth_stencil: DepthStencil| {
+                Arc::new(
+                    GraphicsPipeline::start()
+                        .vertex_input_single_buffer::<DebugVertex>()
+                        .vertex_shader(vs.main_entry_point(), ())
+                        .line_list()
+                        .viewports_dynamic_scissors_irrelevant(1)
+                        .fragment_shader(fs.main_entry_point(), ())
+                        .depth_stencil(depth_stencil)
+                        .blend_alpha_blending()
+                        .render_pass(subpass.clone())
+                        .build(gfx_queue.device().clone())
+                        .unwrap(),
+                ) as Arc<GraphicsPipelineAbstract + Send + Sync>
+            };
+
+            // Tested against the scene depth, but lines don't occlude each other.
+            let depth_tested = pipeline(DepthStencil {
+                depth_write: false,
+                depth_compare: Compare::LessOrEqual,
+                ..DepthStencil::disabled()
+            });
+            (depth_tested, pipeline(DepthStencil::disabled()))
+        };
+
+        DebugDrawSystem {
+            vertex_buffer_pool: CpuBufferPool::new(gfx_queue.device().clone(), BufferUsage::vertex_buffer()),
+            gfx_queue,
+            depth_tested_pipeline,
+            on_top_pipeline,
+            decode_srgb: is_srgb(output_format),
+        }
+    }
+
+    pub fn draw(
+        &self,
+        viewport_dimensions: [u32; 2],
+        world_to_framebuffer: Matrix4<f32>,
+        debug: &DebugDraw,
+    ) -> AutoCommandBuffer {
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            self.on_top_pipeline.clone().subpass(),
+        ).unwrap();
+
+        let dynamic_state = viewport_state(viewport_dimensions);
+        let push_constants = vs::ty::PushConstants {
+            world_to_framebuffer: world_to_framebuffer.into(),
+        };
+
+        let layers = [
+            (&self.depth_tested_pipeline, &debug.depth_tested),
+            (&self.on_top_pipeline, &debug.on_top),
+        ];
+        for &(pipeline, lines) in &layers {
+            if lines.vertices.is_empty() {
+                continue;
+            }
+
+            let vertices: Vec<DebugVertex> = if self.decode_srgb {
+                // The output encodes to sRGB itself, so colors have to be given linear.
+                lines
+                    .vertices
+                    .iter()
+                    .map(|vertex| DebugVertex {
+                        position: vertex.position,
+                        color: srgb_to_linear(vertex.color),
+                    })
+                    .collect()
+            } else {
+                lines.vertices.clone()
+            };
+            let vertex_buffer = self.vertex_buffer_pool.chunk(vertices).unwrap();
+
+            builder = builder
+                .draw(
+                    pipeline.clone(),
+                    dynamic_state.clone(),
+                    vec![Arc::new(vertex_buffer)],
+                    (),
+                    push_constants.clone(),
+                )
+                .unwrap();
+        }
+
+        builder.build().unwrap()
+    }
+}
+
+fn srgb_to_linear(color: [f32; 4]) -> [f32; 4] {
+    let decode = |c: f32| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    [decode(color[0]), decode(color[1]), decode(color[2]), color[3]]
+}
+
+#[derive(Debug, Clone)]
+struct DebugVertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+impl_vertex!(DebugVertex, position, color);
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec4 v_color;
+
+layout(push_constant) uniform PushConstants {
+    mat4 world_to_framebuffer;
+} push_constants;
+
+void main() {
+    v_color = color;
+    gl_Position = push_constants.world_to_framebuffer * vec4(position, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec4 v_color;
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    f_color = v_color;
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::prelude::*;
+    use cgmath::{Matrix4, Point3, Vector3};
+
+    use super::DebugLines;
+    use super::CIRCLE_SEGMENTS;
+
+    const WHITE: [f32; 4] = [1.0; 4];
+
+    fn positions(lines: &DebugLines) -> Vec<Point3<f32>> {
+        lines.vertices.iter().map(|vertex| Point3::from(vertex.position)).collect()
+    }
+
+    /// The segments as pairs of endpoints, each pair and the list sorted so they compare
+    /// regardless of drawing order.
+    fn segments(lines: &DebugLines) -> Vec<[[i32; 3]; 2]> {
+        let round = |p: Point3<f32>| [p.x.round() as i32, p.y.round() as i32, p.z.round() as i32];
+        let mut segments: Vec<_> = positions(lines)
+            .chunks(2)
+            .map(|pair| {
+                let (a, b) = (round(pair[0]), round(pair[1]));
+                if a < b {
+                    [a, b]
+                } else {
+                    [b, a]
+                }
+            })
+            .collect();
+        segments.sort();
+        segments
+    }
+
+    /// The 12 edges of the box between -1 and 1 in x and y and `near` and `far` in z.
+    fn box_edges(near: i32, far: i32) -> Vec<[[i32; 3]; 2]> {
+        let mut expected = Vec::new();
+        for &x in &[-1, 1] {
+            for &y in &[-1, 1] {
+                expected.push([[x, y, near], [x, y, far]]);
+            }
+            for &z in &[near, far] {
+                expected.push([[x, -1, z], [x, 1, z]]);
+            }
+        }
+        for &y in &[-1, 1] {
+            for &z in &[near, far] {
+                expected.push([[-1, y, z], [1, y, z]]);
+            }
+        }
+        expected.sort();
+        expected
+    }
+
+    fn assert_close(a: Point3<f32>, b: Point3<f32>) {
+        assert!(a.distance(b) < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn aabb_draws_its_twelve_edges() {
+        let mut lines = DebugLines::default();
+        lines.aabb(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0), WHITE);
+        assert_eq!(lines.vertices.len(), 24);
+        assert_eq!(segments(&lines), box_edges(-1, 1));
+    }
+
+    #[test]
+    fn circle_is_closed_and_at_the_radius() {
+        let center = Point3::new(1.0, 2.0, 3.0);
+        let normal = Vector3::new(0.0, 1.0, 1.0);
+        let mut lines = DebugLines::default();
+        lines.circle(center, normal, 2.0, WHITE);
+
+        let points = positions(&lines);
+        assert_eq!(points.len(), 2 * CIRCLE_SEGMENTS);
+        for pair in points.chunks(2).collect::<Vec<_>>().windows(2) {
+            assert_close(pair[0][1], pair[1][0]);
+        }
+        assert_close(points[0], points[points.len() - 1]);
+        for &point in &points {
+            assert!(((point - center).magnitude() - 2.0).abs() < 1e-4);
+            assert!((point - center).dot(normal).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn arrow_has_a_head_at_its_tip() {
+        let from = Point3::new(0.0, 0.0, 0.0);
+        let to = Point3::new(0.0, 0.0, 5.0);
+        let mut lines = DebugLines::default();
+        lines.arrow(from, to, WHITE);
+
+        let points = positions(&lines);
+        assert_eq!(points.len(), 10);
+        assert_eq!((points[0], points[1]), (from, to));
+        for head in points[2..].chunks(2) {
+            assert_eq!(head[0], to);
+            // The head lines end a fifth of the length back from the tip.
+            assert!((head[1].z - 4.0).abs() < 1e-4);
+            assert!(((head[1] - to).magnitude() - (1.0f32 + 0.4 * 0.4).sqrt()).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn zero_length_arrow_is_just_a_line() {
+        let point = Point3::new(1.0, 1.0, 1.0);
+        let mut lines = DebugLines::default();
+        lines.arrow(point, point, WHITE);
+        assert_eq!(lines.vertices.len(), 2);
+    }
+
+    #[test]
+    fn frustum_of_identity_is_the_clip_volume() {
+        let mut lines = DebugLines::default();
+        lines.frustum(Matrix4::identity(), WHITE);
+        assert_eq!(lines.vertices.len(), 24);
+        assert_eq!(segments(&lines), box_edges(0, 1));
+    }
+
+    #[test]
+    fn frustum_of_a_singular_matrix_draws_nothing() {
+        let mut lines = DebugLines::default();
+        lines.frustum(Matrix4::from_scale(0.0), WHITE);
+        assert!(lines.vertices.is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+use std::sync::Arc;
+
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::pipeline::ComputePipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::Sampler;
+
+use super::fullscreen::clamp_sampler;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::system::HDR_IMAGE;
+
+/// Computes a luminance histogram of the HDR scene image and adapts the exposure towards it.
+///
+/// The adapted luminance lives in a storage buffer so the tonemapper can read it without a
+/// round trip through the CPU.
+pub struct EyeAdaptationSystem {
+    pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    sampler: Arc<Sampler>,
+    exposure_buffer: Arc<CpuAccessibleBuffer<cs::ty::Exposure>>,
+}
+
+impl EyeAdaptationSystem {
+    pub fn new(queue: Arc<Queue>) -> EyeAdaptationSystem {
+        let pipeline = {
+            let cs = cs::Shader::load(queue.device().clone()).expect("Could not create shader module");
+
+            Arc::new(
+                ComputePipeline::new(queue.device().clone(), &cs.main_entry_point(), &())
+                    .expect("Failed to create eye adaptation pipeline"),
+            ) as Arc<_>
+        };
+
+        let sampler = clamp_sampler(queue.device(), Filter::Nearest);
+
+        let exposure_buffer = CpuAccessibleBuffer::from_data(
+            queue.device().clone(),
+            BufferUsage {
+                storage_buffer: true,
+                ..BufferUsage::none()
+            },
+            cs::ty::Exposure {
+                adapted_luminance: 0.18,
+            },
+        ).expect("Failed to create exposure buffer");
+
+        EyeAdaptationSystem {
+            pipeline,
+            sampler,
+            exposure_buffer,
+        }
+    }
+
+    /// Buffer holding the current adapted luminance.
+    #[inline]
+    pub fn exposure_buffer(&self) -> Arc<CpuAccessibleBuffer<cs::ty::Exposure>> {
+        self.exposure_buffer.clone()
+    }
+}
+
+impl RenderNode for EyeAdaptationSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.read(HDR_IMAGE);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        let dt = context.delta_time();
+        let settings = context.settings().hdr.clone();
+        if !settings.auto_exposure {
+            return;
+        }
+
+        let descriptor_set = PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+            .add_sampled_image(context.image(HDR_IMAGE), self.sampler.clone())
+            .unwrap()
+            .add_buffer(self.exposure_buffer.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let push_constants = cs::ty::PushConstants {
+            min_log_luminance: settings.min_log_luminance,
+            log_luminance_range: settings.max_log_luminance - settings.min_log_luminance,
+            adaptation: 1.0 - (-dt * settings.adaptation_speed).exp(),
+            low_percentile: 0.5,
+            high_percentile: 0.95,
+        };
+
+        let pipeline = self.pipeline.clone();
+        context.record(|command_buffer| {
+            command_buffer
+                .dispatch([1, 1, 1], pipeline, descriptor_set, push_constants)
+                .unwrap()
+        });
+    }
+}
+
+mod cs {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[src = "
+#version 450
+
+// A single workgroup walks the whole image, every other pixel in each direction.
+layout(local_size_x = 16, local_size_y = 16, local_size_z = 1) in;
+
+layout(set = 0, binding = 0) uniform sampler2D u_hdr;
+layout(set = 0, binding = 1) buffer Exposure {
+    float adapted_luminance;
+} exposure_state;
+
+layout(push_constant) uniform PushConstants {
+    float min_log_luminance;
+    float log_luminance_range;
+    float adaptation;
+    float low_percentile;
+    float high_percentile;
+} push_constants;
+
+const uint BIN_COUNT = 256;
+const int SAMPLE_STEP = 2;
+
+shared uint histogram[BIN_COUNT];
+
+void main() {
+    uint index = gl_LocalInvocationIndex;
+    histogram[index] = 0;
+    barrier();
+
+    ivec2 size = textureSize(u_hdr, 0);
+    for (int y = int(gl_LocalInvocationID.y) * SAMPLE_STEP; y < size.y; y += 16 * SAMPLE_STEP) {
+        for (int x = int(gl_LocalInvocationID.x) * SAMPLE_STEP; x < size.x; x += 16 * SAMPLE_STEP) {
+            vec3 color = texelFetch(u_hdr, ivec2(x, y), 0).rgb;
+            float luminance = dot(color, vec3(0.2126, 0.7152, 0.0722));
+
+            // Bin 0 is reserved for (near) black pixels, which are left out of the average.
+            uint bin = 0;
+            if (luminance > 0.0001) {
+                float t = clamp((log2(luminance) - push_constants.min_log_luminance)
+                    / push_constants.log_luminance_range, 0.0, 1.0);
+                bin = uint(t * float(BIN_COUNT - 2) + 1.0);
+            }
+            atomicAdd(histogram[bin], 1);
+        }
+    }
+    barrier();
+
+    if (index != 0) {
+        return;
+    }
+
+    uint total = 0;
+    for (uint i = 1; i < BIN_COUNT; i++) {
+        total += histogram[i];
+    }
+
+    float target_log_luminance = push_constants.min_log_luminance;
+    if (total > 0) {
+        // Average of the bins between the percentiles, so a few very bright or very dark
+        // pixels can't drag the exposure around.
+        float low = push_constants.low_percentile * float(total);
+        float high = push_constants.high_percentile * float(total);
+        float seen = 0.0;
+        float weighted_sum = 0.0;
+        float weight = 0.0;
+        for (uint i = 1; i < BIN_COUNT; i++) {
+            float count = float(histogram[i]);
+            float lower = max(seen, low);
+            float upper = min(seen + count, high);
+            float included = max(upper - lower, 0.0);
+            float t = (float(i) - 0.5) / float(BIN_COUNT - 2);
+            weighted_sum += included * (push_constants.min_log_luminance + t * push_constants.log_luminance_range);
+            weight += included;
+            seen += count;
+        }
+        if (weight > 0.0) {
+            target_log_luminance = weighted_sum / weight;
+        }
+    }
+
+    float target = exp2(target_log_luminance);
+    float current = exposure_state.adapted_luminance;
+    exposure_state.adapted_luminance = current + (target - current) * push_constants.adaptation;
+}
+"]
+    struct Dummy;
+}
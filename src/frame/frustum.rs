@@ -0,0 +1,115 @@
+use cgmath::{InnerSpace, Matrix4, Point3, Vector3, Vector4};
+
+/// The six planes bounding what a camera sees, for culling.
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    /// Left, right, bottom, top, near and far; `xyz` points inside and is normalized, so
+    /// `dot(xyz, p) + w` is the signed distance of `p`.
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes of `projection * view` in the `-w..w` clip space of
+    /// `cgmath::perspective`, which also contains the `0..w` depth range of Vulkan.
+    pub fn new(view_projection: Matrix4<f32>) -> Frustum {
+        let row = |i: usize| {
+            Vector4::new(
+                view_projection.x[i],
+                view_projection.y[i],
+                view_projection.z[i],
+                view_projection.w[i],
+            )
+        };
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let normalize = |plane: Vector4<f32>| plane / plane.truncate().magnitude();
+
+        Frustum {
+            planes: [
+                normalize(w + x),
+                normalize(w - x),
+                normalize(w + y),
+                normalize(w - y),
+                normalize(w + z),
+                normalize(w - z),
+            ],
+        }
+    }
+
+    #[inline]
+    pub fn planes(&self) -> &[Vector4<f32>; 6] {
+        &self.planes
+    }
+
+    /// Whether any part of the sphere may be visible. Spheres near the corners can pass
+    /// without being inside.
+    pub fn intersects_sphere(&self, center: Point3<f32>, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.truncate().dot(Vector3::new(center.x, center.y, center.z)) + plane.w >= -radius)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{perspective, Deg, InnerSpace, Matrix4, Point3, Vector3};
+
+    use super::Frustum;
+
+    /// Looks down -Z from the origin with a 90 degree field of view, from 1 to 10 away.
+    fn frustum() -> Frustum {
+        Frustum::new(perspective(Deg(90.0), 1.0, 1.0, 10.0))
+    }
+
+    fn distance(frustum: &Frustum, plane: usize, point: Vector3<f32>) -> f32 {
+        let plane = frustum.planes()[plane];
+        plane.truncate().dot(point) + plane.w
+    }
+
+    #[test]
+    fn planes_are_normalized_and_face_inside() {
+        let frustum = frustum();
+        for plane in 0..6 {
+            assert!((frustum.planes()[plane].truncate().magnitude() - 1.0).abs() < 1e-5);
+            assert!(distance(&frustum, plane, Vector3::new(0.0, 0.0, -5.0)) > 0.0);
+        }
+    }
+
+    #[test]
+    fn planes_are_where_the_projection_puts_them() {
+        let frustum = frustum();
+        let on_plane = [
+            Vector3::new(-5.0, 0.0, -5.0),
+            Vector3::new(5.0, 0.0, -5.0),
+            Vector3::new(0.0, -5.0, -5.0),
+            Vector3::new(0.0, 5.0, -5.0),
+            Vector3::new(0.0, 0.0, -1.0),
+            Vector3::new(0.0, 0.0, -10.0),
+        ];
+        for (plane, point) in on_plane.iter().enumerate() {
+            assert!(distance(&frustum, plane, *point).abs() < 1e-4, "plane {}", plane);
+        }
+    }
+
+    #[test]
+    fn spheres_inside_and_outside() {
+        let frustum = frustum();
+        assert!(frustum.intersects_sphere(Point3::new(0.0, 0.0, -5.0), 0.1));
+        // Behind the camera and past the far plane.
+        assert!(!frustum.intersects_sphere(Point3::new(0.0, 0.0, 5.0), 1.0));
+        assert!(!frustum.intersects_sphere(Point3::new(0.0, 0.0, -12.0), 1.0));
+        assert!(frustum.intersects_sphere(Point3::new(0.0, 0.0, -12.0), 3.0));
+        // Just left of the left plane, about 0.7 away from it.
+        assert!(!frustum.intersects_sphere(Point3::new(-6.0, 0.0, -5.0), 0.5));
+        assert!(frustum.intersects_sphere(Point3::new(-6.0, 0.0, -5.0), 1.0));
+    }
+
+    #[test]
+    fn planes_follow_the_view() {
+        let view = Matrix4::look_at(Point3::new(20.0, 0.0, 0.0), Point3::new(0.0, 0.0, 0.0), Vector3::unit_y());
+        let frustum = Frustum::new(perspective(Deg(90.0), 1.0, 1.0, 30.0) * view);
+        assert!(frustum.intersects_sphere(Point3::new(0.0, 0.0, 0.0), 0.5));
+        // Further to the side than ahead of the camera, outside its 90 degrees.
+        assert!(!frustum.intersects_sphere(Point3::new(0.0, 0.0, -25.0), 0.5));
+        assert!(!frustum.intersects_sphere(Point3::new(25.0, 0.0, 0.0), 1.0));
+    }
+}
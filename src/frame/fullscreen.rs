@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::DynamicState;
+use vulkano::device::Device;
+use vulkano::device::Queue;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::sampler::Filter;
+use vulkano::sampler::MipmapMode;
+use vulkano::sampler::Sampler;
+use vulkano::sampler::SamplerAddressMode;
+
+/// Vertex of `fullscreen_triangle`, in clip space.
+#[derive(Debug, Clone)]
+pub struct Vertex {
+    position: [f32; 2],
+}
+impl_vertex!(Vertex, position);
+
+/// A single triangle covering the whole viewport, for passes that shade every pixel of an
+/// image.
+pub fn fullscreen_triangle(gfx_queue: &Arc<Queue>) -> Arc<CpuAccessibleBuffer<[Vertex]>> {
+    CpuAccessibleBuffer::from_iter(
+        gfx_queue.device().clone(),
+        BufferUsage::all(),
+        [
+            Vertex { position: [-1.0, -1.0] },
+            Vertex { position: [-1.0, 3.0] },
+            Vertex { position: [3.0, -1.0] },
+        ].iter()
+            .cloned(),
+    ).expect("Failed to create fullscreen vertex buffer")
+}
+
+/// A viewport over all of an image of `dimensions`.
+pub fn viewport_state(dimensions: [u32; 2]) -> DynamicState {
+    DynamicState {
+        viewports: Some(vec![Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+            depth_range: 0.0..1.0,
+        }]),
+        ..DynamicState::none()
+    }
+}
+
+/// Samples a single mip level, clamping to the edge.
+pub fn clamp_sampler(device: &Arc<Device>, filter: Filter) -> Arc<Sampler> {
+    Sampler::new(
+        device.clone(),
+        filter,
+        filter,
+        MipmapMode::Nearest,
+        SamplerAddressMode::ClampToEdge,
+        SamplerAddressMode::ClampToEdge,
+        SamplerAddressMode::ClampToEdge,
+        0.0,
+        1.0,
+        0.0,
+        0.0,
+    ).unwrap()
+}
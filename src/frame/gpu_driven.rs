@@ -0,0 +1,727 @@
+use std::sync::Arc;
+
+use cgmath::{Matrix4, SquareMatrix};
+use vulkano::buffer::BufferAccess;
+use vulkano::buffer::BufferSlice;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::buffer::DeviceLocalBuffer;
+use vulkano::command_buffer::AutoCommandBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::DrawIndirectCommand;
+use vulkano::descriptor::descriptor_set::FixedSizeDescriptorSetsPool;
+use vulkano::device::Queue;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::pipeline::ComputePipelineAbstract;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Sampler;
+
+use super::frustum::Frustum;
+use super::fullscreen::viewport_state;
+use super::instancing::instanced_fs;
+use super::instancing::instanced_pipeline;
+use super::instancing::instanced_vs;
+use super::instancing::InstanceData;
+use super::instancing::Material;
+use super::instancing::MaterialId;
+use super::instancing::Mesh;
+use super::instancing::MeshId;
+use super::instancing::MeshVertex;
+use super::system::ComputePass;
+use super::system::ComputeStage;
+use super::system::NORMAL_DEPTH_IMAGE;
+
+/// Matches `local_size_x` of `cull_cs`.
+const CULL_LOCAL_SIZE: u32 = 64;
+/// Matches `local_size_x` and `local_size_y` of `hiz_cs`.
+const HIZ_LOCAL_SIZE: u32 = 8;
+/// Size of the finest level of the depth pyramid, whatever the output size.
+const HIZ_WIDTH: u32 = 256;
+const HIZ_HEIGHT: u32 = 128;
+/// Down to 2x1 texels, matches `HIZ_LEVELS` in the shaders.
+const HIZ_LEVELS: u32 = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CullingSettings {
+    /// Draws the scene's repeated meshes through `GpuScene` instead of `InstanceRenderer`.
+    /// Read by the application, like `TransparencySettings::order_independent`.
+    pub gpu_driven: bool,
+    /// Also culls `GpuScene` objects hidden behind the depth of the previous frame.
+    pub occlusion: bool,
+}
+
+impl Default for CullingSettings {
+    fn default() -> CullingSettings {
+        CullingSettings {
+            gpu_driven: false,
+            occlusion: true,
+        }
+    }
+}
+
+/// Index of an object added with `GpuScene::add_object`.
+pub type ObjectId = usize;
+
+/// Of the objects in a `GpuScene`. How many of them are drawn is only known on the GPU.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GpuSceneStats {
+    pub objects: u32,
+    /// Indirect draws, one per mesh and material.
+    pub batches: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Object {
+    mesh: MeshId,
+    material: MaterialId,
+    model: Matrix4<f32>,
+    color: [f32; 4],
+}
+
+/// Laid out like `Object` in `cull_cs`, std430.
+#[derive(Debug, Clone, Copy)]
+struct GpuObject {
+    /// World space center and radius of the bounding sphere.
+    sphere: [f32; 4],
+    model: [[f32; 4]; 4],
+    color: [f32; 4],
+    /// Index of the batch's draw command.
+    command: u32,
+    /// Where the batch's instances start in the instance buffer.
+    first_instance: u32,
+    _padding: [u32; 2],
+}
+
+/// Objects sharing a mesh and a material, drawn by one indirect draw.
+#[derive(Debug, Clone)]
+struct Batch {
+    mesh: MeshId,
+    material: MaterialId,
+    first_instance: u32,
+    count: u32,
+}
+
+/// Everything uploaded for the objects, rebuilt when they change.
+struct SceneBuffers {
+    objects: Arc<CpuAccessibleBuffer<[GpuObject]>>,
+    /// The visible objects of each batch, compacted at the start of its range.
+    instances: Arc<DeviceLocalBuffer<[InstanceData]>>,
+    /// One per batch, `instance_count` is counted up by the culling shader.
+    commands: Arc<DeviceLocalBuffer<[DrawIndirectCommand]>>,
+    /// Copied over `commands` before culling.
+    empty_commands: Arc<CpuAccessibleBuffer<[DrawIndirectCommand]>>,
+    batches: Vec<Batch>,
+}
+
+/// Static objects culled and drawn without the CPU touching each of them every frame.
+///
+/// The objects' bounds and transforms are uploaded once, and again after they change. In
+/// `ComputeStage::BeforeRendering` a compute shader tests every object against the view
+/// frustum and, with `CullingSettings::occlusion`, against a depth pyramid built from the
+/// previous frame in `ComputeStage::AfterRendering`. Visible objects are appended to their
+/// batch's range of the instance buffer, counting up the instances of its indirect draw. Since
+/// the pyramid lags a frame behind, objects coming into view from behind others can show up a
+/// frame late.
+pub struct GpuScene {
+    queue: Arc<Queue>,
+    meshes: Vec<Mesh>,
+    materials: Vec<Material>,
+    objects: Vec<Object>,
+    /// Whether `objects` changed since `buffers` were built.
+    dirty: bool,
+    buffers: Option<SceneBuffers>,
+    cull_pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    cull_ds_pool: FixedSizeDescriptorSetsPool<Arc<ComputePipelineAbstract + Send + Sync>>,
+    cull_buffer_pool: CpuBufferPool<cull_cs::ty::Cull>,
+    hiz_pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    hiz_ds_pool: FixedSizeDescriptorSetsPool<Arc<ComputePipelineAbstract + Send + Sync>>,
+    /// All levels of the depth pyramid, finest first. Each texel is the farthest view depth
+    /// in its part of the screen.
+    hiz_buffer: Arc<DeviceLocalBuffer<[f32]>>,
+    /// Where each level starts in `hiz_buffer`, for both shaders.
+    hiz_level_offsets: Arc<CpuAccessibleBuffer<[u32]>>,
+    /// View and projection the pyramid was built with, `None` unless it was built since the
+    /// last cull with occlusion.
+    hiz_camera: Option<(Matrix4<f32>, Matrix4<f32>)>,
+    sampler: Arc<Sampler>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    camera_buffer_pool: CpuBufferPool<instanced_vs::ty::Camera>,
+    ds_pool: FixedSizeDescriptorSetsPool<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+}
+
+impl GpuScene {
+    /// `subpass` is the one of `Pass::Deferred`.
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> GpuScene
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let device = queue.device().clone();
+
+        let cull_pipeline = {
+            let cs = cull_cs::Shader::load(device.clone()).expect("Could not create shader module");
+            let pipeline = ComputePipeline::new(device.clone(), &cs.main_entry_point(), &()).unwrap();
+            Arc::new(pipeline) as Arc<ComputePipelineAbstract + Send + Sync>
+        };
+        let hiz_pipeline = {
+            let cs = hiz_cs::Shader::load(device.clone()).expect("Could not create shader module");
+            let pipeline = ComputePipeline::new(device.clone(), &cs.main_entry_point(), &()).unwrap();
+            Arc::new(pipeline) as Arc<ComputePipelineAbstract + Send + Sync>
+        };
+
+        let level_texels = |level: u32| (HIZ_WIDTH >> level) * (HIZ_HEIGHT >> level);
+        let level_offsets: Vec<u32> = (0..HIZ_LEVELS)
+            .scan(0, |offset, level| {
+                let level_offset = *offset;
+                *offset += level_texels(level);
+                Some(level_offset)
+            })
+            .collect();
+        let hiz_texels = level_offsets[HIZ_LEVELS as usize - 1] + level_texels(HIZ_LEVELS - 1);
+        let hiz_buffer = DeviceLocalBuffer::array(
+            device.clone(),
+            hiz_texels as usize,
+            BufferUsage {
+                storage_buffer: true,
+                ..BufferUsage::none()
+            },
+            Some(queue.family()),
+        ).expect("Failed to create depth pyramid buffer");
+        let hiz_level_offsets = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage {
+                storage_buffer: true,
+                ..BufferUsage::none()
+            },
+            level_offsets.into_iter(),
+        ).expect("Failed to create depth pyramid level buffer");
+
+        let pipeline = instanced_pipeline(&queue, subpass);
+
+        GpuScene {
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            objects: Vec::new(),
+            dirty: false,
+            buffers: None,
+            cull_ds_pool: FixedSizeDescriptorSetsPool::new(cull_pipeline.clone(), 0),
+            cull_buffer_pool: CpuBufferPool::uniform_buffer(device.clone()),
+            cull_pipeline,
+            hiz_ds_pool: FixedSizeDescriptorSetsPool::new(hiz_pipeline.clone(), 0),
+            hiz_pipeline,
+            hiz_buffer,
+            hiz_level_offsets,
+            hiz_camera: None,
+            sampler: Sampler::simple_repeat_linear_no_mipmap(device.clone()),
+            camera_buffer_pool: CpuBufferPool::uniform_buffer(device.clone()),
+            ds_pool: FixedSizeDescriptorSetsPool::new(pipeline.clone(), 0),
+            pipeline,
+            queue,
+        }
+    }
+
+    /// A triangle list in model space.
+    pub fn add_mesh(&mut self, vertices: Vec<MeshVertex>) -> MeshId {
+        let mesh = Mesh::new(&self.queue, vertices);
+        self.meshes.push(mesh);
+        self.meshes.len() - 1
+    }
+
+    pub fn add_material(&mut self, material: Material) -> MaterialId {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    #[inline]
+    pub fn material_mut(&mut self, material: MaterialId) -> &mut Material {
+        &mut self.materials[material]
+    }
+
+    /// The model matrix may translate, rotate and scale uniformly.
+    pub fn add_object(&mut self, mesh: MeshId, material: MaterialId, model: Matrix4<f32>, color: [f32; 4]) -> ObjectId {
+        assert!(mesh < self.meshes.len() && material < self.materials.len());
+        self.objects.push(Object {
+            mesh,
+            material,
+            model,
+            color,
+        });
+        self.dirty = true;
+        self.objects.len() - 1
+    }
+
+    /// Uploads all objects again before the next culling, meant for occasional changes.
+    pub fn set_transform(&mut self, object: ObjectId, model: Matrix4<f32>) {
+        self.objects[object].model = model;
+        self.dirty = true;
+    }
+
+    pub fn stats(&self) -> GpuSceneStats {
+        GpuSceneStats {
+            objects: self.objects.len() as u32,
+            batches: self.buffers.as_ref().map_or(0, |buffers| buffers.batches.len() as u32),
+        }
+    }
+
+    /// Culls the objects before rendering, and builds the depth pyramid for occlusion culling
+    /// after it.
+    pub fn update(&mut self, compute_pass: &mut ComputePass, settings: &CullingSettings) {
+        match compute_pass.stage() {
+            ComputeStage::BeforeRendering => self.cull(compute_pass, settings.occlusion),
+            ComputeStage::AfterRendering => {
+                if settings.occlusion {
+                    self.build_depth_pyramid(compute_pass);
+                }
+            }
+        }
+    }
+
+    fn cull(&mut self, compute_pass: &mut ComputePass, occlusion: bool) {
+        if self.objects.is_empty() {
+            return;
+        }
+        if self.dirty {
+            self.upload();
+        }
+        let buffers = self.buffers.as_ref().unwrap();
+
+        let frustum = Frustum::new(compute_pass.projection_matrix() * compute_pass.view_matrix());
+        let mut planes = [[0.0; 4]; 6];
+        for (plane, frustum_plane) in planes.iter_mut().zip(frustum.planes().iter()) {
+            *plane = (*frustum_plane).into();
+        }
+        // Only the pyramid of the previous frame is any good. Culling without it leaves it to
+        // the next cull that uses it, e.g. when the scene is drawn by several cameras and only
+        // one of them does occlusion culling.
+        let hiz_camera = if occlusion { self.hiz_camera.take() } else { None };
+        let (hiz_view, hiz_projection) = hiz_camera.unwrap_or((Matrix4::identity(), Matrix4::identity()));
+        let uniform_buffer = self
+            .cull_buffer_pool
+            .next(cull_cs::ty::Cull {
+                planes,
+                hiz_view: hiz_view.into(),
+                hiz_projection: hiz_projection.into(),
+                object_count: self.objects.len() as u32,
+                occlusion: (occlusion && hiz_camera.is_some()) as u32,
+            })
+            .unwrap();
+        let descriptor_set = self
+            .cull_ds_pool
+            .next()
+            .add_buffer(uniform_buffer)
+            .unwrap()
+            .add_buffer(buffers.objects.clone())
+            .unwrap()
+            .add_buffer(buffers.commands.clone())
+            .unwrap()
+            .add_buffer(buffers.instances.clone())
+            .unwrap()
+            .add_buffer(self.hiz_buffer.clone())
+            .unwrap()
+            .add_buffer(self.hiz_level_offsets.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let (empty_commands, commands) = (buffers.empty_commands.clone(), buffers.commands.clone());
+        compute_pass.record(|command_buffer| command_buffer.copy_buffer(empty_commands, commands).unwrap());
+        let groups = (self.objects.len() as u32 + CULL_LOCAL_SIZE - 1) / CULL_LOCAL_SIZE;
+        compute_pass.dispatch([groups, 1, 1], self.cull_pipeline.clone(), descriptor_set, ());
+    }
+
+    fn build_depth_pyramid(&mut self, compute_pass: &mut ComputePass) {
+        let descriptor_set = Arc::new(
+            self.hiz_ds_pool
+                .next()
+                .add_sampled_image(compute_pass.image(NORMAL_DEPTH_IMAGE), self.sampler.clone())
+                .unwrap()
+                .add_buffer(self.hiz_buffer.clone())
+                .unwrap()
+                .add_buffer(self.hiz_level_offsets.clone())
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+
+        let source_size = compute_pass.dimensions(NORMAL_DEPTH_IMAGE);
+        for level in 0..HIZ_LEVELS {
+            let (width, height) = (HIZ_WIDTH >> level, HIZ_HEIGHT >> level);
+            compute_pass.dispatch(
+                [
+                    (width + HIZ_LOCAL_SIZE - 1) / HIZ_LOCAL_SIZE,
+                    (height + HIZ_LOCAL_SIZE - 1) / HIZ_LOCAL_SIZE,
+                    1,
+                ],
+                self.hiz_pipeline.clone(),
+                descriptor_set.clone(),
+                hiz_cs::ty::PushConstants { source_size, level },
+            );
+        }
+        self.hiz_camera = Some((compute_pass.view_matrix(), compute_pass.projection_matrix()));
+    }
+
+    /// Drops the depth pyramid, for when GPU driven culling is turned off. It would be stale
+    /// by the time culling is back on.
+    pub fn discard_depth_pyramid(&mut self) {
+        self.hiz_camera = None;
+    }
+
+    /// Sorts the objects into batches and uploads them.
+    fn upload(&mut self) {
+        let device = self.queue.device().clone();
+
+        let mut order: Vec<usize> = (0..self.objects.len()).collect();
+        order.sort_by_key(|&index| (self.objects[index].mesh, self.objects[index].material));
+
+        let mut batches: Vec<Batch> = Vec::new();
+        let mut gpu_objects = Vec::with_capacity(order.len());
+        for (position, &index) in order.iter().enumerate() {
+            let object = &self.objects[index];
+            let same_batch = batches
+                .last()
+                .map_or(false, |batch| batch.mesh == object.mesh && batch.material == object.material);
+            if same_batch {
+                batches.last_mut().unwrap().count += 1;
+            } else {
+                batches.push(Batch {
+                    mesh: object.mesh,
+                    material: object.material,
+                    first_instance: position as u32,
+                    count: 1,
+                });
+            }
+
+            let (center, radius) = self.meshes[object.mesh].bounds(&object.model);
+            gpu_objects.push(GpuObject {
+                sphere: [center.x, center.y, center.z, radius],
+                model: object.model.into(),
+                color: object.color,
+                command: (batches.len() - 1) as u32,
+                first_instance: batches.last().unwrap().first_instance,
+                _padding: [0; 2],
+            });
+        }
+
+        let objects = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage {
+                storage_buffer: true,
+                ..BufferUsage::none()
+            },
+            gpu_objects.into_iter(),
+        ).expect("Failed to create object buffer");
+
+        let instances = DeviceLocalBuffer::array(
+            device.clone(),
+            self.objects.len(),
+            BufferUsage {
+                storage_buffer: true,
+                vertex_buffer: true,
+                ..BufferUsage::none()
+            },
+            Some(self.queue.family()),
+        ).expect("Failed to create instance buffer");
+
+        let commands = DeviceLocalBuffer::array(
+            device.clone(),
+            batches.len(),
+            BufferUsage {
+                storage_buffer: true,
+                indirect_buffer: true,
+                transfer_destination: true,
+                ..BufferUsage::none()
+            },
+            Some(self.queue.family()),
+        ).expect("Failed to create indirect draw buffer");
+
+        let meshes = &self.meshes;
+        let empty_commands = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage::transfer_source(),
+            batches.iter().map(|batch| DrawIndirectCommand {
+                vertex_count: meshes[batch.mesh].vertex_buffer.len() as u32,
+                instance_count: 0,
+                first_vertex: 0,
+                first_instance: 0,
+            }),
+        ).expect("Failed to create indirect draw buffer");
+
+        self.buffers = Some(SceneBuffers {
+            objects,
+            instances,
+            commands,
+            empty_commands,
+            batches,
+        });
+        self.dirty = false;
+    }
+
+    /// For `Pass::Deferred`, lit by the `SunUniforms` in `sun_buffer`. Draws what the latest
+    /// culling found visible, with one indirect draw per batch.
+    pub fn draw(
+        &mut self,
+        dimensions: [u32; 2],
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+        sun_buffer: Arc<BufferAccess + Send + Sync>,
+    ) -> AutoCommandBuffer {
+        let camera_buffer = self
+            .camera_buffer_pool
+            .next(instanced_vs::ty::Camera {
+                view: view.into(),
+                projection: projection.into(),
+            })
+            .unwrap();
+        let descriptor_set = Arc::new(
+            self.ds_pool
+                .next()
+                .add_buffer(camera_buffer)
+                .unwrap()
+                .add_buffer(sun_buffer)
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+
+        let dynamic_state = viewport_state(dimensions);
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            self.pipeline.clone().subpass(),
+        ).unwrap();
+        if let Some(ref buffers) = self.buffers {
+            for (index, batch) in buffers.batches.iter().enumerate() {
+                let range = batch.first_instance as usize..(batch.first_instance + batch.count) as usize;
+                let instances = BufferSlice::from_typed_buffer_access(buffers.instances.clone())
+                    .slice(range)
+                    .unwrap();
+                let command = BufferSlice::from_typed_buffer_access(buffers.commands.clone())
+                    .slice(index..index + 1)
+                    .unwrap();
+                builder = builder
+                    .draw_indirect(
+                        self.pipeline.clone(),
+                        dynamic_state.clone(),
+                        vec![
+                            self.meshes[batch.mesh].vertex_buffer.clone() as Arc<BufferAccess + Send + Sync>,
+                            Arc::new(instances) as Arc<BufferAccess + Send + Sync>,
+                        ],
+                        command,
+                        descriptor_set.clone(),
+                        instanced_fs::ty::PushConstants {
+                            color: self.materials[batch.material].color,
+                        },
+                    )
+                    .unwrap();
+            }
+        }
+        builder.build().unwrap()
+    }
+}
+
+mod cull_cs {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[src = "
+#version 450
+
+layout(local_size_x = 64) in;
+
+const uint HIZ_WIDTH = 256;
+const uint HIZ_HEIGHT = 128;
+const uint HIZ_LEVELS = 8;
+
+struct Object {
+    vec4 sphere;
+    mat4 model;
+    vec4 color;
+    uint command;
+    uint first_instance;
+};
+
+struct DrawCommand {
+    uint vertex_count;
+    uint instance_count;
+    uint first_vertex;
+    uint first_instance;
+};
+
+struct Instance {
+    vec4 model0;
+    vec4 model1;
+    vec4 model2;
+    vec4 model3;
+    vec4 color;
+};
+
+layout(std140, set = 0, binding = 0) uniform Cull {
+    // Pointing inside, normalized.
+    vec4 planes[6];
+    // Camera of the depth pyramid.
+    mat4 hiz_view;
+    mat4 hiz_projection;
+    uint object_count;
+    uint occlusion;
+} cull;
+
+layout(std430, set = 0, binding = 1) readonly buffer Objects {
+    Object data[];
+} objects;
+
+layout(std430, set = 0, binding = 2) buffer Commands {
+    DrawCommand data[];
+} commands;
+
+layout(std430, set = 0, binding = 3) writeonly buffer Instances {
+    Instance data[];
+} instances;
+
+layout(std430, set = 0, binding = 4) readonly buffer DepthPyramid {
+    float data[];
+} hiz;
+
+// Where each level starts in the depth pyramid.
+layout(std430, set = 0, binding = 5) readonly buffer DepthPyramidLevels {
+    uint offsets[];
+} hiz_levels;
+
+bool in_frustum(vec3 center, float radius) {
+    for (int i = 0; i < 6; i++) {
+        if (dot(cull.planes[i].xyz, center) + cull.planes[i].w < -radius) {
+            return false;
+        }
+    }
+    return true;
+}
+
+bool occluded(vec3 center, float radius) {
+    vec3 view_center = (cull.hiz_view * vec4(center, 1.0)).xyz;
+    float nearest = -view_center.z - radius;
+    if (nearest <= 0.0) {
+        // Reaches behind the camera, no rectangle on the screen bounds it.
+        return false;
+    }
+
+    // The sphere's bounding box, seen through its front face, covers the sphere on screen.
+    vec2 ndc_min = vec2(1.0);
+    vec2 ndc_max = vec2(-1.0);
+    for (int i = 0; i < 4; i++) {
+        vec2 corner = view_center.xy + radius * vec2((i & 1) == 0 ? -1.0 : 1.0, (i & 2) == 0 ? -1.0 : 1.0);
+        vec4 clip = cull.hiz_projection * vec4(corner, -nearest, 1.0);
+        vec2 ndc = clip.xy / clip.w;
+        ndc_min = min(ndc_min, ndc);
+        ndc_max = max(ndc_max, ndc);
+    }
+    ndc_min = clamp(ndc_min, -1.0, 1.0);
+    ndc_max = clamp(ndc_max, -1.0, 1.0);
+
+    vec2 size = vec2(HIZ_WIDTH, HIZ_HEIGHT);
+    vec2 texel_min = (ndc_min * 0.5 + 0.5) * size;
+    vec2 texel_max = (ndc_max * 0.5 + 0.5) * size;
+    // The level where the rectangle covers at most 2x2 texels.
+    vec2 extent = max(texel_max - texel_min, vec2(1.0));
+    uint level = min(uint(ceil(log2(max(extent.x, extent.y)))), HIZ_LEVELS - 1);
+
+    uvec2 level_size = uvec2(HIZ_WIDTH >> level, HIZ_HEIGHT >> level);
+    uvec2 first = min(uvec2(texel_min) >> level, level_size - 1);
+    uvec2 last = min(uvec2(texel_max) >> level, level_size - 1);
+    uint offset = hiz_levels.offsets[level];
+    float farthest = 0.0;
+    for (uint y = first.y; y <= last.y; y++) {
+        for (uint x = first.x; x <= last.x; x++) {
+            farthest = max(farthest, hiz.data[offset + y * level_size.x + x]);
+        }
+    }
+    return nearest > farthest;
+}
+
+void main() {
+    uint index = gl_GlobalInvocationID.x;
+    if (index >= cull.object_count) {
+        return;
+    }
+    Object object = objects.data[index];
+    vec3 center = object.sphere.xyz;
+    float radius = object.sphere.w;
+
+    if (!in_frustum(center, radius)) {
+        return;
+    }
+    if (cull.occlusion != 0 && occluded(center, radius)) {
+        return;
+    }
+
+    uint slot = atomicAdd(commands.data[object.command].instance_count, 1u);
+    instances.data[object.first_instance + slot] = Instance(
+        object.model[0], object.model[1], object.model[2], object.model[3], object.color);
+}
+"]
+    struct Dummy;
+}
+
+mod hiz_cs {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[src = "
+#version 450
+
+layout(local_size_x = 8, local_size_y = 8) in;
+
+const uint HIZ_WIDTH = 256;
+const uint HIZ_HEIGHT = 128;
+// Anything farther than the scene, where nothing was drawn.
+const float FAR = 1.0e30;
+
+// View space normal, view depth in w and 0 where nothing was drawn.
+layout(set = 0, binding = 0) uniform sampler2D normal_depth;
+
+layout(std430, set = 0, binding = 1) buffer DepthPyramid {
+    float data[];
+} hiz;
+
+// Where each level starts in the depth pyramid.
+layout(std430, set = 0, binding = 2) readonly buffer DepthPyramidLevels {
+    uint offsets[];
+} hiz_levels;
+
+layout(push_constant) uniform PushConstants {
+    uvec2 source_size;
+    uint level;
+} push_constants;
+
+void main() {
+    uint level = push_constants.level;
+    uvec2 size = uvec2(HIZ_WIDTH >> level, HIZ_HEIGHT >> level);
+    uvec2 texel = gl_GlobalInvocationID.xy;
+    if (texel.x >= size.x || texel.y >= size.y) {
+        return;
+    }
+
+    float farthest = 0.0;
+    if (level == 0) {
+        // Every pixel of the screen rectangle under the texel.
+        uvec2 source_size = push_constants.source_size;
+        uvec2 first = texel * source_size / size;
+        uvec2 last = max((texel + 1) * source_size / size, first + 1);
+        for (uint y = first.y; y < last.y; y++) {
+            for (uint x = first.x; x < last.x; x++) {
+                float depth = texelFetch(normal_depth, ivec2(x, y), 0).w;
+                farthest = max(farthest, depth > 0.0 ? depth : FAR);
+            }
+        }
+    } else {
+        uvec2 finer_size = size * 2;
+        uint finer = hiz_levels.offsets[level - 1];
+        for (uint i = 0; i < 4; i++) {
+            uvec2 source = texel * 2 + uvec2(i & 1, i >> 1);
+            farthest = max(farthest, hiz.data[finer + source.y * finer_size.x + source.x]);
+        }
+    }
+    hiz.data[hiz_levels.offsets[level] + texel.y * size.x + texel.x] = farthest;
+}
+"]
+    struct Dummy;
+}
@@ -0,0 +1,980 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use cgmath::Matrix4;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::CommandBuffer;
+use vulkano::device::Queue;
+use vulkano::format::ClearValue;
+use vulkano::format::Format;
+use vulkano::framebuffer::AttachmentsList;
+use vulkano::framebuffer::LayoutAttachmentDescription;
+use vulkano::framebuffer::Framebuffer;
+use vulkano::framebuffer::FramebufferAbstract;
+use vulkano::framebuffer::LoadOp;
+use vulkano::framebuffer::LayoutPassDependencyDescription;
+use vulkano::framebuffer::LayoutPassDescription;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::RenderPassDesc;
+use vulkano::framebuffer::RenderPassDescClearValues;
+use vulkano::framebuffer::StoreOp;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::AttachmentImage;
+use vulkano::image::Dimensions;
+use vulkano::image::ImageLayout;
+use vulkano::image::ImageUsage;
+use vulkano::image::ImageViewAccess;
+use vulkano::image::StorageImage;
+use vulkano::SafeDeref;
+
+use super::system::ComputeStage;
+use super::system::RenderSettings;
+use super::viewport::ViewportImage;
+
+/// Name of an image in the render graph.
+pub type ResourceId = &'static str;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageSize {
+    /// Same size as the final output image.
+    Output,
+    /// Size of the final output image multiplied by a factor.
+    Scaled(f32),
+    Fixed([u32; 2]),
+}
+
+impl ImageSize {
+    fn resolve(&self, output_dimensions: [u32; 2]) -> [u32; 2] {
+        let [width, height] = match *self {
+            ImageSize::Output => output_dimensions,
+            ImageSize::Scaled(factor) => [
+                (output_dimensions[0] as f32 * factor) as u32,
+                (output_dimensions[1] as f32 * factor) as u32,
+            ],
+            ImageSize::Fixed(dimensions) => dimensions,
+        };
+        [width.max(1), height.max(1)]
+    }
+}
+
+/// Description of an image the graph allocates and keeps alive between frames.
+///
+/// Images with `storage` usage are allocated as `StorageImage`s, all others as
+/// `AttachmentImage`s.
+#[derive(Debug, Clone)]
+pub struct ImageDesc {
+    pub format: Format,
+    pub size: ImageSize,
+    pub usage: ImageUsage,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Load {
+    Clear(ClearValue),
+    Load,
+    DontCare,
+}
+
+#[derive(Debug, Clone)]
+struct Attachment {
+    resource: ResourceId,
+    load: Load,
+}
+
+/// The images a pass reads and writes.
+///
+/// Attachments make the graph wrap the pass in a render pass. Whether an attachment is
+/// stored is decided by the graph, depending on whether a later pass reads it.
+#[derive(Debug, Clone, Default)]
+pub struct PassDecl {
+    reads: Vec<ResourceId>,
+    writes: Vec<ResourceId>,
+    colors: Vec<Attachment>,
+    depth_stencil: Option<Attachment>,
+}
+
+impl PassDecl {
+    pub fn read(&mut self, resource: ResourceId) -> &mut PassDecl {
+        self.reads.push(resource);
+        self
+    }
+
+    /// Declares a write outside of a render pass, e.g. from a compute shader or a transfer.
+    pub fn write(&mut self, resource: ResourceId) -> &mut PassDecl {
+        self.writes.push(resource);
+        self
+    }
+
+    pub fn color(&mut self, resource: ResourceId, load: Load) -> &mut PassDecl {
+        self.colors.push(Attachment { resource, load });
+        self
+    }
+
+    pub fn depth_stencil(&mut self, resource: ResourceId, load: Load) -> &mut PassDecl {
+        self.depth_stencil = Some(Attachment { resource, load });
+        self
+    }
+
+    fn attachments(&self) -> Vec<&Attachment> {
+        self.colors.iter().chain(self.depth_stencil.iter()).collect()
+    }
+
+    fn has_attachments(&self) -> bool {
+        !self.colors.is_empty() || self.depth_stencil.is_some()
+    }
+
+    fn resources(&self) -> Vec<ResourceId> {
+        let mut resources: Vec<ResourceId> = self.reads.clone();
+        resources.extend(self.writes.iter().cloned());
+        resources.extend(self.attachments().iter().map(|a| a.resource));
+        resources
+    }
+
+    fn reads_resource(&self, resource: ResourceId) -> bool {
+        self.reads.contains(&resource) || self.attachments().iter().any(|a| match a.load {
+            Load::Load => a.resource == resource,
+            _ => false,
+        })
+    }
+
+    fn writes_resource(&self, resource: ResourceId) -> bool {
+        self.writes.contains(&resource) || self.attachments().iter().any(|a| a.resource == resource)
+    }
+
+    /// Changes the value a cleared attachment is cleared to. Returns false if `resource` isn't
+    /// an attachment of the pass that is cleared.
+    fn set_clear_value(&mut self, resource: ResourceId, value: ClearValue) -> bool {
+        let attachment = self.colors
+            .iter_mut()
+            .chain(self.depth_stencil.iter_mut())
+            .find(|a| a.resource == resource);
+        match attachment {
+            Some(&mut Attachment {
+                load: Load::Clear(ref mut clear_value),
+                ..
+            }) => {
+                *clear_value = value;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn clear_values(&self) -> Vec<ClearValue> {
+        self.attachments()
+            .iter()
+            .map(|a| match a.load {
+                Load::Clear(value) => value,
+                _ => ClearValue::None,
+            })
+            .collect()
+    }
+}
+
+/// A pass that the graph records on its own.
+pub trait RenderNode {
+    fn declare(&self, decl: &mut PassDecl);
+
+    /// Called whenever the graph is compiled, with the subpass the node draws in if it declared
+    /// any attachments. Pipelines should be (re)built here.
+    fn prepare(&mut self, _subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {}
+
+    fn record(&mut self, context: &mut PassContext);
+}
+
+pub enum PassBody {
+    Node(Box<RenderNode>),
+    /// A node drawing the viewports of a split screen frame, only recorded in those.
+    Viewports(Box<RenderNode>),
+    /// A node drawing the frame's overlays, only recorded when there are any.
+    Overlays(Box<RenderNode>),
+    /// Handed to the user as `Pass::Deferred`.
+    Deferred,
+    /// Handed to the user as `Pass::Transparent`.
+    Transparent,
+    /// Handed to the user as `Pass::WeightedBlended`, when order independent transparency is
+    /// enabled.
+    WeightedBlended,
+    /// Handed to the user as `Pass::Selection`, when selection outlines are enabled and the
+    /// depth format has a stencil aspect.
+    Selection,
+    /// Handed to the user as `Pass::Compute`, outside of any render pass.
+    Compute(ComputeStage),
+    /// Handed to the user as `Pass::Debug`.
+    Debug,
+    /// Handed to the user as `Pass::Text`.
+    Text,
+    /// Handed to the user as `Pass::Ui`.
+    Ui,
+}
+
+impl PassBody {
+    /// The node the graph records, if it's one of the bodies holding one.
+    fn node_mut(&mut self) -> Option<&mut Box<RenderNode>> {
+        match *self {
+            PassBody::Node(ref mut node) | PassBody::Viewports(ref mut node) | PassBody::Overlays(ref mut node) => {
+                Some(node)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What the frame being recorded looks like, the same for every pass.
+pub struct FrameInputs<'a> {
+    pub settings: &'a RenderSettings,
+    /// Seconds since the previous frame, or the fixed time step if one is set.
+    pub delta_time: f32,
+    pub view: Matrix4<f32>,
+    pub projection: Matrix4<f32>,
+    /// The viewports of a split screen frame, empty otherwise.
+    pub viewports: &'a [ViewportImage],
+    /// Drawn over the frame, see `FrameSystem::add_overlay`.
+    pub overlays: &'a [ViewportImage],
+}
+
+/// Everything a `RenderNode` gets access to while recording.
+pub struct PassContext<'a> {
+    command_buffer: Option<AutoCommandBufferBuilder>,
+    resources: &'a HashMap<ResourceId, Resource>,
+    slot: usize,
+    output_dimensions: [u32; 2],
+    inputs: &'a FrameInputs<'a>,
+}
+
+impl<'a> PassContext<'a> {
+    #[inline]
+    pub fn image(&self, resource: ResourceId) -> Arc<ImageViewAccess + Send + Sync> {
+        self.resources[resource].image(self.slot)
+    }
+
+    #[inline]
+    pub fn dimensions(&self, resource: ResourceId) -> [u32; 2] {
+        self.resources[resource].dimensions
+    }
+
+    #[inline]
+    pub fn output_dimensions(&self) -> [u32; 2] {
+        self.output_dimensions
+    }
+
+    #[inline]
+    pub fn settings(&self) -> &'a RenderSettings {
+        self.inputs.settings
+    }
+
+    #[inline]
+    pub fn delta_time(&self) -> f32 {
+        self.inputs.delta_time
+    }
+
+    #[inline]
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        self.inputs.view
+    }
+
+    #[inline]
+    pub fn projection_matrix(&self) -> Matrix4<f32> {
+        self.inputs.projection
+    }
+
+    #[inline]
+    pub fn viewports(&self) -> &[ViewportImage] {
+        self.inputs.viewports
+    }
+
+    #[inline]
+    pub fn overlays(&self) -> &[ViewportImage] {
+        self.inputs.overlays
+    }
+
+    /// Records commands into the frame's primary command buffer. Only valid for passes
+    /// without attachments.
+    pub fn record<F>(&mut self, f: F)
+    where
+        F: FnOnce(AutoCommandBufferBuilder) -> AutoCommandBufferBuilder,
+    {
+        self.command_buffer = Some(f(self.command_buffer.take().unwrap()));
+    }
+
+    /// Executes a secondary command buffer inside the pass' render pass.
+    pub fn execute<C>(&mut self, command_buffer: C)
+    where
+        C: CommandBuffer + Send + Sync + 'static,
+    {
+        unsafe {
+            self.command_buffer = Some(
+                self.command_buffer
+                    .take()
+                    .unwrap()
+                    .execute_commands(command_buffer)
+                    .unwrap(),
+            );
+        }
+    }
+}
+
+enum ResourceKind {
+    Transient(ImageDesc),
+    /// Provided from outside, like the swapchain images. There can be one image per slot.
+    Imported(Format),
+}
+
+struct Resource {
+    kind: ResourceKind,
+    images: Vec<Arc<ImageViewAccess + Send + Sync>>,
+    dimensions: [u32; 2],
+    /// How many images a transient resource has, see `RenderGraph::set_image_slots`.
+    slots: usize,
+}
+
+impl Resource {
+    fn format(&self) -> Format {
+        match self.kind {
+            ResourceKind::Transient(ref desc) => desc.format,
+            ResourceKind::Imported(format) => format,
+        }
+    }
+
+    fn image(&self, slot: usize) -> Arc<ImageViewAccess + Send + Sync> {
+        match self.images.len() {
+            0 => panic!("Render graph image used before it was allocated"),
+            1 => self.images[0].clone(),
+            _ => self.images[slot].clone(),
+        }
+    }
+}
+
+struct PassEntry {
+    name: &'static str,
+    body: PassBody,
+    decl: PassDecl,
+    render_pass: Option<Arc<RenderPassAbstract + Send + Sync>>,
+}
+
+/// A framebuffer with any number of attachments. vulkano can only erase the attachment list of
+/// its builder to a `Box<AttachmentsList>`, which isn't `Send` or `Sync` even though every
+/// attachment added by the graph is.
+struct GraphFramebuffer(Framebuffer<Arc<RenderPassAbstract + Send + Sync>, Box<AttachmentsList>>);
+
+unsafe impl Send for GraphFramebuffer {}
+unsafe impl Sync for GraphFramebuffer {}
+
+impl Deref for GraphFramebuffer {
+    type Target = Framebuffer<Arc<RenderPassAbstract + Send + Sync>, Box<AttachmentsList>>;
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+unsafe impl SafeDeref for GraphFramebuffer {}
+
+/// Orders passes by the images they read and write, allocates the images and builds the
+/// render passes and framebuffers around them.
+pub struct RenderGraph {
+    queue: Arc<Queue>,
+    resources: HashMap<ResourceId, Resource>,
+    passes: Vec<PassEntry>,
+    order: Vec<usize>,
+    /// Which of the imported images this frame uses, usually the swapchain image index.
+    slot: usize,
+    output_dimensions: [u32; 2],
+    /// Framebuffers by pass index and slot. Only valid as long as no image is replaced and the
+    /// graph isn't recompiled.
+    framebuffers: HashMap<(usize, usize), Arc<FramebufferAbstract + Send + Sync>>,
+}
+
+impl RenderGraph {
+    pub fn new(queue: Arc<Queue>) -> RenderGraph {
+        RenderGraph {
+            queue,
+            resources: HashMap::new(),
+            passes: Vec::new(),
+            order: Vec::new(),
+            slot: 0,
+            output_dimensions: [0, 0],
+            framebuffers: HashMap::new(),
+        }
+    }
+
+    pub fn add_image(&mut self, resource: ResourceId, desc: ImageDesc) {
+        self.resources.insert(
+            resource,
+            Resource {
+                kind: ResourceKind::Transient(desc),
+                images: Vec::new(),
+                dimensions: [0, 0],
+                slots: 1,
+            },
+        );
+    }
+
+    pub fn import_image(&mut self, resource: ResourceId, format: Format) {
+        self.resources.insert(
+            resource,
+            Resource {
+                kind: ResourceKind::Imported(format),
+                images: Vec::new(),
+                dimensions: [0, 0],
+                slots: 1,
+            },
+        );
+    }
+
+    /// Gives a transient resource an image for each of `slots` slots rather than one shared by
+    /// all of them, for images that are sampled after their frame, like the HDR images of
+    /// render targets.
+    pub fn set_image_slots(&mut self, resource: ResourceId, slots: usize) {
+        self.resources
+            .get_mut(resource)
+            .expect("Setting the slots of an image that was never added")
+            .slots = slots.max(1);
+    }
+
+    /// Binds the images of an imported resource, one per slot. Binding the same images again
+    /// is free, anything else drops the cached framebuffers.
+    pub fn bind_images(&mut self, resource: ResourceId, images: Vec<Arc<ImageViewAccess + Send + Sync>>) {
+        assert!(!images.is_empty(), "Binding no images to `{}`", resource);
+        let resource = self.resources
+            .get_mut(resource)
+            .expect("Binding an image that was never imported");
+
+        let unchanged = resource.images.len() == images.len()
+            && resource.images.iter().zip(&images).all(|(a, b)| Arc::ptr_eq(a, b));
+        if unchanged {
+            return;
+        }
+
+        let dimensions = images[0].dimensions();
+        resource.dimensions = [dimensions.width(), dimensions.height()];
+        resource.images = images;
+        self.framebuffers.clear();
+    }
+
+    #[inline]
+    pub fn set_slot(&mut self, slot: usize) {
+        self.slot = slot;
+    }
+
+    #[inline]
+    pub fn dimensions(&self, resource: ResourceId) -> [u32; 2] {
+        self.resources[resource].dimensions
+    }
+
+    /// The image of the current slot.
+    pub fn image(&self, resource: ResourceId) -> Arc<ImageViewAccess + Send + Sync> {
+        self.image_at(resource, self.slot)
+    }
+
+    /// The image of `slot`, whichever slot is current.
+    pub fn image_at(&self, resource: ResourceId, slot: usize) -> Arc<ImageViewAccess + Send + Sync> {
+        self.resources[resource].image(slot)
+    }
+
+    pub fn add_pass(&mut self, name: &'static str, body: PassBody, decl: PassDecl) {
+        let index = self.passes.len();
+        self.insert_pass(index, name, body, decl);
+    }
+
+    pub fn add_node<N>(&mut self, name: &'static str, node: N)
+    where
+        N: RenderNode + 'static,
+    {
+        let mut decl = PassDecl::default();
+        node.declare(&mut decl);
+        self.add_pass(name, PassBody::Node(Box::new(node)), decl);
+    }
+
+    pub fn add_node_before<N>(&mut self, anchor: &str, name: &'static str, node: N)
+    where
+        N: RenderNode + 'static,
+    {
+        let mut decl = PassDecl::default();
+        node.declare(&mut decl);
+        self.add_pass_before(anchor, name, PassBody::Node(Box::new(node)), decl);
+    }
+
+    /// Registers a pass ahead of `anchor`, so it goes first among passes writing the same images.
+    pub fn add_pass_before(&mut self, anchor: &str, name: &'static str, body: PassBody, decl: PassDecl) {
+        let index = pass_position(&self.passes, anchor);
+        self.insert_pass(index, name, body, decl);
+    }
+
+    fn insert_pass(&mut self, index: usize, name: &'static str, body: PassBody, decl: PassDecl) {
+        assert!(
+            self.passes.iter().all(|p| p.name != name),
+            "Pass `{}` is already in the render graph",
+            name
+        );
+        for resource in decl.resources() {
+            assert!(
+                self.resources.contains_key(resource),
+                "Pass `{}` uses unknown image `{}`",
+                name,
+                resource
+            );
+        }
+
+        self.passes.insert(
+            index,
+            PassEntry {
+                name,
+                body,
+                decl,
+                render_pass: None,
+            },
+        );
+        self.compile();
+    }
+
+    /// Changes what an attachment that `pass` clears is cleared to, from the next frame on.
+    /// Only the value can change, not whether the attachment is cleared.
+    pub fn set_clear_value(&mut self, pass: &str, resource: ResourceId, value: ClearValue) {
+        let entry = self.passes
+            .iter_mut()
+            .find(|p| p.name == pass)
+            .unwrap_or_else(|| panic!("No pass named `{}` in the render graph", pass));
+        assert!(
+            entry.decl.set_clear_value(resource, value),
+            "Pass `{}` doesn't clear `{}`",
+            pass,
+            resource
+        );
+    }
+
+    /// The subpass a pass draws in, for building pipelines against.
+    pub fn subpass(&self, name: &str) -> Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>> {
+        self.passes
+            .iter()
+            .find(|p| p.name == name)
+            .and_then(|p| p.render_pass.clone())
+            .map(|render_pass| Subpass::from(render_pass, 0).unwrap())
+    }
+
+    /// Indices of the passes in execution order.
+    #[inline]
+    pub fn order(&self) -> &[usize] {
+        &self.order
+    }
+
+    #[inline]
+    pub fn body(&self, index: usize) -> &PassBody {
+        &self.passes[index].body
+    }
+
+    #[inline]
+    pub fn name(&self, index: usize) -> &'static str {
+        self.passes[index].name
+    }
+
+    fn compile(&mut self) {
+        self.order = sort(&self.passes);
+        self.framebuffers.clear();
+
+        for position in 0..self.order.len() {
+            let render_pass = self.render_pass_desc(position).map(|desc| {
+                Arc::new(desc.build_render_pass(self.queue.device().clone()).unwrap())
+                    as Arc<RenderPassAbstract + Send + Sync>
+            });
+
+            let pass = &mut self.passes[self.order[position]];
+            pass.render_pass = render_pass.clone();
+            if let Some(node) = pass.body.node_mut() {
+                node.prepare(render_pass.map(|render_pass| Subpass::from(render_pass, 0).unwrap()));
+            }
+        }
+    }
+
+    fn render_pass_desc(&self, position: usize) -> Option<GraphRenderPassDesc> {
+        let decl = &self.passes[self.order[position]].decl;
+        if !decl.has_attachments() {
+            return None;
+        }
+
+        let mut desc = GraphRenderPassDesc {
+            attachments: Vec::new(),
+            color_attachments: Vec::new(),
+            depth_stencil: None,
+        };
+
+        for attachment in &decl.colors {
+            let layout = ImageLayout::ColorAttachmentOptimal;
+            desc.color_attachments.push((desc.attachments.len(), layout));
+            desc.attachments.push(self.attachment_desc(position, attachment, layout));
+        }
+
+        if let Some(ref attachment) = decl.depth_stencil {
+            let layout = ImageLayout::DepthStencilAttachmentOptimal;
+            desc.depth_stencil = Some((desc.attachments.len(), layout));
+            desc.attachments.push(self.attachment_desc(position, attachment, layout));
+        }
+
+        Some(desc)
+    }
+
+    fn attachment_desc(
+        &self,
+        position: usize,
+        attachment: &Attachment,
+        layout: ImageLayout,
+    ) -> LayoutAttachmentDescription {
+        let (load, initial_layout) = match attachment.load {
+            Load::Clear(_) => (LoadOp::Clear, ImageLayout::Undefined),
+            Load::Load => (LoadOp::Load, layout),
+            Load::DontCare => (LoadOp::DontCare, ImageLayout::Undefined),
+        };
+        let store = if self.is_used_after(position, attachment.resource) {
+            StoreOp::Store
+        } else {
+            StoreOp::DontCare
+        };
+
+        LayoutAttachmentDescription {
+            format: self.resources[attachment.resource].format(),
+            samples: 1,
+            load,
+            store,
+            stencil_load: load,
+            stencil_store: store,
+            initial_layout,
+            final_layout: layout,
+        }
+    }
+
+    fn is_used_after(&self, position: usize, resource: ResourceId) -> bool {
+        if let ResourceKind::Imported(_) = self.resources[resource].kind {
+            // Imported images outlive the frame.
+            return true;
+        }
+
+        self.order[position + 1..]
+            .iter()
+            .any(|&index| self.passes[index].decl.reads_resource(resource))
+    }
+
+    /// Makes sure every transient image exists at the size it needs for `output_dimensions`.
+    pub fn allocate(&mut self, output_dimensions: [u32; 2]) {
+        self.output_dimensions = output_dimensions;
+
+        let queue = &self.queue;
+        let mut reallocated = false;
+        for resource in self.resources.values_mut() {
+            if let ResourceKind::Transient(ref desc) = resource.kind {
+                let dimensions = desc.size.resolve(output_dimensions);
+                if resource.images.len() != resource.slots || resource.dimensions != dimensions {
+                    resource.images = (0..resource.slots)
+                        .map(|_| create_image(queue, desc, dimensions))
+                        .collect();
+                    resource.dimensions = dimensions;
+                    reallocated = true;
+                }
+            }
+        }
+
+        if reallocated {
+            self.framebuffers.clear();
+        }
+    }
+
+    /// The framebuffer of a pass for the current slot, built on first use.
+    pub fn framebuffer(&mut self, index: usize) -> Option<Arc<FramebufferAbstract + Send + Sync>> {
+        let key = (index, self.slot);
+        if let Some(framebuffer) = self.framebuffers.get(&key) {
+            return Some(framebuffer.clone());
+        }
+
+        let framebuffer = self.build_framebuffer(index);
+        if let Some(ref framebuffer) = framebuffer {
+            self.framebuffers.insert(key, framebuffer.clone());
+        }
+        framebuffer
+    }
+
+    fn build_framebuffer(&self, index: usize) -> Option<Arc<FramebufferAbstract + Send + Sync>> {
+        let pass = &self.passes[index];
+        let render_pass = match pass.render_pass {
+            Some(ref render_pass) => render_pass.clone(),
+            None => return None,
+        };
+
+        let mut framebuffer = Framebuffer::start(render_pass).boxed();
+        for attachment in pass.decl.attachments() {
+            let image = self.resources[attachment.resource].image(self.slot);
+            framebuffer = framebuffer.add(image).unwrap().boxed();
+        }
+
+        Some(Arc::new(GraphFramebuffer(framebuffer.build().unwrap())))
+    }
+
+    #[inline]
+    pub fn clear_values(&self, index: usize) -> Vec<ClearValue> {
+        self.passes[index].decl.clear_values()
+    }
+
+    /// Records a body holding a node, like `PassBody::Node`. Other bodies are left to the caller.
+    pub fn record(
+        &mut self,
+        index: usize,
+        command_buffer: AutoCommandBufferBuilder,
+        inputs: &FrameInputs,
+    ) -> AutoCommandBufferBuilder {
+        let RenderGraph {
+            ref mut passes,
+            ref resources,
+            slot,
+            output_dimensions,
+            ..
+        } = *self;
+
+        match passes[index].body.node_mut() {
+            Some(node) => {
+                let mut context = PassContext {
+                    command_buffer: Some(command_buffer),
+                    resources,
+                    slot,
+                    output_dimensions,
+                    inputs,
+                };
+                node.record(&mut context);
+                context.command_buffer.take().unwrap()
+            }
+            None => command_buffer,
+        }
+    }
+}
+
+/// Index of the pass named `name`, in registration order.
+fn pass_position(passes: &[PassEntry], name: &str) -> usize {
+    passes
+        .iter()
+        .position(|p| p.name == name)
+        .unwrap_or_else(|| panic!("No pass named `{}` in the render graph", name))
+}
+
+/// Topological sort on the declared reads and writes, falling back to registration order.
+fn sort(passes: &[PassEntry]) -> Vec<usize> {
+    let count = passes.len();
+    let mut dependents = vec![Vec::new(); count];
+    let mut num_dependencies = vec![0; count];
+
+    for before in 0..count {
+        for after in before + 1..count {
+            if must_precede(&passes[before].decl, &passes[after].decl) {
+                dependents[before].push(after);
+                num_dependencies[after] += 1;
+            }
+        }
+    }
+
+    let mut ready: BinaryHeap<Reverse<usize>> = (0..count)
+        .filter(|&i| num_dependencies[i] == 0)
+        .map(Reverse)
+        .collect();
+    let mut order = Vec::with_capacity(count);
+    while let Some(Reverse(index)) = ready.pop() {
+        order.push(index);
+        for &dependent in &dependents[index] {
+            num_dependencies[dependent] -= 1;
+            if num_dependencies[dependent] == 0 {
+                ready.push(Reverse(dependent));
+            }
+        }
+    }
+
+    assert!(order.len() == count, "Render graph contains a cycle");
+    order
+}
+
+/// Whether a pass has to run before one registered after it. Passes touching the same image run
+/// in registration order when at least one of them writes it: readers see the writers
+/// registered before them, and finish before later writers.
+fn must_precede(before: &PassDecl, after: &PassDecl) -> bool {
+    before.resources().into_iter().any(|resource| {
+        if before.writes_resource(resource) {
+            after.reads_resource(resource) || after.writes_resource(resource)
+        } else {
+            before.reads_resource(resource) && after.writes_resource(resource)
+        }
+    })
+}
+
+fn create_image(queue: &Arc<Queue>, desc: &ImageDesc, dimensions: [u32; 2]) -> Arc<ImageViewAccess + Send + Sync> {
+    if desc.usage.storage {
+        StorageImage::new(
+            queue.device().clone(),
+            Dimensions::Dim2d {
+                width: dimensions[0],
+                height: dimensions[1],
+            },
+            desc.format,
+            Some(queue.family()),
+        ).unwrap() as Arc<_>
+    } else {
+        AttachmentImage::with_usage(queue.device().clone(), dimensions, desc.format, desc.usage).unwrap() as Arc<_>
+    }
+}
+
+/// Single subpass render pass built from a pass' declared attachments.
+struct GraphRenderPassDesc {
+    attachments: Vec<LayoutAttachmentDescription>,
+    color_attachments: Vec<(usize, ImageLayout)>,
+    depth_stencil: Option<(usize, ImageLayout)>,
+}
+
+unsafe impl RenderPassDesc for GraphRenderPassDesc {
+    #[inline]
+    fn num_attachments(&self) -> usize {
+        self.attachments.len()
+    }
+
+    #[inline]
+    fn attachment_desc(&self, num: usize) -> Option<LayoutAttachmentDescription> {
+        self.attachments.get(num).cloned()
+    }
+
+    #[inline]
+    fn num_subpasses(&self) -> usize {
+        1
+    }
+
+    fn subpass_desc(&self, num: usize) -> Option<LayoutPassDescription> {
+        if num != 0 {
+            return None;
+        }
+
+        Some(LayoutPassDescription {
+            color_attachments: self.color_attachments.clone(),
+            depth_stencil: self.depth_stencil.clone(),
+            input_attachments: Vec::new(),
+            resolve_attachments: Vec::new(),
+            preserve_attachments: Vec::new(),
+        })
+    }
+
+    #[inline]
+    fn num_dependencies(&self) -> usize {
+        0
+    }
+
+    #[inline]
+    fn dependency_desc(&self, _num: usize) -> Option<LayoutPassDependencyDescription> {
+        None
+    }
+}
+
+unsafe impl RenderPassDescClearValues<Vec<ClearValue>> for GraphRenderPassDesc {
+    fn convert_clear_values(&self, values: Vec<ClearValue>) -> Box<Iterator<Item = ClearValue>> {
+        Box::new(values.into_iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::pass_position;
+    use super::sort;
+    use super::Load;
+    use super::PassBody;
+    use super::PassDecl;
+    use super::PassEntry;
+
+    fn pass<F>(name: &'static str, declare: F) -> PassEntry
+    where
+        F: FnOnce(&mut PassDecl),
+    {
+        let mut decl = PassDecl::default();
+        declare(&mut decl);
+        PassEntry {
+            name,
+            body: PassBody::Deferred,
+            decl,
+            render_pass: None,
+        }
+    }
+
+    fn names(passes: &[PassEntry]) -> Vec<&'static str> {
+        sort(passes).into_iter().map(|index| passes[index].name).collect()
+    }
+
+    #[test]
+    fn readers_run_after_the_writers_before_them() {
+        let passes = vec![
+            pass("write", |decl| {
+                decl.write("a");
+            }),
+            pass("read", |decl| {
+                decl.read("a").write("b");
+            }),
+            pass("load", |decl| {
+                decl.color("b", Load::Load);
+            }),
+        ];
+        assert_eq!(names(&passes), ["write", "read", "load"]);
+    }
+
+    #[test]
+    fn writers_wait_for_the_readers_before_them() {
+        let passes = vec![
+            pass("read", |decl| {
+                decl.read("a");
+            }),
+            pass("write", |decl| {
+                decl.write("a");
+            }),
+        ];
+        assert_eq!(names(&passes), ["read", "write"]);
+    }
+
+    #[test]
+    fn independent_passes_keep_their_registration_order() {
+        let passes = vec![
+            pass("b", |decl| {
+                decl.write("b");
+            }),
+            pass("a", |decl| {
+                decl.write("a");
+            }),
+            pass("read_a", |decl| {
+                decl.read("a");
+            }),
+            pass("c", |decl| {
+                decl.write("c");
+            }),
+            pass("read_b", |decl| {
+                decl.read("b");
+            }),
+        ];
+        assert_eq!(names(&passes), ["b", "a", "read_a", "c", "read_b"]);
+    }
+
+    #[test]
+    fn passes_added_before_an_anchor_write_first() {
+        let mut passes = vec![
+            pass("scene", |decl| {
+                decl.color("hdr", Load::DontCare);
+            }),
+            pass("tonemap", |decl| {
+                decl.read("hdr").color("output", Load::DontCare);
+            }),
+        ];
+        let index = pass_position(&passes, "scene");
+        passes.insert(
+            index,
+            pass("background", |decl| {
+                decl.color("hdr", Load::DontCare);
+            }),
+        );
+        assert_eq!(names(&passes), ["background", "scene", "tonemap"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "No pass named `missing`")]
+    fn unknown_anchors_panic() {
+        let passes = vec![pass("scene", |_| {})];
+        pass_position(&passes, "missing");
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use cgmath::{InnerSpace, Matrix4, Point3, Transform};
+use vulkano::buffer::BufferAccess;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::AutoCommandBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::DynamicState;
+use vulkano::descriptor::descriptor_set::FixedSizeDescriptorSetsPool;
+use vulkano::device::Queue;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::vertex::OneVertexOneInstanceDefinition;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+use super::frustum::Frustum;
+
+/// Index of a mesh added with `InstanceRenderer::add_mesh`.
+pub type MeshId = usize;
+/// Index of a material added with `InstanceRenderer::add_material`.
+pub type MaterialId = usize;
+
+/// Vertex of an instanced mesh, in model space.
+#[derive(Debug, Clone)]
+pub struct MeshVertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+}
+impl_vertex!(MeshVertex, position, normal);
+
+/// Shared by all instances drawn with it, multiplied with each instance's color.
+#[derive(Debug, Clone, Copy)]
+pub struct Material {
+    pub color: [f32; 4],
+}
+
+/// What was drawn by the latest `InstanceRenderer::draw`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InstanceStats {
+    /// Draw calls, one per mesh and material with visible instances.
+    pub batches: u32,
+    pub drawn: u32,
+    pub culled: u32,
+}
+
+/// Second vertex input of the pipeline, advancing once per instance. Also laid out like
+/// `Instance` in the GPU culling shader, std430.
+#[derive(Debug, Clone)]
+pub struct InstanceData {
+    pub model0: [f32; 4],
+    pub model1: [f32; 4],
+    pub model2: [f32; 4],
+    pub model3: [f32; 4],
+    pub color: [f32; 4],
+}
+impl_vertex!(InstanceData, model0, model1, model2, model3, color);
+
+pub struct Mesh {
+    pub vertex_buffer: Arc<CpuAccessibleBuffer<[MeshVertex]>>,
+    /// Bounding sphere in model space.
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl Mesh {
+    pub fn new(queue: &Arc<Queue>, vertices: Vec<MeshVertex>) -> Mesh {
+        assert!(!vertices.is_empty(), "Instanced meshes need vertices");
+
+        let positions: Vec<Point3<f32>> = vertices.iter().map(|v| Point3::from(v.position)).collect();
+        let mut min = positions[0];
+        let mut max = positions[0];
+        for p in &positions {
+            min = Point3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+            max = Point3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+        }
+        let center = min + (max - min) * 0.5;
+        let radius = positions
+            .iter()
+            .map(|p| (p - center).magnitude())
+            .fold(0.0, f32::max);
+
+        let vertex_buffer = CpuAccessibleBuffer::from_iter(
+            queue.device().clone(),
+            BufferUsage::vertex_buffer(),
+            vertices.into_iter(),
+        ).expect("Failed to create mesh vertex buffer");
+
+        Mesh {
+            vertex_buffer,
+            center,
+            radius,
+        }
+    }
+
+    /// Bounding sphere in world space, for a model matrix without non-uniform scaling.
+    pub fn bounds(&self, model: &Matrix4<f32>) -> (Point3<f32>, f32) {
+        (model.transform_point(self.center), self.radius * model.x.truncate().magnitude())
+    }
+}
+
+impl InstanceData {
+    pub fn new(model: &Matrix4<f32>, color: [f32; 4]) -> InstanceData {
+        InstanceData {
+            model0: model.x.into(),
+            model1: model.y.into(),
+            model2: model.z.into(),
+            model3: model.w.into(),
+            color,
+        }
+    }
+}
+
+/// Draws `MeshVertex` and `InstanceData` vertices into the deferred pass, lit by the sun. Its
+/// descriptor set holds the `instanced_vs::ty::Camera` and the `SunUniforms`, the material
+/// color is a push constant.
+pub fn instanced_pipeline<R>(queue: &Arc<Queue>, subpass: Subpass<R>) -> Arc<GraphicsPipelineAbstract + Send + Sync>
+where
+    R: RenderPassAbstract + Send + Sync + 'static,
+{
+    let device = queue.device().clone();
+    let vs = instanced_vs::Shader::load(device.clone()).expect("Could not create shader module");
+    let fs = instanced_fs::Shader::load(device.clone()).expect("Could not create shader module");
+
+    Arc::new(
+        GraphicsPipeline::start()
+            .vertex_input(OneVertexOneInstanceDefinition::<MeshVertex, InstanceData>::new())
+            .vertex_shader(vs.main_entry_point(), ())
+            .triangle_list()
+            .viewports_dynamic_scissors_irrelevant(1)
+            .fragment_shader(fs.main_entry_point(), ())
+            .depth_stencil_simple_depth()
+            .render_pass(subpass)
+            .build(device)
+            .unwrap(),
+    ) as Arc<GraphicsPipelineAbstract + Send + Sync>
+}
+
+#[derive(Debug, Clone)]
+struct Instance {
+    model: Matrix4<f32>,
+    color: [f32; 4],
+}
+
+/// Draws many copies of the same meshes into the deferred pass, with one draw call per mesh
+/// and material.
+///
+/// Instances are queued with `add_instance` in any order over the frame, then `draw` culls
+/// them against the view frustum, groups the rest by mesh and material and clears the queue.
+/// Model matrices may translate, rotate and scale uniformly.
+pub struct InstanceRenderer {
+    queue: Arc<Queue>,
+    meshes: Vec<Mesh>,
+    materials: Vec<Material>,
+    batches: BTreeMap<(MeshId, MaterialId), Vec<Instance>>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    instance_buffer_pool: CpuBufferPool<InstanceData>,
+    camera_buffer_pool: CpuBufferPool<instanced_vs::ty::Camera>,
+    ds_pool: FixedSizeDescriptorSetsPool<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    stats: InstanceStats,
+}
+
+impl InstanceRenderer {
+    /// `subpass` is the one of `Pass::Deferred`.
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> InstanceRenderer
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let device = queue.device().clone();
+        let pipeline = instanced_pipeline(&queue, subpass);
+
+        InstanceRenderer {
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            batches: BTreeMap::new(),
+            instance_buffer_pool: CpuBufferPool::vertex_buffer(device.clone()),
+            camera_buffer_pool: CpuBufferPool::uniform_buffer(device.clone()),
+            ds_pool: FixedSizeDescriptorSetsPool::new(pipeline.clone(), 0),
+            pipeline,
+            queue,
+            stats: InstanceStats::default(),
+        }
+    }
+
+    /// A triangle list in model space.
+    pub fn add_mesh(&mut self, vertices: Vec<MeshVertex>) -> MeshId {
+        let mesh = Mesh::new(&self.queue, vertices);
+        self.meshes.push(mesh);
+        self.meshes.len() - 1
+    }
+
+    pub fn add_material(&mut self, material: Material) -> MaterialId {
+        self.materials.push(material);
+        self.materials.len() - 1
+    }
+
+    #[inline]
+    pub fn material_mut(&mut self, material: MaterialId) -> &mut Material {
+        &mut self.materials[material]
+    }
+
+    /// Queues a copy of `mesh` for the next `draw`.
+    pub fn add_instance(&mut self, mesh: MeshId, material: MaterialId, model: Matrix4<f32>, color: [f32; 4]) {
+        assert!(mesh < self.meshes.len() && material < self.materials.len());
+        self.batches
+            .entry((mesh, material))
+            .or_insert_with(Vec::new)
+            .push(Instance { model, color });
+    }
+
+    /// Of the latest `draw`.
+    #[inline]
+    pub fn stats(&self) -> InstanceStats {
+        self.stats
+    }
+
+    /// For `Pass::Deferred`, lit by the `SunUniforms` in `sun_buffer`. Draws and clears the
+    /// queued instances.
+    pub fn draw(
+        &mut self,
+        dimensions: [u32; 2],
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+        sun_buffer: Arc<BufferAccess + Send + Sync>,
+    ) -> AutoCommandBuffer {
+        let frustum = Frustum::new(projection * view);
+
+        let camera_buffer = self
+            .camera_buffer_pool
+            .next(instanced_vs::ty::Camera {
+                view: view.into(),
+                projection: projection.into(),
+            })
+            .unwrap();
+        let descriptor_set = Arc::new(
+            self.ds_pool
+                .next()
+                .add_buffer(camera_buffer)
+                .unwrap()
+                .add_buffer(sun_buffer)
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [dimensions[0] as f32, dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut stats = InstanceStats::default();
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            self.pipeline.clone().subpass(),
+        ).unwrap();
+        for (&(mesh, material), instances) in &mut self.batches {
+            let mesh = &self.meshes[mesh];
+            let visible: Vec<InstanceData> = instances
+                .iter()
+                .filter(|instance| {
+                    let (center, radius) = mesh.bounds(&instance.model);
+                    frustum.intersects_sphere(center, radius)
+                })
+                .map(|instance| InstanceData::new(&instance.model, instance.color))
+                .collect();
+            stats.culled += (instances.len() - visible.len()) as u32;
+            instances.clear();
+            if visible.is_empty() {
+                continue;
+            }
+            stats.drawn += visible.len() as u32;
+            stats.batches += 1;
+
+            let instance_buffer = self.instance_buffer_pool.chunk(visible).unwrap();
+            builder = builder
+                .draw(
+                    self.pipeline.clone(),
+                    dynamic_state.clone(),
+                    vec![
+                        mesh.vertex_buffer.clone() as Arc<BufferAccess + Send + Sync>,
+                        Arc::new(instance_buffer) as Arc<BufferAccess + Send + Sync>,
+                    ],
+                    descriptor_set.clone(),
+                    instanced_fs::ty::PushConstants {
+                        color: self.materials[material].color,
+                    },
+                )
+                .unwrap();
+        }
+        self.stats = stats;
+        builder.build().unwrap()
+    }
+}
+
+pub mod instanced_vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec4 model0;
+layout(location = 3) in vec4 model1;
+layout(location = 4) in vec4 model2;
+layout(location = 5) in vec4 model3;
+layout(location = 6) in vec4 color;
+
+layout(location = 0) out vec3 v_normal;
+layout(location = 1) out vec3 v_view_position;
+layout(location = 2) out vec3 v_view_normal;
+layout(location = 3) out vec4 v_color;
+
+layout(std140, set = 0, binding = 0) uniform Camera {
+    mat4 view;
+    mat4 projection;
+} camera;
+
+void main() {
+    mat4 model = mat4(model0, model1, model2, model3);
+    // Uniform scale only, so the model matrix works for normals.
+    v_normal = normalize(mat3(model) * normal);
+    vec4 view_position = camera.view * model * vec4(position, 1.0);
+    v_view_position = view_position.xyz;
+    v_view_normal = mat3(camera.view) * v_normal;
+    v_color = color;
+    gl_Position = camera.projection * view_position;
+}
+"]
+    struct Dummy;
+}
+
+pub mod instanced_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec3 v_normal;
+layout(location = 1) in vec3 v_view_position;
+layout(location = 2) in vec3 v_view_normal;
+layout(location = 3) in vec4 v_color;
+
+layout(location = 0) out vec4 f_color;
+layout(location = 1) out vec4 f_normal_depth;
+layout(location = 2) out vec4 f_ambient;
+
+layout(std140, set = 0, binding = 1) uniform Sun {
+    vec4 direction;
+    vec4 color;
+} sun;
+
+layout(push_constant) uniform PushConstants {
+    vec4 color;
+} material;
+
+void main() {
+    vec3 albedo = v_color.rgb * material.color.rgb;
+    vec3 normal = normalize(v_normal);
+    float diffuse = max(dot(normal, sun.direction.xyz), 0.0);
+    vec3 ambient = albedo * 0.25;
+
+    f_color = vec4(ambient + albedo * 0.75 * diffuse * sun.color.rgb, 1.0);
+    f_normal_depth = vec4(normalize(v_view_normal), -v_view_position.z);
+    f_ambient = vec4(ambient, 0.0);
+}
+"]
+    struct Dummy;
+}
@@ -1,4 +1,66 @@
+pub use self::background::{Background, ClearSettings};
+pub use self::bloom::BloomSettings;
+pub use self::debug::{DebugDraw, DebugLines};
+pub use self::frustum::Frustum;
+pub use self::gpu_driven::{CullingSettings, GpuScene, GpuSceneStats, ObjectId};
+pub use self::graph::ImageDesc;
+pub use self::graph::ImageSize;
+pub use self::graph::Load;
+pub use self::graph::PassContext;
+pub use self::graph::PassDecl;
+pub use self::graph::RenderNode;
+pub use self::graph::ResourceId;
+pub use self::instancing::{InstanceRenderer, InstanceStats, Material, MaterialId, MeshId, MeshVertex};
+pub use self::outline::{selection_silhouette_stencil, selection_visible_stencil, OutlineSettings, MAX_OUTLINE_WIDTH};
+pub use self::particles::{Emitter, ParticleBlend, ParticleSystem};
+pub use self::random::Random;
+pub use self::render_mode::RenderMode;
+pub use self::render_target::{RenderTargets, SceneView, TargetCamera, TargetId, TargetImages, DEFAULT_MAX_DEPTH};
+pub use self::screen::{Screen, ScreenKind, ScreenRenderer};
+pub use self::screenshot::timestamped_screenshot_path;
+pub use self::screenshot::CapturedFrame;
+pub use self::sky::{SkySettings, SunUniforms};
+pub use self::skybox::{Cubemap, SkyboxSettings};
+pub use self::ssao::{SsaoSettings, MAX_SSAO_SAMPLES};
 pub use self::system::FrameSystem;
 pub use self::system::Pass;
+pub use self::system::{ComputePass, ComputeStage};
+pub use self::system::RenderSettings;
+pub use self::system::depth_stencil_format;
+pub use self::system::{DEPTH_FORMAT, DEPTH_STENCIL_FORMAT, HDR_FORMAT, HEADLESS_FORMAT};
+pub use self::system::{ACCUM_FORMAT, AMBIENT_FORMAT, AO_FORMAT, NORMAL_DEPTH_FORMAT, REVEAL_FORMAT, SELECTION_FORMAT};
+pub use self::system::{ACCUM_IMAGE, AMBIENT_IMAGE, AO_BLUR_IMAGE, AO_IMAGE, BLOOM_IMAGE, DEPTH_IMAGE, FINAL_IMAGE,
+                       HDR_IMAGE, NORMAL_DEPTH_IMAGE, REVEAL_IMAGE, SELECTION_IMAGE};
+pub use self::system::{COMPUTE_PASS, DEBUG_PASS, GEOMETRY_PASS, OVERLAYS_PASS, POST_COMPUTE_PASS, SELECTION_PASS,
+                       TEXT_PASS, TRANSPARENT_PASS, UI_PASS, VIEWPORTS_PASS, WEIGHTED_BLENDED_PASS};
+pub use self::text::{Align, Anchor, TextItem};
+pub use self::tonemap::HdrSettings;
+pub use self::tonemap::Tonemapper;
+pub use self::transparency::{accumulation_blend, revealage_blend, sort_back_to_front, TransparencySettings};
+pub use self::viewport::{Viewport, ViewportImage, ViewportRect};
 
+mod background;
+mod bloom;
+mod debug;
+mod exposure;
+mod frustum;
+mod fullscreen;
+mod gpu_driven;
+mod graph;
+mod instancing;
+mod outline;
+mod particles;
+mod random;
+mod render_mode;
+mod render_target;
+mod screen;
+mod screenshot;
+mod sky;
+mod skybox;
+mod ssao;
 mod system;
+mod text;
+mod tonemap;
+mod transparency;
+mod ui;
+mod viewport;
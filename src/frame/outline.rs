@@ -0,0 +1,465 @@
+use std::sync::Arc;
+
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::format::FormatTy;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::ImageUsage;
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::blend::BlendFactor;
+use vulkano::pipeline::blend::BlendOp;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::depth_stencil::Stencil;
+use vulkano::pipeline::depth_stencil::StencilOp;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::Sampler;
+
+use super::fullscreen::clamp_sampler;
+use super::fullscreen::fullscreen_triangle;
+use super::fullscreen::viewport_state;
+use super::fullscreen::Vertex;
+use super::graph::ImageDesc;
+use super::graph::ImageSize;
+use super::graph::Load;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::system::DEPTH_IMAGE;
+use super::system::FINAL_IMAGE;
+use super::system::SELECTION_FORMAT;
+use super::system::SELECTION_IMAGE;
+use super::tonemap::is_srgb;
+
+/// Stencil bit set wherever a selected object covers the screen, hidden or not.
+const SILHOUETTE_BIT: u32 = 1;
+/// Stencil bit set where a selected object is the nearest surface.
+const VISIBLE_BIT: u32 = 2;
+/// The stencil bits reserved for the selection, which `ClearSettings::stencil` can't set.
+pub const SELECTION_STENCIL_BITS: u32 = SILHOUETTE_BIT | VISIBLE_BIT;
+
+/// Widths beyond this are clamped, the outline shader searches a square of twice this size.
+pub const MAX_OUTLINE_WIDTH: f32 = 8.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutlineSettings {
+    /// Only has an effect if the frame system's depth format has a stencil aspect.
+    pub enabled: bool,
+    /// A display color like those of `DebugDraw`, alpha blended over the final image.
+    pub color: [f32; 4],
+    /// In pixels, the same at any distance.
+    pub width: f32,
+    /// Outlines the parts of selected objects hidden behind others with a dashed line.
+    pub show_hidden: bool,
+    /// Length of a dash and of the gap after it, in pixels.
+    pub dash_length: f32,
+}
+
+impl Default for OutlineSettings {
+    fn default() -> OutlineSettings {
+        OutlineSettings {
+            enabled: true,
+            color: [1.0, 0.6, 0.1, 1.0],
+            width: 3.0,
+            show_hidden: true,
+            dash_length: 6.0,
+        }
+    }
+}
+
+/// Whether `format` can hold the selection marks of `Pass::Selection`.
+pub fn has_stencil(format: Format) -> bool {
+    match format.ty() {
+        FormatTy::Stencil | FormatTy::DepthStencil => true,
+        _ => false,
+    }
+}
+
+fn mark(bit: u32) -> Stencil {
+    Stencil {
+        compare: Compare::Always,
+        pass_op: StencilOp::Replace,
+        fail_op: StencilOp::Keep,
+        depth_fail_op: StencilOp::Keep,
+        compare_mask: Some(bit),
+        write_mask: Some(bit),
+        reference: Some(bit),
+    }
+}
+
+fn test(bit: u32, reference: u32) -> Stencil {
+    Stencil {
+        compare: Compare::Equal,
+        pass_op: StencilOp::Keep,
+        fail_op: StencilOp::Keep,
+        depth_fail_op: StencilOp::Keep,
+        compare_mask: Some(bit),
+        write_mask: Some(0),
+        reference: Some(reference),
+    }
+}
+
+/// Depth/stencil state of the first draw of selected objects in `Pass::Selection`, marking
+/// everything they cover whether it's hidden or not.
+pub fn selection_silhouette_stencil() -> DepthStencil {
+    DepthStencil {
+        depth_write: false,
+        depth_compare: Compare::Always,
+        stencil_front: mark(SILHOUETTE_BIT),
+        stencil_back: mark(SILHOUETTE_BIT),
+        ..DepthStencil::disabled()
+    }
+}
+
+/// Depth/stencil state of the second draw of selected objects in `Pass::Selection`, marking
+/// the parts that passed the depth test in the geometry pass.
+pub fn selection_visible_stencil() -> DepthStencil {
+    DepthStencil {
+        depth_write: false,
+        depth_compare: Compare::LessOrEqual,
+        stencil_front: mark(VISIBLE_BIT),
+        stencil_back: mark(VISIBLE_BIT),
+        ..DepthStencil::disabled()
+    }
+}
+
+/// Copies the selection marks from the stencil buffer into the selection image, which
+/// unlike the stencil aspect can be sampled. Red is the silhouette, green the visible part.
+pub struct SelectionMaskSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    enabled: bool,
+    pipelines: Option<[Arc<GraphicsPipelineAbstract + Send + Sync>; 2]>,
+}
+
+impl SelectionMaskSystem {
+    /// Does nothing unless `depth_format` has a stencil aspect.
+    pub fn new(gfx_queue: Arc<Queue>, depth_format: Format) -> SelectionMaskSystem {
+        SelectionMaskSystem {
+            vertex_buffer: fullscreen_triangle(&gfx_queue),
+            gfx_queue,
+            enabled: has_stencil(depth_format),
+            pipelines: None,
+        }
+    }
+
+    pub fn image_desc() -> ImageDesc {
+        ImageDesc {
+            format: SELECTION_FORMAT,
+            size: ImageSize::Output,
+            usage: ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            },
+        }
+    }
+}
+
+impl RenderNode for SelectionMaskSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.color(SELECTION_IMAGE, Load::Clear([0.0, 0.0, 0.0, 0.0].into()))
+            .depth_stencil(DEPTH_IMAGE, Load::Load);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        if !self.enabled {
+            return;
+        }
+        let subpass = subpass.unwrap();
+        let device = self.gfx_queue.device().clone();
+        let vs = vs::Shader::load(device.clone()).expect("Could not create shader module");
+        let fs = mask_fs::Shader::load(device.clone()).expect("Could not create shader module");
+
+        // Both draws add their channel, the visible part is always inside the silhouette.
+        let pipeline = |bit: u32| -> Arc<GraphicsPipelineAbstract + Send + Sync> {
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input_single_buffer::<Vertex>()
+                    .vertex_shader(vs.main_entry_point(), ())
+                    .triangle_list()
+                    .viewports_dynamic_scissors_irrelevant(1)
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .depth_stencil(DepthStencil {
+                        depth_write: false,
+                        depth_compare: Compare::Always,
+                        stencil_front: test(bit, bit),
+                        stencil_back: test(bit, bit),
+                        ..DepthStencil::disabled()
+                    })
+                    .blend_collective(AttachmentBlend {
+                        enabled: true,
+                        color_op: BlendOp::Add,
+                        color_source: BlendFactor::One,
+                        color_destination: BlendFactor::One,
+                        alpha_op: BlendOp::Add,
+                        alpha_source: BlendFactor::One,
+                        alpha_destination: BlendFactor::One,
+                        ..AttachmentBlend::pass_through()
+                    })
+                    .render_pass(subpass.clone())
+                    .build(device.clone())
+                    .unwrap(),
+            )
+        };
+        self.pipelines = Some([pipeline(SILHOUETTE_BIT), pipeline(VISIBLE_BIT)]);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        // Without anything marked the cleared mask is all the outline pass needs.
+        if !self.enabled || !context.settings().outline.enabled {
+            return;
+        }
+        let pipelines = self.pipelines.clone().unwrap();
+        let viewport_dimensions = context.dimensions(SELECTION_IMAGE);
+        let dynamic_state = viewport_state(viewport_dimensions);
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipelines[0].clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipelines[0].clone(),
+                dynamic_state.clone(),
+                vec![self.vertex_buffer.clone()],
+                (),
+                mask_fs::ty::PushConstants {
+                    channel: [1.0, 0.0, 0.0, 0.0],
+                },
+            )
+            .unwrap()
+            .draw(
+                pipelines[1].clone(),
+                dynamic_state,
+                vec![self.vertex_buffer.clone()],
+                (),
+                mask_fs::ty::PushConstants {
+                    channel: [0.0, 1.0, 0.0, 0.0],
+                },
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+/// Draws a constant width outline around the selection mask into the final image, solid
+/// next to visible parts and dashed next to hidden ones. The inside of the silhouette is left
+/// alone by testing the stencil.
+pub struct OutlineSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    enabled: bool,
+    decode_srgb: bool,
+    pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    sampler: Arc<Sampler>,
+}
+
+impl OutlineSystem {
+    /// Does nothing unless `depth_format` has a stencil aspect.
+    pub fn new(gfx_queue: Arc<Queue>, output_format: Format, depth_format: Format) -> OutlineSystem {
+        let sampler = clamp_sampler(gfx_queue.device(), Filter::Nearest);
+
+        OutlineSystem {
+            vertex_buffer: fullscreen_triangle(&gfx_queue),
+            gfx_queue,
+            enabled: has_stencil(depth_format),
+            decode_srgb: is_srgb(output_format),
+            pipeline: None,
+            sampler,
+        }
+    }
+}
+
+impl RenderNode for OutlineSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.read(SELECTION_IMAGE)
+            .color(FINAL_IMAGE, Load::Load)
+            .depth_stencil(DEPTH_IMAGE, Load::Load);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        if !self.enabled {
+            return;
+        }
+        let vs = vs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = outline_fs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+
+        self.pipeline = Some(Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil(DepthStencil {
+                    depth_write: false,
+                    depth_compare: Compare::Always,
+                    stencil_front: test(SILHOUETTE_BIT, 0),
+                    stencil_back: test(SILHOUETTE_BIT, 0),
+                    ..DepthStencil::disabled()
+                })
+                .blend_alpha_blending()
+                .render_pass(subpass.unwrap())
+                .build(self.gfx_queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        let settings = context.settings().outline.clone();
+        if !self.enabled || !settings.enabled || settings.width <= 0.0 {
+            return;
+        }
+        let pipeline = self.pipeline.clone().unwrap();
+        let viewport_dimensions = context.dimensions(FINAL_IMAGE);
+
+        let descriptor_set = PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_sampled_image(context.image(SELECTION_IMAGE), self.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let push_constants = outline_fs::ty::PushConstants {
+            color: settings.color,
+            width: settings.width.min(MAX_OUTLINE_WIDTH),
+            dash_length: settings.dash_length.max(1.0),
+            show_hidden: settings.show_hidden as u32,
+            decode_srgb: self.decode_srgb as u32,
+        };
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipeline.clone(),
+                viewport_state(viewport_dimensions),
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                push_constants,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 position;
+
+void main() {
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod mask_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) out vec4 f_mask;
+
+layout(push_constant) uniform PushConstants {
+    vec4 channel;
+} push_constants;
+
+void main() {
+    f_mask = push_constants.channel;
+}
+"]
+    struct Dummy;
+}
+
+mod outline_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+// Matches MAX_OUTLINE_WIDTH.
+const int MAX_RADIUS = 8;
+
+layout(set = 0, binding = 0) uniform sampler2D u_selection;
+
+layout(location = 0) out vec4 f_color;
+
+layout(push_constant) uniform PushConstants {
+    vec4 color;
+    float width;
+    float dash_length;
+    uint show_hidden;
+    uint decode_srgb;
+} push_constants;
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    ivec2 size = textureSize(u_selection, 0);
+    int radius = min(int(ceil(push_constants.width)), MAX_RADIUS);
+
+    // Distances to the nearest marked pixel of the silhouette and of its visible part.
+    float to_silhouette = 1e9;
+    float to_visible = 1e9;
+    for (int y = -radius; y <= radius; y++) {
+        for (int x = -radius; x <= radius; x++) {
+            ivec2 neighbor = clamp(texel + ivec2(x, y), ivec2(0), size - 1);
+            vec2 mask = texelFetch(u_selection, neighbor, 0).rg;
+            float distance = length(vec2(x, y));
+            if (mask.r > 0.5) {
+                to_silhouette = min(to_silhouette, distance);
+            }
+            if (mask.g > 0.5) {
+                to_visible = min(to_visible, distance);
+            }
+        }
+    }
+
+    // Half a pixel of falloff smooths the outer edge.
+    float coverage = clamp(push_constants.width + 0.5 - to_visible, 0.0, 1.0);
+    if (coverage <= 0.0) {
+        if (push_constants.show_hidden == 0) {
+            discard;
+        }
+        float dash = floor((gl_FragCoord.x + gl_FragCoord.y) / push_constants.dash_length);
+        if (mod(dash, 2.0) != 0.0) {
+            discard;
+        }
+        coverage = clamp(push_constants.width + 0.5 - to_silhouette, 0.0, 1.0);
+        if (coverage <= 0.0) {
+            discard;
+        }
+    }
+
+    vec4 color = push_constants.color;
+    // Like debug lines, the color is meant to end up on screen as it is.
+    if (push_constants.decode_srgb != 0) {
+        color.rgb = mix(
+            color.rgb / 12.92,
+            pow((color.rgb + 0.055) / 1.055, vec3(2.4)),
+            greaterThan(color.rgb, vec3(0.04045)));
+    }
+    f_color = vec4(color.rgb, color.a * coverage);
+}
+"]
+    struct Dummy;
+}
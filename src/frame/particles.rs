@@ -0,0 +1,619 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use cgmath::{Deg, InnerSpace, Matrix4, Point3, Vector3};
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::buffer::DeviceLocalBuffer;
+use vulkano::command_buffer::AutoCommandBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::DescriptorSet;
+use vulkano::descriptor::descriptor_set::FixedSizeDescriptorSetsPool;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::PipelineLayoutAbstract;
+use vulkano::device::Queue;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::blend::BlendFactor;
+use vulkano::pipeline::blend::BlendOp;
+use vulkano::pipeline::depth_stencil::Compare;
+use vulkano::pipeline::depth_stencil::DepthStencil;
+use vulkano::pipeline::vertex::BufferlessDefinition;
+use vulkano::pipeline::vertex::BufferlessVertices;
+use vulkano::pipeline::ComputePipeline;
+use vulkano::pipeline::ComputePipelineAbstract;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+use super::fullscreen::viewport_state;
+use super::system::ComputePass;
+use super::system::ComputeStage;
+
+/// Matches `local_size_x` of the simulation shader.
+const LOCAL_SIZE: u32 = 256;
+
+/// How particles are blended into the HDR image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticleBlend {
+    /// Adds light, for sparks and glows. Independent of the drawing order.
+    Additive,
+    /// Covers what's behind, for smoke. Particles aren't sorted, so overlapping ones of the
+    /// same emitter may blend in the wrong order.
+    Alpha,
+}
+
+/// Spawns particles at a constant rate. Colors are linear HDR colors, sizes are the side
+/// lengths of the billboards in world units; both are interpolated over each particle's
+/// lifetime.
+#[derive(Debug, Clone)]
+pub struct Emitter {
+    pub position: Point3<f32>,
+    /// Particles spawn anywhere in a sphere of this radius around `position`.
+    pub radius: f32,
+    /// Particles per second.
+    pub rate: f32,
+    /// In seconds, picked uniformly for each particle.
+    pub lifetime: Range<f32>,
+    /// Axis of the cone the initial velocities point into.
+    pub direction: Vector3<f32>,
+    /// Half of the cone's opening angle, 180° emits in all directions.
+    pub spread: Deg<f32>,
+    pub speed: Range<f32>,
+    /// Gravity, buoyancy or wind.
+    pub acceleration: Vector3<f32>,
+    /// Fraction of the velocity lost per second.
+    pub drag: f32,
+    pub start_color: [f32; 4],
+    pub end_color: [f32; 4],
+    pub start_size: f32,
+    pub end_size: f32,
+    pub blend: ParticleBlend,
+}
+
+impl Default for Emitter {
+    fn default() -> Emitter {
+        Emitter {
+            position: Point3::new(0.0, 0.0, 0.0),
+            radius: 0.0,
+            rate: 100.0,
+            lifetime: 1.0..2.0,
+            direction: Vector3::new(0.0, 1.0, 0.0),
+            spread: Deg(15.0),
+            speed: 0.5..1.0,
+            acceleration: Vector3::new(0.0, 0.0, 0.0),
+            drag: 0.0,
+            start_color: [1.0, 1.0, 1.0, 1.0],
+            end_color: [1.0, 1.0, 1.0, 0.0],
+            start_size: 0.05,
+            end_size: 0.05,
+            blend: ParticleBlend::Additive,
+        }
+    }
+}
+
+impl Emitter {
+    /// Particles alive at once at the most, when emitting at `rate`.
+    pub fn capacity(&self) -> u32 {
+        (self.rate * self.lifetime.end).ceil() as u32 + 1
+    }
+}
+
+/// The part of the particle buffers owned by an emitter, used as a ring: every frame the
+/// oldest slots after `head` are respawned.
+#[derive(Debug, Clone)]
+struct EmitterSlots {
+    offset: u32,
+    capacity: u32,
+    head: u32,
+    /// Fraction of a particle carried over to the next frame.
+    pending: f32,
+    /// Whether the slots still hold garbage from before the emitter was added.
+    reset: bool,
+}
+
+/// Particles simulated entirely on the GPU and drawn as camera facing billboards.
+///
+/// The state of all particles lives in two storage buffers: every frame `simulate` reads the
+/// one written in the previous frame and writes the other, which `draw` then reads. Each
+/// emitter owns a fixed range of them, sized by `Emitter::capacity` when it's added; raising
+/// its rate later recycles the oldest particles early.
+pub struct ParticleSystem {
+    queue: Arc<Queue>,
+    capacity: u32,
+    allocated: u32,
+    emitters: Vec<Emitter>,
+    slots: Vec<EmitterSlots>,
+    /// Index of the buffer written by the latest `simulate`.
+    current: usize,
+    seed: u32,
+    simulate_pipeline: Arc<ComputePipelineAbstract + Send + Sync>,
+    /// Reading buffer `i` and writing the other one.
+    simulate_sets: [Arc<DescriptorSet + Send + Sync>; 2],
+    additive_pipeline: Arc<BillboardPipeline>,
+    alpha_pipeline: Arc<BillboardPipeline>,
+    buffers: [Arc<DeviceLocalBuffer<[Particle]>>; 2],
+    camera_buffer_pool: CpuBufferPool<billboard_vs::ty::Camera>,
+    ds_pool: FixedSizeDescriptorSetsPool<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+}
+
+/// Kept concrete, as only a pipeline with a `BufferlessDefinition` can draw `BufferlessVertices`.
+type BillboardPipeline = GraphicsPipeline<
+    BufferlessDefinition,
+    Box<PipelineLayoutAbstract + Send + Sync>,
+    Arc<RenderPassAbstract + Send + Sync>,
+>;
+
+impl ParticleSystem {
+    /// Room for `capacity` particles over all emitters, drawn in `subpass`, which has the HDR
+    /// image as its only color attachment and the scene depth, like `Pass::Transparent`.
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>, capacity: u32) -> ParticleSystem
+    where
+        R: RenderPassAbstract + Send + Sync + Clone + 'static,
+    {
+        let device = queue.device().clone();
+
+        let buffers = {
+            let create_buffer = || {
+                DeviceLocalBuffer::array(
+                    device.clone(),
+                    capacity as usize,
+                    BufferUsage {
+                        storage_buffer: true,
+                        ..BufferUsage::none()
+                    },
+                    Some(queue.family()),
+                ).expect("Failed to create particle buffer")
+            };
+            [create_buffer(), create_buffer()]
+        };
+
+        let simulate_pipeline = {
+            let cs = simulate_cs::Shader::load(device.clone()).expect("Could not create shader module");
+            let pipeline = ComputePipeline::new(device.clone(), &cs.main_entry_point(), &()).unwrap();
+            Arc::new(pipeline) as Arc<ComputePipelineAbstract + Send + Sync>
+        };
+        let simulate_sets = {
+            let simulate_set = |previous: usize| {
+                Arc::new(
+                    PersistentDescriptorSet::start(simulate_pipeline.clone(), 0)
+                        .add_buffer(buffers[previous].clone())
+                        .unwrap()
+                        .add_buffer(buffers[1 - previous].clone())
+                        .unwrap()
+                        .build()
+                        .unwrap(),
+                ) as Arc<DescriptorSet + Send + Sync>
+            };
+            [simulate_set(0), simulate_set(1)]
+        };
+
+        let vs = billboard_vs::Shader::load(device.clone()).expect("Could not create shader module");
+        let fs = billboard_fs::Shader::load(device.clone()).expect("Could not create shader module");
+
+        // Tested against the opaque geometry, but without hiding each other.
+        let depth_stencil = DepthStencil {
+            depth_write: false,
+            depth_compare: Compare::Less,
+            ..DepthStencil::disabled()
+        };
+
+        let subpass = Subpass::from(
+            Arc::new(subpass.render_pass().clone()) as Arc<RenderPassAbstract + Send + Sync>,
+            subpass.index(),
+        ).unwrap();
+
+        let additive_pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input(BufferlessDefinition)
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil(depth_stencil.clone())
+                .blend_collective(AttachmentBlend {
+                    enabled: true,
+                    color_op: BlendOp::Add,
+                    color_source: BlendFactor::SrcAlpha,
+                    color_destination: BlendFactor::One,
+                    alpha_op: BlendOp::Add,
+                    alpha_source: BlendFactor::Zero,
+                    alpha_destination: BlendFactor::One,
+                    ..AttachmentBlend::pass_through()
+                })
+                .render_pass(subpass.clone())
+                .build(device.clone())
+                .unwrap(),
+        );
+
+        let alpha_pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input(BufferlessDefinition)
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil(depth_stencil)
+                .blend_alpha_blending()
+                .render_pass(subpass)
+                .build(device.clone())
+                .unwrap(),
+        );
+
+        ParticleSystem {
+            capacity,
+            allocated: 0,
+            emitters: Vec::new(),
+            slots: Vec::new(),
+            current: 0,
+            seed: 0,
+            simulate_pipeline,
+            simulate_sets,
+            camera_buffer_pool: CpuBufferPool::uniform_buffer(device.clone()),
+            ds_pool: FixedSizeDescriptorSetsPool::new(additive_pipeline.clone() as Arc<_>, 0),
+            additive_pipeline,
+            alpha_pipeline,
+            buffers,
+            queue,
+        }
+    }
+
+    /// Total number of particles there's room for.
+    #[inline]
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Number of particles owned by emitters, alive or not.
+    #[inline]
+    pub fn allocated(&self) -> u32 {
+        self.allocated
+    }
+
+    /// Returns the emitter's index. Panics if there's no room left for `Emitter::capacity`
+    /// more particles.
+    pub fn add_emitter(&mut self, emitter: Emitter) -> usize {
+        let capacity = emitter.capacity();
+        assert!(
+            self.allocated + capacity <= self.capacity,
+            "No room for {} more particles, {} of {} are taken",
+            capacity,
+            self.allocated,
+            self.capacity
+        );
+
+        self.slots.push(EmitterSlots {
+            offset: self.allocated,
+            capacity,
+            head: 0,
+            pending: 0.0,
+            reset: true,
+        });
+        self.allocated += capacity;
+        self.emitters.push(emitter);
+        self.emitters.len() - 1
+    }
+
+    #[inline]
+    pub fn emitters(&self) -> &[Emitter] {
+        &self.emitters
+    }
+
+    /// For changing emitters in place, their capacity stays what it was when they were added.
+    #[inline]
+    pub fn emitters_mut(&mut self) -> &mut [Emitter] {
+        &mut self.emitters
+    }
+
+    /// Spawns, moves and ages all particles by the frame's delta time, in the
+    /// `ComputeStage::BeforeRendering` pass. Does nothing in the other compute passes.
+    pub fn simulate(&mut self, compute_pass: &mut ComputePass) {
+        if compute_pass.stage() != ComputeStage::BeforeRendering {
+            return;
+        }
+        let delta_time = compute_pass.delta_time();
+        let previous = self.current;
+        self.current = 1 - previous;
+        self.seed = self.seed.wrapping_add(1);
+
+        for (emitter, slots) in self.emitters.iter().zip(self.slots.iter_mut()) {
+            slots.pending += emitter.rate.max(0.0) * delta_time;
+            let spawn_count = (slots.pending.floor() as u32).min(slots.capacity);
+            slots.pending -= spawn_count as f32;
+            // Only a fraction of a particle can be carried over, even when some were dropped.
+            slots.pending = slots.pending.min(1.0);
+
+            let direction = if emitter.direction.magnitude2() > 0.0 {
+                emitter.direction.normalize()
+            } else {
+                Vector3::new(0.0, 1.0, 0.0)
+            };
+            let push_constants = simulate_cs::ty::PushConstants {
+                position: [emitter.position.x, emitter.position.y, emitter.position.z, emitter.radius],
+                direction: [direction.x, direction.y, direction.z, emitter.spread.0.to_radians().cos()],
+                acceleration: [
+                    emitter.acceleration.x,
+                    emitter.acceleration.y,
+                    emitter.acceleration.z,
+                    emitter.drag.max(0.0),
+                ],
+                lifetime: [emitter.lifetime.start, emitter.lifetime.end],
+                speed: [emitter.speed.start, emitter.speed.end],
+                delta_time,
+                offset: slots.offset,
+                capacity: slots.capacity,
+                head: slots.head,
+                spawn_count,
+                seed: self.seed,
+                reset: slots.reset as u32,
+            };
+            let groups = (slots.capacity + LOCAL_SIZE - 1) / LOCAL_SIZE;
+            compute_pass.dispatch(
+                [groups, 1, 1],
+                self.simulate_pipeline.clone(),
+                self.simulate_sets[previous].clone(),
+                push_constants,
+            );
+
+            slots.head = (slots.head + spawn_count) % slots.capacity;
+            slots.reset = false;
+        }
+    }
+
+    /// For `Pass::Transparent`, draws the particles as of the latest `simulate`.
+    pub fn draw(&mut self, dimensions: [u32; 2], view: Matrix4<f32>, projection: Matrix4<f32>) -> AutoCommandBuffer {
+        let camera_buffer = self
+            .camera_buffer_pool
+            .next(billboard_vs::ty::Camera {
+                view: view.into(),
+                projection: projection.into(),
+            })
+            .unwrap();
+        let descriptor_set = Arc::new(
+            self.ds_pool
+                .next()
+                .add_buffer(camera_buffer)
+                .unwrap()
+                .add_buffer(self.buffers[self.current].clone())
+                .unwrap()
+                .build()
+                .unwrap(),
+        );
+
+        let dynamic_state = viewport_state(dimensions);
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            self.additive_pipeline.clone().subpass(),
+        ).unwrap();
+        for (emitter, slots) in self.emitters.iter().zip(self.slots.iter()) {
+            // Not simulated yet, its slots hold garbage.
+            if slots.reset {
+                continue;
+            }
+            let pipeline = match emitter.blend {
+                ParticleBlend::Additive => self.additive_pipeline.clone(),
+                ParticleBlend::Alpha => self.alpha_pipeline.clone(),
+            };
+            // One quad per slot, dead particles collapse to nothing in the vertex shader.
+            builder = builder
+                .draw(
+                    pipeline,
+                    dynamic_state.clone(),
+                    BufferlessVertices {
+                        vertices: 6,
+                        instances: slots.capacity as usize,
+                    },
+                    descriptor_set.clone(),
+                    billboard_vs::ty::PushConstants {
+                        start_color: emitter.start_color,
+                        end_color: emitter.end_color,
+                        start_size: emitter.start_size,
+                        end_size: emitter.end_size,
+                        offset: slots.offset,
+                    },
+                )
+                .unwrap();
+        }
+        builder.build().unwrap()
+    }
+}
+
+/// Laid out like `Particle` in the shaders, std430.
+#[derive(Debug, Clone, Copy)]
+struct Particle {
+    /// World space, `w` is the age in seconds.
+    position_age: [f32; 4],
+    /// `w` is the lifetime in seconds, the particle is dead once its age reaches it.
+    velocity_lifetime: [f32; 4],
+}
+
+mod simulate_cs {
+    #[derive(VulkanoShader)]
+    #[ty = "compute"]
+    #[src = "
+#version 450
+
+layout(local_size_x = 256) in;
+
+struct Particle {
+    vec4 position_age;
+    vec4 velocity_lifetime;
+};
+
+layout(std430, set = 0, binding = 0) readonly buffer Previous {
+    Particle data[];
+} previous;
+
+layout(std430, set = 0, binding = 1) writeonly buffer Current {
+    Particle data[];
+} current;
+
+layout(push_constant) uniform PushConstants {
+    // w: radius of the spawn sphere
+    vec4 position;
+    // w: cosine of the cone's half angle
+    vec4 direction;
+    // w: drag
+    vec4 acceleration;
+    vec2 lifetime;
+    vec2 speed;
+    float delta_time;
+    uint offset;
+    uint capacity;
+    uint head;
+    uint spawn_count;
+    uint seed;
+    uint reset;
+} push_constants;
+
+const float TAU = 6.28318530718;
+
+// PCG hash, good enough to decorrelate neighbouring slots.
+uint hash(uint x) {
+    uint state = x * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+float random(inout uint state) {
+    state = hash(state);
+    return float(state) / 4294967295.0;
+}
+
+vec3 random_direction(inout uint state, vec3 axis, float cos_spread) {
+    float cos_theta = mix(1.0, cos_spread, random(state));
+    float sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+    float phi = TAU * random(state);
+    vec3 u = normalize(cross(abs(axis.x) > 0.9 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0), axis));
+    vec3 v = cross(axis, u);
+    return (u * cos(phi) + v * sin(phi)) * sin_theta + axis * cos_theta;
+}
+
+void integrate(inout Particle particle, float dt) {
+    vec3 velocity = particle.velocity_lifetime.xyz + push_constants.acceleration.xyz * dt;
+    velocity /= 1.0 + push_constants.acceleration.w * dt;
+    particle.position_age.xyz += velocity * dt;
+    particle.position_age.w += dt;
+    particle.velocity_lifetime.xyz = velocity;
+}
+
+void main() {
+    uint slot = gl_GlobalInvocationID.x;
+    if (slot >= push_constants.capacity) {
+        return;
+    }
+    uint index = push_constants.offset + slot;
+    float dt = push_constants.delta_time;
+
+    uint since_head = (slot + push_constants.capacity - push_constants.head) % push_constants.capacity;
+    Particle particle;
+    if (since_head < push_constants.spawn_count) {
+        uint state = hash(index ^ hash(push_constants.seed));
+        vec3 offset = push_constants.position.w * pow(random(state), 1.0 / 3.0)
+            * random_direction(state, vec3(0.0, 1.0, 0.0), -1.0);
+        vec3 direction = random_direction(state, push_constants.direction.xyz, push_constants.direction.w);
+        float speed = mix(push_constants.speed.x, push_constants.speed.y, random(state));
+        float lifetime = mix(push_constants.lifetime.x, push_constants.lifetime.y, random(state));
+        particle.position_age = vec4(push_constants.position.xyz + offset, 0.0);
+        particle.velocity_lifetime = vec4(direction * speed, lifetime);
+        // Spread over the frame instead of spawning in bursts, the first one at its start.
+        float spawned_ago = dt * (1.0 - (float(since_head) + 0.5) / float(push_constants.spawn_count));
+        integrate(particle, spawned_ago);
+    } else if (push_constants.reset != 0) {
+        particle.position_age = vec4(0.0);
+        particle.velocity_lifetime = vec4(0.0);
+    } else {
+        particle = previous.data[index];
+        if (particle.position_age.w < particle.velocity_lifetime.w) {
+            integrate(particle, dt);
+        }
+    }
+    current.data[index] = particle;
+}
+"]
+    struct Dummy;
+}
+
+mod billboard_vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+struct Particle {
+    vec4 position_age;
+    vec4 velocity_lifetime;
+};
+
+layout(std140, set = 0, binding = 0) uniform Camera {
+    mat4 view;
+    mat4 projection;
+} camera;
+
+layout(std430, set = 0, binding = 1) readonly buffer Particles {
+    Particle data[];
+} particles;
+
+layout(push_constant) uniform PushConstants {
+    vec4 start_color;
+    vec4 end_color;
+    float start_size;
+    float end_size;
+    uint offset;
+} push_constants;
+
+layout(location = 0) out vec4 v_color;
+layout(location = 1) out vec2 v_corner;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(-1.0, 1.0),
+    vec2(-1.0, 1.0), vec2(1.0, -1.0), vec2(1.0, 1.0)
+);
+
+void main() {
+    Particle particle = particles.data[push_constants.offset + gl_InstanceIndex];
+    float age = particle.position_age.w;
+    float lifetime = particle.velocity_lifetime.w;
+    if (age >= lifetime) {
+        // Outside the clip volume, the whole quad is culled.
+        gl_Position = vec4(0.0, 0.0, 2.0, 1.0);
+        v_color = vec4(0.0);
+        v_corner = vec2(0.0);
+        return;
+    }
+
+    float t = age / lifetime;
+    v_color = mix(push_constants.start_color, push_constants.end_color, t);
+    v_corner = CORNERS[gl_VertexIndex];
+
+    // Offset in view space, so the quad faces the camera.
+    float size = mix(push_constants.start_size, push_constants.end_size, t);
+    vec4 view_position = camera.view * vec4(particle.position_age.xyz, 1.0);
+    view_position.xy += v_corner * 0.5 * size;
+    gl_Position = camera.projection * view_position;
+}
+"]
+    struct Dummy;
+}
+
+mod billboard_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec4 v_color;
+layout(location = 1) in vec2 v_corner;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    // Round, with a soft edge.
+    float coverage = 1.0 - smoothstep(0.5, 1.0, length(v_corner));
+    f_color = vec4(v_color.rgb, v_color.a * coverage);
+}
+"]
+    struct Dummy;
+}
@@ -0,0 +1,41 @@
+/// xorshift32, for things that only have to look random and be the same every run.
+#[derive(Debug, Clone)]
+pub struct Random(u32);
+
+impl Random {
+    /// `seed` can't be 0, the sequence would never leave it.
+    pub fn new(seed: u32) -> Random {
+        assert!(seed != 0, "A xorshift seed of 0 only ever gives 0");
+        Random(seed)
+    }
+
+    /// In 0..1.
+    pub fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Random;
+
+    #[test]
+    fn values_are_in_unit_range() {
+        let mut random = Random::new(1);
+        for _ in 0..10_000 {
+            let value = random.next();
+            assert!(value >= 0.0 && value < 1.0, "{}", value);
+        }
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let (mut a, mut b) = (Random::new(0x9e37_79b9), Random::new(0x9e37_79b9));
+        for _ in 0..100 {
+            assert_eq!(a.next(), b.next());
+        }
+    }
+}
@@ -0,0 +1,50 @@
+/// How scene geometry is shaded, to inspect meshes. Geometry renderers pick their pipeline
+/// variant from this, the frame system shows the debug views without tonemapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    Shaded,
+    /// Edges only, as lines when the device supports `fill_mode_non_solid`.
+    Wireframe,
+    /// Shaded, with the edges drawn on top.
+    ShadedWireframe,
+    /// World space normals, mapped from -1..1 to 0..1.
+    Normals,
+    /// Distance from the camera, black near and white far.
+    LinearDepth,
+    /// A checkerboard in texture coordinates, to spot stretching and seams.
+    UvChecker,
+    /// Additive layers without depth testing, brighter where more surfaces overlap.
+    Overdraw,
+    /// Shaded geometry is drawn as usual, the frame system then shows only the screen-space
+    /// ambient occlusion, white where nothing is occluded.
+    AmbientOcclusion,
+}
+
+impl RenderMode {
+    pub fn next(self) -> RenderMode {
+        match self {
+            RenderMode::Shaded => RenderMode::Wireframe,
+            RenderMode::Wireframe => RenderMode::ShadedWireframe,
+            RenderMode::ShadedWireframe => RenderMode::Normals,
+            RenderMode::Normals => RenderMode::LinearDepth,
+            RenderMode::LinearDepth => RenderMode::UvChecker,
+            RenderMode::UvChecker => RenderMode::Overdraw,
+            RenderMode::Overdraw => RenderMode::AmbientOcclusion,
+            RenderMode::AmbientOcclusion => RenderMode::Shaded,
+        }
+    }
+
+    /// Whether the colors are data rather than lighting, and are shown as they are.
+    pub fn is_debug_view(self) -> bool {
+        match self {
+            RenderMode::Shaded | RenderMode::Wireframe | RenderMode::ShadedWireframe => false,
+            _ => true,
+        }
+    }
+}
+
+impl Default for RenderMode {
+    fn default() -> RenderMode {
+        RenderMode::Shaded
+    }
+}
@@ -0,0 +1,414 @@
+use std::sync::Arc;
+
+use cgmath::{InnerSpace, Matrix, Matrix4, SquareMatrix, Vector4};
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::ImageViewAccess;
+use vulkano::sync::GpuFuture;
+
+use super::system::FrameSystem;
+use super::system::Pass;
+use super::system::RenderSettings;
+
+/// Index of a target added with `RenderTargets::add`.
+pub type TargetId = usize;
+
+/// Levels of targets showing targets rendered by default, see `RenderTargets::set_max_depth`.
+pub const DEFAULT_MAX_DEPTH: usize = 2;
+
+/// Where a render target looks at the scene from.
+#[derive(Debug, Clone, Copy)]
+pub enum TargetCamera {
+    /// The same for every viewer, like a security camera or a minimap.
+    Fixed {
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+    },
+    /// The viewer reflected in `plane`, whose `xyz` is the unit normal on the reflecting side
+    /// and `w` the offset, so `dot(xyz, p) + w` is the signed distance of `p`. Whatever is
+    /// behind the plane is clipped away by the near plane.
+    Mirror { plane: Vector4<f32> },
+}
+
+impl TargetCamera {
+    /// A mirror in the plane of a `Screen` placed by `model`.
+    pub fn mirror(model: &Matrix4<f32>) -> TargetCamera {
+        let normal = (model * Vector4::new(0.0, 0.0, 1.0, 0.0)).truncate().normalize();
+        let center = (model * Vector4::new(0.0, 0.0, 0.0, 1.0)).truncate();
+        TargetCamera::Mirror {
+            plane: normal.extend(-normal.dot(center)),
+        }
+    }
+
+    /// The view and projection of the target seen by a viewer with `view` and `projection`.
+    /// `None` for a mirror the viewer is behind, as it only shows its back.
+    pub fn matrices(&self, view: Matrix4<f32>, projection: Matrix4<f32>) -> Option<(Matrix4<f32>, Matrix4<f32>)> {
+        match *self {
+            TargetCamera::Fixed { view, projection } => Some((view, projection)),
+            TargetCamera::Mirror { plane } => {
+                let eye = view.invert().unwrap() * Vector4::new(0.0, 0.0, 0.0, 1.0);
+                if plane.dot(eye) <= 0.0 {
+                    return None;
+                }
+                let mirror_view = view * reflection(plane);
+                // Planes transform with the inverse transpose of what transforms points.
+                let view_plane = mirror_view.invert().unwrap().transpose() * plane;
+                Some((mirror_view, oblique_projection(projection, view_plane)))
+            }
+        }
+    }
+}
+
+/// Reflects points in `plane`, see `TargetCamera::Mirror`.
+pub fn reflection(plane: Vector4<f32>) -> Matrix4<f32> {
+    let (x, y, z, d) = (plane.x, plane.y, plane.z, plane.w);
+    Matrix4::new(
+        1.0 - 2.0 * x * x, -2.0 * x * y, -2.0 * x * z, 0.0,
+        -2.0 * x * y, 1.0 - 2.0 * y * y, -2.0 * y * z, 0.0,
+        -2.0 * x * z, -2.0 * y * z, 1.0 - 2.0 * z * z, 0.0,
+        -2.0 * d * x, -2.0 * d * y, -2.0 * d * z, 1.0,
+    )
+}
+
+/// Replaces the near plane of `projection` with `plane`, in view space, so only what's on its
+/// positive side gets drawn (Lengyel, "Oblique View Frustum Depth Projection and Clipping").
+/// The far plane is tilted to keep the corners of the original far plane, and depth ends up
+/// in the `0..w` range of Vulkan.
+pub fn oblique_projection(projection: Matrix4<f32>, plane: Vector4<f32>) -> Matrix4<f32> {
+    let inverse = projection.invert().unwrap();
+    // The far corner on the side of the plane that's furthest from the frustum.
+    let clip_plane = inverse.transpose() * plane;
+    let corner = inverse * Vector4::new(clip_plane.x.signum(), clip_plane.y.signum(), 1.0, 1.0);
+    let w_row = Vector4::new(projection.x.w, projection.y.w, projection.z.w, projection.w.w);
+    let z_row = plane * (w_row.dot(corner) / plane.dot(corner));
+
+    let mut oblique = projection;
+    oblique.x.z = z_row.x;
+    oblique.y.z = z_row.y;
+    oblique.z.z = z_row.z;
+    oblique.w.z = z_row.w;
+    oblique
+}
+
+/// The images of the targets shown in a frame, by `TargetId`.
+#[derive(Clone)]
+pub struct TargetImages {
+    images: Vec<Option<Arc<ImageViewAccess + Send + Sync>>>,
+    hdr_images: Vec<Option<Arc<ImageViewAccess + Send + Sync>>>,
+}
+
+impl TargetImages {
+    /// No image for any of `count` targets.
+    pub fn none(count: usize) -> TargetImages {
+        TargetImages {
+            images: vec![None; count],
+            hdr_images: vec![None; count],
+        }
+    }
+
+    /// The tonemapped image, e.g. for an overlay. `None` where the target wasn't rendered for
+    /// this frame, because the recursion depth was reached or the viewer is behind the mirror.
+    pub fn get(&self, target: TargetId) -> Option<Arc<ImageViewAccess + Send + Sync>> {
+        self.images.get(target).and_then(|image| image.clone())
+    }
+
+    /// Like `get`, but the HDR image from before bloom and tonemapping, for showing the target
+    /// in the scene of a frame that tonemaps it.
+    pub fn hdr(&self, target: TargetId) -> Option<Arc<ImageViewAccess + Send + Sync>> {
+        self.hdr_images.get(target).and_then(|image| image.clone())
+    }
+}
+
+/// `settings` for a target. Occlusion culling is left off, the depth pyramid of the scene
+/// belongs to the window's camera.
+fn target_settings(settings: &RenderSettings) -> RenderSettings {
+    let mut target_settings = settings.clone();
+    target_settings.culling.occlusion = false;
+    target_settings
+}
+
+/// How a frame looks at the scene, for the code drawing into its passes: the window's frame as
+/// well as those of the render targets.
+#[derive(Clone)]
+pub struct SceneView {
+    pub view: Matrix4<f32>,
+    pub projection: Matrix4<f32>,
+    pub dimensions: [u32; 2],
+    pub settings: RenderSettings,
+    /// The target being rendered, `None` for the frame the targets are rendered for.
+    pub target: Option<TargetId>,
+    /// The images the screens in the frame show.
+    pub screens: TargetImages,
+}
+
+struct RenderTarget {
+    camera: TargetCamera,
+    frame_system: FrameSystem,
+    dimensions: [u32; 2],
+}
+
+/// Cameras rendering the scene into images of their own, which the main frame samples, e.g.
+/// on the screens of a `ScreenRenderer`. Each target has a headless frame system with its own
+/// resolution and render settings.
+///
+/// Targets can show other targets, up to `max_depth` levels deep. Every level has an image of
+/// its own, as a mirror seen in a mirror is rendered from another camera than the first one.
+/// Mirrors are rendered again for every mirror they're seen in, so the cost grows with the
+/// number of mirrors to the power of the depth. Fixed targets are rendered once per level.
+pub struct RenderTargets {
+    queue: Arc<Queue>,
+    depth_format: Format,
+    targets: Vec<RenderTarget>,
+    max_depth: usize,
+    /// The settings of the latest `set_settings`.
+    settings: Option<RenderSettings>,
+}
+
+impl RenderTargets {
+    /// `depth_format` should be that of the window's frame system, so pipelines built against
+    /// its subpasses can draw in the targets' as well.
+    pub fn new(queue: Arc<Queue>, depth_format: Format) -> RenderTargets {
+        RenderTargets {
+            queue,
+            depth_format,
+            targets: Vec::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            settings: None,
+        }
+    }
+
+    /// Adds a target rendering images of `dimensions`, with the settings of the latest
+    /// `set_settings`, or the default ones before that.
+    pub fn add(&mut self, camera: TargetCamera, dimensions: [u32; 2]) -> TargetId {
+        let mut frame_system =
+            FrameSystem::headless_with_depth_format(self.queue.clone(), dimensions, self.depth_format);
+        frame_system.set_headless_images(dimensions, self.max_depth.max(1));
+        if let Some(ref settings) = self.settings {
+            *frame_system.settings_mut() = target_settings(settings);
+        }
+        self.targets.push(RenderTarget {
+            camera,
+            frame_system,
+            dimensions,
+        });
+        self.targets.len() - 1
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    #[inline]
+    pub fn camera(&self, target: TargetId) -> TargetCamera {
+        self.targets[target].camera
+    }
+
+    #[inline]
+    pub fn set_camera(&mut self, target: TargetId, camera: TargetCamera) {
+        self.targets[target].camera = camera;
+    }
+
+    #[inline]
+    pub fn dimensions(&self, target: TargetId) -> [u32; 2] {
+        self.targets[target].dimensions
+    }
+
+    /// Reallocates the images of `target` when their size changes.
+    pub fn set_dimensions(&mut self, target: TargetId, dimensions: [u32; 2]) {
+        let max_depth = self.max_depth;
+        let target = &mut self.targets[target];
+        if target.dimensions != dimensions {
+            target.frame_system.set_headless_images(dimensions, max_depth.max(1));
+            target.dimensions = dimensions;
+        }
+    }
+
+    /// For render settings of a single target, which the next change in `set_settings`
+    /// overwrites.
+    #[inline]
+    pub fn frame_system_mut(&mut self, target: TargetId) -> &mut FrameSystem {
+        &mut self.targets[target].frame_system
+    }
+
+    /// Renders all targets with `settings`, e.g. those of the window's frame system each frame.
+    /// The targets are only updated when `settings` differ from the previous ones.
+    pub fn set_settings(&mut self, settings: &RenderSettings) {
+        if self.settings.as_ref() == Some(settings) {
+            return;
+        }
+        for target in &mut self.targets {
+            *target.frame_system.settings_mut() = target_settings(settings);
+        }
+        self.settings = Some(settings.clone());
+    }
+
+    #[inline]
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// How many levels of targets are rendered: 1 for targets that don't show any targets
+    /// themselves, 0 to render none at all.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        if max_depth == self.max_depth {
+            return;
+        }
+        for target in &mut self.targets {
+            target.frame_system.set_headless_images(target.dimensions, max_depth.max(1));
+        }
+        self.max_depth = max_depth;
+    }
+
+    /// Renders the targets seen by a viewer with `view` and `projection`, calling `draw` with
+    /// each pass of their frames. Returns the future of the last frame, which the viewer's
+    /// frame has to wait for, and the images its screens show.
+    pub fn render<F, D>(
+        &mut self,
+        before_future: F,
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+        mut draw: D,
+    ) -> (Box<GpuFuture>, TargetImages)
+    where
+        F: GpuFuture + 'static,
+        D: FnMut(Pass, &SceneView),
+    {
+        let mut rendered = vec![vec![false; self.targets.len()]; self.max_depth];
+        self.render_level(Box::new(before_future), 0, view, projection, &mut rendered, &mut draw)
+    }
+
+    /// Renders every target at `level` for one viewer, each after the targets it shows.
+    /// `rendered` marks the fixed targets already rendered at each level in this frame.
+    fn render_level<D>(
+        &mut self,
+        mut future: Box<GpuFuture>,
+        level: usize,
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+        rendered: &mut [Vec<bool>],
+        draw: &mut D,
+    ) -> (Box<GpuFuture>, TargetImages)
+    where
+        D: FnMut(Pass, &SceneView),
+    {
+        let mut images = TargetImages::none(self.targets.len());
+        if level >= self.max_depth {
+            return (future, images);
+        }
+
+        for id in 0..self.targets.len() {
+            let (target_view, target_projection) = match self.targets[id].camera.matrices(view, projection) {
+                Some(matrices) => matrices,
+                None => continue,
+            };
+            let fixed = match self.targets[id].camera {
+                TargetCamera::Fixed { .. } => true,
+                TargetCamera::Mirror { .. } => false,
+            };
+
+            if !fixed || !rendered[level][id] {
+                let (shown_future, screens) =
+                    self.render_level(future, level + 1, target_view, target_projection, rendered, draw);
+
+                let target = &mut self.targets[id];
+                let scene_view = SceneView {
+                    view: target_view,
+                    projection: target_projection,
+                    dimensions: target.dimensions,
+                    settings: target.frame_system.settings().clone(),
+                    target: Some(id),
+                    screens,
+                };
+                let mut frame = target
+                    .frame_system
+                    .frame(shown_future, level, target_view, target_projection);
+                let mut after_future = None;
+                while let Some(pass) = frame.next_pass() {
+                    match pass {
+                        Pass::Finished(af) => after_future = Some(af),
+                        pass => draw(pass, &scene_view),
+                    }
+                }
+                future = after_future.unwrap();
+                rendered[level][id] = true;
+            }
+            images.images[id] = Some(self.targets[id].frame_system.output_image_at(level));
+            images.hdr_images[id] = Some(self.targets[id].frame_system.hdr_image_at(level));
+        }
+        (future, images)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{perspective, Deg, InnerSpace, Matrix4, Point3, SquareMatrix, Vector3, Vector4};
+
+    use super::{oblique_projection, reflection, TargetCamera};
+
+    fn assert_close(a: Vector4<f32>, b: Vector4<f32>) {
+        assert!((a - b).magnitude() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    /// The plane `y = 1`, with its reflecting side up.
+    fn plane() -> Vector4<f32> {
+        Vector4::new(0.0, 1.0, 0.0, -1.0)
+    }
+
+    #[test]
+    fn reflection_mirrors_points_in_the_plane() {
+        let reflect = reflection(plane());
+        assert_close(reflect * Vector4::new(2.0, 1.0, 3.0, 1.0), Vector4::new(2.0, 1.0, 3.0, 1.0));
+        assert_close(reflect * Vector4::new(2.0, 3.0, 3.0, 1.0), Vector4::new(2.0, -1.0, 3.0, 1.0));
+        // Directions only flip their normal part.
+        assert_close(reflect * Vector4::new(1.0, 1.0, 0.0, 0.0), Vector4::new(1.0, -1.0, 0.0, 0.0));
+        let (twice, identity) = (reflect * reflect, Matrix4::<f32>::identity());
+        for column in 0..4 {
+            assert_close(twice[column], identity[column]);
+        }
+    }
+
+    #[test]
+    fn oblique_projection_clips_at_the_plane() {
+        let projection = perspective(Deg(60.0), 1.0, 0.1, 100.0);
+        // The view space plane z = -5, facing away from the camera.
+        let oblique = oblique_projection(projection, Vector4::new(0.0, 0.0, -1.0, -5.0));
+        let depth = |z: f32| {
+            let clip = oblique * Vector4::new(0.5, 0.5, z, 1.0);
+            clip.z / clip.w
+        };
+        assert!(depth(-5.0).abs() < 1e-4);
+        assert!(depth(-6.0) > 0.0 && depth(-6.0) <= 1.0);
+        assert!(depth(-4.0) < 0.0);
+        // Only depth changes.
+        let point = Vector4::new(0.5, 0.5, -8.0, 1.0);
+        let (a, b) = (projection * point, oblique * point);
+        assert_close(Vector4::new(a.x, a.y, 0.0, a.w), Vector4::new(b.x, b.y, 0.0, b.w));
+    }
+
+    #[test]
+    fn mirror_is_not_seen_from_behind() {
+        let mirror = TargetCamera::Mirror { plane: plane() };
+        let projection = perspective(Deg(60.0), 1.0, 0.1, 100.0);
+        let view_from = |eye: Point3<f32>| Matrix4::look_at(eye, Point3::new(0.0, 1.0, 0.0), Vector3::unit_z());
+        assert!(mirror.matrices(view_from(Point3::new(0.0, 5.0, 0.0)), projection).is_some());
+        assert!(mirror.matrices(view_from(Point3::new(0.0, -5.0, 0.0)), projection).is_none());
+        // In the plane there's nothing to see either.
+        assert!(mirror.matrices(view_from(Point3::new(5.0, 1.0, 0.0)), projection).is_none());
+    }
+
+    #[test]
+    fn mirror_view_looks_from_the_reflected_eye() {
+        let mirror = TargetCamera::Mirror { plane: plane() };
+        let projection = perspective(Deg(60.0), 1.0, 0.1, 100.0);
+        let view = Matrix4::look_at(Point3::new(1.0, 4.0, 2.0), Point3::new(0.0, 1.0, 0.0), Vector3::unit_y());
+        let (mirror_view, _) = mirror.matrices(view, projection).unwrap();
+        let eye = mirror_view.invert().unwrap() * Vector4::new(0.0, 0.0, 0.0, 1.0);
+        assert_close(eye, Vector4::new(1.0, -2.0, 2.0, 1.0));
+    }
+}
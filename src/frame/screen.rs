@@ -0,0 +1,271 @@
+use std::sync::Arc;
+
+use cgmath::Matrix4;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::AutoCommandBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::DynamicState;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::Dimensions;
+use vulkano::image::ImageViewAccess;
+use vulkano::image::ImmutableImage;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::Sampler;
+use vulkano::sync::GpuFuture;
+
+use super::fullscreen::clamp_sampler;
+use super::render_target::SceneView;
+use super::render_target::TargetId;
+
+/// How a screen maps the image of its target onto its quad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenKind {
+    /// Shows the whole image, like a monitor.
+    Monitor,
+    /// Shows the part of the image right behind each pixel, for a target with a
+    /// `TargetCamera::Mirror` in the plane of the screen. Left out of its own target's frames.
+    Mirror,
+}
+
+/// A quad showing the image of a render target.
+#[derive(Debug, Clone)]
+pub struct Screen {
+    pub target: TargetId,
+    pub kind: ScreenKind,
+    /// Places a unit quad spanning -0.5..0.5 in x and y and facing +z, with the top of the
+    /// image at +y.
+    pub model: Matrix4<f32>,
+    /// Scales the image. It's the target's HDR image, so like the rest of the frame showing it
+    /// it's tonemapped by that frame alone.
+    pub brightness: f32,
+}
+
+/// Draws screens in `Pass::Deferred`, showing the images of `RenderTargets`. Screens whose
+/// target wasn't rendered for the frame are black.
+pub struct ScreenRenderer {
+    queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[ScreenVertex]>>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    uniform_buffer_pool: CpuBufferPool<screen_vs::ty::Matrices>,
+    sampler: Arc<Sampler>,
+    /// Shown by screens without an image.
+    blank: Arc<ImmutableImage<Format>>,
+    screens: Vec<Screen>,
+}
+
+impl ScreenRenderer {
+    /// Waits for the upload of the blank image, so only meant for startup.
+    pub fn new<R>(queue: Arc<Queue>, subpass: Subpass<R>) -> ScreenRenderer
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let device = queue.device().clone();
+
+        let vertex_buffer = CpuAccessibleBuffer::from_iter(
+            device.clone(),
+            BufferUsage::vertex_buffer(),
+            [
+                [-0.5, -0.5],
+                [0.5, -0.5],
+                [0.5, 0.5],
+                [-0.5, -0.5],
+                [0.5, 0.5],
+                [-0.5, 0.5],
+            ].iter()
+                .map(|&position| ScreenVertex { position }),
+        ).expect("Failed to create screen vertex buffer");
+
+        let vs = screen_vs::Shader::load(device.clone()).expect("Could not create shader module");
+        let fs = screen_fs::Shader::load(device.clone()).expect("Could not create shader module");
+        let pipeline = Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<ScreenVertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil_simple_depth()
+                .render_pass(subpass)
+                .build(device.clone())
+                .unwrap(),
+        ) as Arc<GraphicsPipelineAbstract + Send + Sync>;
+
+        let sampler = clamp_sampler(&device, Filter::Linear);
+
+        let (blank, upload_future) = ImmutableImage::from_iter(
+            vec![[0u8, 0, 0, 255]].into_iter(),
+            Dimensions::Dim2d { width: 1, height: 1 },
+            Format::R8G8B8A8Srgb,
+            queue.clone(),
+        ).expect("Failed to create blank screen image");
+        upload_future
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        ScreenRenderer {
+            uniform_buffer_pool: CpuBufferPool::uniform_buffer(device),
+            queue,
+            vertex_buffer,
+            pipeline,
+            sampler,
+            blank,
+            screens: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, screen: Screen) {
+        self.screens.push(screen);
+    }
+
+    #[inline]
+    pub fn screens(&self) -> &[Screen] {
+        &self.screens
+    }
+
+    #[inline]
+    pub fn screens_mut(&mut self) -> &mut [Screen] {
+        &mut self.screens
+    }
+
+    /// For `Pass::Deferred` of the frame described by `view`.
+    pub fn draw(&self, view: &SceneView) -> AutoCommandBuffer {
+        let [width, height] = view.dimensions;
+        let dynamic_state = DynamicState {
+            viewports: Some(vec![Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [width as f32, height as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        };
+
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.queue.device().clone(),
+            self.queue.family(),
+            self.pipeline.clone().subpass(),
+        ).unwrap();
+        for screen in &self.screens {
+            // A mirror's own frame is clipped at its plane, the quad would only fight with it.
+            if screen.kind == ScreenKind::Mirror && view.target == Some(screen.target) {
+                continue;
+            }
+            let image = view
+                .screens
+                .hdr(screen.target)
+                .unwrap_or_else(|| self.blank.clone() as Arc<ImageViewAccess + Send + Sync>);
+
+            let model_view = view.view * screen.model;
+            let uniform_buffer = self
+                .uniform_buffer_pool
+                .next(screen_vs::ty::Matrices {
+                    mvp: (view.projection * model_view).into(),
+                    model_view: model_view.into(),
+                })
+                .unwrap();
+            let descriptor_set = PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+                .add_buffer(uniform_buffer)
+                .unwrap()
+                .add_sampled_image(image, self.sampler.clone())
+                .unwrap()
+                .build()
+                .unwrap();
+            let push_constants = screen_fs::ty::PushConstants {
+                framebuffer_size: [width as f32, height as f32],
+                mirror: (screen.kind == ScreenKind::Mirror) as i32,
+                brightness: screen.brightness,
+            };
+
+            builder = builder
+                .draw(
+                    self.pipeline.clone(),
+                    dynamic_state.clone(),
+                    vec![self.vertex_buffer.clone()],
+                    descriptor_set,
+                    push_constants,
+                )
+                .unwrap();
+        }
+        builder.build().unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ScreenVertex {
+    position: [f32; 2],
+}
+impl_vertex!(ScreenVertex, position);
+
+mod screen_vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(std140, set = 0, binding = 0) uniform Matrices {
+    mat4 mvp;
+    mat4 model_view;
+} matrices;
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec2 v_uv;
+layout(location = 1) out vec3 v_view_position;
+layout(location = 2) out vec3 v_view_normal;
+
+void main() {
+    v_uv = vec2(position.x + 0.5, 0.5 - position.y);
+    v_view_position = (matrices.model_view * vec4(position, 0.0, 1.0)).xyz;
+    v_view_normal = (matrices.model_view * vec4(0.0, 0.0, 1.0, 0.0)).xyz;
+    gl_Position = matrices.mvp * vec4(position, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod screen_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 v_uv;
+layout(location = 1) in vec3 v_view_position;
+layout(location = 2) in vec3 v_view_normal;
+
+layout(location = 0) out vec4 f_color;
+layout(location = 1) out vec4 f_normal_depth;
+layout(location = 2) out vec4 f_ambient;
+
+layout(set = 0, binding = 1) uniform sampler2D u_screen;
+
+layout(push_constant) uniform PushConstants {
+    vec2 framebuffer_size;
+    int mirror;
+    float brightness;
+} push_constants;
+
+void main() {
+    // A mirror's image was rendered from the reflected camera with the same projection, so
+    // what it shows at a pixel is right where the pixel is.
+    vec2 uv = push_constants.mirror != 0 ? gl_FragCoord.xy / push_constants.framebuffer_size : v_uv;
+    vec3 color = texture(u_screen, uv).rgb * push_constants.brightness;
+
+    // Screens give off light rather than reflect it, so there's nothing ambient to occlude.
+    f_color = vec4(color, 1.0);
+    f_normal_depth = vec4(normalize(v_view_normal), -v_view_position.z);
+    f_ambient = vec4(0.0);
+}
+"]
+    struct Dummy;
+}
@@ -0,0 +1,202 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
+
+use image;
+use time;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::device::Device;
+use vulkano::format::Format;
+use vulkano::image::ImageAccess;
+
+/// `screenshot-<date>-<time>-<millis>.png` in the working directory.
+pub fn timestamped_screenshot_path() -> PathBuf {
+    let now = time::now();
+    let name = format!(
+        "screenshot-{}-{:03}.png",
+        now.strftime("%Y%m%d-%H%M%S").unwrap(),
+        now.tm_nsec / 1_000_000
+    );
+    PathBuf::from(name)
+}
+
+/// Whether frames rendered into images of `format` can be saved.
+pub fn supports_format(format: Format) -> bool {
+    match format {
+        Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb | Format::R8G8B8A8Unorm | Format::R8G8B8A8Srgb => true,
+        _ => false,
+    }
+}
+
+/// Where a captured frame ends up.
+#[derive(Debug, Clone)]
+pub enum CaptureTarget {
+    Png(PathBuf),
+    /// Kept until taken with `FrameSystem::take_readbacks`.
+    Memory,
+}
+
+/// A frame read back to memory, as sRGB encoded RGBA8.
+#[derive(Debug, Clone)]
+pub struct CapturedFrame {
+    pub dimensions: [u32; 2],
+    pub pixels: Vec<u8>,
+}
+
+/// Copies output images into host visible buffers and writes them out as PNG once the GPU is
+/// done with them, without waiting on it.
+pub struct ScreenshotSystem {
+    device: Arc<Device>,
+    pending: Vec<PendingScreenshot>,
+    /// Buffers whose captures were read, kept for the next ones as long as the size matches.
+    free_buffers: Vec<([u32; 2], Arc<CpuAccessibleBuffer<[[u8; 4]]>>)>,
+    captured: Vec<CapturedFrame>,
+    /// Threads encoding PNG files, joined by `finish`.
+    saving: Vec<JoinHandle<()>>,
+}
+
+struct PendingScreenshot {
+    buffer: Arc<CpuAccessibleBuffer<[[u8; 4]]>>,
+    dimensions: [u32; 2],
+    format: Format,
+    target: CaptureTarget,
+}
+
+impl ScreenshotSystem {
+    pub fn new(device: Arc<Device>) -> ScreenshotSystem {
+        ScreenshotSystem {
+            device,
+            pending: Vec::new(),
+            free_buffers: Vec::new(),
+            captured: Vec::new(),
+            saving: Vec::new(),
+        }
+    }
+
+    /// Records a copy of `image` at the end of `command_buffer`, to end up at `target`.
+    pub fn capture(
+        &mut self,
+        command_buffer: AutoCommandBufferBuilder,
+        image: Arc<ImageAccess + Send + Sync>,
+        dimensions: [u32; 2],
+        format: Format,
+        target: CaptureTarget,
+    ) -> AutoCommandBufferBuilder {
+        assert!(supports_format(format));
+
+        let pixel_count = (dimensions[0] * dimensions[1]) as usize;
+        self.free_buffers.retain(|&(size, _)| size == dimensions);
+        let buffer = match self.free_buffers.pop() {
+            Some((_, buffer)) => buffer,
+            None => CpuAccessibleBuffer::from_iter(
+                self.device.clone(),
+                BufferUsage {
+                    transfer_destination: true,
+                    ..BufferUsage::none()
+                },
+                (0..pixel_count).map(|_| [0u8; 4]),
+            ).expect("Failed to create screenshot buffer"),
+        };
+
+        let command_buffer = command_buffer
+            .copy_image_to_buffer(image, buffer.clone())
+            .unwrap();
+
+        self.pending.push(PendingScreenshot {
+            buffer,
+            dimensions,
+            format,
+            target,
+        });
+
+        command_buffer
+    }
+
+    /// Saves the captures the GPU has finished writing. Their buffers stay locked until the
+    /// frame that wrote them is cleaned up, so this never blocks.
+    pub fn poll(&mut self) {
+        let mut index = 0;
+        while index < self.pending.len() {
+            let pixels = match self.pending[index].buffer.read() {
+                Ok(pixels) => pixels.to_vec(),
+                Err(_) => {
+                    index += 1;
+                    continue;
+                }
+            };
+
+            let PendingScreenshot {
+                buffer,
+                dimensions,
+                format,
+                target,
+            } = self.pending.remove(index);
+            self.free_buffers.push((dimensions, buffer));
+
+            match target {
+                CaptureTarget::Memory => self.captured.push(CapturedFrame {
+                    dimensions,
+                    pixels: to_rgba8(&pixels, format),
+                }),
+                // Encoding takes a while, keep it off the render thread.
+                CaptureTarget::Png(path) => {
+                    self.saving.push(thread::spawn(move || {
+                        let data = to_rgba8(&pixels, format);
+                        match image::save_buffer(&path, &data, dimensions[0], dimensions[1], image::RGBA(8)) {
+                            Ok(()) => println!("Saved screenshot to {}", path.display()),
+                            Err(err) => println!("Failed to save screenshot to {}: {}", path.display(), err),
+                        }
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Saves the captures the GPU has finished writing and waits until their PNG files are
+    /// written. Meant for shutdown, once the GPU is done with every submitted frame.
+    pub fn finish(&mut self) {
+        self.poll();
+        for handle in self.saving.drain(..) {
+            handle.join().unwrap();
+        }
+    }
+
+    /// Number of `CaptureTarget::Memory` captures the GPU hasn't finished yet.
+    pub fn pending_captures(&self) -> usize {
+        self.pending
+            .iter()
+            .filter(|pending| match pending.target {
+                CaptureTarget::Memory => true,
+                CaptureTarget::Png(_) => false,
+            })
+            .count()
+    }
+
+    /// Finished `CaptureTarget::Memory` captures, oldest first.
+    pub fn take_captured(&mut self) -> Vec<CapturedFrame> {
+        self.poll();
+        self.captured.drain(..).collect()
+    }
+}
+
+/// Both the sRGB and UNORM outputs already hold sRGB encoded values, only the channel order
+/// differs. Alpha is meaningless after presentation.
+fn to_rgba8(pixels: &[[u8; 4]], format: Format) -> Vec<u8> {
+    let swap_red_blue = match format {
+        Format::B8G8R8A8Unorm | Format::B8G8R8A8Srgb => true,
+        _ => false,
+    };
+    pixels
+        .iter()
+        .flat_map(|&[a, b, c, _]| {
+            if swap_red_blue {
+                vec![c, b, a, 255]
+            } else {
+                vec![a, b, c, 255]
+            }
+        })
+        .collect()
+}
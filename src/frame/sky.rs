@@ -0,0 +1,424 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use cgmath::{InnerSpace, Vector3};
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::device::Queue;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+use super::background::background_depth_stencil;
+use super::fullscreen::fullscreen_triangle;
+use super::fullscreen::viewport_state;
+use super::fullscreen::Vertex;
+use super::graph::Load;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::skybox::clip_to_direction;
+use super::system::DEPTH_IMAGE;
+use super::system::HDR_IMAGE;
+
+/// Lengths are in meters, and scattering coefficients per meter at sea level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkySettings {
+    /// Draws the atmosphere behind the scene, instead of the skybox cubemap if one is set.
+    pub enabled: bool,
+    /// Hours since midnight, in 0..24.
+    pub time_of_day: f32,
+    /// Seconds a full day takes. The time of day stands still when this is 0.
+    pub day_length: f32,
+    /// Tilts the sun's path away from the zenith, towards -Z, in degrees.
+    pub latitude: f32,
+    /// Radiance of the sun before it enters the atmosphere.
+    pub sun_intensity: f32,
+    pub rayleigh_scattering: [f32; 3],
+    pub rayleigh_scale_height: f32,
+    pub mie_scattering: f32,
+    pub mie_scale_height: f32,
+    /// Henyey-Greenstein asymmetry of the Mie phase function, how much haze glows around the
+    /// sun.
+    pub mie_anisotropy: f32,
+}
+
+impl Default for SkySettings {
+    fn default() -> SkySettings {
+        SkySettings {
+            enabled: false,
+            time_of_day: 10.0,
+            day_length: 120.0,
+            latitude: 30.0,
+            sun_intensity: 20.0,
+            rayleigh_scattering: [5.5e-6, 13.0e-6, 22.4e-6],
+            rayleigh_scale_height: 8000.0,
+            mie_scattering: 21e-6,
+            mie_scale_height: 1200.0,
+            mie_anisotropy: 0.758,
+        }
+    }
+}
+
+impl SkySettings {
+    /// Moves the time of day forward by `dt` real seconds.
+    pub fn advance(&mut self, dt: f32) {
+        if self.day_length > 0.0 {
+            self.time_of_day = (self.time_of_day + dt * 24.0 / self.day_length) % 24.0;
+        }
+    }
+
+    /// Unit vector pointing towards the sun. It rises at +X at 6 and sets at -X at 18.
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        let hour_angle = (self.time_of_day / 24.0 - 0.5) * 2.0 * PI;
+        let latitude = self.latitude.to_radians();
+        let (x, y) = (-hour_angle.sin(), hour_angle.cos());
+        Vector3::new(x, y * latitude.cos(), -y * latitude.sin()).normalize()
+    }
+
+    /// Fraction of sunlight left after passing through the atmosphere, per channel. Fades to
+    /// black as the sun goes below the horizon.
+    pub fn sun_transmittance(&self) -> Vector3<f32> {
+        let elevation = self.sun_direction().y;
+        if elevation < -0.1 {
+            return Vector3::new(0.0, 0.0, 0.0);
+        }
+
+        // Kasten and Young's air mass, the relative path length through the atmosphere.
+        let zenith = elevation.max(-1.0).min(1.0).acos().to_degrees().min(90.0);
+        let air_mass = 1.0 / (zenith.to_radians().cos() + 0.50572 * (96.07995 - zenith).powf(-1.6364));
+        let mie = 1.1 * self.mie_scattering * self.mie_scale_height;
+        let horizon_fade = ((elevation + 0.1) / 0.1).min(1.0);
+
+        let channel = |rayleigh: f32| (-(rayleigh * self.rayleigh_scale_height + mie) * air_mass).exp() * horizon_fade;
+        Vector3::new(
+            channel(self.rayleigh_scattering[0]),
+            channel(self.rayleigh_scattering[1]),
+            channel(self.rayleigh_scattering[2]),
+        )
+    }
+
+    /// The sun as a uniform block for geometry shaders.
+    pub fn sun_uniforms(&self) -> SunUniforms {
+        let direction = self.sun_direction();
+        let color = self.sun_transmittance();
+        SunUniforms {
+            direction: [direction.x, direction.y, direction.z, 0.0],
+            color: [color.x, color.y, color.z, 1.0],
+        }
+    }
+}
+
+/// Matches this block, at any set and binding:
+///
+/// ```glsl
+/// layout(set = 0, binding = 1) uniform Sun {
+///     vec4 direction; // Towards the sun, in world space
+///     vec4 color;     // Linear, 1 for sunlight that wasn't attenuated
+/// } sun;
+/// ```
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SunUniforms {
+    pub direction: [f32; 4],
+    pub color: [f32; 4],
+}
+
+impl SunUniforms {
+    /// Unattenuated sunlight from a fixed direction above the scene, for when the sky is off.
+    pub fn neutral() -> SunUniforms {
+        let direction = Vector3::new(0.3, 1.0, 0.5).normalize();
+        SunUniforms {
+            direction: [direction.x, direction.y, direction.z, 0.0],
+            color: [1.0, 1.0, 1.0, 1.0],
+        }
+    }
+}
+
+/// Draws single scattering through a Rayleigh and Mie atmosphere behind the scene.
+pub struct SkySystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+}
+
+impl SkySystem {
+    pub fn new(gfx_queue: Arc<Queue>) -> SkySystem {
+        let vertex_buffer = fullscreen_triangle(&gfx_queue);
+
+        SkySystem {
+            gfx_queue,
+            vertex_buffer,
+            pipeline: None,
+        }
+    }
+}
+
+impl RenderNode for SkySystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.color(HDR_IMAGE, Load::Load)
+            .depth_stencil(DEPTH_IMAGE, Load::Load);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        let vs = vs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = fs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+
+        self.pipeline = Some(Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil(background_depth_stencil())
+                .render_pass(subpass.unwrap())
+                .build(self.gfx_queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        let settings = context.settings().sky.clone();
+        if !settings.enabled {
+            return;
+        }
+        let pipeline = self.pipeline.clone().unwrap();
+        let viewport_dimensions = context.dimensions(HDR_IMAGE);
+
+        let clip_to_direction = clip_to_direction(context.view_matrix(), context.projection_matrix());
+
+        let sun = settings.sun_direction();
+        let rayleigh = settings.rayleigh_scattering;
+        let push_constants = vs::ty::PushConstants {
+            clip_to_direction: clip_to_direction.into(),
+            sun: [sun.x, sun.y, sun.z, settings.sun_intensity],
+            rayleigh: [rayleigh[0], rayleigh[1], rayleigh[2], settings.rayleigh_scale_height],
+            mie: [
+                settings.mie_scattering,
+                settings.mie_scale_height,
+                settings.mie_anisotropy,
+                0.0,
+            ],
+            depth: context.settings().clear.clear_depth(),
+        };
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipeline.clone(),
+                viewport_state(viewport_dimensions),
+                vec![self.vertex_buffer.clone()],
+                (),
+                push_constants,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec3 v_direction;
+
+layout(push_constant) uniform PushConstants {
+    mat4 clip_to_direction;
+    vec4 sun;
+    vec4 rayleigh;
+    vec4 mie;
+    float depth;
+} push_constants;
+
+void main() {
+    vec4 direction = push_constants.clip_to_direction * vec4(position, 1.0, 1.0);
+    v_direction = direction.xyz / direction.w;
+    gl_Position = vec4(position, push_constants.depth, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec3 v_direction;
+
+layout(location = 0) out vec4 f_color;
+
+// sun: direction, intensity. rayleigh: scattering, scale height. mie: scattering, scale
+// height, anisotropy.
+layout(push_constant) uniform PushConstants {
+    mat4 clip_to_direction;
+    vec4 sun;
+    vec4 rayleigh;
+    vec4 mie;
+    float depth;
+} push_constants;
+
+const float PI = 3.14159265;
+const float PLANET_RADIUS = 6371e3;
+const float ATMOSPHERE_RADIUS = 6471e3;
+const float VIEWER_HEIGHT = 1000.0;
+const int PRIMARY_STEPS = 16;
+const int LIGHT_STEPS = 8;
+// Angular radius of the sun disc.
+const float SUN_COS = 0.99996;
+
+// Distance along the ray to where it leaves a sphere around the origin it starts in.
+float exit_distance(vec3 origin, vec3 direction, float radius) {
+    float b = dot(origin, direction);
+    float c = dot(origin, origin) - radius * radius;
+    return -b + sqrt(max(b * b - c, 0.0));
+}
+
+void main() {
+    vec3 direction = normalize(v_direction);
+    vec3 sun_direction = push_constants.sun.xyz;
+    vec3 rayleigh_scattering = push_constants.rayleigh.xyz;
+    float rayleigh_height = push_constants.rayleigh.w;
+    float mie_scattering = push_constants.mie.x;
+    float mie_height = push_constants.mie.y;
+    float g = push_constants.mie.z;
+
+    vec3 origin = vec3(0.0, PLANET_RADIUS + VIEWER_HEIGHT, 0.0);
+    float step_length = exit_distance(origin, direction, ATMOSPHERE_RADIUS) / float(PRIMARY_STEPS);
+
+    vec3 rayleigh_sum = vec3(0.0);
+    vec3 mie_sum = vec3(0.0);
+    float rayleigh_depth = 0.0;
+    float mie_depth = 0.0;
+
+    for (int i = 0; i < PRIMARY_STEPS; i++) {
+        vec3 position = origin + direction * (float(i) + 0.5) * step_length;
+        float height = length(position) - PLANET_RADIUS;
+        if (height < 0.0) {
+            // Looking into the ground.
+            break;
+        }
+
+        float rayleigh_density = exp(-height / rayleigh_height) * step_length;
+        float mie_density = exp(-height / mie_height) * step_length;
+        rayleigh_depth += rayleigh_density;
+        mie_depth += mie_density;
+
+        float light_step = exit_distance(position, sun_direction, ATMOSPHERE_RADIUS) / float(LIGHT_STEPS);
+        float light_rayleigh_depth = 0.0;
+        float light_mie_depth = 0.0;
+        bool shadowed = false;
+        for (int j = 0; j < LIGHT_STEPS; j++) {
+            vec3 light_position = position + sun_direction * (float(j) + 0.5) * light_step;
+            float light_height = length(light_position) - PLANET_RADIUS;
+            if (light_height < 0.0) {
+                shadowed = true;
+                break;
+            }
+            light_rayleigh_depth += exp(-light_height / rayleigh_height) * light_step;
+            light_mie_depth += exp(-light_height / mie_height) * light_step;
+        }
+        if (shadowed) {
+            continue;
+        }
+
+        vec3 attenuation = exp(-(rayleigh_scattering * (rayleigh_depth + light_rayleigh_depth)
+            + 1.1 * mie_scattering * (mie_depth + light_mie_depth)));
+        rayleigh_sum += rayleigh_density * attenuation;
+        mie_sum += mie_density * attenuation;
+    }
+
+    float mu = dot(direction, sun_direction);
+    float rayleigh_phase = 3.0 / (16.0 * PI) * (1.0 + mu * mu);
+    float mie_phase = 3.0 / (8.0 * PI) * ((1.0 - g * g) * (1.0 + mu * mu))
+        / ((2.0 + g * g) * pow(1.0 + g * g - 2.0 * g * mu, 1.5));
+
+    vec3 color = push_constants.sun.w
+        * (rayleigh_phase * rayleigh_scattering * rayleigh_sum + mie_phase * mie_scattering * mie_sum);
+
+    if (mu > SUN_COS) {
+        vec3 transmittance = exp(-(rayleigh_scattering * rayleigh_depth + 1.1 * mie_scattering * mie_depth));
+        color += push_constants.sun.w * transmittance;
+    }
+
+    f_color = vec4(color, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::InnerSpace;
+
+    use super::SkySettings;
+
+    fn at(time_of_day: f32, latitude: f32) -> SkySettings {
+        SkySettings {
+            time_of_day,
+            latitude,
+            ..SkySettings::default()
+        }
+    }
+
+    #[test]
+    fn advance_wraps_around_midnight() {
+        let mut sky = SkySettings {
+            time_of_day: 23.0,
+            day_length: 24.0,
+            ..SkySettings::default()
+        };
+        sky.advance(2.0);
+        assert!((sky.time_of_day - 1.0).abs() < 1e-4, "{}", sky.time_of_day);
+    }
+
+    #[test]
+    fn advance_stands_still_without_day_length() {
+        let mut sky = SkySettings {
+            time_of_day: 15.0,
+            day_length: 0.0,
+            ..SkySettings::default()
+        };
+        sky.advance(100.0);
+        assert_eq!(sky.time_of_day, 15.0);
+    }
+
+    #[test]
+    fn sun_rises_in_the_east_and_sets_in_the_west() {
+        let sunrise = at(6.0, 0.0).sun_direction();
+        let noon = at(12.0, 0.0).sun_direction();
+        let sunset = at(18.0, 0.0).sun_direction();
+        assert!((sunrise.x - 1.0).abs() < 1e-4 && sunrise.y.abs() < 1e-4, "{:?}", sunrise);
+        assert!((noon.y - 1.0).abs() < 1e-4, "{:?}", noon);
+        assert!((sunset.x + 1.0).abs() < 1e-4 && sunset.y.abs() < 1e-4, "{:?}", sunset);
+        assert!(at(0.0, 0.0).sun_direction().y < -0.99);
+    }
+
+    #[test]
+    fn latitude_tilts_noon_towards_negative_z() {
+        let noon = at(12.0, 30.0).sun_direction();
+        assert!((noon.y - 30f32.to_radians().cos()).abs() < 1e-4, "{:?}", noon);
+        assert!((noon.z + 30f32.to_radians().sin()).abs() < 1e-4, "{:?}", noon);
+    }
+
+    #[test]
+    fn sun_direction_is_normalized() {
+        for hour in 0..24 {
+            let direction = at(hour as f32, 45.0).sun_direction();
+            assert!((direction.magnitude() - 1.0).abs() < 1e-4, "{:?} at {}", direction, hour);
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use cgmath::{Matrix3, Matrix4, SquareMatrix};
+use image;
+use image::hdr::HDRDecoder;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::Dimensions;
+use vulkano::image::ImageCreationError;
+use vulkano::image::ImmutableImage;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::Sampler;
+use vulkano::sync::GpuFuture;
+
+use super::background::background_depth_stencil;
+use super::fullscreen::clamp_sampler;
+use super::fullscreen::fullscreen_triangle;
+use super::fullscreen::viewport_state;
+use super::fullscreen::Vertex;
+use super::graph::Load;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::system::DEPTH_IMAGE;
+use super::system::HDR_IMAGE;
+
+/// A cube texture drawn behind the scene.
+#[derive(Clone)]
+pub struct Cubemap {
+    image: Arc<ImmutableImage<Format>>,
+}
+
+impl fmt::Debug for Cubemap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cubemap({:?})", self.image.dimensions())
+    }
+}
+
+/// Cubemaps are the same if they share their image.
+impl PartialEq for Cubemap {
+    fn eq(&self, other: &Cubemap) -> bool {
+        Arc::ptr_eq(&self.image, &other.image)
+    }
+}
+
+impl Cubemap {
+    /// Loads six square LDR images, in the order +X, -X, +Y, -Y, +Z, -Z. The returned future
+    /// has to be joined into the frame's future before the cubemap is first drawn.
+    pub fn from_faces<P>(queue: Arc<Queue>, paths: &[P; 6]) -> image::ImageResult<(Cubemap, Box<GpuFuture>)>
+    where
+        P: AsRef<Path>,
+    {
+        let mut size = None;
+        let mut pixels = Vec::new();
+        for path in paths {
+            let face = image::open(path)?.to_rgba();
+            let (width, height) = face.dimensions();
+            if width != height || size.map_or(false, |size| size != width) {
+                return Err(image::ImageError::DimensionError);
+            }
+            size = Some(width);
+            pixels.extend(face.pixels().map(|pixel| pixel.data));
+        }
+
+        let (image, future) = ImmutableImage::from_iter(
+            pixels.into_iter(),
+            Dimensions::Cubemap { size: size.unwrap() },
+            Format::R8G8B8A8Srgb,
+            queue,
+        ).map_err(upload_error)?;
+        Ok((Cubemap { image }, Box::new(future)))
+    }
+
+    /// Loads a Radiance HDR file in the equirectangular (latitude-longitude) layout, and
+    /// projects it onto cube faces a quarter of its width in size.
+    pub fn from_equirectangular<P>(queue: Arc<Queue>, path: P) -> image::ImageResult<(Cubemap, Box<GpuFuture>)>
+    where
+        P: AsRef<Path>,
+    {
+        let decoder = HDRDecoder::new(BufReader::new(File::open(path)?))?;
+        let metadata = decoder.metadata();
+        let (width, height) = (metadata.width as usize, metadata.height as usize);
+        let texels: Vec<[f32; 3]> = decoder
+            .read_image_hdr()?
+            .into_iter()
+            .map(|pixel| pixel.data)
+            .collect();
+
+        let size = (width / 4).max(1);
+        let mut pixels = Vec::with_capacity(size * size * 6);
+        for face in 0..6 {
+            for y in 0..size {
+                for x in 0..size {
+                    let s = 2.0 * (x as f32 + 0.5) / size as f32 - 1.0;
+                    let t = 2.0 * (y as f32 + 0.5) / size as f32 - 1.0;
+                    let [dx, dy, dz] = face_direction(face, s, t);
+                    let length = (dx * dx + dy * dy + dz * dz).sqrt();
+
+                    let u = 0.5 + dx.atan2(-dz) / (2.0 * PI);
+                    let v = (dy / length).max(-1.0).min(1.0).acos() / PI;
+                    let color = sample_bilinear(&texels, width, height, u, v);
+                    pixels.push([to_f16(color[0]), to_f16(color[1]), to_f16(color[2]), to_f16(1.0)]);
+                }
+            }
+        }
+
+        let (image, future) = ImmutableImage::from_iter(
+            pixels.into_iter(),
+            Dimensions::Cubemap { size: size as u32 },
+            Format::R16G16B16A16Sfloat,
+            queue,
+        ).map_err(upload_error)?;
+        Ok((Cubemap { image }, Box::new(future)))
+    }
+}
+
+/// Cubemaps the device can't create, e.g. ones too large for it, fail like files that can't
+/// be read.
+fn upload_error(err: ImageCreationError) -> image::ImageError {
+    image::ImageError::IoError(io::Error::new(
+        io::ErrorKind::Other,
+        format!("Failed to create cubemap: {}", err),
+    ))
+}
+
+/// Direction through texel `(s, t)` of a cube face, both in -1..1 with `t` pointing down, as
+/// laid out by Vulkan.
+fn face_direction(face: usize, s: f32, t: f32) -> [f32; 3] {
+    match face {
+        0 => [1.0, -t, -s],
+        1 => [-1.0, -t, s],
+        2 => [s, 1.0, t],
+        3 => [s, -1.0, -t],
+        4 => [s, -t, 1.0],
+        _ => [-s, -t, -1.0],
+    }
+}
+
+/// Wraps horizontally and clamps vertically, like the longitude and latitude it samples.
+fn sample_bilinear(texels: &[[f32; 3]], width: usize, height: usize, u: f32, v: f32) -> [f32; 3] {
+    let x = u * width as f32 - 0.5;
+    let y = (v * height as f32 - 0.5).max(0.0).min(height as f32 - 1.0);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+
+    let column = |x: f32| {
+        let width = width as isize;
+        (((x as isize) % width + width) % width) as usize
+    };
+    let row = |y: f32| (y as usize).min(height - 1);
+    let texel = |x: f32, y: f32| texels[row(y) * width + column(x)];
+
+    let mut color = [0.0; 3];
+    for (c, out) in color.iter_mut().enumerate() {
+        let top = texel(x0, y0)[c] * (1.0 - fx) + texel(x0 + 1.0, y0)[c] * fx;
+        let bottom = texel(x0, y0 + 1.0)[c] * (1.0 - fx) + texel(x0 + 1.0, y0 + 1.0)[c] * fx;
+        *out = top * (1.0 - fy) + bottom * fy;
+    }
+    color
+}
+
+/// Rounds towards zero, and flushes values too small for a half to zero.
+fn to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x007f_ffff;
+
+    if value.is_nan() {
+        sign | 0x7e00
+    } else if exponent >= 0x1f {
+        sign | 0x7c00
+    } else if exponent <= 0 {
+        sign
+    } else {
+        sign | ((exponent as u16) << 10) | (mantissa >> 13) as u16
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SkyboxSettings {
+    /// Nothing is drawn behind the scene when there's no cubemap.
+    pub cubemap: Option<Cubemap>,
+    /// Multiplier for the cubemap colors, in scene luminance.
+    pub intensity: f32,
+}
+
+impl Default for SkyboxSettings {
+    fn default() -> SkyboxSettings {
+        SkyboxSettings {
+            cubemap: None,
+            intensity: 1.0,
+        }
+    }
+}
+
+/// Maps clip space positions to world space view directions. The sky is infinitely far away,
+/// so only the camera's rotation matters.
+pub fn clip_to_direction(view: Matrix4<f32>, projection: Matrix4<f32>) -> Matrix4<f32> {
+    let rotation = Matrix4::from(Matrix3::from_cols(
+        view.x.truncate(),
+        view.y.truncate(),
+        view.z.truncate(),
+    ));
+    (projection * rotation)
+        .invert()
+        .expect("Camera projection isn't invertible")
+}
+
+/// Draws the cubemap of `SkyboxSettings` behind the scene.
+pub struct SkyboxSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    sampler: Arc<Sampler>,
+}
+
+impl SkyboxSystem {
+    pub fn new(gfx_queue: Arc<Queue>) -> SkyboxSystem {
+        let vertex_buffer = fullscreen_triangle(&gfx_queue);
+
+        let sampler = clamp_sampler(gfx_queue.device(), Filter::Linear);
+
+        SkyboxSystem {
+            gfx_queue,
+            vertex_buffer,
+            pipeline: None,
+            sampler,
+        }
+    }
+}
+
+impl RenderNode for SkyboxSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.color(HDR_IMAGE, Load::Load)
+            .depth_stencil(DEPTH_IMAGE, Load::Load);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        let vs = vs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = fs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+
+        self.pipeline = Some(Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .depth_stencil(background_depth_stencil())
+                .render_pass(subpass.unwrap())
+                .build(self.gfx_queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        if context.settings().sky.enabled {
+            return;
+        }
+        let settings = context.settings().skybox.clone();
+        let cubemap = match settings.cubemap {
+            Some(cubemap) => cubemap,
+            None => return,
+        };
+        let pipeline = self.pipeline.clone().unwrap();
+        let viewport_dimensions = context.dimensions(HDR_IMAGE);
+
+        let clip_to_direction = clip_to_direction(context.view_matrix(), context.projection_matrix());
+
+        let descriptor_set = PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_sampled_image(cubemap.image, self.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let push_constants = vs::ty::PushConstants {
+            clip_to_direction: clip_to_direction.into(),
+            intensity: settings.intensity,
+            depth: context.settings().clear.clear_depth(),
+        };
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipeline.clone(),
+                viewport_state(viewport_dimensions),
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                push_constants,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 position;
+
+layout(location = 0) out vec3 v_direction;
+
+layout(push_constant) uniform PushConstants {
+    mat4 clip_to_direction;
+    float intensity;
+    float depth;
+} push_constants;
+
+void main() {
+    vec4 direction = push_constants.clip_to_direction * vec4(position, 1.0, 1.0);
+    v_direction = direction.xyz / direction.w;
+    // At the depth clear value, so only where no geometry was drawn.
+    gl_Position = vec4(position, push_constants.depth, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec3 v_direction;
+
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform samplerCube u_sky;
+
+layout(push_constant) uniform PushConstants {
+    mat4 clip_to_direction;
+    float intensity;
+    float depth;
+} push_constants;
+
+void main() {
+    f_color = vec4(texture(u_sky, normalize(v_direction)).rgb * push_constants.intensity, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32;
+
+    use super::face_direction;
+    use super::to_f16;
+
+    #[test]
+    fn to_f16_keeps_representable_values() {
+        assert_eq!(to_f16(0.0), 0x0000);
+        assert_eq!(to_f16(-0.0), 0x8000);
+        assert_eq!(to_f16(1.0), 0x3c00);
+        assert_eq!(to_f16(-2.0), 0xc000);
+        assert_eq!(to_f16(0.5), 0x3800);
+        // The largest half.
+        assert_eq!(to_f16(65504.0), 0x7bff);
+        // The smallest normal half.
+        assert_eq!(to_f16(1.0 / 16384.0), 0x0400);
+    }
+
+    #[test]
+    fn to_f16_rounds_towards_zero() {
+        assert_eq!(to_f16(1.0 + 1.0 / 4096.0), 0x3c00);
+        assert_eq!(to_f16(65519.0), 0x7bff);
+    }
+
+    #[test]
+    fn to_f16_overflows_to_infinity() {
+        assert_eq!(to_f16(65536.0), 0x7c00);
+        assert_eq!(to_f16(1.0e10), 0x7c00);
+        assert_eq!(to_f16(f32::INFINITY), 0x7c00);
+        assert_eq!(to_f16(-1.0e10), 0xfc00);
+        assert_eq!(to_f16(f32::NAN) & 0x7c00, 0x7c00);
+        assert_ne!(to_f16(f32::NAN) & 0x03ff, 0);
+    }
+
+    #[test]
+    fn to_f16_flushes_subnormals() {
+        assert_eq!(to_f16(3.0e-5), 0x0000);
+        assert_eq!(to_f16(1.0e-7), 0x0000);
+        assert_eq!(to_f16(-3.0e-5), 0x8000);
+        assert_eq!(to_f16(f32::MIN_POSITIVE), 0x0000);
+    }
+
+    #[test]
+    fn face_centers_point_along_the_axes() {
+        let expected = [
+            [1.0, 0.0, 0.0],
+            [-1.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [0.0, -1.0, 0.0],
+            [0.0, 0.0, 1.0],
+            [0.0, 0.0, -1.0],
+        ];
+        for (face, &direction) in expected.iter().enumerate() {
+            let center = face_direction(face, 0.0, 0.0);
+            // Faces negate zero for some axes, which compares equal.
+            assert_eq!(center, direction, "face {}", face);
+        }
+    }
+
+    #[test]
+    fn face_corners_follow_the_vulkan_layout() {
+        // Top left texels, s = t = -1, as in the cube map face selection table of the spec.
+        assert_eq!(face_direction(0, -1.0, -1.0), [1.0, 1.0, 1.0]);
+        assert_eq!(face_direction(1, -1.0, -1.0), [-1.0, 1.0, -1.0]);
+        assert_eq!(face_direction(2, -1.0, -1.0), [-1.0, 1.0, -1.0]);
+        assert_eq!(face_direction(3, -1.0, -1.0), [-1.0, -1.0, 1.0]);
+        assert_eq!(face_direction(4, -1.0, -1.0), [-1.0, 1.0, 1.0]);
+        assert_eq!(face_direction(5, -1.0, -1.0), [1.0, 1.0, -1.0]);
+    }
+}
@@ -0,0 +1,641 @@
+use std::sync::Arc;
+
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::Dimensions;
+use vulkano::image::ImageUsage;
+use vulkano::image::ImmutableImage;
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::blend::BlendFactor;
+use vulkano::pipeline::blend::BlendOp;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::MipmapMode;
+use vulkano::sampler::Sampler;
+use vulkano::sampler::SamplerAddressMode;
+use vulkano::sync::GpuFuture;
+
+use super::fullscreen::clamp_sampler;
+use super::fullscreen::fullscreen_triangle;
+use super::fullscreen::viewport_state;
+use super::fullscreen::Vertex;
+use super::graph::ImageDesc;
+use super::graph::ImageSize;
+use super::graph::Load;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::random::Random;
+use super::render_mode::RenderMode;
+use super::system::RenderSettings;
+use super::system::AMBIENT_IMAGE;
+use super::system::AO_BLUR_IMAGE;
+use super::system::AO_FORMAT;
+use super::system::AO_IMAGE;
+use super::system::HDR_IMAGE;
+use super::system::NORMAL_DEPTH_IMAGE;
+
+/// Sample counts beyond this are clamped, it's the size of the kernel.
+pub const MAX_SSAO_SAMPLES: u32 = 64;
+/// Side of the square of random rotations tiled over the screen, which the blur evens out.
+const NOISE_SIZE: u32 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    /// Radius of the sampled hemisphere, in view space units.
+    pub radius: f32,
+    /// Depth difference below which a sample doesn't occlude, against self-occlusion.
+    pub bias: f32,
+    /// Samples per pixel, up to `MAX_SSAO_SAMPLES`.
+    pub samples: u32,
+    /// 0 leaves the ambient term alone, 1 applies the full occlusion.
+    pub strength: f32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> SsaoSettings {
+        SsaoSettings {
+            enabled: true,
+            radius: 0.5,
+            bias: 0.025,
+            samples: 16,
+            strength: 1.0,
+        }
+    }
+}
+
+/// The ambient occlusion view shows it even when it's disabled, other debug views don't get
+/// any since their colors aren't lighting.
+fn is_active(settings: &RenderSettings) -> bool {
+    match settings.render_mode {
+        RenderMode::AmbientOcclusion => true,
+        mode => settings.ssao.enabled && !mode.is_debug_view(),
+    }
+}
+
+fn ao_image_desc() -> ImageDesc {
+    ImageDesc {
+        format: AO_FORMAT,
+        size: ImageSize::Output,
+        usage: ImageUsage {
+            color_attachment: true,
+            sampled: true,
+            ..ImageUsage::none()
+        },
+    }
+}
+
+/// Tiles the noise texture over the image.
+fn noise_sampler(queue: &Arc<Queue>) -> Arc<Sampler> {
+    Sampler::new(
+        queue.device().clone(),
+        Filter::Nearest,
+        Filter::Nearest,
+        MipmapMode::Nearest,
+        SamplerAddressMode::Repeat,
+        SamplerAddressMode::Repeat,
+        SamplerAddressMode::Repeat,
+        0.0,
+        1.0,
+        0.0,
+        0.0,
+    ).unwrap()
+}
+
+/// Samples in the hemisphere around +Z, more of them close to the center.
+fn hemisphere_kernel(random: &mut Random) -> [[f32; 4]; MAX_SSAO_SAMPLES as usize] {
+    let mut kernel = [[0.0; 4]; MAX_SSAO_SAMPLES as usize];
+    for sample in kernel.iter_mut() {
+        let (x, y, z) = loop {
+            let (x, y, z) = (random.next() * 2.0 - 1.0, random.next() * 2.0 - 1.0, random.next());
+            let length = (x * x + y * y + z * z).sqrt();
+            if length > 1e-3 && length <= 1.0 {
+                break (x / length, y / length, z / length);
+            }
+        };
+        let distance = random.next();
+        let scale = 0.1 + 0.9 * distance * distance;
+        *sample = [x * scale, y * scale, z * scale, 0.0];
+    }
+    kernel
+}
+
+/// Computes ambient occlusion from the view space normals and linear depth of the geometry
+/// pass (Crytek's SSAO with normal-oriented hemispheres), into the AO image.
+pub struct SsaoSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    kernel_buffer: Arc<CpuAccessibleBuffer<ssao_fs::ty::Kernel>>,
+    noise: Arc<ImmutableImage<Format>>,
+    sampler: Arc<Sampler>,
+    noise_sampler: Arc<Sampler>,
+}
+
+impl SsaoSystem {
+    /// The returned future uploads the noise texture, it has to be joined into the future of
+    /// the first frame.
+    pub fn new(gfx_queue: Arc<Queue>) -> (SsaoSystem, Box<GpuFuture>) {
+        // The kernel and the noise are the same every run.
+        let mut random = Random::new(0x9e37_79b9);
+        let kernel_buffer = CpuAccessibleBuffer::from_data(
+            gfx_queue.device().clone(),
+            BufferUsage::uniform_buffer(),
+            ssao_fs::ty::Kernel {
+                samples: hemisphere_kernel(&mut random),
+            },
+        ).expect("Failed to create SSAO kernel buffer");
+
+        // Rotations around the normal, as xy in 0..1.
+        let noise_pixels: Vec<[u8; 4]> = (0..NOISE_SIZE * NOISE_SIZE)
+            .map(|_| [(random.next() * 255.0) as u8, (random.next() * 255.0) as u8, 0, 0])
+            .collect();
+        let (noise, upload_future) = ImmutableImage::from_iter(
+            noise_pixels.into_iter(),
+            Dimensions::Dim2d {
+                width: NOISE_SIZE,
+                height: NOISE_SIZE,
+            },
+            Format::R8G8B8A8Unorm,
+            gfx_queue.clone(),
+        ).expect("Failed to create SSAO noise texture");
+        let system = SsaoSystem {
+            vertex_buffer: fullscreen_triangle(&gfx_queue),
+            pipeline: None,
+            kernel_buffer,
+            noise,
+            sampler: clamp_sampler(gfx_queue.device(), Filter::Nearest),
+            noise_sampler: noise_sampler(&gfx_queue),
+            gfx_queue,
+        };
+        (system, Box::new(upload_future))
+    }
+
+    pub fn image_desc() -> ImageDesc {
+        ao_image_desc()
+    }
+}
+
+impl RenderNode for SsaoSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.read(NORMAL_DEPTH_IMAGE).color(AO_IMAGE, Load::DontCare);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        let vs = vs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = ssao_fs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+
+        self.pipeline = Some(Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(subpass.unwrap())
+                .build(self.gfx_queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        if !is_active(context.settings()) {
+            return;
+        }
+        let settings = context.settings().ssao.clone();
+        let pipeline = self.pipeline.clone().unwrap();
+        let viewport_dimensions = context.dimensions(AO_IMAGE);
+
+        let descriptor_set = PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_sampled_image(context.image(NORMAL_DEPTH_IMAGE), self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(self.noise.clone(), self.noise_sampler.clone())
+            .unwrap()
+            .add_buffer(self.kernel_buffer.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let push_constants = ssao_fs::ty::PushConstants {
+            projection: context.projection_matrix().into(),
+            radius: settings.radius.max(1e-3),
+            bias: settings.bias,
+            samples: settings.samples.max(1).min(MAX_SSAO_SAMPLES) as i32,
+        };
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipeline.clone(),
+                viewport_state(viewport_dimensions),
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                push_constants,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+/// Bilateral blur of the AO image over the size of the noise tile, weighted by depth and
+/// normal similarity so occlusion doesn't bleed across edges.
+pub struct SsaoBlurSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    sampler: Arc<Sampler>,
+}
+
+impl SsaoBlurSystem {
+    pub fn new(gfx_queue: Arc<Queue>) -> SsaoBlurSystem {
+        SsaoBlurSystem {
+            vertex_buffer: fullscreen_triangle(&gfx_queue),
+            pipeline: None,
+            sampler: clamp_sampler(gfx_queue.device(), Filter::Nearest),
+            gfx_queue,
+        }
+    }
+
+    pub fn image_desc() -> ImageDesc {
+        ao_image_desc()
+    }
+}
+
+impl RenderNode for SsaoBlurSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.read(AO_IMAGE)
+            .read(NORMAL_DEPTH_IMAGE)
+            .color(AO_BLUR_IMAGE, Load::DontCare);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        let vs = vs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = blur_fs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+
+        self.pipeline = Some(Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(subpass.unwrap())
+                .build(self.gfx_queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        if !is_active(context.settings()) {
+            return;
+        }
+        let pipeline = self.pipeline.clone().unwrap();
+        let viewport_dimensions = context.dimensions(AO_BLUR_IMAGE);
+
+        let descriptor_set = PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_sampled_image(context.image(AO_IMAGE), self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(context.image(NORMAL_DEPTH_IMAGE), self.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipeline.clone(),
+                viewport_state(viewport_dimensions),
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                (),
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+/// Takes the occluded part of the ambient term written by the geometry pass off the HDR
+/// image, or replaces the image with the occlusion in `RenderMode::AmbientOcclusion`.
+pub struct SsaoCompositeSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    subtract_pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    view_pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    sampler: Arc<Sampler>,
+}
+
+impl SsaoCompositeSystem {
+    pub fn new(gfx_queue: Arc<Queue>) -> SsaoCompositeSystem {
+        SsaoCompositeSystem {
+            vertex_buffer: fullscreen_triangle(&gfx_queue),
+            subtract_pipeline: None,
+            view_pipeline: None,
+            sampler: clamp_sampler(gfx_queue.device(), Filter::Nearest),
+            gfx_queue,
+        }
+    }
+}
+
+impl RenderNode for SsaoCompositeSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.read(AO_BLUR_IMAGE)
+            .read(AMBIENT_IMAGE)
+            .color(HDR_IMAGE, Load::Load);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        let subpass = subpass.unwrap();
+        let device = self.gfx_queue.device().clone();
+        let vs = vs::Shader::load(device.clone()).expect("Could not create shader module");
+        let fs = composite_fs::Shader::load(device.clone()).expect("Could not create shader module");
+
+        // Destination minus source, the alpha is left alone.
+        let subtract = AttachmentBlend {
+            enabled: true,
+            color_op: BlendOp::ReverseSubtract,
+            color_source: BlendFactor::One,
+            color_destination: BlendFactor::One,
+            alpha_op: BlendOp::Add,
+            alpha_source: BlendFactor::Zero,
+            alpha_destination: BlendFactor::One,
+            ..AttachmentBlend::pass_through()
+        };
+        let pipeline = |blend: AttachmentBlend| -> Arc<GraphicsPipelineAbstract + Send + Sync> {
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input_single_buffer::<Vertex>()
+                    .vertex_shader(vs.main_entry_point(), ())
+                    .triangle_list()
+                    .viewports_dynamic_scissors_irrelevant(1)
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .blend_collective(blend)
+                    .render_pass(subpass.clone())
+                    .build(device.clone())
+                    .unwrap(),
+            )
+        };
+        self.subtract_pipeline = Some(pipeline(subtract));
+        self.view_pipeline = Some(pipeline(AttachmentBlend::pass_through()));
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        if !is_active(context.settings()) {
+            return;
+        }
+        let debug_view = context.settings().render_mode == RenderMode::AmbientOcclusion;
+        let pipeline = if debug_view {
+            self.view_pipeline.clone().unwrap()
+        } else {
+            self.subtract_pipeline.clone().unwrap()
+        };
+        let viewport_dimensions = context.dimensions(HDR_IMAGE);
+
+        let descriptor_set = PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_sampled_image(context.image(AO_BLUR_IMAGE), self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(context.image(AMBIENT_IMAGE), self.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let push_constants = composite_fs::ty::PushConstants {
+            strength: context.settings().ssao.strength.max(0.0),
+            debug_view: debug_view as u32,
+        };
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipeline.clone(),
+                viewport_state(viewport_dimensions),
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                push_constants,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 position;
+
+void main() {
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod ssao_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+// Matches MAX_SSAO_SAMPLES and NOISE_SIZE.
+const int MAX_SAMPLES = 64;
+const int NOISE_SIZE = 4;
+
+layout(set = 0, binding = 0) uniform sampler2D u_normal_depth;
+layout(set = 0, binding = 1) uniform sampler2D u_noise;
+layout(std140, set = 0, binding = 2) uniform Kernel {
+    vec4 samples[MAX_SAMPLES];
+} kernel;
+
+layout(location = 0) out float f_ao;
+
+layout(push_constant) uniform PushConstants {
+    mat4 projection;
+    float radius;
+    float bias;
+    int samples;
+} push_constants;
+
+// The view space position at `ndc` with linear depth `depth`, for a perspective projection.
+vec3 view_position(vec2 ndc, float depth) {
+    mat4 p = push_constants.projection;
+    return vec3(
+        depth * (ndc.x + p[2][0]) / p[0][0],
+        depth * (ndc.y + p[2][1]) / p[1][1],
+        -depth);
+}
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    vec2 size = vec2(textureSize(u_normal_depth, 0));
+    vec4 normal_depth = texelFetch(u_normal_depth, texel, 0);
+    // Nothing was drawn here.
+    if (normal_depth.w <= 0.0) {
+        f_ao = 1.0;
+        return;
+    }
+
+    vec3 position = view_position(gl_FragCoord.xy / size * 2.0 - 1.0, normal_depth.w);
+    vec3 normal = normalize(normal_depth.xyz);
+
+    // A random rotation around the normal, repeating every few pixels.
+    vec3 random = vec3(texelFetch(u_noise, texel % NOISE_SIZE, 0).xy * 2.0 - 1.0, 0.0);
+    vec3 tangent = random - normal * dot(random, normal);
+    if (dot(tangent, tangent) < 1e-6) {
+        tangent = abs(normal.x) < 0.9 ? vec3(1.0, 0.0, 0.0) : vec3(0.0, 1.0, 0.0);
+        tangent -= normal * dot(tangent, normal);
+    }
+    tangent = normalize(tangent);
+    mat3 tbn = mat3(tangent, cross(normal, tangent), normal);
+
+    float occlusion = 0.0;
+    int samples = min(push_constants.samples, MAX_SAMPLES);
+    for (int i = 0; i < samples; i++) {
+        vec3 sample_position = position + tbn * kernel.samples[i].xyz * push_constants.radius;
+
+        vec4 clip = push_constants.projection * vec4(sample_position, 1.0);
+        vec2 uv = clip.xy / clip.w * 0.5 + 0.5;
+        float scene_depth = texture(u_normal_depth, uv).w;
+        if (scene_depth <= 0.0) {
+            continue;
+        }
+
+        // Surfaces far in front of the hemisphere don't count.
+        float range = smoothstep(0.0, 1.0, push_constants.radius / abs(normal_depth.w - scene_depth));
+        occlusion += (scene_depth <= -sample_position.z - push_constants.bias ? 1.0 : 0.0) * range;
+    }
+
+    f_ao = 1.0 - occlusion / float(samples);
+}
+"]
+    struct Dummy;
+}
+
+mod blur_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+const int RADIUS = 2;
+
+layout(set = 0, binding = 0) uniform sampler2D u_ao;
+layout(set = 0, binding = 1) uniform sampler2D u_normal_depth;
+
+layout(location = 0) out float f_ao;
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    ivec2 size = textureSize(u_ao, 0);
+    vec4 center = texelFetch(u_normal_depth, texel, 0);
+    if (center.w <= 0.0) {
+        f_ao = 1.0;
+        return;
+    }
+
+    float sum = 0.0;
+    float total_weight = 0.0;
+    for (int y = -RADIUS; y <= RADIUS; y++) {
+        for (int x = -RADIUS; x <= RADIUS; x++) {
+            ivec2 neighbor = clamp(texel + ivec2(x, y), ivec2(0), size - 1);
+            vec4 normal_depth = texelFetch(u_normal_depth, neighbor, 0);
+            if (normal_depth.w <= 0.0) {
+                continue;
+            }
+            float normal_weight = pow(max(dot(normal_depth.xyz, center.xyz), 0.0), 8.0);
+            float depth_weight = exp(-abs(normal_depth.w - center.w) / (0.05 * center.w));
+            float weight = normal_weight * depth_weight;
+            sum += texelFetch(u_ao, neighbor, 0).r * weight;
+            total_weight += weight;
+        }
+    }
+
+    f_ao = total_weight > 0.0 ? sum / total_weight : texelFetch(u_ao, texel, 0).r;
+}
+"]
+    struct Dummy;
+}
+
+mod composite_fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D u_ao;
+layout(set = 0, binding = 1) uniform sampler2D u_ambient;
+
+layout(location = 0) out vec4 f_color;
+
+layout(push_constant) uniform PushConstants {
+    float strength;
+    uint debug_view;
+} push_constants;
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    float occlusion = clamp((1.0 - texelFetch(u_ao, texel, 0).r) * push_constants.strength, 0.0, 1.0);
+    if (push_constants.debug_view != 0) {
+        f_color = vec4(vec3(1.0 - occlusion), 1.0);
+    } else {
+        // Subtracted from the lit color.
+        f_color = vec4(texelFetch(u_ambient, texel, 0).rgb * occlusion, 0.0);
+    }
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hemisphere_kernel;
+    use super::Random;
+
+    fn length(sample: &[f32; 4]) -> f32 {
+        (sample[0] * sample[0] + sample[1] * sample[1] + sample[2] * sample[2]).sqrt()
+    }
+
+    #[test]
+    fn kernel_is_in_the_unit_hemisphere() {
+        for sample in hemisphere_kernel(&mut Random::new(0x9e37_79b9)).iter() {
+            assert!(sample[2] >= 0.0, "{:?}", sample);
+            assert!(length(sample) >= 0.1 - 1e-5 && length(sample) <= 1.0 + 1e-5, "{:?}", sample);
+            assert_eq!(sample[3], 0.0);
+        }
+    }
+
+    #[test]
+    fn kernel_is_the_same_every_run() {
+        let a = hemisphere_kernel(&mut Random::new(0x9e37_79b9));
+        let b = hemisphere_kernel(&mut Random::new(0x9e37_79b9));
+        assert_eq!(&a[..], &b[..]);
+        assert!(a.iter().any(|sample| sample != &a[0]));
+    }
+}
@@ -1,140 +1,736 @@
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use cgmath::Matrix4;
+use cgmath::SquareMatrix;
+use imgui::{ImGui, Ui};
+use time;
+use vulkano::buffer::BufferAccess;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuBufferPool;
 use vulkano::command_buffer::AutoCommandBufferBuilder;
 use vulkano::command_buffer::CommandBuffer;
+use vulkano::descriptor::descriptor_set::DescriptorSetsCollection;
+use vulkano::device::Device;
 use vulkano::device::Queue;
 use vulkano::format::Format;
-use vulkano::framebuffer::Framebuffer;
-use vulkano::framebuffer::FramebufferAbstract;
 use vulkano::framebuffer::RenderPassAbstract;
 use vulkano::framebuffer::Subpass;
 use vulkano::image::AttachmentImage;
 use vulkano::image::ImageAccess;
+use vulkano::image::ImageUsage;
 use vulkano::image::ImageViewAccess;
+use vulkano::pipeline::ComputePipelineAbstract;
 use vulkano::sync::GpuFuture;
 use vulkano_text::{DrawText, DrawTextTrait};
 
+use super::background::BackgroundSystem;
+use super::background::ClearSettings;
+use super::bloom::BloomSettings;
+use super::bloom::BloomSystem;
+use super::debug::DebugDraw;
+use super::debug::DebugDrawSystem;
+use super::exposure::EyeAdaptationSystem;
+use super::gpu_driven::CullingSettings;
+use super::graph::FrameInputs;
+use super::graph::ImageDesc;
+use super::graph::ImageSize;
+use super::graph::Load;
+use super::graph::PassBody;
+use super::graph::PassDecl;
+use super::graph::RenderGraph;
+use super::graph::RenderNode;
+use super::graph::ResourceId;
+use super::outline;
+use super::outline::OutlineSettings;
+use super::outline::OutlineSystem;
+use super::outline::SelectionMaskSystem;
+use super::render_mode::RenderMode;
+use super::screenshot;
+use super::screenshot::CaptureTarget;
+use super::screenshot::CapturedFrame;
+use super::screenshot::ScreenshotSystem;
+use super::sky::SkySettings;
+use super::sky::SkySystem;
+use super::sky::SunUniforms;
+use super::skybox::SkyboxSettings;
+use super::skybox::SkyboxSystem;
+use super::ssao::SsaoBlurSystem;
+use super::ssao::SsaoCompositeSystem;
+use super::ssao::SsaoSettings;
+use super::ssao::SsaoSystem;
+use super::text::PanelSystem;
+use super::text::TextItem;
+use super::text::TextMetrics;
+use super::tonemap::HdrSettings;
+use super::transparency::TransparencySettings;
+use super::transparency::WeightedBlendedCompositeSystem;
+use super::tonemap::TonemapSystem;
+use super::ui::UiSystem;
+use super::viewport::Viewport;
+use super::viewport::ViewportCompositeSystem;
+use super::viewport::ViewportImage;
+
+/// Format of the intermediate scene target everything is rendered into before tonemapping.
+pub const HDR_FORMAT: Format = Format::R16G16B16A16Sfloat;
+pub const DEPTH_FORMAT: Format = Format::D16Unorm;
+/// Depth format with a stencil aspect, needed for selection outlines. Not every device
+/// supports it, see `depth_stencil_format`.
+pub const DEPTH_STENCIL_FORMAT: Format = Format::D24Unorm_S8Uint;
+/// View space normal in xyz and linear depth in w, written by the geometry pass for SSAO.
+pub const NORMAL_DEPTH_FORMAT: Format = Format::R16G16B16A16Sfloat;
+/// The ambient part of the geometry pass' color, which SSAO darkens.
+pub const AMBIENT_FORMAT: Format = Format::R16G16B16A16Sfloat;
+pub const AO_FORMAT: Format = Format::R8Unorm;
+/// Format of the selection mask the outline is drawn around.
+pub const SELECTION_FORMAT: Format = Format::R8G8Unorm;
+/// Formats of the weighted blended order independent transparency targets.
+pub const ACCUM_FORMAT: Format = Format::R16G16B16A16Sfloat;
+pub const REVEAL_FORMAT: Format = Format::R16Sfloat;
+/// Format of the image owned by a headless frame system.
+pub const HEADLESS_FORMAT: Format = Format::R8G8B8A8Srgb;
+
+/// The first of `DEPTH_STENCIL_FORMAT` and `D32Sfloat_S8Uint` the device can use as a depth
+/// attachment, or `DEPTH_FORMAT` without selection outlines if it supports neither.
+pub fn depth_stencil_format(device: &Arc<Device>) -> Format {
+    // vulkano doesn't expose the format properties, but checks them when creating an image.
+    [DEPTH_STENCIL_FORMAT, Format::D32Sfloat_S8Uint]
+        .iter()
+        .cloned()
+        .find(|&format| AttachmentImage::new(device.clone(), [1, 1], format).is_ok())
+        .unwrap_or(DEPTH_FORMAT)
+}
+
+/// The output image of the frame, one of those given to `FrameSystem::set_output_images`.
+pub const FINAL_IMAGE: ResourceId = "final";
+pub const HDR_IMAGE: ResourceId = "hdr";
+pub const NORMAL_DEPTH_IMAGE: ResourceId = "normal_depth";
+pub const AMBIENT_IMAGE: ResourceId = "ambient";
+pub const AO_IMAGE: ResourceId = "ao";
+pub const AO_BLUR_IMAGE: ResourceId = "ao_blur";
+pub const DEPTH_IMAGE: ResourceId = "depth";
+pub const BLOOM_IMAGE: ResourceId = "bloom";
+pub const ACCUM_IMAGE: ResourceId = "accum";
+pub const REVEAL_IMAGE: ResourceId = "reveal";
+pub const SELECTION_IMAGE: ResourceId = "selection";
+
+pub const COMPUTE_PASS: &str = "compute";
+pub const GEOMETRY_PASS: &str = "geometry";
+pub const TRANSPARENT_PASS: &str = "transparent";
+pub const WEIGHTED_BLENDED_PASS: &str = "weighted_blended";
+pub const SELECTION_PASS: &str = "selection";
+pub const DEBUG_PASS: &str = "debug";
+pub const TEXT_PASS: &str = "text";
+pub const UI_PASS: &str = "ui";
+pub const VIEWPORTS_PASS: &str = "viewports";
+pub const OVERLAYS_PASS: &str = "overlays";
+pub const POST_COMPUTE_PASS: &str = "post_compute";
+
+/// Where in the frame a `Pass::Compute` runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComputeStage {
+    /// Before anything is rendered, e.g. to generate what's drawn in this frame.
+    BeforeRendering,
+    /// After the whole frame, including the UI, has been rendered.
+    AfterRendering,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RenderSettings {
+    pub render_mode: RenderMode,
+    pub clear: ClearSettings,
+    pub hdr: HdrSettings,
+    pub bloom: BloomSettings,
+    pub skybox: SkyboxSettings,
+    pub sky: SkySettings,
+    pub transparency: TransparencySettings,
+    pub outline: OutlineSettings,
+    pub ssao: SsaoSettings,
+    pub culling: CullingSettings,
+}
+
 pub struct FrameSystem {
     queue: Arc<Queue>,
-    render_pass: Arc<RenderPassAbstract + Send + Sync>,
+    graph: RenderGraph,
+    debug_system: DebugDrawSystem,
+    panel_system: PanelSystem,
+    text_metrics: TextMetrics,
+    ui_system: UiSystem,
+    screenshot_system: ScreenshotSystem,
+    sun_buffer_pool: CpuBufferPool<SunUniforms>,
+    /// Uploads of the passes' own data, joined into the first frame.
+    setup_future: Option<Box<GpuFuture>>,
+    output_format: Format,
+    depth_format: Format,
+    output_images: Vec<Arc<ImageAccess + Send + Sync>>,
+    capture_requests: Vec<CaptureTarget>,
+    settings: RenderSettings,
+    last_cpu_time: time::Duration,
+    last_frame_start: Option<time::PreciseTime>,
+    fixed_time_step: Option<f32>,
+    /// The viewports drawn by the frame being recorded, if it was started with
+    /// `composite_frame`.
+    viewports: Vec<ViewportImage>,
+    /// Drawn over the next frame, see `add_overlay`.
+    overlays: Vec<ViewportImage>,
 }
 
 impl FrameSystem {
     pub fn new(queue: Arc<Queue>, output_format: Format) -> FrameSystem {
-        let render_pass = single_pass_renderpass!(
-            queue.device().clone(),
-            attachments: {
-                final_color: {
-                    load: Clear,
-                    store: Store,
-                    format: output_format,
-                    samples: 1,
+        FrameSystem::with_depth_format(queue, output_format, DEPTH_FORMAT)
+    }
+
+    /// Like `new`, with `depth_format` instead of `DEPTH_FORMAT` for the scene depth, e.g.
+    /// the one `depth_stencil_format` picks to get selection outlines.
+    pub fn with_depth_format(queue: Arc<Queue>, output_format: Format, depth_format: Format) -> FrameSystem {
+        let mut graph = RenderGraph::new(queue.clone());
+
+        graph.import_image(FINAL_IMAGE, output_format);
+        graph.add_image(
+            HDR_IMAGE,
+            ImageDesc {
+                format: HDR_FORMAT,
+                size: ImageSize::Output,
+                usage: ImageUsage {
+                    color_attachment: true,
+                    sampled: true,
+                    ..ImageUsage::none()
                 },
-                depth: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::D16Unorm,
-                    samples: 1,
-                }
             },
-            pass: {
-                color: [final_color],
-                depth_stencil: {depth}
-            }
-        ).unwrap();
+        );
+        graph.add_image(
+            DEPTH_IMAGE,
+            ImageDesc {
+                format: depth_format,
+                size: ImageSize::Output,
+                usage: ImageUsage {
+                    depth_stencil_attachment: true,
+                    ..ImageUsage::none()
+                },
+            },
+        );
+        for &(resource, format) in &[(NORMAL_DEPTH_IMAGE, NORMAL_DEPTH_FORMAT), (AMBIENT_IMAGE, AMBIENT_FORMAT)] {
+            graph.add_image(
+                resource,
+                ImageDesc {
+                    format,
+                    size: ImageSize::Output,
+                    usage: ImageUsage {
+                        color_attachment: true,
+                        sampled: true,
+                        ..ImageUsage::none()
+                    },
+                },
+            );
+        }
+        graph.add_image(AO_IMAGE, SsaoSystem::image_desc());
+        graph.add_image(AO_BLUR_IMAGE, SsaoBlurSystem::image_desc());
+        graph.add_image(BLOOM_IMAGE, BloomSystem::image_desc());
+        graph.add_image(ACCUM_IMAGE, WeightedBlendedCompositeSystem::accum_image_desc());
+        graph.add_image(REVEAL_IMAGE, WeightedBlendedCompositeSystem::reveal_image_desc());
+        graph.add_image(SELECTION_IMAGE, SelectionMaskSystem::image_desc());
+
+        // Without any images the compute pass registered first runs first.
+        graph.add_pass(
+            COMPUTE_PASS,
+            PassBody::Compute(ComputeStage::BeforeRendering),
+            PassDecl::default(),
+        );
+
+        let mut geometry = PassDecl::default();
+        geometry
+            .color(HDR_IMAGE, Load::Clear([0.0, 0.0, 0.0, 0.0].into()))
+            .color(NORMAL_DEPTH_IMAGE, Load::Clear([0.0, 0.0, 0.0, 0.0].into()))
+            .color(AMBIENT_IMAGE, Load::Clear([0.0, 0.0, 0.0, 0.0].into()))
+            .depth_stencil(DEPTH_IMAGE, Load::Clear(1.0f32.into()));
+        graph.add_pass(GEOMETRY_PASS, PassBody::Deferred, geometry);
+        graph.add_node("background", BackgroundSystem::new(queue.clone()));
+        graph.add_node("skybox", SkyboxSystem::new(queue.clone()));
+        graph.add_node("sky", SkySystem::new(queue.clone()));
+
+        // Ambient occlusion only darkens the opaque geometry, before anything is blended over it.
+        let (ssao, ssao_upload) = SsaoSystem::new(queue.clone());
+        graph.add_node("ssao", ssao);
+        graph.add_node("ssao_blur", SsaoBlurSystem::new(queue.clone()));
+        graph.add_node("ssao_composite", SsaoCompositeSystem::new(queue.clone()));
+
+        // Transparent surfaces go over the opaque ones and the sky, tested against but not
+        // writing depth. Those that can't be sorted are accumulated and resolved first.
+        let mut weighted_blended = PassDecl::default();
+        weighted_blended
+            .color(ACCUM_IMAGE, Load::Clear([0.0, 0.0, 0.0, 0.0].into()))
+            .color(REVEAL_IMAGE, Load::Clear([1.0, 0.0, 0.0, 0.0].into()))
+            .depth_stencil(DEPTH_IMAGE, Load::Load);
+        graph.add_pass(WEIGHTED_BLENDED_PASS, PassBody::WeightedBlended, weighted_blended);
+        graph.add_node("weighted_blended_composite", WeightedBlendedCompositeSystem::new(queue.clone()));
+
+        let mut transparent = PassDecl::default();
+        transparent
+            .color(HDR_IMAGE, Load::Load)
+            .depth_stencil(DEPTH_IMAGE, Load::Load);
+        graph.add_pass(TRANSPARENT_PASS, PassBody::Transparent, transparent);
+
+        // Selected objects are marked in the stencil buffer once the scene depth is complete,
+        // the outline around them goes on the tonemapped image.
+        let mut selection = PassDecl::default();
+        selection.depth_stencil(DEPTH_IMAGE, Load::Load);
+        graph.add_pass(SELECTION_PASS, PassBody::Selection, selection);
+        graph.add_node("selection_mask", SelectionMaskSystem::new(queue.clone(), depth_format));
+
+        let eye_adaptation = EyeAdaptationSystem::new(queue.clone());
+        let exposure_buffer = eye_adaptation.exposure_buffer();
+        let tonemap = TonemapSystem::new(queue.clone(), output_format, exposure_buffer);
+
+        graph.add_node("eye_adaptation", eye_adaptation);
+        graph.add_node("bloom", BloomSystem::new(queue.clone()));
+        graph.add_node("tonemap", tonemap);
+        graph.add_node("outline", OutlineSystem::new(queue.clone(), output_format, depth_format));
+
+        // Split screen frames draw their viewports instead of all of the above, and overlays
+        // like a picture-in-picture view go over either.
+        let viewports = ViewportCompositeSystem::new(queue.clone(), output_format);
+        let mut decl = PassDecl::default();
+        viewports.declare(&mut decl);
+        graph.add_pass(VIEWPORTS_PASS, PassBody::Viewports(Box::new(viewports)), decl);
+        let overlays = ViewportCompositeSystem::overlays(queue.clone(), output_format);
+        let mut decl = PassDecl::default();
+        overlays.declare(&mut decl);
+        graph.add_pass(OVERLAYS_PASS, PassBody::Overlays(Box::new(overlays)), decl);
+
+        // Debug lines go on top of the tonemapped image, tested against the scene depth.
+        let mut debug = PassDecl::default();
+        debug
+            .color(FINAL_IMAGE, Load::Load)
+            .depth_stencil(DEPTH_IMAGE, Load::Load);
+        graph.add_pass(DEBUG_PASS, PassBody::Debug, debug);
+
+        // Text panels are drawn in the graph's render pass, vulkano_text then starts its own.
+        let mut text = PassDecl::default();
+        text.color(FINAL_IMAGE, Load::Load);
+        graph.add_pass(TEXT_PASS, PassBody::Text, text);
+
+        let mut ui = PassDecl::default();
+        ui.color(FINAL_IMAGE, Load::Load);
+        graph.add_pass(UI_PASS, PassBody::Ui, ui);
+
+        // Reading the final image puts it after everything drawing into it. The normal and
+        // depth image is kept for it, e.g. for occlusion culling in the next frame.
+        let mut post_compute = PassDecl::default();
+        post_compute.read(FINAL_IMAGE).read(NORMAL_DEPTH_IMAGE);
+        graph.add_pass(
+            POST_COMPUTE_PASS,
+            PassBody::Compute(ComputeStage::AfterRendering),
+            post_compute,
+        );
+
+        let debug_system = DebugDrawSystem::new(queue.clone(), graph.subpass(DEBUG_PASS).unwrap(), output_format);
+        let panel_system = PanelSystem::new(queue.clone(), graph.subpass(TEXT_PASS).unwrap());
+        let ui_system = UiSystem::new(queue.clone(), graph.subpass(UI_PASS).unwrap(), output_format);
 
         FrameSystem {
+            screenshot_system: ScreenshotSystem::new(queue.device().clone()),
+            sun_buffer_pool: CpuBufferPool::new(queue.device().clone(), BufferUsage::uniform_buffer()),
+            setup_future: Some(ssao_upload),
             queue,
-            render_pass: Arc::new(render_pass)
+            graph,
+            debug_system,
+            panel_system,
+            text_metrics: TextMetrics::new(),
+            ui_system,
+            output_format,
+            depth_format,
+            output_images: Vec::new(),
+            capture_requests: Vec::new(),
+            settings: RenderSettings::default(),
+            last_cpu_time: time::Duration::zero(),
+            last_frame_start: None,
+            fixed_time_step: None,
+            viewports: Vec::new(),
+            overlays: Vec::new(),
         }
     }
 
+    /// A frame system that renders into an image it owns instead of a swapchain, so no window
+    /// or surface is needed. Frames are always rendered with image number 0, and can be read
+    /// back with `request_readback`.
+    pub fn headless(queue: Arc<Queue>, dimensions: [u32; 2]) -> FrameSystem {
+        FrameSystem::headless_with_depth_format(queue, dimensions, DEPTH_FORMAT)
+    }
+
+    /// Like `headless`, see `with_depth_format`.
+    pub fn headless_with_depth_format(
+        queue: Arc<Queue>,
+        dimensions: [u32; 2],
+        depth_format: Format,
+    ) -> FrameSystem {
+        let mut frame_system = FrameSystem::with_depth_format(queue, HEADLESS_FORMAT, depth_format);
+        frame_system.set_headless_dimensions(dimensions);
+        frame_system
+    }
+
+    /// Replaces the output image of a headless frame system with one of `dimensions`.
+    pub fn set_headless_dimensions(&mut self, dimensions: [u32; 2]) {
+        self.set_headless_images(dimensions, 1);
+    }
+
+    /// Like `set_headless_dimensions`, with `count` output images that frames pick by image
+    /// number, e.g. to keep several of them around for sampling. There are as many HDR images,
+    /// see `hdr_image_at`.
+    pub fn set_headless_images(&mut self, dimensions: [u32; 2], count: usize) {
+        let images: Vec<_> = (0..count)
+            .map(|_| {
+                AttachmentImage::with_usage(
+                    self.queue.device().clone(),
+                    dimensions,
+                    HEADLESS_FORMAT,
+                    ImageUsage {
+                        color_attachment: true,
+                        transfer_source: true,
+                        sampled: true,
+                        ..ImageUsage::none()
+                    },
+                ).expect("Failed to create headless output image")
+            })
+            .collect();
+        self.graph.set_image_slots(HDR_IMAGE, count);
+        self.set_output_images(&images);
+    }
+
+    /// Subpass of `Pass::Deferred`. Its color attachments are the lit color, the view space
+    /// normal with the linear depth in w (0 where nothing is drawn), and the ambient part of
+    /// the lit color, which ambient occlusion is taken off.
     pub fn deferred_render_pass(&self) -> Subpass<Arc<RenderPassAbstract + Send + Sync>> {
-        Subpass::from(self.render_pass.clone(), 0).unwrap()
+        self.graph.subpass(GEOMETRY_PASS).unwrap()
+    }
+
+    /// Subpass of `Pass::Transparent`, drawing into the HDR image with the scene depth.
+    pub fn transparent_render_pass(&self) -> Subpass<Arc<RenderPassAbstract + Send + Sync>> {
+        self.graph.subpass(TRANSPARENT_PASS).unwrap()
+    }
+
+    /// Subpass of `Pass::WeightedBlended`, with the accumulation and revealage images as its
+    /// two color attachments.
+    pub fn weighted_blended_render_pass(&self) -> Subpass<Arc<RenderPassAbstract + Send + Sync>> {
+        self.graph.subpass(WEIGHTED_BLENDED_PASS).unwrap()
+    }
+
+    /// Subpass of `Pass::Selection`, with only the scene depth/stencil attached. Pipelines
+    /// drawing in it need a fragment shader without outputs.
+    pub fn selection_render_pass(&self) -> Subpass<Arc<RenderPassAbstract + Send + Sync>> {
+        self.graph.subpass(SELECTION_PASS).unwrap()
+    }
+
+    #[inline]
+    pub fn depth_format(&self) -> Format {
+        self.depth_format
+    }
+
+    /// Whether selected objects can be outlined, which needs a stencil aspect.
+    #[inline]
+    pub fn supports_selection(&self) -> bool {
+        outline::has_stencil(self.depth_format)
+    }
+
+    /// Registers an image that passes can declare reads and writes on.
+    pub fn add_image(&mut self, resource: ResourceId, desc: ImageDesc) {
+        self.graph.add_image(resource, desc);
+    }
+
+    /// Registers a custom pass. Where it runs follows from the images it declares.
+    pub fn add_pass<N>(&mut self, name: &'static str, node: N)
+    where
+        N: RenderNode + 'static,
+    {
+        self.graph.add_node(name, node);
+    }
+
+    /// Like `add_pass`, but goes ahead of `anchor` among passes writing the same images, e.g.
+    /// to draw into the final image before the text overlay.
+    pub fn add_pass_before<N>(&mut self, anchor: &str, name: &'static str, node: N)
+    where
+        N: RenderNode + 'static,
+    {
+        self.graph.add_node_before(anchor, name, node);
+    }
+
+    /// Uploads the font atlas of the imgui context drawn in `Pass::Ui`. Has to be called once
+    /// before the first frame.
+    pub fn set_ui_fonts(&mut self, imgui: &mut ImGui) {
+        self.ui_system.set_fonts(imgui);
+    }
+
+    #[inline]
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    #[inline]
+    pub fn settings_mut(&mut self) -> &mut RenderSettings {
+        &mut self.settings
+    }
+
+    /// Sets the images frames are rendered into, usually the swapchain images. Must be called
+    /// again whenever the swapchain is recreated, which also drops the cached framebuffers.
+    /// All size dependent images follow the size of these on the next frame.
+    pub fn set_output_images<I>(&mut self, images: &[I])
+    where
+        I: ImageAccess + ImageViewAccess + Clone + Send + Sync + 'static,
+    {
+        let views = images
+            .iter()
+            .map(|image| Arc::new(image.clone()) as Arc<ImageViewAccess + Send + Sync>)
+            .collect();
+        self.graph.bind_images(FINAL_IMAGE, views);
+        self.output_images = images
+            .iter()
+            .map(|image| Arc::new(image.clone()) as Arc<ImageAccess + Send + Sync>)
+            .collect();
+    }
+
+    /// The image of the current slot frames are rendered into. For a headless frame system it
+    /// is the one of the latest frame, which can also be sampled.
+    pub fn output_image(&self) -> Arc<ImageViewAccess + Send + Sync> {
+        self.graph.image(FINAL_IMAGE)
+    }
+
+    /// The output image frames with `image_num` are rendered into.
+    pub fn output_image_at(&self, image_num: usize) -> Arc<ImageViewAccess + Send + Sync> {
+        self.graph.image_at(FINAL_IMAGE, image_num)
+    }
+
+    /// The HDR image of the frames with `image_num`, before bloom and tonemapping. Only kept
+    /// apart per image number with `set_headless_images`, and only valid once the frame
+    /// system rendered a frame at the current size.
+    pub fn hdr_image_at(&self, image_num: usize) -> Arc<ImageViewAccess + Send + Sync> {
+        self.graph.image_at(HDR_IMAGE, image_num)
+    }
+
+    /// Draws `overlay` over the next frame at its rectangle, e.g. a picture-in-picture view of
+    /// a `RenderTargets` image. It goes under the debug lines and text, and is dropped once the
+    /// frame is finished.
+    pub fn add_overlay(&mut self, overlay: ViewportImage) {
+        self.overlays.push(overlay);
+    }
+
+    /// Saves the next frame as a PNG at `path`. The image is read back and encoded in the
+    /// background once the GPU has finished the frame.
+    pub fn request_screenshot(&mut self, path: PathBuf) {
+        if !screenshot::supports_format(self.output_format) {
+            println!("Screenshots of {:?} images aren't supported", self.output_format);
+            return;
+        }
+        if !self.output_images_copyable() {
+            println!("Screenshots aren't supported, the output images can't be copied from");
+            return;
+        }
+        self.capture_requests.push(CaptureTarget::Png(path));
+    }
+
+    /// Copies the next frame to memory, see `take_readbacks`.
+    pub fn request_readback(&mut self) {
+        assert!(
+            screenshot::supports_format(self.output_format),
+            "Can't read back {:?} images",
+            self.output_format
+        );
+        assert!(self.output_images_copyable(), "Can't read back output images without transfer usage");
+        self.capture_requests.push(CaptureTarget::Memory);
+    }
+
+    /// Whether the output images can be copied from, which captures need. Swapchain images only
+    /// can if the surface supports it.
+    fn output_images_copyable(&self) -> bool {
+        self.output_images
+            .iter()
+            .all(|image| image.inner().image.usage_transfer_source())
     }
 
-    pub fn frame<F, I>(
+    /// Frames requested with `request_readback` whose rendering has finished and whose future
+    /// has been cleaned up or dropped, oldest first.
+    pub fn take_readbacks(&mut self) -> Vec<CapturedFrame> {
+        self.screenshot_system.take_captured()
+    }
+
+    /// Writes out the screenshots of all submitted frames, blocking until they are saved. The
+    /// GPU has to be done with those frames.
+    pub fn finish_captures(&mut self) {
+        self.screenshot_system.finish();
+    }
+
+    /// Readbacks that were requested but can't be taken yet.
+    pub fn pending_readbacks(&self) -> usize {
+        let requested = self.capture_requests
+            .iter()
+            .filter(|target| match **target {
+                CaptureTarget::Memory => true,
+                CaptureTarget::Png(_) => false,
+            })
+            .count();
+        requested + self.screenshot_system.pending_captures()
+    }
+
+    /// Makes time dependent passes like eye adaptation advance by `time_step` seconds per
+    /// frame instead of following the wall clock, e.g. while recording a video.
+    pub fn set_fixed_time_step(&mut self, time_step: Option<f32>) {
+        self.fixed_time_step = time_step;
+    }
+
+    /// CPU time spent recording the last finished frame.
+    #[inline]
+    pub fn last_cpu_time(&self) -> time::Duration {
+        self.last_cpu_time
+    }
+
+    pub fn frame<F>(
         &mut self,
         before_future: F,
-        final_image: I,
-        depth_buffer: &Arc<AttachmentImage>,
-        world_to_framebuffer: Matrix4<f32>,
+        image_num: usize,
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
     ) -> Frame
     where
         F: GpuFuture + 'static,
-        I: ImageAccess + ImageViewAccess + Clone + Send + Sync + 'static,
     {
-        let framebuffer = Arc::new(
-            Framebuffer::start(self.render_pass.clone())
-                .add(final_image.clone())
-                .unwrap()
-                .add(depth_buffer.clone())
-                .unwrap()
-                .build()
-                .unwrap()
-        );
+        self.viewports.clear();
+        self.begin_frame(before_future, image_num, view, projection)
+    }
+
+    /// Starts a split screen frame, which draws the last rendered image of each viewport at its
+    /// rectangle instead of a scene. `before_future` has to include the `Pass::Finished` futures
+    /// of the viewports' frames. Only the text and UI passes are handed out, compute work goes
+    /// in the viewports' frames.
+    pub fn composite_frame<'v, F, I>(&mut self, before_future: F, image_num: usize, viewports: I) -> Frame
+    where
+        F: GpuFuture + 'static,
+        I: IntoIterator<Item = &'v Viewport>,
+    {
+        self.viewports = viewports.into_iter().map(Viewport::image).collect();
+        self.begin_frame(before_future, image_num, Matrix4::identity(), Matrix4::identity())
+    }
+
+    fn begin_frame<F>(
+        &mut self,
+        before_future: F,
+        image_num: usize,
+        view: Matrix4<f32>,
+        projection: Matrix4<f32>,
+    ) -> Frame
+    where
+        F: GpuFuture + 'static,
+    {
+        let started_at = time::PreciseTime::now();
+        let delta_time = match (self.fixed_time_step, self.last_frame_start) {
+            (Some(time_step), _) => time_step,
+            (None, Some(last_frame_start)) => {
+                last_frame_start.to(started_at).num_microseconds().unwrap_or(0) as f32 / 1_000_000.0
+            }
+            (None, None) => 0.0,
+        };
+        self.last_frame_start = Some(started_at);
+        // Without the sky there is no time of day to follow.
+        let sun = if self.settings.sky.enabled {
+            self.settings.sky.advance(delta_time);
+            self.settings.sky.sun_uniforms()
+        } else {
+            SunUniforms::neutral()
+        };
+        let sun_buffer = Arc::new(self.sun_buffer_pool.next(sun).unwrap()) as Arc<_>;
+
+        self.screenshot_system.poll();
+        self.graph.set_slot(image_num);
+
+        {
+            let clear = &self.settings.clear;
+            self.graph.set_clear_value(GEOMETRY_PASS, HDR_IMAGE, clear.color_clear_value());
+            self.graph.set_clear_value(
+                GEOMETRY_PASS,
+                DEPTH_IMAGE,
+                clear.depth_stencil_clear_value(self.depth_format),
+            );
+        }
+
+        let img_dims = self.graph.dimensions(FINAL_IMAGE);
+        self.graph.allocate(img_dims);
+
+        let before_future = match self.setup_future.take() {
+            Some(setup_future) => Box::new(before_future.join(setup_future)) as Box<GpuFuture>,
+            None => Box::new(before_future),
+        };
 
         let command_buffer = Some(
             AutoCommandBufferBuilder::primary_one_time_submit(
                 self.queue.device().clone(),
                 self.queue.family(),
-            ).unwrap()
-                .begin_render_pass(
-                    framebuffer.clone(),
-                    true,
-                    vec![[0.0, 0.0, 0.0, 0.0].into(), 1.0f32.into()],
-                )
-                .unwrap(),
+            ).unwrap(),
         );
 
         Frame {
             system: self,
-            num_pass: 0,
-            before_cb_main_future: Some(Box::new(before_future)),
-            framebuffer,
+            started_at,
+            delta_time,
+            image_num,
+            position: 0,
+            in_render_pass: false,
+            finished: false,
+            before_cb_main_future: Some(before_future),
+            viewport_dimensions: img_dims,
             command_buffer,
-            world_to_framebuffer,
+            view,
+            projection,
+            sun_buffer,
         }
     }
 }
 
 pub struct Frame<'a> {
     system: &'a mut FrameSystem,
-    num_pass: u8,
+    started_at: time::PreciseTime,
+    delta_time: f32,
+    image_num: usize,
+    position: usize,
+    in_render_pass: bool,
+    finished: bool,
     before_cb_main_future: Option<Box<GpuFuture>>,
-    framebuffer: Arc<FramebufferAbstract + Send + Sync>,
+    viewport_dimensions: [u32; 2],
     command_buffer: Option<AutoCommandBufferBuilder>,
-    world_to_framebuffer: Matrix4<f32>,
+    view: Matrix4<f32>,
+    projection: Matrix4<f32>,
+    sun_buffer: Arc<BufferAccess + Send + Sync>,
 }
 
 impl<'a> Frame<'a> {
+    /// Records the graph's passes in order, handing back the ones that need the user.
     pub fn next_pass<'f>(&'f mut self) -> Option<Pass<'f, 'a>> {
-        match {
-            let current_pass = self.num_pass;
-            self.num_pass += 1;
-            current_pass
-        } {
-            0 => Some(Pass::Deferred(DrawPass { frame: self })),
-            1 => {
+        loop {
+            if self.in_render_pass {
                 self.command_buffer = Some(
-                    self
-                        .command_buffer
+                    self.command_buffer
                         .take()
                         .unwrap()
                         .end_render_pass()
-                        .unwrap()
+                        .unwrap(),
                 );
+                self.in_render_pass = false;
+            }
 
-                Some(Pass::Text(TextPass { frame: self }))
-            },
-            2 => {
-                let command_buffer = self
-                    .command_buffer
-                    .take()
-                    .unwrap()
-                    .build()
-                    .unwrap();
+            if self.position == self.system.graph.order().len() {
+                if self.finished {
+                    return None;
+                }
+                self.finished = true;
+
+                let mut command_buffer = self.command_buffer.take().unwrap();
+                for target in self.system.capture_requests.drain(..) {
+                    let image = self.system.output_images[self.image_num].clone();
+                    command_buffer = self.system.screenshot_system.capture(
+                        command_buffer,
+                        image,
+                        self.viewport_dimensions,
+                        self.system.output_format,
+                        target,
+                    );
+                }
+                let command_buffer = command_buffer.build().unwrap();
 
                 let after_main_cb = self
                     .before_cb_main_future
@@ -143,17 +739,88 @@ impl<'a> Frame<'a> {
                     .then_execute(self.system.queue.clone(), command_buffer)
                     .unwrap();
 
-                Some(Pass::Finished(Box::new(after_main_cb)))
+                self.system.last_cpu_time = self.started_at.to(time::PreciseTime::now());
+                self.system.overlays.clear();
+
+                return Some(Pass::Finished(Box::new(after_main_cb)));
+            }
+
+            let index = self.system.graph.order()[self.position];
+            self.position += 1;
+
+            let compositing = !self.system.viewports.is_empty();
+            let skipped = match *self.system.graph.body(index) {
+                PassBody::Viewports(_) => !compositing,
+                PassBody::Overlays(_) => self.system.overlays.is_empty(),
+                // The scene is in the viewports' frames, so composited ones only get text and UI.
+                PassBody::Text | PassBody::Ui => false,
+                _ if compositing => true,
+                PassBody::WeightedBlended => !self.system.settings.transparency.order_independent,
+                PassBody::Selection => !self.system.settings.outline.enabled || !self.system.supports_selection(),
+                _ => false,
+            };
+            if skipped {
+                continue;
+            }
+
+            if let Some(framebuffer) = self.system.graph.framebuffer(index) {
+                self.command_buffer = Some(
+                    self.command_buffer
+                        .take()
+                        .unwrap()
+                        .begin_render_pass(framebuffer, true, self.system.graph.clear_values(index))
+                        .unwrap(),
+                );
+                self.in_render_pass = true;
+            }
+
+            match *self.system.graph.body(index) {
+                PassBody::Node(_) | PassBody::Viewports(_) | PassBody::Overlays(_) => {
+                    let command_buffer = self.command_buffer.take().unwrap();
+                    let system = &mut *self.system;
+                    let inputs = FrameInputs {
+                        settings: &system.settings,
+                        delta_time: self.delta_time,
+                        view: self.view,
+                        projection: self.projection,
+                        viewports: &system.viewports,
+                        overlays: &system.overlays,
+                    };
+                    self.command_buffer = Some(system.graph.record(index, command_buffer, &inputs));
+                }
+                PassBody::Deferred => return Some(Pass::Deferred(DrawPass { frame: self })),
+                PassBody::Transparent => return Some(Pass::Transparent(DrawPass { frame: self })),
+                PassBody::WeightedBlended => return Some(Pass::WeightedBlended(DrawPass { frame: self })),
+                PassBody::Selection => return Some(Pass::Selection(DrawPass { frame: self })),
+                PassBody::Compute(stage) => return Some(Pass::Compute(ComputePass { frame: self, stage })),
+                PassBody::Debug => return Some(Pass::Debug(DebugPass { frame: self })),
+                PassBody::Ui => return Some(Pass::Ui(UiPass { frame: self })),
+                PassBody::Text => {
+                    return Some(Pass::Text(TextPass {
+                        frame: self,
+                        items: Vec::new(),
+                    }))
+                }
             }
-            _ => None,
         }
     }
 }
 
 pub enum Pass<'f, 's: 'f> {
+    /// Handed out twice per frame, see `ComputePass::stage`, but not in split screen frames.
+    Compute(ComputePass<'f, 's>),
     Deferred(DrawPass<'f, 's>),
-    EndRenderPass,
+    /// Alpha blended surfaces, to be drawn back to front, see `sort_back_to_front`.
+    Transparent(DrawPass<'f, 's>),
+    /// Order independent transparency, only handed out when enabled in the settings.
+    WeightedBlended(DrawPass<'f, 's>),
+    /// Selected objects are drawn here twice, first with `selection_silhouette_stencil` and
+    /// then with `selection_visible_stencil`, to be outlined. Only handed out when outlines
+    /// are enabled and the depth format has a stencil aspect.
+    Selection(DrawPass<'f, 's>),
+    Debug(DebugPass<'f, 's>),
     Text(TextPass<'f, 's>),
+    Ui(UiPass<'f, 's>),
     Finished(Box<GpuFuture>),
 }
 
@@ -181,37 +848,206 @@ impl<'f, 's: 'f> DrawPass<'f, 's> {
 
     #[inline]
     pub fn viewport_dimensions(&self) -> [u32; 2] {
-        let dims = self.frame.framebuffer.dimensions();
-        [dims[0], dims[1]]
+        self.frame.viewport_dimensions
     }
 
     #[inline]
     pub fn world_to_framebuffer_matrix(&self) -> Matrix4<f32> {
-        self.frame.world_to_framebuffer
+        self.frame.projection * self.frame.view
+    }
+
+    #[inline]
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        self.frame.view
+    }
+
+    #[inline]
+    pub fn projection_matrix(&self) -> Matrix4<f32> {
+        self.frame.projection
+    }
+
+    /// Uniform buffer with this frame's `SunUniforms`, for shading geometry.
+    #[inline]
+    pub fn sun_buffer(&self) -> Arc<BufferAccess + Send + Sync> {
+        self.frame.sun_buffer.clone()
     }
 }
 
-pub struct TextPass<'f, 's: 'f> {
-    frame: &'f mut Frame<'s>
+/// Records compute work into the frame's command buffer, outside of any render pass. It's
+/// submitted with the rest of the frame, so it runs after the future given to
+/// `FrameSystem::frame` and before the one of `Pass::Finished`, and buffers it writes can be
+/// used by the passes after it.
+pub struct ComputePass<'f, 's: 'f> {
+    frame: &'f mut Frame<'s>,
+    stage: ComputeStage,
 }
 
-impl<'f, 's: 'f> TextPass<'f, 's> {
+impl<'f, 's: 'f> ComputePass<'f, 's> {
     #[inline]
-    pub fn write(&mut self, text: &str, text_drawer: &mut DrawText, image_num: usize) {
-        text_drawer.queue_text(
-            200.0,
-            50.0,
-            20.0,
-            [1.0, 1.0, 1.0, 1.0],
-            &text
-        );
+    pub fn stage(&self) -> ComputeStage {
+        self.stage
+    }
+
+    /// Seconds since the previous frame, or the fixed time step.
+    #[inline]
+    pub fn delta_time(&self) -> f32 {
+        self.frame.delta_time
+    }
+
+    #[inline]
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        self.frame.view
+    }
 
+    #[inline]
+    pub fn projection_matrix(&self) -> Matrix4<f32> {
+        self.frame.projection
+    }
+
+    /// An image of the render graph, only meaningful in the `AfterRendering` stage, which can
+    /// read `FINAL_IMAGE` and `NORMAL_DEPTH_IMAGE`.
+    pub fn image(&self, resource: ResourceId) -> Arc<ImageViewAccess + Send + Sync> {
+        self.frame.system.graph.image(resource)
+    }
+
+    #[inline]
+    pub fn dimensions(&self, resource: ResourceId) -> [u32; 2] {
+        self.frame.system.graph.dimensions(resource)
+    }
+
+    pub fn dispatch<Cp, S, Pc>(&mut self, dimensions: [u32; 3], pipeline: Cp, sets: S, constants: Pc)
+    where
+        Cp: ComputePipelineAbstract + Send + Sync + 'static + Clone,
+        S: DescriptorSetsCollection,
+    {
         self.frame.command_buffer = Some(
             self.frame
                 .command_buffer
                 .take()
                 .unwrap()
-                .draw_text(text_drawer, image_num)
+                .dispatch(dimensions, pipeline, sets, constants)
+                .unwrap(),
+        );
+    }
+
+    /// For anything else that has to happen outside a render pass, like copies.
+    pub fn record<F>(&mut self, f: F)
+    where
+        F: FnOnce(AutoCommandBufferBuilder) -> AutoCommandBufferBuilder,
+    {
+        self.frame.command_buffer = Some(f(self.frame.command_buffer.take().unwrap()));
+    }
+}
+
+pub struct DebugPass<'f, 's: 'f> {
+    frame: &'f mut Frame<'s>,
+}
+
+impl<'f, 's: 'f> DebugPass<'f, 's> {
+    /// Draws everything accumulated in `debug` with the frame's world to framebuffer matrix,
+    /// then clears it for the next frame.
+    pub fn draw(&mut self, debug: &mut DebugDraw) {
+        let command_buffer = self.frame.system.debug_system.draw(
+            self.frame.viewport_dimensions,
+            self.frame.projection * self.frame.view,
+            debug,
         );
+        debug.clear();
+
+        unsafe {
+            self.frame.command_buffer = Some(
+                self.frame
+                    .command_buffer
+                    .take()
+                    .unwrap()
+                    .execute_commands(command_buffer)
+                    .unwrap(),
+            );
+        }
+    }
+}
+
+pub struct TextPass<'f, 's: 'f> {
+    frame: &'f mut Frame<'s>,
+    items: Vec<TextItem>,
+}
+
+impl<'f, 's: 'f> TextPass<'f, 's> {
+    #[inline]
+    pub fn queue(&mut self, item: TextItem) {
+        self.items.push(item);
+    }
+
+    #[inline]
+    pub fn viewport_dimensions(&self) -> [u32; 2] {
+        self.frame.viewport_dimensions
+    }
+
+    /// Draws all queued items, panels first.
+    pub fn draw(mut self, text_drawer: &mut DrawText, image_num: usize) {
+        let dimensions = self.frame.viewport_dimensions;
+        let items: Vec<_> = {
+            let metrics = &self.frame.system.text_metrics;
+            self.items
+                .drain(..)
+                .map(|item| (item.layout(metrics, dimensions), item))
+                .collect()
+        };
+
+        let panels: Vec<_> = items
+            .iter()
+            .filter_map(|&(ref layout, ref item)| item.background.map(|color| (layout.bounds, color)))
+            .collect();
+        let panel_cb = self.frame.system.panel_system.draw(dimensions, &panels);
+
+        for &(ref layout, ref item) in &items {
+            for &(ref line, [x, y]) in &layout.lines {
+                text_drawer.queue_text(x, y, item.size, item.color, line);
+            }
+        }
+
+        let command_buffer = unsafe {
+            self.frame
+                .command_buffer
+                .take()
+                .unwrap()
+                .execute_commands(panel_cb)
+                .unwrap()
+                .end_render_pass()
+                .unwrap()
+        };
+        self.frame.in_render_pass = false;
+
+        self.frame.command_buffer = Some(command_buffer.draw_text(text_drawer, image_num));
+    }
+}
+
+pub struct UiPass<'f, 's: 'f> {
+    frame: &'f mut Frame<'s>,
+}
+
+impl<'f, 's: 'f> UiPass<'f, 's> {
+    /// Renders a finished imgui frame.
+    pub fn draw(&mut self, ui: Ui) {
+        let command_buffer = self.frame
+            .system
+            .ui_system
+            .draw(self.frame.viewport_dimensions, ui);
+
+        unsafe {
+            self.frame.command_buffer = Some(
+                self.frame
+                    .command_buffer
+                    .take()
+                    .unwrap()
+                    .execute_commands(command_buffer)
+                    .unwrap(),
+            );
+        }
+    }
+
+    #[inline]
+    pub fn viewport_dimensions(&self) -> [u32; 2] {
+        self.frame.viewport_dimensions
     }
 }
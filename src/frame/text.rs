@@ -0,0 +1,415 @@
+use std::sync::Arc;
+
+use rusttype::point;
+use rusttype::Font;
+use rusttype::FontCollection;
+use rusttype::Scale;
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::AutoCommandBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::DynamicState;
+use vulkano::device::Queue;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+
+/// The font vulkano_text draws with. It doesn't expose its copy, so text is measured with
+/// this one.
+const FONT_DATA: &[u8] = include_bytes!("DejaVuSans.ttf");
+
+/// Measures text the way vulkano_text lays it out.
+pub struct TextMetrics {
+    font: Font<'static>,
+}
+
+impl TextMetrics {
+    pub fn new() -> TextMetrics {
+        TextMetrics {
+            font: FontCollection::from_bytes(FONT_DATA).into_font().unwrap(),
+        }
+    }
+
+    /// Width in pixels of a single line of text, kerning included.
+    pub fn width(&self, line: &str, size: f32) -> f32 {
+        self.font
+            .layout(line, Scale::uniform(size), point(0.0, 0.0))
+            .last()
+            .map_or(0.0, |glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+    }
+
+    /// Distance in pixels from the top of a line to its baseline.
+    pub fn ascent(&self, size: f32) -> f32 {
+        self.font.v_metrics(Scale::uniform(size)).ascent
+    }
+}
+
+/// Which point of the screen a text item is positioned relative to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+    Center,
+}
+
+/// Alignment of the lines of a text item relative to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone)]
+pub struct TextItem {
+    pub text: String,
+    /// Offset in pixels from the anchor, pointing into the screen.
+    pub position: [f32; 2],
+    pub anchor: Anchor,
+    pub size: f32,
+    pub color: [f32; 4],
+    pub align: Align,
+    /// Distance between baselines, relative to `size`.
+    pub line_spacing: f32,
+    /// Color of the panel drawn behind the text, if any.
+    pub background: Option<[f32; 4]>,
+    pub padding: f32,
+}
+
+impl TextItem {
+    pub fn new(text: &str) -> TextItem {
+        TextItem {
+            text: text.to_owned(),
+            position: [10.0, 10.0],
+            anchor: Anchor::TopLeft,
+            size: 20.0,
+            color: [1.0, 1.0, 1.0, 1.0],
+            align: Align::Left,
+            line_spacing: 1.2,
+            background: None,
+            padding: 6.0,
+        }
+    }
+
+    #[inline]
+    pub fn position(mut self, x: f32, y: f32) -> TextItem {
+        self.position = [x, y];
+        self
+    }
+
+    #[inline]
+    pub fn anchor(mut self, anchor: Anchor) -> TextItem {
+        self.anchor = anchor;
+        self
+    }
+
+    #[inline]
+    pub fn size(mut self, size: f32) -> TextItem {
+        self.size = size;
+        self
+    }
+
+    #[inline]
+    pub fn color(mut self, color: [f32; 4]) -> TextItem {
+        self.color = color;
+        self
+    }
+
+    #[inline]
+    pub fn align(mut self, align: Align) -> TextItem {
+        self.align = align;
+        self
+    }
+
+    #[inline]
+    pub fn background(mut self, color: [f32; 4]) -> TextItem {
+        self.background = Some(color);
+        self
+    }
+
+    /// Lays out the item on a screen of `screen` pixels.
+    pub fn layout(&self, metrics: &TextMetrics, screen: [u32; 2]) -> TextLayout {
+        let line_height = self.size * self.line_spacing;
+        let widths: Vec<f32> = self.text
+            .lines()
+            .map(|line| metrics.width(line, self.size))
+            .collect();
+        let block_width = widths.iter().cloned().fold(0.0, f32::max);
+        let block_height = widths.len() as f32 * line_height;
+
+        let (screen_width, screen_height) = (screen[0] as f32, screen[1] as f32);
+        let [x, y] = self.position;
+        let (left, top) = match self.anchor {
+            Anchor::TopLeft => (x, y),
+            Anchor::TopRight => (screen_width - x - block_width, y),
+            Anchor::BottomLeft => (x, screen_height - y - block_height),
+            Anchor::BottomRight => (screen_width - x - block_width, screen_height - y - block_height),
+            Anchor::Center => (
+                (screen_width - block_width) / 2.0 + x,
+                (screen_height - block_height) / 2.0 + y,
+            ),
+        };
+
+        let lines = self.text
+            .lines()
+            .zip(widths.iter())
+            .enumerate()
+            .map(|(i, (line, &width))| {
+                let line_left = match self.align {
+                    Align::Left => left,
+                    Align::Center => left + (block_width - width) / 2.0,
+                    Align::Right => left + block_width - width,
+                };
+                let baseline = top + i as f32 * line_height + metrics.ascent(self.size);
+                (line.to_owned(), [line_left, baseline])
+            })
+            .collect();
+
+        TextLayout {
+            lines,
+            bounds: [
+                [left - self.padding, top - self.padding],
+                [left + block_width + self.padding, top + block_height + self.padding],
+            ],
+        }
+    }
+}
+
+pub struct TextLayout {
+    /// Every line with the position of the start of its baseline.
+    pub lines: Vec<(String, [f32; 2])>,
+    /// Top left and bottom right corner of the block, including padding.
+    pub bounds: [[f32; 2]; 2],
+}
+
+/// Draws the translucent panels behind text items.
+pub struct PanelSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer_pool: CpuBufferPool<PanelVertex>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+}
+
+impl PanelSystem {
+    pub fn new<R>(gfx_queue: Arc<Queue>, subpass: Subpass<R>) -> PanelSystem
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let pipeline = {
+            let vs = vs::Shader::load(gfx_queue.device().clone()).expect("Could not create shader module");
+            let fs = fs::Shader::load(gfx_queue.device().clone()).expect("Could not create shader module");
+
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input_single_buffer::<PanelVertex>()
+                    .vertex_shader(vs.main_entry_point(), ())
+                    .triangle_list()
+                    .viewports_dynamic_scissors_irrelevant(1)
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .blend_alpha_blending()
+                    .render_pass(subpass)
+                    .build(gfx_queue.device().clone())
+                    .unwrap(),
+            ) as Arc<_>
+        };
+
+        PanelSystem {
+            vertex_buffer_pool: CpuBufferPool::new(gfx_queue.device().clone(), BufferUsage::vertex_buffer()),
+            gfx_queue,
+            pipeline,
+        }
+    }
+
+    /// Draws one quad per `(bounds, color)`, with bounds in pixels.
+    pub fn draw(&self, viewport_dimensions: [u32; 2], panels: &[([[f32; 2]; 2], [f32; 4])]) -> AutoCommandBuffer {
+        let [width, height] = [viewport_dimensions[0] as f32, viewport_dimensions[1] as f32];
+        let to_ndc = |[x, y]: [f32; 2]| [x / width * 2.0 - 1.0, y / height * 2.0 - 1.0];
+
+        let vertices: Vec<PanelVertex> = panels
+            .iter()
+            .flat_map(|&([min, max], color)| {
+                let (a, b) = (to_ndc(min), to_ndc(max));
+                vec![
+                    PanelVertex { position: [a[0], a[1]], color },
+                    PanelVertex { position: [b[0], a[1]], color },
+                    PanelVertex { position: [a[0], b[1]], color },
+                    PanelVertex { position: [b[0], a[1]], color },
+                    PanelVertex { position: [b[0], b[1]], color },
+                    PanelVertex { position: [a[0], b[1]], color },
+                ]
+            })
+            .collect();
+
+        let builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            self.pipeline.clone().subpass(),
+        ).unwrap();
+
+        if vertices.is_empty() {
+            return builder.build().unwrap();
+        }
+
+        let vertex_buffer = self.vertex_buffer_pool.chunk(vertices).unwrap();
+
+        builder
+            .draw(
+                self.pipeline.clone(),
+                DynamicState {
+                    viewports: Some(vec![Viewport {
+                        origin: [0.0, 0.0],
+                        dimensions: [width, height],
+                        depth_range: 0.0..1.0,
+                    }]),
+                    ..DynamicState::none()
+                },
+                vec![Arc::new(vertex_buffer)],
+                (),
+                (),
+            )
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PanelVertex {
+    position: [f32; 2],
+    color: [f32; 4],
+}
+impl_vertex!(PanelVertex, position, color);
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 position;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec4 v_color;
+
+void main() {
+    v_color = color;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec4 v_color;
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    f_color = v_color;
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Align;
+    use super::Anchor;
+    use super::TextItem;
+    use super::TextMetrics;
+
+    const SCREEN: [u32; 2] = [800, 600];
+
+    fn item(text: &str) -> TextItem {
+        let mut item = TextItem::new(text).position(10.0, 20.0);
+        item.padding = 0.0;
+        item
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn widths_follow_the_glyphs() {
+        let metrics = TextMetrics::new();
+        assert_eq!(metrics.width("", 20.0), 0.0);
+        assert!(metrics.width("WWW", 20.0) > metrics.width("iii", 20.0));
+        assert_close(metrics.width("ab", 40.0), 2.0 * metrics.width("ab", 20.0));
+    }
+
+    #[test]
+    fn anchors_place_the_block_from_their_corner() {
+        let metrics = TextMetrics::new();
+        let width = metrics.width("Hello", 20.0);
+        let height = 20.0 * 1.2;
+
+        let bounds = |anchor| item("Hello").anchor(anchor).layout(&metrics, SCREEN).bounds;
+        assert_eq!(bounds(Anchor::TopLeft), [[10.0, 20.0], [10.0 + width, 20.0 + height]]);
+
+        let [[left, top], [right, bottom]] = bounds(Anchor::BottomRight);
+        assert_close(right, 790.0);
+        assert_close(bottom, 580.0);
+        assert_close(left, 790.0 - width);
+        assert_close(top, 580.0 - height);
+
+        let [[left, top], _] = bounds(Anchor::TopRight);
+        assert_close(left, 790.0 - width);
+        assert_close(top, 20.0);
+
+        let [[left, top], _] = bounds(Anchor::BottomLeft);
+        assert_close(left, 10.0);
+        assert_close(top, 580.0 - height);
+
+        let [[left, top], _] = bounds(Anchor::Center);
+        assert_close(left, (800.0 - width) / 2.0 + 10.0);
+        assert_close(top, (600.0 - height) / 2.0 + 20.0);
+    }
+
+    #[test]
+    fn lines_are_aligned_within_the_widest() {
+        let metrics = TextMetrics::new();
+        let long = metrics.width("A longer line", 20.0);
+        let short = metrics.width("Short", 20.0);
+
+        let starts = |align| -> Vec<f32> {
+            item("A longer line\nShort")
+                .align(align)
+                .layout(&metrics, SCREEN)
+                .lines
+                .iter()
+                .map(|&(_, [x, _])| x)
+                .collect()
+        };
+        assert_eq!(starts(Align::Left), [10.0, 10.0]);
+        let center = starts(Align::Center);
+        assert_close(center[0], 10.0);
+        assert_close(center[1], 10.0 + (long - short) / 2.0);
+        let right = starts(Align::Right);
+        assert_close(right[0], 10.0);
+        assert_close(right[1], 10.0 + long - short);
+    }
+
+    #[test]
+    fn lines_are_spaced_by_the_line_height() {
+        let metrics = TextMetrics::new();
+        let layout = item("one\ntwo\nthree").layout(&metrics, SCREEN);
+
+        let lines: Vec<_> = layout.lines.iter().map(|&(ref line, _)| line.as_str()).collect();
+        assert_eq!(lines, ["one", "two", "three"]);
+
+        let ascent = metrics.ascent(20.0);
+        assert!(ascent > 0.0 && ascent < 20.0);
+        for (i, &(_, [_, baseline])) in layout.lines.iter().enumerate() {
+            assert_close(baseline, 20.0 + i as f32 * 24.0 + ascent);
+        }
+        assert_close(layout.bounds[1][1], 20.0 + 3.0 * 24.0);
+    }
+}
@@ -0,0 +1,315 @@
+use std::sync::Arc;
+
+use vulkano::buffer::BufferAccess;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::Sampler;
+
+use super::fullscreen::clamp_sampler;
+use super::fullscreen::fullscreen_triangle;
+use super::fullscreen::viewport_state;
+use super::fullscreen::Vertex;
+use super::graph::Load;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::system::BLOOM_IMAGE;
+use super::system::FINAL_IMAGE;
+use super::system::HDR_IMAGE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tonemapper {
+    Reinhard,
+    AcesFilmic,
+    Uncharted2,
+}
+
+impl Tonemapper {
+    pub fn next(self) -> Tonemapper {
+        match self {
+            Tonemapper::Reinhard => Tonemapper::AcesFilmic,
+            Tonemapper::AcesFilmic => Tonemapper::Uncharted2,
+            Tonemapper::Uncharted2 => Tonemapper::Reinhard,
+        }
+    }
+
+    fn shader_index(self) -> i32 {
+        match self {
+            Tonemapper::Reinhard => 0,
+            Tonemapper::AcesFilmic => 1,
+            Tonemapper::Uncharted2 => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HdrSettings {
+    pub tonemapper: Tonemapper,
+    /// Exposure in EV. Acts as exposure compensation when `auto_exposure` is enabled.
+    pub exposure: f32,
+    pub auto_exposure: bool,
+    /// How fast the adapted luminance follows the scene luminance, per second.
+    pub adaptation_speed: f32,
+    pub min_log_luminance: f32,
+    pub max_log_luminance: f32,
+}
+
+impl Default for HdrSettings {
+    fn default() -> HdrSettings {
+        HdrSettings {
+            tonemapper: Tonemapper::AcesFilmic,
+            exposure: 0.0,
+            auto_exposure: true,
+            adaptation_speed: 1.5,
+            min_log_luminance: -10.0,
+            max_log_luminance: 6.0,
+        }
+    }
+}
+
+/// Returns true if the hardware performs the sRGB encoding when writing to `format`.
+pub fn is_srgb(format: Format) -> bool {
+    match format {
+        Format::R8Srgb
+        | Format::R8G8Srgb
+        | Format::R8G8B8Srgb
+        | Format::B8G8R8Srgb
+        | Format::R8G8B8A8Srgb
+        | Format::B8G8R8A8Srgb
+        | Format::A8B8G8R8SrgbPack32 => true,
+        _ => false,
+    }
+}
+
+/// Resolves the HDR scene image, with bloom added on top, into the output image.
+pub struct TonemapSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    sampler: Arc<Sampler>,
+    bloom_sampler: Arc<Sampler>,
+    exposure_buffer: Arc<BufferAccess + Send + Sync>,
+    encode_srgb: bool,
+}
+
+impl TonemapSystem {
+    pub fn new(
+        gfx_queue: Arc<Queue>,
+        output_format: Format,
+        exposure_buffer: Arc<BufferAccess + Send + Sync>,
+    ) -> TonemapSystem {
+        let vertex_buffer = fullscreen_triangle(&gfx_queue);
+
+        let sampler = clamp_sampler(gfx_queue.device(), Filter::Nearest);
+
+        let bloom_sampler = clamp_sampler(gfx_queue.device(), Filter::Linear);
+
+        TonemapSystem {
+            gfx_queue,
+            vertex_buffer,
+            pipeline: None,
+            sampler,
+            bloom_sampler,
+            exposure_buffer,
+            encode_srgb: !is_srgb(output_format),
+        }
+    }
+}
+
+impl RenderNode for TonemapSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.read(HDR_IMAGE)
+            .read(BLOOM_IMAGE)
+            .color(FINAL_IMAGE, Load::DontCare);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        let vs = vs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = fs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+
+        self.pipeline = Some(Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(subpass.unwrap())
+                .build(self.gfx_queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        let pipeline = self.pipeline.clone().unwrap();
+        let settings = &context.settings().hdr;
+        let bloom_settings = &context.settings().bloom;
+        let viewport_dimensions = context.dimensions(FINAL_IMAGE);
+
+        let descriptor_set = PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_sampled_image(context.image(HDR_IMAGE), self.sampler.clone())
+            .unwrap()
+            .add_buffer(self.exposure_buffer.clone())
+            .unwrap()
+            .add_sampled_image(context.image(BLOOM_IMAGE), self.bloom_sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let push_constants = if context.settings().render_mode.is_debug_view() {
+            fs::ty::PushConstants {
+                tonemapper: -1,
+                exposure: 1.0,
+                auto_exposure: 0,
+                encode_srgb: self.encode_srgb as i32,
+                bloom_intensity: 0.0,
+            }
+        } else {
+            fs::ty::PushConstants {
+                tonemapper: settings.tonemapper.shader_index(),
+                exposure: 2.0f32.powf(settings.exposure),
+                auto_exposure: settings.auto_exposure as i32,
+                encode_srgb: self.encode_srgb as i32,
+                bloom_intensity: if bloom_settings.enabled {
+                    bloom_settings.intensity
+                } else {
+                    0.0
+                },
+            }
+        };
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipeline.clone(),
+                viewport_state(viewport_dimensions),
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                push_constants,
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 position;
+
+void main() {
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D u_hdr;
+layout(set = 0, binding = 1) buffer Exposure {
+    float adapted_luminance;
+} exposure_state;
+layout(set = 0, binding = 2) uniform sampler2D u_bloom;
+
+layout(push_constant) uniform PushConstants {
+    int tonemapper;
+    float exposure;
+    int auto_exposure;
+    int encode_srgb;
+    float bloom_intensity;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+
+vec3 reinhard(vec3 color) {
+    return color / (1.0 + color);
+}
+
+// Narkowicz 2015, ACES filmic curve fit.
+vec3 aces_filmic(vec3 color) {
+    const float a = 2.51;
+    const float b = 0.03;
+    const float c = 2.43;
+    const float d = 0.59;
+    const float e = 0.14;
+    return clamp((color * (a * color + b)) / (color * (c * color + d) + e), 0.0, 1.0);
+}
+
+vec3 uncharted2_curve(vec3 x) {
+    const float A = 0.15;
+    const float B = 0.50;
+    const float C = 0.10;
+    const float D = 0.20;
+    const float E = 0.02;
+    const float F = 0.30;
+    return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
+}
+
+vec3 uncharted2(vec3 color) {
+    const float white_point = 11.2;
+    const float exposure_bias = 2.0;
+    vec3 curr = uncharted2_curve(exposure_bias * color);
+    vec3 white_scale = 1.0 / uncharted2_curve(vec3(white_point));
+    return curr * white_scale;
+}
+
+vec3 linear_to_srgb(vec3 color) {
+    vec3 low = color * 12.92;
+    vec3 high = 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, lessThanEqual(color, vec3(0.0031308)));
+}
+
+void main() {
+    vec3 hdr = texelFetch(u_hdr, ivec2(gl_FragCoord.xy), 0).rgb;
+    vec2 uv = gl_FragCoord.xy / vec2(textureSize(u_hdr, 0));
+    // The bloom image isn't written when bloom is disabled, and may hold anything.
+    if (push_constants.bloom_intensity > 0.0) {
+        hdr += texture(u_bloom, uv).rgb * push_constants.bloom_intensity;
+    }
+
+    float exposure = push_constants.exposure;
+    if (push_constants.auto_exposure != 0) {
+        exposure *= 0.18 / max(exposure_state.adapted_luminance, 0.0001);
+    }
+    vec3 color = hdr * exposure;
+
+    if (push_constants.tonemapper == 0) {
+        color = reinhard(color);
+    } else if (push_constants.tonemapper == 1) {
+        color = aces_filmic(color);
+    } else if (push_constants.tonemapper == 2) {
+        color = uncharted2(color);
+    }
+    // Anything else shows debug views unchanged.
+
+    if (push_constants.encode_srgb != 0) {
+        color = linear_to_srgb(clamp(color, 0.0, 1.0));
+    }
+
+    f_color = vec4(color, 1.0);
+}
+"]
+    struct Dummy;
+}
@@ -0,0 +1,281 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use cgmath::{InnerSpace, Matrix4, Point3, Transform};
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::ImageUsage;
+use vulkano::pipeline::blend::AttachmentBlend;
+use vulkano::pipeline::blend::BlendFactor;
+use vulkano::pipeline::blend::BlendOp;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::Sampler;
+
+use super::fullscreen::clamp_sampler;
+use super::fullscreen::fullscreen_triangle;
+use super::fullscreen::viewport_state;
+use super::fullscreen::Vertex;
+use super::graph::ImageDesc;
+use super::graph::ImageSize;
+use super::graph::Load;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::system::ACCUM_FORMAT;
+use super::system::ACCUM_IMAGE;
+use super::system::HDR_IMAGE;
+use super::system::REVEAL_FORMAT;
+use super::system::REVEAL_IMAGE;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransparencySettings {
+    /// Hands out `Pass::WeightedBlended`, for surfaces that intersect or can't be sorted.
+    pub order_independent: bool,
+}
+
+impl Default for TransparencySettings {
+    fn default() -> TransparencySettings {
+        TransparencySettings {
+            order_independent: false,
+        }
+    }
+}
+
+/// Sorts `items` so the one farthest from the camera of `view` comes first, which is the order
+/// `Pass::Transparent` expects them to be drawn in.
+pub fn sort_back_to_front<T, F>(items: &mut [T], view: Matrix4<f32>, position: F)
+where
+    F: Fn(&T) -> Point3<f32>,
+{
+    let distance = |item: &T| {
+        let in_view = view.transform_point(position(item));
+        (in_view - Point3::new(0.0, 0.0, 0.0)).magnitude2()
+    };
+    items.sort_by(|a, b| distance(b).partial_cmp(&distance(a)).unwrap_or(Ordering::Equal));
+}
+
+/// Blending of the first output of `Pass::WeightedBlended` pipelines, a plain sum.
+pub fn accumulation_blend() -> AttachmentBlend {
+    AttachmentBlend {
+        enabled: true,
+        color_op: BlendOp::Add,
+        color_source: BlendFactor::One,
+        color_destination: BlendFactor::One,
+        alpha_op: BlendOp::Add,
+        alpha_source: BlendFactor::One,
+        alpha_destination: BlendFactor::One,
+        ..AttachmentBlend::pass_through()
+    }
+}
+
+/// Blending of the second output of `Pass::WeightedBlended` pipelines, the product of one
+/// minus each surface's coverage.
+pub fn revealage_blend() -> AttachmentBlend {
+    AttachmentBlend {
+        enabled: true,
+        color_op: BlendOp::Add,
+        color_source: BlendFactor::Zero,
+        color_destination: BlendFactor::OneMinusSrcColor,
+        alpha_op: BlendOp::Add,
+        alpha_source: BlendFactor::Zero,
+        alpha_destination: BlendFactor::OneMinusSrcAlpha,
+        ..AttachmentBlend::pass_through()
+    }
+}
+
+/// Resolves the accumulation and revealage images of weighted blended order independent
+/// transparency (McGuire and Bavoil 2013) over the HDR image.
+///
+/// Geometry drawn in `Pass::WeightedBlended` writes two outputs, with the blending set up by
+/// `accumulation_blend` and `revealage_blend`:
+///
+/// ```glsl
+/// layout(location = 0) out vec4 f_accum;  // vec4(color.rgb * color.a, color.a) * weight
+/// layout(location = 1) out float f_reveal; // color.a
+/// ```
+pub struct WeightedBlendedCompositeSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    sampler: Arc<Sampler>,
+}
+
+impl WeightedBlendedCompositeSystem {
+    pub fn new(gfx_queue: Arc<Queue>) -> WeightedBlendedCompositeSystem {
+        let vertex_buffer = fullscreen_triangle(&gfx_queue);
+
+        let sampler = clamp_sampler(gfx_queue.device(), Filter::Nearest);
+
+        WeightedBlendedCompositeSystem {
+            gfx_queue,
+            vertex_buffer,
+            pipeline: None,
+            sampler,
+        }
+    }
+
+    pub fn accum_image_desc() -> ImageDesc {
+        ImageDesc {
+            format: ACCUM_FORMAT,
+            size: ImageSize::Output,
+            usage: ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            },
+        }
+    }
+
+    pub fn reveal_image_desc() -> ImageDesc {
+        ImageDesc {
+            format: REVEAL_FORMAT,
+            size: ImageSize::Output,
+            usage: ImageUsage {
+                color_attachment: true,
+                sampled: true,
+                ..ImageUsage::none()
+            },
+        }
+    }
+}
+
+impl RenderNode for WeightedBlendedCompositeSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        decl.read(ACCUM_IMAGE)
+            .read(REVEAL_IMAGE)
+            .color(HDR_IMAGE, Load::Load);
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        let vs = vs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = fs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+
+        self.pipeline = Some(Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .blend_alpha_blending()
+                .render_pass(subpass.unwrap())
+                .build(self.gfx_queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        // Nothing was accumulated, `Frame` skipped the weighted blended pass.
+        if !context.settings().transparency.order_independent {
+            return;
+        }
+        let pipeline = self.pipeline.clone().unwrap();
+        let viewport_dimensions = context.dimensions(HDR_IMAGE);
+
+        let descriptor_set = PersistentDescriptorSet::start(pipeline.clone(), 0)
+            .add_sampled_image(context.image(ACCUM_IMAGE), self.sampler.clone())
+            .unwrap()
+            .add_sampled_image(context.image(REVEAL_IMAGE), self.sampler.clone())
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let command_buffer = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap()
+            .draw(
+                pipeline.clone(),
+                viewport_state(viewport_dimensions),
+                vec![self.vertex_buffer.clone()],
+                descriptor_set,
+                (),
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        context.execute(command_buffer);
+    }
+}
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 position;
+
+void main() {
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D u_accum;
+layout(set = 0, binding = 1) uniform sampler2D u_reveal;
+
+layout(location = 0) out vec4 f_color;
+
+void main() {
+    ivec2 texel = ivec2(gl_FragCoord.xy);
+    float reveal = texelFetch(u_reveal, texel, 0).r;
+    if (reveal >= 1.0) {
+        // No transparent surface covers this pixel.
+        discard;
+    }
+    vec4 accum = texelFetch(u_accum, texel, 0);
+    vec3 average = accum.rgb / clamp(accum.a, 1e-4, 5e4);
+    f_color = vec4(average, 1.0 - reveal);
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Matrix4, Point3, Vector3};
+
+    use super::sort_back_to_front;
+
+    #[test]
+    fn farthest_from_the_camera_comes_first() {
+        let view = Matrix4::look_at(
+            Point3::new(0.0, 0.0, 5.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+        );
+        let mut items = vec![
+            ("near", Point3::new(0.0, 0.0, 4.0)),
+            ("far", Point3::new(0.0, 0.0, -10.0)),
+            ("middle", Point3::new(1.0, 0.0, 0.0)),
+            // Behind the camera, but still farther than `near`.
+            ("behind", Point3::new(0.0, 0.0, 8.0)),
+        ];
+        sort_back_to_front(&mut items, view, |item| item.1);
+        let names: Vec<_> = items.iter().map(|item| item.0).collect();
+        assert_eq!(names, ["far", "middle", "behind", "near"]);
+    }
+
+    #[test]
+    fn nan_positions_dont_panic() {
+        let mut items = vec![Point3::new(0.0, 0.0, 1.0), Point3::new(::std::f32::NAN, 0.0, 0.0)];
+        sort_back_to_front(&mut items, Matrix4::from_scale(1.0), |point| *point);
+        assert_eq!(items.len(), 2);
+    }
+}
@@ -0,0 +1,252 @@
+use std::sync::Arc;
+
+use imgui::{ImGui, Ui};
+use vulkano::buffer::BufferUsage;
+use vulkano::buffer::CpuBufferPool;
+use vulkano::command_buffer::AutoCommandBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::DynamicState;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::descriptor::DescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::Dimensions;
+use vulkano::image::ImmutableImage;
+use vulkano::pipeline::viewport::Scissor;
+use vulkano::pipeline::viewport::Viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sync::GpuFuture;
+
+use super::fullscreen::clamp_sampler;
+use super::tonemap::is_srgb;
+
+/// Renders the draw lists of an imgui frame on top of the final image.
+pub struct UiSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer_pool: CpuBufferPool<UiVertex>,
+    index_buffer_pool: CpuBufferPool<u16>,
+    pipeline: Arc<GraphicsPipelineAbstract + Send + Sync>,
+    /// The font atlas, only known once `set_fonts` has been called.
+    font_set: Option<Arc<DescriptorSet + Send + Sync>>,
+    decode_srgb: bool,
+}
+
+impl UiSystem {
+    pub fn new<R>(gfx_queue: Arc<Queue>, subpass: Subpass<R>, output_format: Format) -> UiSystem
+    where
+        R: RenderPassAbstract + Send + Sync + 'static,
+    {
+        let pipeline = {
+            let vs = vs::Shader::load(gfx_queue.device().clone()).expect("Could not create shader module");
+            let fs = fs::Shader::load(gfx_queue.device().clone()).expect("Could not create shader module");
+
+            Arc::new(
+                GraphicsPipeline::start()
+                    .vertex_input_single_buffer::<UiVertex>()
+                    .vertex_shader(vs.main_entry_point(), ())
+                    .triangle_list()
+                    .viewports_scissors_dynamic(1)
+                    .fragment_shader(fs.main_entry_point(), ())
+                    .blend_alpha_blending()
+                    .render_pass(subpass)
+                    .build(gfx_queue.device().clone())
+                    .unwrap(),
+            ) as Arc<_>
+        };
+
+        UiSystem {
+            vertex_buffer_pool: CpuBufferPool::new(gfx_queue.device().clone(), BufferUsage::vertex_buffer()),
+            index_buffer_pool: CpuBufferPool::new(gfx_queue.device().clone(), BufferUsage::index_buffer()),
+            gfx_queue,
+            pipeline,
+            font_set: None,
+            decode_srgb: is_srgb(output_format),
+        }
+    }
+
+    /// Uploads the font atlas of `imgui`. Waits for the upload, so only meant for startup.
+    pub fn set_fonts(&mut self, imgui: &mut ImGui) {
+        let queue = self.gfx_queue.clone();
+        let (texture, upload_future) = imgui.prepare_texture(|handle| {
+            ImmutableImage::from_iter(
+                handle.pixels.iter().cloned(),
+                Dimensions::Dim2d {
+                    width: handle.width,
+                    height: handle.height,
+                },
+                Format::R8G8B8A8Unorm,
+                queue,
+            ).expect("Failed to create font texture")
+        });
+        upload_future
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+
+        let sampler = clamp_sampler(self.gfx_queue.device(), Filter::Linear);
+
+        self.font_set = Some(Arc::new(
+            PersistentDescriptorSet::start(self.pipeline.clone(), 0)
+                .add_sampled_image(texture, sampler)
+                .unwrap()
+                .build()
+                .unwrap(),
+        ));
+    }
+
+    pub fn draw(&self, viewport_dimensions: [u32; 2], ui: Ui) -> AutoCommandBuffer {
+        let font_set = self.font_set
+            .clone()
+            .expect("FrameSystem::set_ui_fonts has to be called before drawing a UI");
+
+        let [width, height] = [viewport_dimensions[0] as f32, viewport_dimensions[1] as f32];
+        let viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [width, height],
+            depth_range: 0.0..1.0,
+        };
+        let push_constants = vs::ty::PushConstants {
+            scale: [2.0 / width, 2.0 / height],
+            decode_srgb: self.decode_srgb as u32,
+        };
+
+        let mut builder = Some(
+            AutoCommandBufferBuilder::secondary_graphics(
+                self.gfx_queue.device().clone(),
+                self.gfx_queue.family(),
+                self.pipeline.clone().subpass(),
+            ).unwrap(),
+        );
+
+        ui.render::<_, ()>(|_, draw_list| {
+            if draw_list.vtx_buffer.is_empty() {
+                return Ok(());
+            }
+
+            let vertices: Vec<UiVertex> = draw_list
+                .vtx_buffer
+                .iter()
+                .map(|vertex| UiVertex {
+                    pos: [vertex.pos.x, vertex.pos.y],
+                    uv: [vertex.uv.x, vertex.uv.y],
+                    col: vertex.col,
+                })
+                .collect();
+            let vertex_buffer = Arc::new(self.vertex_buffer_pool.chunk(vertices).unwrap());
+
+            let mut index_offset = 0;
+            for command in draw_list.cmd_buffer {
+                let count = command.elem_count as usize;
+                let indices = &draw_list.idx_buffer[index_offset..index_offset + count];
+                index_offset += count;
+
+                // Clip rectangles are given as min and max corners, clamp them to the screen.
+                let clip = command.clip_rect;
+                let min = [clip.x.max(0.0), clip.y.max(0.0)];
+                let max = [clip.z.min(width), clip.w.min(height)];
+                if count == 0 || max[0] <= min[0] || max[1] <= min[1] {
+                    continue;
+                }
+
+                let index_buffer = self.index_buffer_pool.chunk(indices.iter().cloned()).unwrap();
+                let dynamic_state = DynamicState {
+                    viewports: Some(vec![viewport.clone()]),
+                    scissors: Some(vec![Scissor {
+                        origin: [min[0] as i32, min[1] as i32],
+                        dimensions: [(max[0] - min[0]) as u32, (max[1] - min[1]) as u32],
+                    }]),
+                    ..DynamicState::none()
+                };
+
+                builder = Some(
+                    builder
+                        .take()
+                        .unwrap()
+                        .draw_indexed(
+                            self.pipeline.clone(),
+                            dynamic_state,
+                            vec![vertex_buffer.clone()],
+                            index_buffer,
+                            font_set.clone(),
+                            push_constants.clone(),
+                        )
+                        .unwrap(),
+                );
+            }
+
+            Ok(())
+        }).unwrap();
+
+        builder.unwrap().build().unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct UiVertex {
+    pos: [f32; 2],
+    uv: [f32; 2],
+    /// Packed 8 bit RGBA, as imgui gives it.
+    col: u32,
+}
+impl_vertex!(UiVertex, pos, uv, col);
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 pos;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in uint col;
+
+layout(location = 0) out vec2 v_uv;
+layout(location = 1) out vec4 v_color;
+
+layout(push_constant) uniform PushConstants {
+    vec2 scale;
+    uint decode_srgb;
+} push_constants;
+
+void main() {
+    vec4 color = unpackUnorm4x8(col);
+    // imgui colors are meant to end up on screen as they are.
+    if (push_constants.decode_srgb != 0) {
+        color.rgb = mix(
+            color.rgb / 12.92,
+            pow((color.rgb + 0.055) / 1.055, vec3(2.4)),
+            greaterThan(color.rgb, vec3(0.04045)));
+    }
+
+    v_uv = uv;
+    v_color = color;
+    gl_Position = vec4(pos * push_constants.scale - 1.0, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 v_uv;
+layout(location = 1) in vec4 v_color;
+
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform sampler2D u_font;
+
+void main() {
+    f_color = v_color * texture(u_font, v_uv);
+}
+"]
+    struct Dummy;
+}
@@ -0,0 +1,315 @@
+use std::sync::Arc;
+
+use cgmath::Matrix4;
+use vulkano::buffer::CpuAccessibleBuffer;
+use vulkano::command_buffer::AutoCommandBufferBuilder;
+use vulkano::command_buffer::DynamicState;
+use vulkano::descriptor::descriptor_set::PersistentDescriptorSet;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::framebuffer::RenderPassAbstract;
+use vulkano::framebuffer::Subpass;
+use vulkano::image::ImageViewAccess;
+use vulkano::pipeline::viewport;
+use vulkano::pipeline::GraphicsPipeline;
+use vulkano::pipeline::GraphicsPipelineAbstract;
+use vulkano::sampler::Filter;
+use vulkano::sampler::Sampler;
+use vulkano::sync::GpuFuture;
+
+use super::fullscreen::clamp_sampler;
+use super::fullscreen::fullscreen_triangle;
+use super::fullscreen::Vertex;
+use super::graph::Load;
+use super::graph::PassContext;
+use super::graph::PassDecl;
+use super::graph::RenderNode;
+use super::system::Frame;
+use super::system::FrameSystem;
+use super::system::FINAL_IMAGE;
+use super::tonemap::is_srgb;
+
+/// A rectangle of the output image in pixels, with its origin at the top left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ViewportRect {
+    pub origin: [u32; 2],
+    pub dimensions: [u32; 2],
+}
+
+impl ViewportRect {
+    /// Whether a position in window pixels, like that of the cursor, is inside.
+    pub fn contains(&self, position: [f64; 2]) -> bool {
+        let [x, y] = position;
+        let [left, top] = [self.origin[0] as f64, self.origin[1] as f64];
+        x >= left && y >= top && x < left + self.dimensions[0] as f64 && y < top + self.dimensions[1] as f64
+    }
+
+    #[inline]
+    pub fn aspect_ratio(&self) -> f32 {
+        self.dimensions[0].max(1) as f32 / self.dimensions[1].max(1) as f32
+    }
+
+    /// The dynamic viewport that draws into the rectangle.
+    pub fn dynamic_state(&self) -> DynamicState {
+        DynamicState {
+            viewports: Some(vec![viewport::Viewport {
+                origin: [self.origin[0] as f32, self.origin[1] as f32],
+                dimensions: [self.dimensions[0] as f32, self.dimensions[1] as f32],
+                depth_range: 0.0..1.0,
+            }]),
+            ..DynamicState::none()
+        }
+    }
+}
+
+/// One view of a split screen. It has a frame system of its own, so its own render settings,
+/// and renders into an image the size of its rectangle, which `FrameSystem::composite_frame`
+/// then draws into the output image at that rectangle.
+///
+/// The viewport's frames are recorded like any other, with the scene drawn by the user. Their
+/// `Pass::Text` and `Pass::Ui` can be ignored, overlays go on the composited frame instead.
+pub struct Viewport {
+    frame_system: FrameSystem,
+    rect: ViewportRect,
+}
+
+impl Viewport {
+    /// `depth_format` should be that of the window's frame system, so pipelines built against
+    /// its subpasses can draw in the viewport's as well.
+    pub fn new(queue: Arc<Queue>, rect: ViewportRect, depth_format: Format) -> Viewport {
+        Viewport {
+            frame_system: FrameSystem::headless_with_depth_format(queue, rect.dimensions, depth_format),
+            rect,
+        }
+    }
+
+    #[inline]
+    pub fn rect(&self) -> ViewportRect {
+        self.rect
+    }
+
+    /// Moves the viewport, reallocating its images when its size changes.
+    pub fn set_rect(&mut self, rect: ViewportRect) {
+        if rect.dimensions != self.rect.dimensions {
+            self.frame_system.set_headless_dimensions(rect.dimensions);
+        }
+        self.rect = rect;
+    }
+
+    #[inline]
+    pub fn frame_system(&self) -> &FrameSystem {
+        &self.frame_system
+    }
+
+    #[inline]
+    pub fn frame_system_mut(&mut self) -> &mut FrameSystem {
+        &mut self.frame_system
+    }
+
+    /// Starts a frame of the viewport. Its `Pass::Finished` future has to be part of the one
+    /// given to `FrameSystem::composite_frame`.
+    pub fn frame<F>(&mut self, before_future: F, view: Matrix4<f32>, projection: Matrix4<f32>) -> Frame
+    where
+        F: GpuFuture + 'static,
+    {
+        self.frame_system.frame(before_future, 0, view, projection)
+    }
+
+    pub fn image(&self) -> ViewportImage {
+        ViewportImage {
+            image: self.frame_system.output_image(),
+            rect: self.rect,
+        }
+    }
+}
+
+/// The rendered image of a viewport, or any image drawn over a frame with
+/// `FrameSystem::add_overlay`, and where it goes. The image is stretched over the rectangle.
+#[derive(Clone)]
+pub struct ViewportImage {
+    pub image: Arc<ImageViewAccess + Send + Sync>,
+    pub rect: ViewportRect,
+}
+
+/// Draws the images of the viewports of a split screen frame into the output image. Only runs
+/// in frames started with `FrameSystem::composite_frame`, where it replaces the whole scene.
+/// The `overlays` variant draws the overlays of a frame on top of it instead.
+pub struct ViewportCompositeSystem {
+    gfx_queue: Arc<Queue>,
+    vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
+    pipeline: Option<Arc<GraphicsPipelineAbstract + Send + Sync>>,
+    sampler: Arc<Sampler>,
+    encode_srgb: bool,
+    overlays: bool,
+}
+
+impl ViewportCompositeSystem {
+    pub fn new(gfx_queue: Arc<Queue>, output_format: Format) -> ViewportCompositeSystem {
+        ViewportCompositeSystem::with_images(gfx_queue, output_format, false)
+    }
+
+    pub fn overlays(gfx_queue: Arc<Queue>, output_format: Format) -> ViewportCompositeSystem {
+        ViewportCompositeSystem::with_images(gfx_queue, output_format, true)
+    }
+
+    fn with_images(gfx_queue: Arc<Queue>, output_format: Format, overlays: bool) -> ViewportCompositeSystem {
+        let vertex_buffer = fullscreen_triangle(&gfx_queue);
+
+        let sampler = clamp_sampler(gfx_queue.device(), Filter::Linear);
+
+        ViewportCompositeSystem {
+            gfx_queue,
+            vertex_buffer,
+            pipeline: None,
+            sampler,
+            encode_srgb: !is_srgb(output_format),
+            overlays,
+        }
+    }
+}
+
+impl RenderNode for ViewportCompositeSystem {
+    fn declare(&self, decl: &mut PassDecl) {
+        if self.overlays {
+            decl.color(FINAL_IMAGE, Load::Load);
+        } else {
+            // Whatever the viewports leave uncovered stays black.
+            decl.color(FINAL_IMAGE, Load::Clear([0.0, 0.0, 0.0, 1.0].into()));
+        }
+    }
+
+    fn prepare(&mut self, subpass: Option<Subpass<Arc<RenderPassAbstract + Send + Sync>>>) {
+        let vs = vs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+        let fs = fs::Shader::load(self.gfx_queue.device().clone()).expect("Could not create shader module");
+
+        self.pipeline = Some(Arc::new(
+            GraphicsPipeline::start()
+                .vertex_input_single_buffer::<Vertex>()
+                .vertex_shader(vs.main_entry_point(), ())
+                .triangle_list()
+                .viewports_dynamic_scissors_irrelevant(1)
+                .fragment_shader(fs.main_entry_point(), ())
+                .render_pass(subpass.unwrap())
+                .build(self.gfx_queue.device().clone())
+                .unwrap(),
+        ) as Arc<_>);
+    }
+
+    fn record(&mut self, context: &mut PassContext) {
+        let pipeline = self.pipeline.clone().unwrap();
+        let mut builder = AutoCommandBufferBuilder::secondary_graphics(
+            self.gfx_queue.device().clone(),
+            self.gfx_queue.family(),
+            pipeline.clone().subpass(),
+        ).unwrap();
+
+        // The fullscreen triangle clipped to a rectangle covers its image exactly.
+        let images = if self.overlays {
+            context.overlays().to_vec()
+        } else {
+            context.viewports().to_vec()
+        };
+        for viewport in images {
+            let descriptor_set = PersistentDescriptorSet::start(pipeline.clone(), 0)
+                .add_sampled_image(viewport.image.clone(), self.sampler.clone())
+                .unwrap()
+                .build()
+                .unwrap();
+            let push_constants = fs::ty::PushConstants {
+                origin: [viewport.rect.origin[0] as f32, viewport.rect.origin[1] as f32],
+                size: [viewport.rect.dimensions[0] as f32, viewport.rect.dimensions[1] as f32],
+                encode_srgb: self.encode_srgb as i32,
+            };
+
+            builder = builder
+                .draw(
+                    pipeline.clone(),
+                    viewport.rect.dynamic_state(),
+                    vec![self.vertex_buffer.clone()],
+                    descriptor_set,
+                    push_constants,
+                )
+                .unwrap();
+        }
+
+        context.execute(builder.build().unwrap());
+    }
+}
+
+mod vs {
+    #[derive(VulkanoShader)]
+    #[ty = "vertex"]
+    #[src = "
+#version 450
+
+layout(location = 0) in vec2 position;
+
+void main() {
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+mod fs {
+    #[derive(VulkanoShader)]
+    #[ty = "fragment"]
+    #[src = "
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D u_viewport;
+
+layout(push_constant) uniform PushConstants {
+    vec2 origin;
+    vec2 size;
+    int encode_srgb;
+} push_constants;
+
+layout(location = 0) out vec4 f_color;
+
+vec3 linear_to_srgb(vec3 color) {
+    vec3 low = color * 12.92;
+    vec3 high = 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, lessThanEqual(color, vec3(0.0031308)));
+}
+
+void main() {
+    // The viewport images are sRGB, so this is linear whatever the output format.
+    vec2 uv = (gl_FragCoord.xy - push_constants.origin) / push_constants.size;
+    vec3 color = texture(u_viewport, uv).rgb;
+    if (push_constants.encode_srgb != 0) {
+        color = linear_to_srgb(clamp(color, 0.0, 1.0));
+    }
+    f_color = vec4(color, 1.0);
+}
+"]
+    struct Dummy;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ViewportRect;
+
+    #[test]
+    fn contains_is_half_open() {
+        let rect = ViewportRect {
+            origin: [10, 20],
+            dimensions: [30, 40],
+        };
+        assert!(rect.contains([10.0, 20.0]));
+        assert!(rect.contains([39.9, 59.9]));
+        assert!(!rect.contains([40.0, 30.0]));
+        assert!(!rect.contains([20.0, 60.0]));
+        assert!(!rect.contains([9.9, 30.0]));
+        assert!(!rect.contains([20.0, 19.9]));
+    }
+
+    #[test]
+    fn empty_rect_contains_nothing() {
+        let rect = ViewportRect {
+            origin: [10, 20],
+            dimensions: [0, 0],
+        };
+        assert!(!rect.contains([10.0, 20.0]));
+    }
+}
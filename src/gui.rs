@@ -0,0 +1,393 @@
+use std::collections::VecDeque;
+
+use cgmath::Vector3;
+use imgui::{ImGui, ImGuiKey, ImGuiSetCond_FirstUseEver, ImStr, Ui};
+use winit;
+
+use camera::Camera;
+use frame::{Background, GpuSceneStats, InstanceStats, RenderMode, RenderSettings, Tonemapper, MAX_OUTLINE_WIDTH,
+            MAX_SSAO_SAMPLES};
+
+const TONEMAPPERS: [Tonemapper; 3] = [Tonemapper::Reinhard, Tonemapper::AcesFilmic, Tonemapper::Uncharted2];
+const RENDER_MODES: [RenderMode; 8] = [
+    RenderMode::Shaded,
+    RenderMode::Wireframe,
+    RenderMode::ShadedWireframe,
+    RenderMode::Normals,
+    RenderMode::LinearDepth,
+    RenderMode::UvChecker,
+    RenderMode::Overdraw,
+    RenderMode::AmbientOcclusion,
+];
+const FRAME_TIME_HISTORY: usize = 120;
+
+/// Numbers shown in the frame stats panel.
+pub struct FrameStats {
+    pub fps: i64,
+    pub render_time_ms: f32,
+    pub cpu_time_ms: f32,
+    /// Owned by emitters, alive or not.
+    pub particles: u32,
+    pub instances: InstanceStats,
+    pub gpu_scene: GpuSceneStats,
+}
+
+/// Owns the imgui context, feeds it winit input and builds the debug panels.
+pub struct Gui {
+    imgui: ImGui,
+    mouse_down: [bool; 5],
+    want_capture_mouse: bool,
+    want_capture_keyboard: bool,
+    frame_times: VecDeque<f32>,
+    pub visible: bool,
+}
+
+impl Gui {
+    pub fn new() -> Gui {
+        let mut imgui = ImGui::init();
+        imgui.set_ini_filename(None);
+
+        // Key indices are the ImGuiKey values themselves, see `key_index`.
+        for &key in &[
+            ImGuiKey::Tab,
+            ImGuiKey::LeftArrow,
+            ImGuiKey::RightArrow,
+            ImGuiKey::UpArrow,
+            ImGuiKey::DownArrow,
+            ImGuiKey::PageUp,
+            ImGuiKey::PageDown,
+            ImGuiKey::Home,
+            ImGuiKey::End,
+            ImGuiKey::Delete,
+            ImGuiKey::Backspace,
+            ImGuiKey::Enter,
+            ImGuiKey::Escape,
+            ImGuiKey::A,
+            ImGuiKey::C,
+            ImGuiKey::V,
+            ImGuiKey::X,
+            ImGuiKey::Y,
+            ImGuiKey::Z,
+        ] {
+            imgui.set_imgui_key(key, key as u8);
+        }
+
+        Gui {
+            imgui,
+            mouse_down: [false; 5],
+            want_capture_mouse: false,
+            want_capture_keyboard: false,
+            frame_times: VecDeque::with_capacity(FRAME_TIME_HISTORY),
+            visible: true,
+        }
+    }
+
+    #[inline]
+    pub fn imgui_mut(&mut self) -> &mut ImGui {
+        &mut self.imgui
+    }
+
+    /// Whether the UI used the keyboard last frame, in which case the scene shouldn't react to it.
+    #[inline]
+    pub fn wants_keyboard(&self) -> bool {
+        self.visible && self.want_capture_keyboard
+    }
+
+    #[inline]
+    pub fn wants_mouse(&self) -> bool {
+        self.visible && self.want_capture_mouse
+    }
+
+    pub fn handle_event(&mut self, event: &winit::WindowEvent) {
+        match *event {
+            winit::WindowEvent::CursorMoved {
+                position: (x, y), ..
+            } => self.imgui.set_mouse_pos(x as f32, y as f32),
+            winit::WindowEvent::MouseInput { state, button, .. } => {
+                let index = match button {
+                    winit::MouseButton::Left => 0,
+                    winit::MouseButton::Right => 1,
+                    winit::MouseButton::Middle => 2,
+                    winit::MouseButton::Other(n) if n < 2 => 3 + n as usize,
+                    winit::MouseButton::Other(_) => return,
+                };
+                self.mouse_down[index] = state == winit::ElementState::Pressed;
+                self.imgui.set_mouse_down(&self.mouse_down);
+            }
+            winit::WindowEvent::MouseWheel { delta, .. } => {
+                let lines = match delta {
+                    winit::MouseScrollDelta::LineDelta(_, y) => y,
+                    winit::MouseScrollDelta::PixelDelta(_, y) => y / 20.0,
+                };
+                self.imgui.set_mouse_wheel(lines);
+            }
+            winit::WindowEvent::ReceivedCharacter(character) => {
+                self.imgui.add_input_character(character);
+            }
+            winit::WindowEvent::KeyboardInput { input, .. } => {
+                let pressed = input.state == winit::ElementState::Pressed;
+                self.imgui.set_key_ctrl(input.modifiers.ctrl);
+                self.imgui.set_key_shift(input.modifiers.shift);
+                self.imgui.set_key_alt(input.modifiers.alt);
+                self.imgui.set_key_super(input.modifiers.logo);
+                if let Some(key) = input.virtual_keycode.and_then(key_index) {
+                    self.imgui.set_key(key, pressed);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Starts a UI frame and builds the camera, render settings and frame stats panels. The
+    /// returned `Ui` is drawn by `frame::Pass::Ui`.
+    pub fn frame<'a>(
+        &'a mut self,
+        dimensions: [u32; 2],
+        dt: f32,
+        camera: &mut Camera,
+        settings: &mut RenderSettings,
+        stats: &FrameStats,
+    ) -> Ui<'a> {
+        if self.frame_times.len() == FRAME_TIME_HISTORY {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(stats.render_time_ms);
+        let frame_times: Vec<f32> = self.frame_times.iter().cloned().collect();
+
+        let size = (dimensions[0], dimensions[1]);
+        // imgui asserts on a zero delta, which the first frames report.
+        let ui = self.imgui.frame(size, size, dt.max(0.001));
+        self.want_capture_mouse = ui.want_capture_mouse();
+        self.want_capture_keyboard = ui.want_capture_keyboard();
+
+        if self.visible {
+            stats_panel(&ui, stats, &frame_times);
+            camera_panel(&ui, camera);
+            render_settings_panel(&ui, settings);
+        }
+
+        ui
+    }
+}
+
+fn key_index(key: winit::VirtualKeyCode) -> Option<u8> {
+    let key = match key {
+        winit::VirtualKeyCode::Tab => ImGuiKey::Tab,
+        winit::VirtualKeyCode::Left => ImGuiKey::LeftArrow,
+        winit::VirtualKeyCode::Right => ImGuiKey::RightArrow,
+        winit::VirtualKeyCode::Up => ImGuiKey::UpArrow,
+        winit::VirtualKeyCode::Down => ImGuiKey::DownArrow,
+        winit::VirtualKeyCode::PageUp => ImGuiKey::PageUp,
+        winit::VirtualKeyCode::PageDown => ImGuiKey::PageDown,
+        winit::VirtualKeyCode::Home => ImGuiKey::Home,
+        winit::VirtualKeyCode::End => ImGuiKey::End,
+        winit::VirtualKeyCode::Delete => ImGuiKey::Delete,
+        winit::VirtualKeyCode::Back => ImGuiKey::Backspace,
+        winit::VirtualKeyCode::Return => ImGuiKey::Enter,
+        winit::VirtualKeyCode::Escape => ImGuiKey::Escape,
+        winit::VirtualKeyCode::A => ImGuiKey::A,
+        winit::VirtualKeyCode::C => ImGuiKey::C,
+        winit::VirtualKeyCode::V => ImGuiKey::V,
+        winit::VirtualKeyCode::X => ImGuiKey::X,
+        winit::VirtualKeyCode::Y => ImGuiKey::Y,
+        winit::VirtualKeyCode::Z => ImGuiKey::Z,
+        _ => return None,
+    };
+    Some(key as u8)
+}
+
+fn stats_panel(ui: &Ui, stats: &FrameStats, frame_times: &[f32]) {
+    ui.window(im_str!("Frame stats"))
+        .position((10.0, 90.0), ImGuiSetCond_FirstUseEver)
+        .always_auto_resize(true)
+        .build(|| {
+            ui.text(im_str!("{} FPS", stats.fps));
+            ui.text(im_str!("Render time: {:.2} ms", stats.render_time_ms));
+            ui.text(im_str!("CPU time: {:.2} ms", stats.cpu_time_ms));
+            ui.text(im_str!("Particles: {}", stats.particles));
+            ui.text(im_str!(
+                "Instances: {} drawn, {} culled, {} batches",
+                stats.instances.drawn,
+                stats.instances.culled,
+                stats.instances.batches
+            ));
+            ui.text(im_str!(
+                "GPU scene: {} objects, {} indirect draws",
+                stats.gpu_scene.objects,
+                stats.gpu_scene.batches
+            ));
+            ui.plot_lines(im_str!("Frame time"), frame_times)
+                .scale_min(0.0)
+                .build();
+        });
+}
+
+fn camera_panel(ui: &Ui, camera: &mut Camera) {
+    ui.window(im_str!("Camera"))
+        .position((10.0, 230.0), ImGuiSetCond_FirstUseEver)
+        .always_auto_resize(true)
+        .build(|| {
+            let mut position: [f32; 3] = camera.position().into();
+            if ui.input_float3(im_str!("Position"), &mut position).build() {
+                camera.set_position(Vector3::from(position));
+            }
+
+            let (mut yaw, mut pitch) = camera.orientation();
+            let yaw_changed = ui.slider_float(im_str!("Yaw"), &mut yaw, -180.0, 180.0).build();
+            let pitch_changed = ui.slider_float(im_str!("Pitch"), &mut pitch, -89.0, 89.0).build();
+            if yaw_changed || pitch_changed {
+                camera.set_orientation(yaw, pitch);
+            }
+
+            let mut speed = camera.movement_speed();
+            if ui.slider_float(im_str!("Speed"), &mut speed, 0.1, 10.0).build() {
+                camera.set_movement_speed(speed);
+            }
+        });
+}
+
+fn render_settings_panel(ui: &Ui, settings: &mut RenderSettings) {
+    ui.window(im_str!("Render settings"))
+        .position((10.0, 370.0), ImGuiSetCond_FirstUseEver)
+        .always_auto_resize(true)
+        .build(|| {
+            let mode_names: Vec<ImStr> = RENDER_MODES
+                .iter()
+                .map(|mode| ImStr::from(format!("{:?}", mode)))
+                .collect();
+            let mut current_mode = RENDER_MODES
+                .iter()
+                .position(|&mode| mode == settings.render_mode)
+                .unwrap_or(0) as i32;
+            if ui.combo(im_str!("Render mode"), &mut current_mode, &mode_names, -1) {
+                settings.render_mode = RENDER_MODES[current_mode as usize];
+            }
+
+            background_settings(ui, &mut settings.clear.background);
+            let mut stencil = settings.clear.stencil as i32;
+            if ui.slider_int(im_str!("Clear stencil"), &mut stencil, 0, 255).build() {
+                settings.clear.stencil = stencil as u32;
+            }
+
+            ui.separator();
+
+            let hdr = &mut settings.hdr;
+            let names: Vec<ImStr> = TONEMAPPERS
+                .iter()
+                .map(|tonemapper| ImStr::from(format!("{:?}", tonemapper)))
+                .collect();
+            let mut current = TONEMAPPERS
+                .iter()
+                .position(|&tonemapper| tonemapper == hdr.tonemapper)
+                .unwrap_or(0) as i32;
+            if ui.combo(im_str!("Tonemapper"), &mut current, &names, -1) {
+                hdr.tonemapper = TONEMAPPERS[current as usize];
+            }
+            ui.checkbox(im_str!("Auto exposure"), &mut hdr.auto_exposure);
+            ui.slider_float(im_str!("Exposure (EV)"), &mut hdr.exposure, -8.0, 8.0)
+                .build();
+            ui.slider_float(im_str!("Adaptation speed"), &mut hdr.adaptation_speed, 0.1, 10.0)
+                .build();
+
+            ui.separator();
+
+            let bloom = &mut settings.bloom;
+            ui.checkbox(im_str!("Bloom"), &mut bloom.enabled);
+            ui.slider_float(im_str!("Threshold"), &mut bloom.threshold, 0.0, 5.0)
+                .build();
+            ui.slider_float(im_str!("Knee"), &mut bloom.knee, 0.0, 1.0).build();
+            ui.slider_float(im_str!("Intensity"), &mut bloom.intensity, 0.0, 1.0)
+                .build();
+            ui.slider_float(im_str!("Radius"), &mut bloom.radius, 0.1, 4.0).build();
+
+            ui.separator();
+
+            ui.checkbox(
+                im_str!("Order independent transparency"),
+                &mut settings.transparency.order_independent,
+            );
+
+            ui.separator();
+
+            ui.checkbox(im_str!("GPU driven culling"), &mut settings.culling.gpu_driven);
+            ui.checkbox(im_str!("Occlusion culling"), &mut settings.culling.occlusion);
+
+            ui.separator();
+
+            let ssao = &mut settings.ssao;
+            ui.checkbox(im_str!("Ambient occlusion"), &mut ssao.enabled);
+            ui.slider_float(im_str!("AO radius"), &mut ssao.radius, 0.05, 2.0)
+                .build();
+            ui.slider_float(im_str!("AO bias"), &mut ssao.bias, 0.0, 0.2).build();
+            let mut samples = ssao.samples as i32;
+            if ui.slider_int(im_str!("AO samples"), &mut samples, 1, MAX_SSAO_SAMPLES as i32)
+                .build()
+            {
+                ssao.samples = samples as u32;
+            }
+            ui.slider_float(im_str!("AO strength"), &mut ssao.strength, 0.0, 2.0)
+                .build();
+
+            ui.separator();
+
+            let outline = &mut settings.outline;
+            ui.checkbox(im_str!("Selection outline"), &mut outline.enabled);
+            ui.input_float4(im_str!("Outline color"), &mut outline.color).build();
+            ui.slider_float(im_str!("Outline width"), &mut outline.width, 0.0, MAX_OUTLINE_WIDTH)
+                .build();
+            ui.checkbox(im_str!("Dashed hidden parts"), &mut outline.show_hidden);
+            ui.slider_float(im_str!("Dash length"), &mut outline.dash_length, 1.0, 32.0)
+                .build();
+
+            ui.separator();
+
+            let sky = &mut settings.sky;
+            ui.checkbox(im_str!("Procedural sky"), &mut sky.enabled);
+            ui.slider_float(im_str!("Time of day"), &mut sky.time_of_day, 0.0, 24.0)
+                .build();
+            ui.slider_float(im_str!("Day length (s)"), &mut sky.day_length, 0.0, 600.0)
+                .build();
+            ui.slider_float(im_str!("Latitude"), &mut sky.latitude, -80.0, 80.0)
+                .build();
+            ui.slider_float(im_str!("Sun intensity"), &mut sky.sun_intensity, 0.0, 50.0)
+                .build();
+            ui.slider_float(im_str!("Haze"), &mut sky.mie_anisotropy, 0.0, 0.99)
+                .build();
+
+            let skybox = &mut settings.skybox;
+            if skybox.cubemap.is_some() {
+                ui.separator();
+                ui.slider_float(im_str!("Sky intensity"), &mut skybox.intensity, 0.0, 4.0)
+                    .build();
+            }
+        });
+}
+
+fn background_settings(ui: &Ui, background: &mut Background) {
+    let mut gradient = match *background {
+        Background::Gradient { .. } => true,
+        Background::Color(_) => false,
+    };
+    if ui.checkbox(im_str!("Gradient background"), &mut gradient) {
+        *background = match *background {
+            Background::Color(color) => Background::Gradient {
+                top: color,
+                bottom: color,
+            },
+            Background::Gradient { top, .. } => Background::Color(top),
+        };
+    }
+
+    match *background {
+        Background::Color(ref mut color) => {
+            ui.input_float4(im_str!("Background"), color).build();
+        }
+        Background::Gradient {
+            ref mut top,
+            ref mut bottom,
+        } => {
+            ui.input_float4(im_str!("Top"), top).build();
+            ui.input_float4(im_str!("Bottom"), bottom).build();
+        }
+    }
+}
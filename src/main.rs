@@ -12,29 +12,28 @@ extern crate vulkano_win;
 
 extern crate vulkano_text;
 
+extern crate rusttype;
+
 extern crate cgmath;
 
 extern crate time;
 
+extern crate image;
+
+#[macro_use]
+extern crate imgui;
+
 mod camera;
+mod demo;
 mod fps;
 mod frame;
+mod gui;
+mod recording;
+mod split_screen;
 mod vulkan;
 
 use cgmath::Matrix4;
-use vulkano::buffer::BufferUsage;
-use vulkano::buffer::CpuAccessibleBuffer;
-use vulkano::buffer::CpuBufferPool;
-use vulkano::command_buffer::AutoCommandBufferBuilder;
-use vulkano::command_buffer::DynamicState;
-use vulkano::device::Device;
-use vulkano::format::Format;
-use vulkano::framebuffer::Framebuffer;
-use vulkano::framebuffer::Subpass;
-use vulkano::image::AttachmentImage;
-use vulkano::pipeline::viewport::Viewport;
-use vulkano::pipeline::GraphicsPipeline;
-use vulkano::pipeline::GraphicsPipelineAbstract;
+use cgmath::Point3;
 use vulkano::swapchain;
 use vulkano::swapchain::AcquireError;
 use vulkano::swapchain::SwapchainCreationError;
@@ -43,123 +42,60 @@ use vulkano::sync::GpuFuture;
 
 use vulkano::instance::debug::DebugCallback;
 
+use std::env;
 use std::mem;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use cgmath::SquareMatrix;
 
 fn main() {
+    let options = parse_options(env::args().skip(1));
+    if let Some(ref headless) = options.headless {
+        run_headless(headless);
+        return;
+    }
+
     let mut fps = fps::FPS::new(time::Duration::milliseconds(100));
 
     let instance = vulkan::initialize_instance();
     let mut scene = vulkan::Scene::new(&instance);
 
-    let mut camera = camera::Camera::new();
+    let camera = camera::Camera::new();
 
-    let depth_buffer = AttachmentImage::transient(
-        scene.device.clone(),
-        scene.images[0].dimensions(),
-        Format::D16Unorm,
-    ).unwrap();
-
-    let render_pass = Arc::new(
-        single_pass_renderpass!(
-            scene.queue.device().clone(),
-            attachments: {
-                final_color: {
-                    load: Clear,
-                    store: Store,
-                    format: scene.swapchain.format(),
-                    samples: 1,
-                },
-                depth: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::D16Unorm,
-                    samples: 1,
-                }
-            },
-            pass: {
-                color: [final_color],
-                depth_stencil: {depth}
-            }
-        ).unwrap(),
+    // Frame system
+    let mut frame_system = frame::FrameSystem::with_depth_format(
+        scene.queue.clone(),
+        scene.swapchain.format(),
+        frame::depth_stencil_format(&scene.device),
     );
+    frame_system.set_output_images(&scene.images);
+    let split_screen = split_screen::SplitScreen::new(scene.queue.clone(), frame_system.depth_format());
 
-    let sub_pass = Subpass::from(render_pass.clone(), 0).unwrap();
-
-    let (vs, fs) = create_shader_modules(&scene.device);
-
-    let mut framebuffers: Option<Vec<Arc<vulkano::framebuffer::Framebuffer<_, _>>>> = None;
-
-    let vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>> = {
-        CpuAccessibleBuffer::from_iter(
-            scene.device.clone(),
-            BufferUsage::all(),
-            [
-                Vertex {
-                    pos: [-0.5, -0.25, -0.5],
-                    color: [1.0, 0.0, 0.0, 1.0],
-                },
-                Vertex {
-                    pos: [0.0, 0.5, 1.0],
-                    color: [0.0, 1.0, 0.0, 1.0],
-                },
-                Vertex {
-                    pos: [0.25, -0.1, 0.0],
-                    color: [0.0, 0.0, 1.0, 1.0],
-                },
-                Vertex {
-                    pos: [0.0, 0.5, 1.0],
-                    color: [0.0, 1.0, 0.0, 1.0],
-                },
-                Vertex {
-                    pos: [0.25, -0.1, 0.0],
-                    color: [0.0, 0.0, 1.0, 1.0],
-                },
-                Vertex {
-                    pos: [0.5, 0.5, 0.0],
-                    color: [0.0, 0.0, 1.0, 1.0],
-                },
-                Vertex {
-                    pos: [0.5, 0.5, 0.0],
-                    color: [0.0, 0.0, 1.0, 1.0],
-                },
-                Vertex {
-                    pos: [1.5, 1.5, 0.0],
-                    color: [0.0, 0.0, 1.0, 1.0],
-                },
-                Vertex {
-                    pos: [0.5, 1.5, 0.0],
-                    color: [0.0, 1.0, 1.0, 1.0],
-                },
-            ].iter()
-                .cloned(),
-        ).expect("Failed to create vertex buffer")
-    };
+    let mut demo_scene = demo::DemoScene::new(scene.queue.clone(), &frame_system);
+    let mut render_targets = frame::RenderTargets::new(scene.queue.clone(), frame_system.depth_format());
+    demo_scene.add_screens(&mut render_targets);
+    let minimap = demo::minimap_target(&mut render_targets, MINIMAP_SIZE);
 
-    let pipeline: Arc<GraphicsPipelineAbstract + Send + Sync> = {
-        Arc::new(
-            GraphicsPipeline::start()
-                .vertex_input_single_buffer::<Vertex>()
-                .vertex_shader(vs.main_entry_point(), ())
-                .triangle_list()
-                .viewports_dynamic_scissors_irrelevant(1)
-                .fragment_shader(fs.main_entry_point(), ())
-                .render_pass(sub_pass)
-                .build(scene.device.clone())
-                .unwrap(),
-        ) as Arc<_>
-    };
+    let mut debug_draw = frame::DebugDraw::new();
+
+    let mut gui = gui::Gui::new();
+    frame_system.set_ui_fonts(gui.imgui_mut());
 
-    let uniform_buffer_pool: CpuBufferPool<vs::ty::bufferVals> =
-        CpuBufferPool::uniform_buffer(scene.device.clone());
-    let mut ds_pool =
-        vulkano::descriptor::descriptor_set::FixedSizeDescriptorSetsPool::new(pipeline.clone(), 0);
+    // While stopping, the recording is kept until the frames still in flight have been read back.
+    let mut recorder = options.recording.clone().map(recording::Recorder::start);
+    let mut stopping_recorder: Option<recording::Recorder> = None;
 
     let mut recreate_swapchain = false;
+    let mut unsupported_dimensions = false;
     let mut previous_frame_end = Box::new(now(scene.device.clone())) as Box<GpuFuture>;
 
+    if let Some(ref path) = options.skybox {
+        let (cubemap, upload_future) = load_skybox(scene.queue.clone(), path);
+        frame_system.settings_mut().skybox.cubemap = Some(cubemap);
+        previous_frame_end = Box::new(previous_frame_end.join(upload_future)) as Box<_>;
+    }
+
     let _callback = DebugCallback::errors_and_warnings(&instance, |msg| {
         println!("Debug callback: {:?}", msg.description);
     }).ok();
@@ -171,12 +107,80 @@ fn main() {
         &scene.images,
     );
 
-    // Frame system
-    let mut frame_system = frame::FrameSystem::new(scene.queue.clone(), scene.swapchain.format());
-
+    let mut app = App {
+        camera,
+        frame_system,
+        split_screen,
+        render_targets,
+        demo_scene,
+        gui,
+        show_minimap: true,
+    };
     loop {
         previous_frame_end.cleanup_finished();
 
+        let mut requests = EventRequests::default();
+        // Recordings play back at their own frame rate, whatever the actual one.
+        let dt = match recorder {
+            Some(ref recorder) => recorder.options().time_step(),
+            None => fps.average_render_time() as f32 / 1000.0,
+        };
+        scene.events_loop.poll_events(|ev| handle_event(ev, &mut app, dt, &mut requests));
+
+        // Nothing can be rendered into a minimized window or one the swapchain can't be resized
+        // to, so block until something happens instead of spinning on swapchain recreation.
+        while !requests.done && (unsupported_dimensions || is_minimized(&scene.window)) {
+            scene.events_loop.run_forever(|ev| {
+                handle_event(ev, &mut app, dt, &mut requests);
+                winit::ControlFlow::Break
+            });
+            unsupported_dimensions = false;
+            recreate_swapchain = true;
+        }
+        recreate_swapchain |= requests.recreate_swapchain;
+        // Events are in for this frame, the rest of it works on the parts of the app.
+        let App {
+            ref mut camera,
+            ref mut frame_system,
+            ref mut split_screen,
+            ref mut render_targets,
+            ref mut demo_scene,
+            ref mut gui,
+            show_minimap,
+        } = app;
+
+        if requests.done {
+            // Screenshots and recorded frames of the frames in flight are still to be written.
+            previous_frame_end
+                .then_signal_fence_and_flush()
+                .unwrap()
+                .wait(None)
+                .unwrap();
+            frame_system.finish_captures();
+            if recorder.is_some() || stopping_recorder.is_some() {
+                record_finished_frames(frame_system, &recorder, &stopping_recorder);
+                for recorder in stopping_recorder.into_iter().chain(recorder) {
+                    recorder.finish();
+                }
+            }
+            return;
+        }
+
+        if requests.toggle_recording {
+            if recorder.is_some() {
+                stopping_recorder = recorder.take();
+            } else if stopping_recorder.is_some() {
+                println!("Still finishing the previous recording");
+            } else {
+                let recording_options = options
+                    .recording
+                    .clone()
+                    .unwrap_or_else(|| recording::RecordingOptions::timestamped(options.record_fps));
+                recorder = Some(recording::Recorder::start(recording_options));
+            }
+        }
+        frame_system.set_fixed_time_step(recorder.as_ref().map(|recorder| recorder.options().time_step()));
+
         if recreate_swapchain {
             let dimensions = {
                 let (new_width, new_height) = scene.window.window().get_inner_size().unwrap();
@@ -186,8 +190,9 @@ fn main() {
             let (new_swapchain, new_images) =
                 match scene.swapchain.recreate_with_dimension(dimensions) {
                     Ok(r) => r,
-                    // Tends to happen on manual resize
+                    // Tends to happen on manual resize, try again after the next event
                     Err(SwapchainCreationError::UnsupportedDimensions) => {
+                        unsupported_dimensions = true;
                         continue;
                     }
                     Err(err) => panic!("{:?}", err),
@@ -196,31 +201,16 @@ fn main() {
             mem::replace(&mut scene.swapchain, new_swapchain);
             mem::replace(&mut scene.images, new_images);
 
-            framebuffers = None;
-
-            recreate_swapchain = false;
-        }
-
-        if framebuffers.is_none() {
-            let new_framebuffers = Some(
-                scene
-                    .images
-                    .iter()
-                    .map(|image| {
-                        Arc::new(
-                            Framebuffer::start(render_pass.clone())
-                                .add(image.clone())
-                                .unwrap()
-                                .add(depth_buffer.clone())
-                                .unwrap()
-                                .build()
-                                .unwrap(),
-                        )
-                    })
-                    .collect::<Vec<_>>(),
+            frame_system.set_output_images(&scene.images);
+            split_screen.resize(dimensions);
+            text_drawer = vulkano_text::DrawText::new(
+                scene.device.clone(),
+                scene.queue.clone(),
+                scene.swapchain.clone(),
+                &scene.images,
             );
 
-            mem::replace(&mut framebuffers, new_framebuffers);
+            recreate_swapchain = false;
         }
 
         let (image_num, acquire_future) =
@@ -236,71 +226,167 @@ fn main() {
 
         let [width, height] = scene.images[0].dimensions();
 
+        if requests.switch_layout {
+            let layout = split_screen.layout().next();
+            split_screen.set_layout(layout, [width, height], camera, frame_system);
+            println!("Layout: {:?}", layout);
+        }
+
         let future = previous_frame_end.join(acquire_future);
 
-        let mut frame = frame_system.frame(
-            future,
-            scene.images[image_num].clone(),
-            &depth_buffer,
-            Matrix4::identity(),
-        );
-        let mut after_future = None;
-        while let Some(pass) = frame.next_pass() {
-            match pass {
-                frame::Pass::Deferred(mut draw_pass) => {
-                    let mvp = camera.projection * camera.view_matrix() * camera.world;
-                    let uniform_buffer = uniform_buffer_pool
-                        .next(vs::ty::bufferVals { mvp: mvp.into() })
-                        .unwrap();
-                    let descriptor_set = ds_pool
-                        .next()
-                        .add_buffer(uniform_buffer)
-                        .unwrap()
-                        .build()
-                        .unwrap();
-
-                    let cb = AutoCommandBufferBuilder::secondary_graphics(
-                        scene.queue.device().clone(),
-                        scene.queue.family(),
-                        pipeline.clone().subpass(),
-                    ).unwrap()
-                        .draw(
-                            pipeline.clone(),
-                            DynamicState {
-                                viewports: Some(vec![Viewport {
-                                    origin: [0.0, 0.0],
-                                    dimensions: [width as f32, height as f32],
-                                    depth_range: 0.0..1.0,
-                                }]),
-                                ..DynamicState::none()
-                            },
-                            vec![vertex_buffer.clone()],
-                            descriptor_set,
-                            (),
-                        )
-                        .unwrap()
-                        .build()
-                        .unwrap();
-
-                    draw_pass.execute(cb);
-                }
-                frame::Pass::Text(mut text_pass) => {
-                    text_pass.write(
-                        &format!(
-                            "Render time: {} ms ({} FPS)",
-                            fps.average_render_time(),
-                            fps.current_fps()
-                        ),
-                        &mut text_drawer,
-                        image_num,
-                    );
-                }
-                frame::Pass::Finished(af) => {
-                    after_future = Some(af);
+        let cpu_time = frame_system.last_cpu_time();
+        let stats = gui::FrameStats {
+            fps: fps.current_fps(),
+            render_time_ms: fps.average_render_time() as f32,
+            cpu_time_ms: cpu_time.num_microseconds().unwrap_or(0) as f32 / 1000.0,
+            particles: demo_scene.particles.allocated(),
+            instances: demo_scene.instances.stats(),
+            gpu_scene: demo_scene.gpu_scene.stats(),
+        };
+        // The panels and keys change the camera and settings of the viewport under the cursor.
+        let (mut ui, settings_text) = {
+            let (active_camera, settings) = match split_screen.active_view_mut() {
+                Some(view) => (&mut view.camera, view.viewport.frame_system_mut().settings_mut()),
+                None => (&mut *camera, frame_system.settings_mut()),
+            };
+            let ui = gui.frame([width, height], dt, active_camera, settings, &stats);
+            (Some(ui), render_settings_text(settings))
+        };
+
+        if recorder.is_some() {
+            frame_system.request_readback();
+        }
+
+        let settings = frame_system.settings().clone();
+        render_targets.set_settings(&settings);
+        let gpu_driven = settings.culling.gpu_driven
+            || split_screen.is_split()
+                && split_screen
+                    .views()
+                    .iter()
+                    .any(|view| view.viewport.frame_system().settings().culling.gpu_driven);
+        if !gpu_driven {
+            demo_scene.gpu_scene.discard_depth_pyramid();
+        }
+        let mut scene_view = frame::SceneView {
+            view: camera.view_matrix(),
+            projection: camera.projection,
+            dimensions: [width, height],
+            settings: settings.clone(),
+            target: None,
+            screens: frame::TargetImages::none(0),
+        };
+        // The render targets are seen from every camera, mirrors show something else in each.
+        let (future, screens) = if split_screen.is_split() {
+            // Each viewport renders its own frame, and the window's frame draws them side by side.
+            let mut future = Box::new(future) as Box<GpuFuture>;
+            let mut screens = frame::TargetImages::none(0);
+            for (index, view) in split_screen.views_mut().iter_mut().enumerate() {
+                let view_scene = {
+                    let (view_matrix, projection) = (view.camera.view_matrix(), view.camera.projection);
+                    let (targets_future, view_screens) =
+                        render_targets.render(future, view_matrix, projection, |pass, target_view| {
+                            demo_scene.draw_pass(pass, target_view, false);
+                        });
+                    future = targets_future;
+                    screens = view_screens.clone();
+                    let mut settings = view.viewport.frame_system().settings().clone();
+                    // The viewports share the scene's depth pyramid, which is only good for the
+                    // camera it was built with, even if occlusion is turned on in another view.
+                    if view.kind != split_screen::ViewKind::Perspective {
+                        settings.culling.occlusion = false;
+                    }
+                    frame::SceneView {
+                        view: view_matrix,
+                        projection,
+                        dimensions: view.viewport.rect().dimensions,
+                        settings,
+                        target: None,
+                        screens: view_screens,
+                    }
+                };
+                draw_debug_gizmos(&mut debug_draw);
+
+                let mut frame = view.viewport.frame(future, view_scene.view, view_scene.projection);
+                let mut after_future = None;
+                while let Some(pass) = frame.next_pass() {
+                    match demo_scene.draw_pass(pass, &view_scene, index == 0) {
+                        Some(frame::Pass::Debug(mut debug_pass)) => debug_pass.draw(&mut debug_draw),
+                        Some(frame::Pass::Finished(af)) => after_future = Some(af),
+                        // Overlays go on the window's frame.
+                        _ => (),
+                    }
                 }
-                _ => {}
+                future = after_future.unwrap();
+            }
+            (future, screens)
+        } else {
+            render_targets.render(future, scene_view.view, scene_view.projection, |pass, target_view| {
+                demo_scene.draw_pass(pass, target_view, false);
+            })
+        };
+        if show_minimap {
+            if let Some(image) = screens.get(minimap) {
+                frame_system.add_overlay(frame::ViewportImage {
+                    image,
+                    rect: minimap_rect([width, height]),
+                });
             }
         }
+        scene_view.screens = screens;
+
+        let after_future = {
+            let mut frame = if split_screen.is_split() {
+                frame_system.composite_frame(future, image_num, split_screen.views().iter().map(|view| &view.viewport))
+            } else {
+                draw_debug_gizmos(&mut debug_draw);
+                frame_system.frame(future, image_num, scene_view.view, scene_view.projection)
+            };
+            let mut after_future = None;
+            while let Some(pass) = frame.next_pass() {
+                match demo_scene.draw_pass(pass, &scene_view, true) {
+                    Some(frame::Pass::Debug(mut debug_pass)) => {
+                        debug_pass.draw(&mut debug_draw);
+                    }
+                    Some(frame::Pass::Text(mut text_pass)) => {
+                        let panel = [0.0, 0.0, 0.0, 0.5];
+                        text_pass.queue(
+                            frame::TextItem::new(&format!(
+                                "Render time: {} ms ({} FPS)\nCPU: {:.2} ms",
+                                fps.average_render_time(),
+                                fps.current_fps(),
+                                cpu_time.num_microseconds().unwrap_or(0) as f64 / 1000.0
+                            )).background(panel),
+                        );
+                        text_pass.queue(
+                            frame::TextItem::new(&settings_text)
+                                .anchor(frame::Anchor::TopRight)
+                                .align(frame::Align::Right)
+                                .background(panel),
+                        );
+                        text_pass.queue(
+                            frame::TextItem::new(CONTROLS_HELP)
+                                .anchor(frame::Anchor::BottomLeft)
+                                .size(16.0)
+                                .color([0.8, 0.8, 0.8, 1.0])
+                                .background(panel),
+                        );
+                        for label in split_screen.labels() {
+                            text_pass.queue(label);
+                        }
+                        text_pass.draw(&mut text_drawer, image_num);
+                    }
+                    Some(frame::Pass::Ui(mut ui_pass)) => {
+                        ui_pass.draw(ui.take().unwrap());
+                    }
+                    Some(frame::Pass::Finished(af)) => {
+                        after_future = Some(af);
+                    }
+                    _ => (),
+                }
+            }
+            after_future
+        };
 
         let after_frame = after_future
             .unwrap()
@@ -310,86 +396,489 @@ fn main() {
 
         previous_frame_end = Box::new(after_frame) as Box<_>;
 
+        record_finished_frames(frame_system, &recorder, &stopping_recorder);
+        if stopping_recorder.is_some() && frame_system.pending_readbacks() == 0 {
+            stopping_recorder.take().unwrap().finish();
+        }
+
         fps.end_frame();
+    }
+}
+
+struct Options {
+    headless: Option<HeadlessOptions>,
+    /// `--record PATH [--record-fps N]` starts recording right away.
+    recording: Option<recording::RecordingOptions>,
+    record_fps: u32,
+    /// `--skybox PATH`, see `load_skybox`.
+    skybox: Option<PathBuf>,
+}
+
+/// Hands the frames read back so far to the recording they belong to. A stopping recording's
+/// frames all come before those of a new one.
+fn record_finished_frames(
+    frame_system: &mut frame::FrameSystem,
+    recorder: &Option<recording::Recorder>,
+    stopping_recorder: &Option<recording::Recorder>,
+) {
+    for captured in frame_system.take_readbacks() {
+        match stopping_recorder.as_ref().or(recorder.as_ref()) {
+            Some(recorder) => recorder.add_frame(captured),
+            None => println!("Dropping a read back frame without a recording"),
+        }
+    }
+}
 
-        let mut done = false;
-        scene.events_loop.poll_events(|ev| match ev {
-            winit::Event::WindowEvent {
-                event: winit::WindowEvent::Closed,
-                ..
-            } => done = true,
-            winit::Event::WindowEvent {
-                event: winit::WindowEvent::Resized(_, _),
-                ..
-            } => {
-                recreate_swapchain = true;
-                println!("resize");
+/// Set with `--headless [--size WIDTHxHEIGHT] [--frames N] [--output PATH] [--preset NAME]`.
+struct HeadlessOptions {
+    dimensions: [u32; 2],
+    frames: u32,
+    output: PathBuf,
+    preset: Option<String>,
+}
+
+fn parse_options<I>(args: I) -> Options
+where
+    I: Iterator<Item = String>,
+{
+    let mut headless = false;
+    let mut record_path = None;
+    let mut record_fps = 60;
+    let mut skybox = None;
+    let mut options = HeadlessOptions {
+        dimensions: [800, 600],
+        frames: 1,
+        output: PathBuf::from("headless.png"),
+        preset: None,
+    };
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => headless = true,
+            "--size" => {
+                let size = args.next().expect("--size needs a value");
+                let mut parts = size.split('x').map(|part| part.parse().expect("Invalid --size"));
+                options.dimensions = [parts.next().unwrap(), parts.next().expect("Invalid --size")];
             }
-            winit::Event::WindowEvent {
-                event: winit::WindowEvent::KeyboardInput { input, .. },
-                ..
-            } => camera.handle_input(&input, fps.average_render_time() as f32 / 1000.0),
-            _ => (),
-        });
-
-        if done {
-            return;
+            "--frames" => {
+                options.frames = args.next()
+                    .and_then(|frames| frames.parse().ok())
+                    .expect("--frames needs a number");
+            }
+            "--output" => options.output = PathBuf::from(args.next().expect("--output needs a path")),
+            "--preset" => options.preset = Some(args.next().expect("--preset needs a name")),
+            "--record" => record_path = Some(PathBuf::from(args.next().expect("--record needs a path"))),
+            "--record-fps" => {
+                record_fps = args.next()
+                    .and_then(|fps| fps.parse().ok())
+                    .expect("--record-fps needs a number");
+            }
+            "--skybox" => skybox = Some(PathBuf::from(args.next().expect("--skybox needs a path"))),
+            _ => println!("Ignoring unknown argument {}", arg),
+        }
+    }
+
+    Options {
+        headless: if headless { Some(options) } else { None },
+        recording: record_path.map(|path| recording::RecordingOptions::from_path(path, record_fps)),
+        record_fps,
+        skybox,
+    }
+}
+
+/// Loads an equirectangular `.hdr` file, or a directory with the six faces of a cubemap named
+/// `posx`, `negx`, `posy`, `negy`, `posz` and `negz`.
+fn load_skybox(queue: Arc<vulkano::device::Queue>, path: &Path) -> (frame::Cubemap, Box<GpuFuture>) {
+    let loaded = if path.is_dir() {
+        let mut faces: [PathBuf; 6] = Default::default();
+        for (face, name) in faces.iter_mut().zip(&["posx", "negx", "posy", "negy", "posz", "negz"]) {
+            *face = ["png", "jpg", "jpeg"]
+                .iter()
+                .map(|extension| path.join(format!("{}.{}", name, extension)))
+                .find(|face| face.exists())
+                .unwrap_or_else(|| panic!("No {} face in {}", name, path.display()));
+        }
+        frame::Cubemap::from_faces(queue, &faces)
+    } else {
+        frame::Cubemap::from_equirectangular(queue, path)
+    };
+    loaded.unwrap_or_else(|err| panic!("Failed to load skybox {}: {}", path.display(), err))
+}
+
+/// Renders `options.frames` frames without a window and saves the last one.
+fn run_headless(options: &HeadlessOptions) {
+    let instance = vulkan::initialize_headless_instance();
+    let scene = vulkan::HeadlessScene::new(&instance);
+
+    let mut camera = camera::Camera::new();
+    camera.set_aspect_ratio(options.dimensions[0] as f32 / options.dimensions[1] as f32);
+    let mut frame_system = frame::FrameSystem::headless_with_depth_format(
+        scene.queue.clone(),
+        options.dimensions,
+        frame::depth_stencil_format(&scene.device),
+    );
+    let mut demo_scene = demo::DemoScene::new(scene.queue.clone(), &frame_system);
+    let mut render_targets = frame::RenderTargets::new(scene.queue.clone(), frame_system.depth_format());
+    let mut minimap = None;
+    let mut debug_draw = frame::DebugDraw::new();
+    demo_scene.animated = false;
+    if let Some(ref name) = options.preset {
+        let preset = render_preset(name);
+        *frame_system.settings_mut() = preset.settings;
+        if preset.waves {
+            demo_scene.animated = true;
+            // Keeps the waves and particles the same from run to run.
+            frame_system.set_fixed_time_step(Some(1.0 / 60.0));
+        }
+        demo_scene.geometry.objects_mut()[0].selected = preset.select_first_object;
+        if preset.render_targets {
+            demo_scene.add_screens(&mut render_targets);
+            minimap = Some(demo::minimap_target(&mut render_targets, MINIMAP_SIZE));
         }
     }
+    let settings = frame_system.settings().clone();
+    render_targets.set_settings(&settings);
+
+    for frame_index in 0..options.frames {
+        if frame_index + 1 == options.frames {
+            frame_system.request_readback();
+        }
+        draw_debug_gizmos(&mut debug_draw);
+
+        let (future, screens) = render_targets.render(
+            now(scene.device.clone()),
+            camera.view_matrix(),
+            camera.projection,
+            |pass, target_view| {
+                demo_scene.draw_pass(pass, target_view, false);
+            },
+        );
+        if let Some(image) = minimap.and_then(|minimap| screens.get(minimap)) {
+            frame_system.add_overlay(frame::ViewportImage {
+                image,
+                rect: minimap_rect(options.dimensions),
+            });
+        }
+        let scene_view = frame::SceneView {
+            view: camera.view_matrix(),
+            projection: camera.projection,
+            dimensions: options.dimensions,
+            settings: settings.clone(),
+            target: None,
+            screens,
+        };
+
+        let mut frame = frame_system.frame(future, 0, scene_view.view, scene_view.projection);
+        let mut after_future = None;
+        while let Some(pass) = frame.next_pass() {
+            match demo_scene.draw_pass(pass, &scene_view, true) {
+                Some(frame::Pass::Debug(mut debug_pass)) => debug_pass.draw(&mut debug_draw),
+                Some(frame::Pass::Finished(af)) => after_future = Some(af),
+                // Text and UI need a swapchain and an input source, leave them out.
+                _ => (),
+            }
+        }
+
+        after_future
+            .unwrap()
+            .then_signal_fence_and_flush()
+            .unwrap()
+            .wait(None)
+            .unwrap();
+    }
+
+    let captured = frame_system
+        .take_readbacks()
+        .pop()
+        .expect("Readback of the last frame is missing");
+    image::save_buffer(
+        &options.output,
+        &captured.pixels,
+        captured.dimensions[0],
+        captured.dimensions[1],
+        image::RGBA(8),
+    ).expect("Failed to save headless frame");
+    println!("Saved frame to {}", options.output.display());
 }
 
-fn create_shader_modules(device: &Arc<Device>) -> (vs::Shader, fs::Shader) {
-    let vs = vs::Shader::load(device.clone()).expect("Could not create shader module");
-    let fs = fs::Shader::load(device.clone()).expect("Could not create shader module");
+/// A setup that gives the same image on every run, used by the golden image tests.
+struct RenderPreset {
+    settings: frame::RenderSettings,
+    /// Selects the first demo object. What's selected belongs to the scene rather than the
+    /// render settings.
+    select_first_object: bool,
+    /// Adds the demo screens and a minimap to the scene.
+    render_targets: bool,
+    /// Draws the waves and particles, stepping them by a fixed time every frame.
+    waves: bool,
+}
 
-    (vs, fs)
+/// Auto exposure and the day/night cycle depend on frame timing, so they are off in all presets.
+fn render_preset(name: &str) -> RenderPreset {
+    let mut settings = frame::RenderSettings::default();
+    settings.hdr.auto_exposure = false;
+    settings.sky.day_length = 0.0;
+    let mut select_first_object = false;
+    let mut render_targets = false;
+    let mut waves = false;
+    match name {
+        "default" => (),
+        "no-bloom" => settings.bloom.enabled = false,
+        "reinhard" => settings.hdr.tonemapper = frame::Tonemapper::Reinhard,
+        "uncharted2" => settings.hdr.tonemapper = frame::Tonemapper::Uncharted2,
+        "overexposed" => settings.hdr.exposure = 2.0,
+        "sky" => {
+            settings.sky.enabled = true;
+            settings.sky.time_of_day = 16.5;
+        }
+        "oit" => settings.transparency.order_independent = true,
+        "normals" => settings.render_mode = frame::RenderMode::Normals,
+        "outline" => {
+            settings.outline.width = 4.0;
+            select_first_object = true;
+        }
+        "ambient-occlusion" => settings.render_mode = frame::RenderMode::AmbientOcclusion,
+        "no-ssao" => settings.ssao.enabled = false,
+        "gpu-driven" => settings.culling.gpu_driven = true,
+        "render-targets" => render_targets = true,
+        "waves" => waves = true,
+        "gradient" => {
+            settings.clear.background = frame::Background::Gradient {
+                top: [0.1, 0.2, 0.6, 1.0],
+                bottom: [0.6, 0.5, 0.4, 1.0],
+            }
+        }
+        _ => panic!("Unknown render preset {}", name),
+    }
+    RenderPreset {
+        settings,
+        select_first_object,
+        render_targets,
+        waves,
+    }
 }
 
-mod vs {
-    #[derive(VulkanoShader)]
-    #[ty = "vertex"]
-    #[src = "
-#version 400
-
-#extension GL_ARB_separate_shader_objects : enable
-#extension GL_ARB_shading_language_420pack : enable
-
-layout (std140, binding = 0) uniform bufferVals {
-    mat4 mvp;
-} myBufferVals;
-
-layout (location = 0) in vec3 pos;
-layout (location = 1) in vec4 color;
-layout (location = 0) out vec4 out_color;
-void main() {
-    out_color = color;
-    gl_Position = myBufferVals.mvp * vec4(pos, 1.0);
-    // gl_Position = vec4(pos, 1.0);
+/// Everything of the windowed demo that input changes.
+struct App {
+    camera: camera::Camera,
+    frame_system: frame::FrameSystem,
+    split_screen: split_screen::SplitScreen,
+    render_targets: frame::RenderTargets,
+    demo_scene: demo::DemoScene,
+    gui: gui::Gui,
+    show_minimap: bool,
 }
-"]
-    struct Dummy;
+
+/// What the events of one pass over the event loop ask for, acted on once they're all in.
+#[derive(Debug, Default)]
+struct EventRequests {
+    done: bool,
+    recreate_swapchain: bool,
+    toggle_recording: bool,
+    switch_layout: bool,
 }
 
-mod fs {
-    #[derive(VulkanoShader)]
-    #[ty = "fragment"]
-    #[src = "
-#version 400
-#extension GL_ARB_separate_shader_objects : enable
-#extension GL_ARB_shading_language_420pack : enable
-layout (location = 0) in vec4 color;
-layout (location = 0) out vec4 f_color;
-void main() {
-   //outColor = vec4(1.0, 0.0, 0.0, 1.0);
-   f_color = color;
+fn handle_event(ev: winit::Event, app: &mut App, dt: f32, requests: &mut EventRequests) {
+    if let winit::Event::WindowEvent { ref event, .. } = ev {
+        app.gui.handle_event(event);
+    }
+
+    match ev {
+        winit::Event::WindowEvent {
+            event: winit::WindowEvent::Closed,
+            ..
+        } => requests.done = true,
+        winit::Event::WindowEvent {
+            event: winit::WindowEvent::Resized(_, _),
+            ..
+        } => {
+            requests.recreate_swapchain = true;
+            println!("resize");
+        }
+        winit::Event::WindowEvent {
+            event: winit::WindowEvent::CursorMoved {
+                position: (x, y), ..
+            },
+            ..
+        } => app.split_screen.set_cursor([x, y]),
+        winit::Event::WindowEvent {
+            event: winit::WindowEvent::KeyboardInput { input, .. },
+            ..
+        } => {
+            if input.state == winit::ElementState::Pressed {
+                match input.virtual_keycode {
+                    Some(winit::VirtualKeyCode::F1) => app.gui.visible = !app.gui.visible,
+                    Some(winit::VirtualKeyCode::F9) => requests.toggle_recording = true,
+                    Some(winit::VirtualKeyCode::L) => requests.switch_layout = true,
+                    Some(winit::VirtualKeyCode::P) => app.show_minimap = !app.show_minimap,
+                    Some(winit::VirtualKeyCode::R) => {
+                        let max_depth = (app.render_targets.max_depth() + 1) % (MAX_TARGET_DEPTH + 1);
+                        app.render_targets.set_max_depth(max_depth);
+                        println!("Render target depth: {}", max_depth);
+                    }
+                    Some(winit::VirtualKeyCode::F12) => {
+                        app.frame_system.request_screenshot(frame::timestamped_screenshot_path())
+                    }
+                    _ => (),
+                }
+            }
+            if app.gui.wants_keyboard() {
+                return;
+            }
+            let (camera, frame_system) = match app.split_screen.active_view_mut() {
+                Some(view) => (&mut view.camera, view.viewport.frame_system_mut()),
+                None => (&mut app.camera, &mut app.frame_system),
+            };
+            if input.state == winit::ElementState::Pressed {
+                handle_render_settings_input(&input, frame_system);
+                handle_selection_input(&input, &mut app.demo_scene.geometry);
+            }
+            camera.handle_input(&input, dt)
+        }
+        _ => (),
+    }
+}
+
+fn is_minimized(window: &vulkano::swapchain::Surface<winit::Window>) -> bool {
+    match window.window().get_inner_size() {
+        Some((width, height)) => width == 0 || height == 0,
+        None => false,
+    }
+}
+
+fn handle_render_settings_input(input: &winit::KeyboardInput, frame_system: &mut frame::FrameSystem) {
+    match input.virtual_keycode {
+        Some(winit::VirtualKeyCode::T) => {
+            let settings = &mut frame_system.settings_mut().hdr;
+            settings.tonemapper = settings.tonemapper.next();
+            println!("Tonemapper: {:?}", settings.tonemapper);
+        }
+        Some(winit::VirtualKeyCode::E) => {
+            let settings = &mut frame_system.settings_mut().hdr;
+            settings.auto_exposure = !settings.auto_exposure;
+            println!("Auto exposure: {}", settings.auto_exposure);
+        }
+        Some(winit::VirtualKeyCode::Add) | Some(winit::VirtualKeyCode::Equals) => {
+            let settings = &mut frame_system.settings_mut().hdr;
+            settings.exposure += 0.25;
+            println!("Exposure: {} EV", settings.exposure);
+        }
+        Some(winit::VirtualKeyCode::Subtract) | Some(winit::VirtualKeyCode::Minus) => {
+            let settings = &mut frame_system.settings_mut().hdr;
+            settings.exposure -= 0.25;
+            println!("Exposure: {} EV", settings.exposure);
+        }
+        Some(winit::VirtualKeyCode::B) => {
+            let settings = &mut frame_system.settings_mut().bloom;
+            settings.enabled = !settings.enabled;
+            println!("Bloom: {}", settings.enabled);
+        }
+        Some(winit::VirtualKeyCode::M) => {
+            let settings = frame_system.settings_mut();
+            settings.render_mode = settings.render_mode.next();
+            println!("Render mode: {:?}", settings.render_mode);
+        }
+        Some(winit::VirtualKeyCode::O) => {
+            let settings = &mut frame_system.settings_mut().transparency;
+            settings.order_independent = !settings.order_independent;
+            println!("Order independent transparency: {}", settings.order_independent);
+        }
+        Some(winit::VirtualKeyCode::LBracket) => {
+            let settings = &mut frame_system.settings_mut().bloom;
+            settings.threshold = (settings.threshold - 0.1).max(0.0);
+            println!("Bloom threshold: {}", settings.threshold);
+        }
+        Some(winit::VirtualKeyCode::RBracket) => {
+            let settings = &mut frame_system.settings_mut().bloom;
+            settings.threshold += 0.1;
+            println!("Bloom threshold: {}", settings.threshold);
+        }
+        _ => (),
+    }
+}
+
+/// The number keys toggle the selection of the demo object with that number.
+fn handle_selection_input(input: &winit::KeyboardInput, geometry: &mut demo::DemoGeometry) {
+    let index = match input.virtual_keycode {
+        Some(winit::VirtualKeyCode::Key1) => 0,
+        Some(winit::VirtualKeyCode::Key2) => 1,
+        _ => return,
+    };
+    if let Some(object) = geometry.objects_mut().get_mut(index) {
+        object.selected = !object.selected;
+        println!("{} selected: {}", object.name, object.selected);
+    }
+}
+
+/// Reference grid, world axes and the bounds of the test geometry.
+fn draw_debug_gizmos(debug_draw: &mut frame::DebugDraw) {
+    debug_draw
+        .depth_tested()
+        .grid(Point3::new(0.0, -0.5, 0.0), 4.0, 8, [0.5, 0.5, 0.5, 0.5])
+        .aabb(Point3::new(-0.5, -0.25, -0.5), Point3::new(1.5, 1.5, 1.0), [1.0, 1.0, 0.0, 1.0])
+        .sphere(Point3::new(0.83, 1.17, 0.0), 0.1, [0.0, 1.0, 1.0, 1.0]);
+    debug_draw.on_top().axes(Matrix4::identity(), 0.5);
+}
+
+const CONTROLS_HELP: &str = "T: tonemapper  E: auto exposure  +/-: exposure
+B: bloom  [/]: bloom threshold  M: render mode  O: order independent transparency
+1/2: select objects  L: split screen layout  P: minimap  R: render target depth
+F1: settings UI  F9: record  F12: screenshot";
+
+/// Width and height of the minimap, in its render target as well as in the window.
+const MINIMAP_SIZE: u32 = 200;
+/// Highest render target depth the R key goes to before wrapping around to none at all.
+const MAX_TARGET_DEPTH: usize = 3;
+
+/// Where the minimap goes in a window of `dimensions`, its bottom right corner.
+fn minimap_rect(dimensions: [u32; 2]) -> frame::ViewportRect {
+    let margin = 10;
+    let size = MINIMAP_SIZE.min(dimensions[0].saturating_sub(2 * margin)).min(dimensions[1].saturating_sub(2 * margin));
+    frame::ViewportRect {
+        origin: [
+            dimensions[0].saturating_sub(size + margin),
+            dimensions[1].saturating_sub(size + margin),
+        ],
+        dimensions: [size.max(1), size.max(1)],
+    }
 }
-"]
-    struct Dummy;
+
+fn render_settings_text(settings: &frame::RenderSettings) -> String {
+    format!(
+        "Mode: {:?}\nTonemapper: {:?}\nExposure: {:+.2} EV{}\nBloom: {}",
+        settings.render_mode,
+        settings.hdr.tonemapper,
+        settings.hdr.exposure,
+        if settings.hdr.auto_exposure { " (auto)" } else { "" },
+        if settings.bloom.enabled {
+            format!("threshold {:.1}", settings.bloom.threshold)
+        } else {
+            "off".to_owned()
+        }
+    )
 }
 
-#[derive(Debug, Clone)]
-struct Vertex {
-    pos: [f32; 3],
-    color: [f32; 4],
+#[cfg(test)]
+mod tests {
+    use super::{minimap_rect, MINIMAP_SIZE};
+
+    #[test]
+    fn minimap_goes_in_the_bottom_right_corner() {
+        let rect = minimap_rect([1280, 720]);
+        assert_eq!(rect.dimensions, [MINIMAP_SIZE, MINIMAP_SIZE]);
+        assert_eq!(rect.origin, [1280 - MINIMAP_SIZE - 10, 720 - MINIMAP_SIZE - 10]);
+    }
+
+    #[test]
+    fn minimap_fits_tiny_windows() {
+        for &dimensions in &[[1, 1], [5, 5], [19, 400], [400, 21], [MINIMAP_SIZE, MINIMAP_SIZE]] {
+            let rect = minimap_rect(dimensions);
+            assert!(rect.dimensions[0] >= 1 && rect.dimensions[1] >= 1, "{:?}", dimensions);
+            assert_eq!(rect.dimensions[0], rect.dimensions[1]);
+            for axis in 0..2 {
+                assert!(rect.origin[axis] + rect.dimensions[axis] <= dimensions[axis], "{:?}", dimensions);
+            }
+        }
+    }
 }
-impl_vertex!(Vertex, pos, color);
@@ -0,0 +1,218 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread;
+use std::thread::JoinHandle;
+
+use image;
+use time;
+
+use frame::CapturedFrame;
+
+/// Frames waiting to be written before `Recorder::add_frame` blocks, so a slow disk holds up
+/// rendering instead of piling up frames in memory.
+const QUEUED_FRAMES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingFormat {
+    /// `frame-000000.png`, `frame-000001.png`, ... in a directory.
+    PngSequence,
+    /// A single uncompressed YUV 4:4:4 stream.
+    Y4m,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecordingOptions {
+    pub format: RecordingFormat,
+    pub path: PathBuf,
+    /// Simulated frames per second, independent of how fast frames are actually rendered.
+    pub frame_rate: u32,
+}
+
+impl RecordingOptions {
+    /// Picks the format from the extension of `path`, a PNG sequence unless it's `.y4m`.
+    pub fn from_path(path: PathBuf, frame_rate: u32) -> RecordingOptions {
+        let format = match path.extension() {
+            Some(extension) if extension == "y4m" => RecordingFormat::Y4m,
+            _ => RecordingFormat::PngSequence,
+        };
+        RecordingOptions {
+            format,
+            path,
+            frame_rate,
+        }
+    }
+
+    /// A timestamped PNG sequence directory in the working directory.
+    pub fn timestamped(frame_rate: u32) -> RecordingOptions {
+        let name = format!("recording-{}", time::now().strftime("%Y%m%d-%H%M%S").unwrap());
+        RecordingOptions::from_path(PathBuf::from(name), frame_rate)
+    }
+
+    #[inline]
+    pub fn time_step(&self) -> f32 {
+        1.0 / self.frame_rate as f32
+    }
+}
+
+/// Writes read back frames on a background thread, in the order they're given.
+pub struct Recorder {
+    options: RecordingOptions,
+    sender: SyncSender<CapturedFrame>,
+    writer: JoinHandle<io::Result<u64>>,
+}
+
+impl Recorder {
+    pub fn start(options: RecordingOptions) -> Recorder {
+        let (sender, receiver) = mpsc::sync_channel(QUEUED_FRAMES);
+        let writer = {
+            let options = options.clone();
+            thread::spawn(move || write_frames(&options, receiver))
+        };
+        println!("Recording to {}", options.path.display());
+
+        Recorder {
+            options,
+            sender,
+            writer,
+        }
+    }
+
+    #[inline]
+    pub fn options(&self) -> &RecordingOptions {
+        &self.options
+    }
+
+    pub fn add_frame(&self, frame: CapturedFrame) {
+        // If the writer failed, `finish` reports why.
+        let _ = self.sender.send(frame);
+    }
+
+    /// Waits for all frames to be written.
+    pub fn finish(self) {
+        drop(self.sender);
+        match self.writer.join().unwrap() {
+            Ok(frames) => println!("Recorded {} frames to {}", frames, self.options.path.display()),
+            Err(err) => println!("Recording to {} failed: {}", self.options.path.display(), err),
+        }
+    }
+}
+
+fn write_frames(options: &RecordingOptions, receiver: Receiver<CapturedFrame>) -> io::Result<u64> {
+    let mut frames = 0;
+    let mut y4m = None;
+
+    if options.format == RecordingFormat::PngSequence {
+        fs::create_dir_all(&options.path)?;
+    }
+
+    for frame in receiver {
+        let [width, height] = frame.dimensions;
+        match options.format {
+            RecordingFormat::PngSequence => {
+                let path = options.path.join(format!("frame-{:06}.png", frames));
+                image::save_buffer(&path, &frame.pixels, width, height, image::RGBA(8))?;
+            }
+            RecordingFormat::Y4m => {
+                if y4m.is_none() {
+                    let mut file = BufWriter::new(File::create(&options.path)?);
+                    writeln!(file, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444", width, height, options.frame_rate)?;
+                    y4m = Some((file, frame.dimensions));
+                }
+                let (ref mut file, dimensions) = *y4m.as_mut().unwrap();
+                if frame.dimensions != dimensions {
+                    // A stream can't change size, the window was resized while recording.
+                    println!("Skipping frame of a different size than the recording");
+                    continue;
+                }
+                write_y4m_frame(file, &frame.pixels)?;
+            }
+        }
+        frames += 1;
+    }
+
+    if let Some((mut file, _)) = y4m {
+        file.flush()?;
+    }
+    Ok(frames)
+}
+
+/// One planar 4:4:4 frame, BT.601 limited range.
+fn write_y4m_frame<W: Write>(out: &mut W, pixels: &[u8]) -> io::Result<()> {
+    let pixel_count = pixels.len() / 4;
+    let mut planes = vec![0u8; pixel_count * 3];
+    for (i, rgba) in pixels.chunks(4).enumerate() {
+        let (r, g, b) = (rgba[0] as f32, rgba[1] as f32, rgba[2] as f32);
+        let luma = 16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0;
+        let cb = 128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0;
+        let cr = 128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0;
+        planes[i] = luma.round() as u8;
+        planes[pixel_count + i] = cb.round() as u8;
+        planes[2 * pixel_count + i] = cr.round() as u8;
+    }
+
+    out.write_all(b"FRAME\n")?;
+    out.write_all(&planes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::write_y4m_frame;
+    use super::RecordingFormat;
+    use super::RecordingOptions;
+
+    #[test]
+    fn format_follows_the_extension() {
+        let options = RecordingOptions::from_path(PathBuf::from("out/clip.y4m"), 30);
+        assert_eq!(options.format, RecordingFormat::Y4m);
+        assert_eq!(options.path, PathBuf::from("out/clip.y4m"));
+        assert_eq!(options.time_step(), 1.0 / 30.0);
+
+        for path in &["frames", "clip.png", "y4m"] {
+            let options = RecordingOptions::from_path(PathBuf::from(path), 60);
+            assert_eq!(options.format, RecordingFormat::PngSequence);
+        }
+    }
+
+    #[test]
+    fn y4m_frames_are_bt601_limited_range() {
+        let pixels = [
+            255, 255, 255, 255, // white
+            0, 0, 0, 255, // black
+            255, 0, 0, 255, // red
+            0, 0, 255, 0, // blue, alpha is ignored
+        ];
+        let mut out = Vec::new();
+        write_y4m_frame(&mut out, &pixels).unwrap();
+
+        assert_eq!(&out[..6], &b"FRAME\n"[..]);
+        let planes = &out[6..];
+        assert_eq!(planes.len(), 4 * 3);
+        assert_eq!(&planes[0..4], [235, 16, 81, 41]);
+        assert_eq!(&planes[4..8], [128, 128, 90, 240]);
+        assert_eq!(&planes[8..12], [128, 128, 240, 110]);
+    }
+
+    #[test]
+    fn y4m_chroma_is_not_subsampled() {
+        // A checkerboard keeps its full resolution in every plane.
+        let pixels: Vec<u8> = (0..4)
+            .flat_map(|i| if i % 2 == 0 { vec![255, 0, 0, 255] } else { vec![0, 255, 0, 255] })
+            .collect();
+        let mut out = Vec::new();
+        write_y4m_frame(&mut out, &pixels).unwrap();
+
+        let planes = &out[6..];
+        for plane in planes.chunks(4) {
+            assert_eq!(plane[0], plane[2]);
+            assert_eq!(plane[1], plane[3]);
+            assert_ne!(plane[0], plane[1]);
+        }
+    }
+}
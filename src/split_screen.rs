@@ -0,0 +1,333 @@
+use std::sync::Arc;
+
+use cgmath;
+use cgmath::{Matrix4, Rad, Vector3};
+use std;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+
+use camera;
+use camera::Camera;
+use frame;
+use frame::ViewportRect;
+
+/// How the window is divided between cameras.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// The whole window shows the main camera, without any viewports.
+    Single,
+    /// Perspective on the left, top view on the right.
+    SideBySide,
+    /// Perspective, top, front and side views in the four quarters of the window.
+    Quad,
+}
+
+impl Layout {
+    pub fn next(self) -> Layout {
+        match self {
+            Layout::Single => Layout::SideBySide,
+            Layout::SideBySide => Layout::Quad,
+            Layout::Quad => Layout::Single,
+        }
+    }
+
+    fn views(self) -> &'static [ViewKind] {
+        match self {
+            Layout::Single => &[],
+            Layout::SideBySide => &[ViewKind::Perspective, ViewKind::Top],
+            Layout::Quad => &[ViewKind::Perspective, ViewKind::Top, ViewKind::Front, ViewKind::Side],
+        }
+    }
+
+    /// The rectangle of each of `views` in a window of `dimensions`.
+    fn rects(self, dimensions: [u32; 2]) -> Vec<ViewportRect> {
+        let [width, height] = dimensions;
+        let (columns, rows) = match self {
+            Layout::Single => (1, 1),
+            Layout::SideBySide => (2, 1),
+            Layout::Quad => (2, 2),
+        };
+
+        // The last column and row take what's left of odd sizes.
+        let cell = |index: u32, count: u32, size: u32| {
+            let start = size * index / count;
+            let end = size * (index + 1) / count;
+            (start, (end - start).max(1))
+        };
+        (0..self.views().len() as u32)
+            .map(|index| {
+                let (x, width) = cell(index % columns, columns, width);
+                let (y, height) = cell(index / columns, rows, height);
+                ViewportRect {
+                    origin: [x, y],
+                    dimensions: [width, height],
+                }
+            })
+            .collect()
+    }
+}
+
+/// What the camera of a viewport looks at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViewKind {
+    Perspective,
+    /// Orthographic, looking down.
+    Top,
+    /// Orthographic, looking along -z.
+    Front,
+    /// Orthographic, looking along -x.
+    Side,
+}
+
+/// Half the height of the world shown by the orthographic views.
+const ORTHOGRAPHIC_EXTENT: f32 = 4.0;
+/// How far the orthographic cameras are from the origin.
+const ORTHOGRAPHIC_DISTANCE: f32 = 20.0;
+
+impl ViewKind {
+    fn name(self) -> &'static str {
+        match self {
+            ViewKind::Perspective => "Perspective",
+            ViewKind::Top => "Top",
+            ViewKind::Front => "Front",
+            ViewKind::Side => "Side",
+        }
+    }
+
+    /// The camera the view starts with, with the orientation of `main` for the perspective one.
+    fn camera(self, main: &Camera) -> Camera {
+        let mut camera = main.clone();
+        let (position, yaw, pitch) = match self {
+            ViewKind::Perspective => return camera,
+            ViewKind::Top => (Vector3::new(0.0, ORTHOGRAPHIC_DISTANCE, 0.0), -90.0, -89.0),
+            ViewKind::Front => (Vector3::new(0.0, 0.0, ORTHOGRAPHIC_DISTANCE), -90.0, 0.0),
+            ViewKind::Side => (Vector3::new(ORTHOGRAPHIC_DISTANCE, 0.0, 0.0), 180.0, 0.0),
+        };
+        camera.set_position(position);
+        camera.set_orientation(yaw, pitch);
+        camera
+    }
+
+    fn projection(self, aspect_ratio: f32) -> Matrix4<f32> {
+        match self {
+            ViewKind::Perspective => {
+                cgmath::perspective(Rad(std::f32::consts::FRAC_PI_2), aspect_ratio, 0.01, 100.0)
+            }
+            _ => {
+                let (y, x) = (ORTHOGRAPHIC_EXTENT, ORTHOGRAPHIC_EXTENT * aspect_ratio);
+                camera::orthographic(x, y, 0.01, ORTHOGRAPHIC_DISTANCE * 2.0)
+            }
+        }
+    }
+}
+
+/// A viewport of the split screen and its camera.
+pub struct View {
+    pub kind: ViewKind,
+    pub camera: Camera,
+    pub viewport: frame::Viewport,
+}
+
+/// The window's cameras, either the main one on its own or several in viewports side by side.
+/// Input goes to the camera and render settings of the viewport under the cursor.
+pub struct SplitScreen {
+    queue: Arc<Queue>,
+    depth_format: Format,
+    layout: Layout,
+    views: Vec<View>,
+    /// Views the current layout leaves out, kept with their frame systems for when a layout
+    /// shows them again.
+    parked: Vec<View>,
+    /// Index of the view under the cursor.
+    active: usize,
+    cursor: [f64; 2],
+}
+
+impl SplitScreen {
+    /// Starts with `Layout::Single`. The viewports will use `depth_format`, which should be
+    /// that of the window's frame system.
+    pub fn new(queue: Arc<Queue>, depth_format: Format) -> SplitScreen {
+        SplitScreen {
+            queue,
+            depth_format,
+            layout: Layout::Single,
+            views: Vec::new(),
+            parked: Vec::new(),
+            active: 0,
+            cursor: [0.0, 0.0],
+        }
+    }
+
+    #[inline]
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Whether frames are rendered through viewports rather than straight into the window.
+    #[inline]
+    pub fn is_split(&self) -> bool {
+        !self.views.is_empty()
+    }
+
+    #[inline]
+    pub fn views(&self) -> &[View] {
+        &self.views
+    }
+
+    #[inline]
+    pub fn views_mut(&mut self) -> &mut [View] {
+        &mut self.views
+    }
+
+    /// Switches to `layout` in a window of `dimensions`. Views shown before come back with their
+    /// render settings and cameras, new ones start out with the settings of `frame_system`.
+    /// The perspective view looks through `camera`, and leaving the split screen puts `camera`
+    /// where the perspective view was.
+    pub fn set_layout(
+        &mut self,
+        layout: Layout,
+        dimensions: [u32; 2],
+        camera: &mut Camera,
+        frame_system: &frame::FrameSystem,
+    ) {
+        if let Some(perspective) = self.views.iter().find(|view| view.kind == ViewKind::Perspective) {
+            let (yaw, pitch) = perspective.camera.orientation();
+            camera.set_position(perspective.camera.position());
+            camera.set_orientation(yaw, pitch);
+        }
+
+        let rects = layout.rects(dimensions);
+        let queue = self.queue.clone();
+        let depth_format = self.depth_format;
+        let views = {
+            let parked = &mut self.parked;
+            parked.extend(self.views.drain(..));
+            layout
+                .views()
+                .iter()
+                .zip(rects)
+                .map(|(&kind, rect)| {
+                    if let Some(index) = parked.iter().position(|view| view.kind == kind) {
+                        let mut view = parked.swap_remove(index);
+                        if kind == ViewKind::Perspective {
+                            view.camera = kind.camera(&*camera);
+                        }
+                        view.camera.projection = kind.projection(rect.aspect_ratio());
+                        view.viewport.set_rect(rect);
+                        return view;
+                    }
+
+                    let mut viewport = frame::Viewport::new(queue.clone(), rect, depth_format);
+                    {
+                        let settings = viewport.frame_system_mut().settings_mut();
+                        *settings = frame_system.settings().clone();
+                        // The depth pyramid is only good for the camera it was built with, so a
+                        // scene shared by the viewports can only be occlusion culled in one of them.
+                        if kind != ViewKind::Perspective {
+                            settings.culling.occlusion = false;
+                        }
+                    }
+                    let mut view_camera = kind.camera(&*camera);
+                    view_camera.projection = kind.projection(rect.aspect_ratio());
+                    View {
+                        kind,
+                        camera: view_camera,
+                        viewport,
+                    }
+                })
+                .collect()
+        };
+        self.views = views;
+        self.layout = layout;
+        self.update_active();
+    }
+
+    /// Fits the viewports into a window of `dimensions`, e.g. after the swapchain was recreated.
+    pub fn resize(&mut self, dimensions: [u32; 2]) {
+        for (view, rect) in self.views.iter_mut().zip(self.layout.rects(dimensions)) {
+            view.viewport.set_rect(rect);
+            view.camera.projection = view.kind.projection(rect.aspect_ratio());
+        }
+        self.update_active();
+    }
+
+    /// Follows the cursor, `position` being in window pixels.
+    pub fn set_cursor(&mut self, position: [f64; 2]) {
+        self.cursor = position;
+        self.update_active();
+    }
+
+    fn update_active(&mut self) {
+        let cursor = self.cursor;
+        if let Some(index) = self.views.iter().position(|view| view.viewport.rect().contains(cursor)) {
+            self.active = index;
+        }
+        self.active = self.active.min(self.views.len().saturating_sub(1));
+    }
+
+    /// The view under the cursor, `None` with `Layout::Single`.
+    pub fn active_view_mut(&mut self) -> Option<&mut View> {
+        self.views.get_mut(self.active)
+    }
+
+    /// Labels every view with its name, the one getting input highlighted.
+    pub fn labels(&self) -> Vec<frame::TextItem> {
+        self.views
+            .iter()
+            .enumerate()
+            .map(|(index, view)| {
+                let [x, y] = view.viewport.rect().origin;
+                let color = if index == self.active {
+                    [1.0, 0.8, 0.2, 1.0]
+                } else {
+                    [0.8, 0.8, 0.8, 1.0]
+                };
+                frame::TextItem::new(view.kind.name())
+                    .position(x as f32 + 10.0, y as f32 + 10.0)
+                    .size(16.0)
+                    .color(color)
+                    .background([0.0, 0.0, 0.0, 0.5])
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Layout;
+
+    #[test]
+    fn single_has_no_viewports() {
+        assert!(Layout::Single.rects([640, 480]).is_empty());
+    }
+
+    #[test]
+    fn rects_tile_the_window() {
+        for &layout in &[Layout::SideBySide, Layout::Quad] {
+            for &dimensions in &[[640, 480], [641, 481], [1, 1]] {
+                let rects = layout.rects(dimensions);
+                assert_eq!(rects.len(), layout.views().len());
+                let [width, height] = dimensions;
+                for y in 0..height {
+                    for x in 0..width {
+                        let position = [x as f64 + 0.5, y as f64 + 0.5];
+                        let covering = rects.iter().filter(|rect| rect.contains(position)).count();
+                        // Windows smaller than the grid can't give every rectangle a pixel.
+                        if width >= 2 && height >= 2 {
+                            assert_eq!(covering, 1, "{:?} {:?} at {:?}", layout, dimensions, position);
+                        } else {
+                            assert!(covering >= 1);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn side_by_side_splits_odd_widths() {
+        let rects = Layout::SideBySide.rects([641, 480]);
+        assert_eq!((rects[0].origin, rects[0].dimensions), ([0, 0], [320, 480]));
+        assert_eq!((rects[1].origin, rects[1].dimensions), ([320, 0], [321, 480]));
+    }
+}
@@ -1,8 +1,9 @@
 use vulkano::device::{Device, DeviceExtensions, Queue};
+use vulkano::image::ImageUsage;
 use vulkano::image::SwapchainImage;
 use vulkano::instance;
 use vulkano::instance::{
-    ApplicationInfo, Instance, PhysicalDevice, QueueFamily,
+    ApplicationInfo, Features, Instance, InstanceExtensions, PhysicalDevice, QueueFamily,
 };
 use vulkano::swapchain::PresentMode;
 use vulkano::swapchain::{Surface, SurfaceTransform, Swapchain};
@@ -28,7 +29,11 @@ impl<'a> Scene<'a> {
     pub fn new(instance: &'a Arc<Instance>) -> Scene<'a> {
         let physical = get_physical_device(&instance);
         let queue_family = get_queue_family(&physical);
-        let (device, queue) = initialize_device_and_queues(&physical, queue_family);
+        let device_extensions = DeviceExtensions {
+            khr_swapchain: true,
+            ..DeviceExtensions::none()
+        };
+        let (device, queue) = initialize_device_and_queues(&physical, queue_family, &device_extensions);
         let (events_loop, window) = initialize_events_loop_and_window(&instance);
         let (swapchain, images) = initialize_swapchain(&window, &physical, &device, &queue);
 
@@ -45,19 +50,49 @@ impl<'a> Scene<'a> {
     }
 }
 
+/// A device and queue without any window, surface or swapchain, for rendering offscreen.
+pub struct HeadlessScene<'a> {
+    instance: Arc<Instance>,
+    physical: PhysicalDevice<'a>,
+    pub device: Arc<Device>,
+    pub queue: Arc<Queue>,
+}
+
+impl<'a> HeadlessScene<'a> {
+    pub fn new(instance: &'a Arc<Instance>) -> HeadlessScene<'a> {
+        let physical = get_physical_device(&instance);
+        let queue_family = get_queue_family(&physical);
+        let (device, queue) = initialize_device_and_queues(&physical, queue_family, &DeviceExtensions::none());
+
+        HeadlessScene {
+            instance: instance.clone(),
+            physical,
+            device,
+            queue,
+        }
+    }
+}
+
 pub fn initialize_instance() -> Arc<Instance> {
+    create_instance(&vulkano_win::required_extensions())
+}
+
+/// An instance without the surface extensions, so it works where there is no display.
+pub fn initialize_headless_instance() -> Arc<Instance> {
+    create_instance(&InstanceExtensions::none())
+}
+
+fn create_instance(extensions: &InstanceExtensions) -> Arc<Instance> {
     println!("Creating Vulkan instance");
     print_layer_list();
 
     let mut app_info = ApplicationInfo::default();
     app_info.application_name = Some(Cow::from("Vulkano test"));
 
-    let extensions = vulkano_win::required_extensions();
-
     // let layer = "VK_LAYER_LUNARG_standard_validation";
     let layers = vec![];
 
-    Instance::new(Some(&app_info), &extensions, layers).expect("Failed to create instance")
+    Instance::new(Some(&app_info), extensions, layers).expect("Failed to create instance")
 }
 
 fn print_layer_list() {
@@ -105,9 +140,10 @@ fn get_queue_family<'a>(physical: &'a PhysicalDevice) -> QueueFamily<'a> {
     physical
         .queue_families()
         .find(|&q| {
-            q.supports_graphics() // TODO: window supported
+            // Compute passes are recorded into the same command buffers as the rendering.
+            q.supports_graphics() && q.supports_compute() // TODO: window supported
         })
-        .expect("Coulnd't find a graphical queue")
+        .expect("Coulnd't find a queue supporting graphics and compute")
 }
 
 fn print_queue_families(physical: &PhysicalDevice) {
@@ -139,18 +175,23 @@ fn print_queue_families(physical: &PhysicalDevice) {
 fn initialize_device_and_queues<'a>(
     physical: &'a PhysicalDevice,
     queue_family: QueueFamily,
+    device_extensions: &DeviceExtensions,
 ) -> (Arc<Device>, Arc<Queue>) {
-    let (device, mut queues) = {
-        // TODO: Look into extensions
-        let device_extensions = DeviceExtensions {
-            khr_swapchain: true,
-            ..DeviceExtensions::none()
-        };
+    // Only what the renderer uses, some features like robust buffer access cost performance.
+    // Wireframes fall back to filled triangles without `fill_mode_non_solid`, and the weighted
+    // blended transparency pipeline blends its two attachments differently.
+    let supported = physical.supported_features();
+    let features = Features {
+        fill_mode_non_solid: supported.fill_mode_non_solid,
+        independent_blend: supported.independent_blend,
+        ..Features::none()
+    };
 
+    let (device, mut queues) = {
         Device::new(
             *physical,
-            physical.supported_features(),
-            &device_extensions,
+            &features,
+            device_extensions,
             [(queue_family, 0.5)].iter().cloned(),
         ).expect("Failed to create device")
     };
@@ -196,6 +237,14 @@ fn initialize_swapchain<'a>(
         // TODO: Select best format?
         let format = caps.supported_formats[0].0;
 
+        // Screenshots copy the swapchain images, which not every surface allows. The frame
+        // system checks the images and refuses screenshots if it can't.
+        let usage = ImageUsage {
+            color_attachment: true,
+            transfer_source: caps.supported_usage_flags.transfer_source,
+            ..ImageUsage::none()
+        };
+
         Swapchain::new(
             device.clone(),
             window.clone(),
@@ -203,7 +252,7 @@ fn initialize_swapchain<'a>(
             format,
             dimensions,
             1,
-            caps.supported_usage_flags,
+            usage,
             queue,
             SurfaceTransform::Identity,
             alpha,
@@ -0,0 +1,376 @@
+//! Golden image tests: renders presets headlessly and compares them with the references in
+//! `tests/golden`.
+//!
+//! Needs a Vulkan implementation, a CPU one like lavapipe works:
+//!
+//! ```text
+//! VK_ICD_FILENAMES=/usr/share/vulkan/icd.d/lvp_icd.x86_64.json cargo test --test golden -- --ignored
+//! ```
+//!
+//! The rendering tests are ignored by default so a plain `cargo test` doesn't need a device,
+//! and fail when run without one.
+//!
+//! Run with `GOLDEN_BLESS=1` to (re)write the references after an intended change. On failure,
+//! the rendered image and a diff are written to `target/golden`.
+
+extern crate image;
+extern crate vulkano;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use image::{Rgba, RgbaImage};
+use vulkano::instance::Instance;
+use vulkano::instance::InstanceExtensions;
+use vulkano::instance::PhysicalDevice;
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+/// A few frames, so anything depending on the previous frame settles.
+const FRAMES: u32 = 3;
+
+/// Largest difference in any channel for a pixel to still count as equal.
+const CHANNEL_TOLERANCE: u8 = 8;
+/// Fraction of pixels allowed to differ by more than `CHANNEL_TOLERANCE`.
+const MAX_DIFFERING_PIXELS: f64 = 0.002;
+/// Lowest accepted mean structural similarity of the luminance.
+const MIN_SSIM: f64 = 0.98;
+
+const SSIM_WINDOW: u32 = 8;
+
+fn references_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn output_dir() -> PathBuf {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join("golden");
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The binary sits next to the directory the test executable is in.
+fn renderer_binary() -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join(format!("vulkan_test{}", env::consts::EXE_SUFFIX))
+}
+
+/// Whether there's a device to render with, e.g. not on CI machines without a Vulkan loader.
+fn vulkan_available() -> bool {
+    match Instance::new(None, &InstanceExtensions::none(), None) {
+        Ok(instance) => PhysicalDevice::enumerate(&instance).next().is_some(),
+        Err(_) => false,
+    }
+}
+
+fn render(preset: &str) -> RgbaImage {
+    let output = output_dir().join(format!("{}.png", preset));
+    let status = Command::new(renderer_binary())
+        .arg("--headless")
+        .args(&["--size", &format!("{}x{}", WIDTH, HEIGHT)])
+        .args(&["--frames", &FRAMES.to_string()])
+        .args(&["--preset", preset])
+        .arg("--output")
+        .arg(&output)
+        .status()
+        .expect("Failed to run the renderer, was it built?");
+    assert!(status.success(), "Rendering preset {} failed", preset);
+
+    image::open(&output).unwrap().to_rgba()
+}
+
+fn check_golden(preset: &str) {
+    assert!(vulkan_available(), "No Vulkan device available to render {} with", preset);
+
+    let actual = render(preset);
+    let reference_path = references_dir().join(format!("{}.png", preset));
+
+    if env::var_os("GOLDEN_BLESS").is_some() {
+        fs::create_dir_all(references_dir()).unwrap();
+        actual.save(&reference_path).unwrap();
+        return;
+    }
+
+    let reference = match image::open(&reference_path) {
+        Ok(reference) => reference.to_rgba(),
+        Err(err) => panic!(
+            "No reference for {} at {} ({}), run with GOLDEN_BLESS=1 to create it",
+            preset,
+            reference_path.display(),
+            err
+        ),
+    };
+    assert_eq!(
+        reference.dimensions(),
+        actual.dimensions(),
+        "Reference for {} has the wrong size",
+        preset
+    );
+
+    let comparison = compare(&reference, &actual);
+    if comparison.differing_pixels > MAX_DIFFERING_PIXELS || comparison.ssim < MIN_SSIM {
+        let diff_path = output_dir().join(format!("{}-diff.png", preset));
+        comparison.diff.save(&diff_path).unwrap();
+        panic!(
+            "{} differs from its reference: {:.3}% of pixels off by more than {}, SSIM {:.4}. \
+             Diff written to {}",
+            preset,
+            comparison.differing_pixels * 100.0,
+            CHANNEL_TOLERANCE,
+            comparison.ssim,
+            diff_path.display()
+        );
+    }
+}
+
+struct Comparison {
+    /// Fraction of pixels outside the tolerance.
+    differing_pixels: f64,
+    ssim: f64,
+    /// Darkened reference, with the pixels outside the tolerance in red.
+    diff: RgbaImage,
+}
+
+fn compare(reference: &RgbaImage, actual: &RgbaImage) -> Comparison {
+    let (width, height) = reference.dimensions();
+    let mut diff = RgbaImage::new(width, height);
+    let mut differing = 0u32;
+
+    for (x, y, expected) in reference.enumerate_pixels() {
+        let got = actual.get_pixel(x, y);
+        let max_difference = (0..3)
+            .map(|c| (i32::from(expected.data[c]) - i32::from(got.data[c])).abs())
+            .max()
+            .unwrap() as u8;
+
+        let pixel = if max_difference > CHANNEL_TOLERANCE {
+            differing += 1;
+            Rgba {
+                data: [255, 0, 0, 255],
+            }
+        } else {
+            let gray = (luminance(*expected) * 255.0 / 4.0) as u8;
+            Rgba {
+                data: [gray, gray, gray, 255],
+            }
+        };
+        diff.put_pixel(x, y, pixel);
+    }
+
+    Comparison {
+        differing_pixels: f64::from(differing) / f64::from(width * height),
+        ssim: mean_ssim(reference, actual),
+        diff,
+    }
+}
+
+fn luminance(pixel: Rgba<u8>) -> f64 {
+    let [r, g, b, _] = pixel.data;
+    (0.2126 * f64::from(r) + 0.7152 * f64::from(g) + 0.0722 * f64::from(b)) / 255.0
+}
+
+/// Structural similarity of the luminance, averaged over non-overlapping windows.
+fn mean_ssim(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    const C1: f64 = 0.01 * 0.01;
+    const C2: f64 = 0.03 * 0.03;
+
+    let (width, height) = a.dimensions();
+    let mut total = 0.0;
+    let mut windows = 0u32;
+
+    for window_y in 0..height / SSIM_WINDOW {
+        for window_x in 0..width / SSIM_WINDOW {
+            let samples: Vec<(f64, f64)> = (0..SSIM_WINDOW * SSIM_WINDOW)
+                .map(|i| {
+                    let x = window_x * SSIM_WINDOW + i % SSIM_WINDOW;
+                    let y = window_y * SSIM_WINDOW + i / SSIM_WINDOW;
+                    (luminance(*a.get_pixel(x, y)), luminance(*b.get_pixel(x, y)))
+                })
+                .collect();
+            let n = samples.len() as f64;
+
+            let mean_a = samples.iter().map(|s| s.0).sum::<f64>() / n;
+            let mean_b = samples.iter().map(|s| s.1).sum::<f64>() / n;
+            let var_a = samples.iter().map(|s| (s.0 - mean_a).powi(2)).sum::<f64>() / n;
+            let var_b = samples.iter().map(|s| (s.1 - mean_b).powi(2)).sum::<f64>() / n;
+            let covariance = samples
+                .iter()
+                .map(|s| (s.0 - mean_a) * (s.1 - mean_b))
+                .sum::<f64>() / n;
+
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+
+    total / f64::from(windows)
+}
+
+#[test]
+#[ignore]
+fn golden_default() {
+    check_golden("default");
+}
+
+#[test]
+#[ignore]
+fn golden_no_bloom() {
+    check_golden("no-bloom");
+}
+
+#[test]
+#[ignore]
+fn golden_reinhard() {
+    check_golden("reinhard");
+}
+
+#[test]
+#[ignore]
+fn golden_uncharted2() {
+    check_golden("uncharted2");
+}
+
+#[test]
+#[ignore]
+fn golden_overexposed() {
+    check_golden("overexposed");
+}
+
+#[test]
+#[ignore]
+fn golden_sky() {
+    check_golden("sky");
+}
+
+#[test]
+#[ignore]
+fn golden_oit() {
+    check_golden("oit");
+}
+
+#[test]
+#[ignore]
+fn golden_normals() {
+    check_golden("normals");
+}
+
+#[test]
+#[ignore]
+fn golden_gradient() {
+    check_golden("gradient");
+}
+
+#[test]
+#[ignore]
+fn golden_outline() {
+    check_golden("outline");
+}
+
+#[test]
+#[ignore]
+fn golden_ambient_occlusion() {
+    check_golden("ambient-occlusion");
+}
+
+#[test]
+#[ignore]
+fn golden_no_ssao() {
+    check_golden("no-ssao");
+}
+
+#[test]
+#[ignore]
+fn golden_gpu_driven() {
+    check_golden("gpu-driven");
+}
+
+#[test]
+#[ignore]
+fn golden_render_targets() {
+    check_golden("render-targets");
+}
+
+#[test]
+#[ignore]
+fn golden_waves() {
+    check_golden("waves");
+}
+
+fn gradient(width: u32, height: u32) -> RgbaImage {
+    RgbaImage::from_fn(width, height, |x, y| {
+        let value = ((x + y) * 255 / (width + height - 2)) as u8;
+        Rgba {
+            data: [value, value, value, 255],
+        }
+    })
+}
+
+#[test]
+fn compare_identical_images() {
+    let image = gradient(32, 16);
+    let comparison = compare(&image, &image);
+    assert!(comparison.differing_pixels.abs() < 1e-12);
+    assert!((comparison.ssim - 1.0).abs() < 1e-9);
+    assert!(comparison.diff.pixels().all(|pixel| pixel.data[0] == pixel.data[1]));
+}
+
+#[test]
+fn compare_marks_pixels_outside_the_tolerance() {
+    let reference = gradient(32, 16);
+    let mut actual = reference.clone();
+    actual.get_pixel_mut(3, 4).data[1] += CHANNEL_TOLERANCE;
+    actual.get_pixel_mut(5, 6).data[2] -= CHANNEL_TOLERANCE + 1;
+
+    let comparison = compare(&reference, &actual);
+    assert!((comparison.differing_pixels - 1.0 / (32.0 * 16.0)).abs() < 1e-12);
+    assert_eq!(comparison.diff.get_pixel(5, 6).data, [255, 0, 0, 255]);
+    assert_ne!(comparison.diff.get_pixel(3, 4).data, [255, 0, 0, 255]);
+}
+
+#[test]
+fn compare_ignores_alpha() {
+    let reference = gradient(16, 16);
+    let mut actual = reference.clone();
+    for pixel in actual.pixels_mut() {
+        pixel.data[3] = 0;
+    }
+    assert!(compare(&reference, &actual).differing_pixels.abs() < 1e-12);
+}
+
+#[test]
+fn ssim_drops_with_structure() {
+    let reference = gradient(64, 64);
+    let inverted = RgbaImage::from_fn(64, 64, |x, y| {
+        let [r, g, b, a] = reference.get_pixel(x, y).data;
+        Rgba {
+            data: [255 - r, 255 - g, 255 - b, a],
+        }
+    });
+    let brighter = RgbaImage::from_fn(64, 64, |x, y| {
+        let [r, g, b, a] = reference.get_pixel(x, y).data;
+        Rgba {
+            data: [r.saturating_add(2), g.saturating_add(2), b.saturating_add(2), a],
+        }
+    });
+
+    let slightly_off = mean_ssim(&reference, &brighter);
+    assert!(slightly_off < 1.0 && slightly_off > MIN_SSIM);
+    assert!(mean_ssim(&reference, &inverted) < 0.0);
+}
+
+#[test]
+fn ssim_of_flat_images_follows_their_mean() {
+    let flat = |value| {
+        RgbaImage::from_pixel(16, 16, Rgba {
+            data: [value, value, value, 255],
+        })
+    };
+    assert!((mean_ssim(&flat(128), &flat(128)) - 1.0).abs() < 1e-9);
+    assert!(mean_ssim(&flat(0), &flat(255)) < 0.01);
+}